@@ -24,18 +24,18 @@ async fn main() -> Result<(), anyhow::Error> {
     let collector = Registry::default().with(logger).with(env_filter);
     tracing::subscriber::set_global_default(collector).unwrap();
 
-    let smart_home_api =
-        smarthome::get_smart_home_api(config.clone()).context("Smart home API init failed")?;
+    let backends =
+        Arc::new(smarthome::Backends::new(config.clone()).context("Smart home API init failed")?);
 
     let health_join_handle = if config.health_check.enable_server {
         let c = config.clone();
-        tokio::spawn(health_check::run(c))
+        tokio::spawn(health_check::run(c, backends.clone()))
     } else {
         tokio::spawn(pending())
     };
 
     tracing::info!("Starting controller");
-    let controller_join_handle = tokio::spawn(run(config, smart_home_api));
+    let controller_join_handle = tokio::spawn(run(config, backends));
 
     tokio::select! {
         res = controller_join_handle => match res {
@@ -0,0 +1,45 @@
+use anyhow::Context;
+use config::{Environment, File, FileFormat};
+use light_operator::config::Config;
+use light_operator::smarthome;
+use std::sync::Arc;
+
+/// Print the devices the configured smart home platform can find, with the
+/// IDs to use in Light resources. Pass a backend name to list the devices of
+/// one of the named backends instead of the default one.
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let backend = std::env::args().nth(1);
+
+    let conf = config::Config::builder()
+        .add_source(File::new("config", FileFormat::Yaml).required(true))
+        .add_source(File::new("config.local", FileFormat::Yaml).required(false))
+        .add_source(Environment::default().separator("__").prefix("LO"))
+        .build()
+        .expect("Configuration parsing failed");
+
+    let mut config: Config = conf.try_deserialize().unwrap();
+    if let Some(backend) = &backend {
+        config = config
+            .for_backend(backend)
+            .with_context(|| format!("Backend {backend} is not configured"))?;
+    }
+
+    let smart_home_api =
+        smarthome::get_smart_home_api(Arc::new(config)).context("Smart home API init failed")?;
+
+    let devices = smart_home_api
+        .list_devices()
+        .await
+        .context("Listing devices failed")?;
+
+    for device in devices {
+        println!(
+            "{}\t{}\t{}",
+            device.id,
+            device.name.as_deref().unwrap_or("-"),
+            device.model.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
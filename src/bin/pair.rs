@@ -0,0 +1,40 @@
+use anyhow::{bail, Context};
+use config::{Environment, File, FileFormat};
+use light_operator::config::Config;
+use light_operator::smarthome;
+use std::sync::Arc;
+
+/// Pair with a device or bridge of the configured smart home platform and
+/// print the credentials to add to the configuration. Pass a backend name
+/// before the address to pair with a device of one of the named backends
+/// instead of the default one.
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (backend, address) = match args.as_slice() {
+        [address] => (None, address),
+        [backend, address] => (Some(backend), address),
+        _ => bail!("Usage: pair [backend] <device address>"),
+    };
+
+    let conf = config::Config::builder()
+        .add_source(File::new("config", FileFormat::Yaml).required(true))
+        .add_source(File::new("config.local", FileFormat::Yaml).required(false))
+        .add_source(Environment::default().separator("__").prefix("LO"))
+        .build()
+        .expect("Configuration parsing failed");
+
+    let mut config: Config = conf.try_deserialize().unwrap();
+    if let Some(backend) = backend {
+        config = config
+            .for_backend(backend)
+            .with_context(|| format!("Backend {backend} is not configured"))?;
+    }
+
+    let credentials = smarthome::pair(Arc::new(config), address)
+        .await
+        .context("Pairing failed")?;
+
+    println!("{credentials}");
+    Ok(())
+}
@@ -1,33 +1,731 @@
+use std::collections::HashMap;
+
 use serde::Deserialize;
 
-#[derive(Default, Deserialize)]
+#[derive(Default, Deserialize, Clone)]
 pub enum SmartHomePlatform {
     #[default]
     SmartThings,
+    Hue,
+    HomeAssistant,
+    Zigbee2Mqtt,
+    Lifx,
+    Wled,
+    Shelly,
+    Tasmota,
+    Deconz,
+    Yeelight,
+    Kasa,
+    Nanoleaf,
+    Esphome,
+    Dmx,
+    Openhab,
+    Govee,
+    Dirigera,
+    Hubitat,
+    Knx,
+    Elgato,
+    Http,
+    Plugin,
+    Simulated,
+    Tuya,
+    /// No default backend, for using only the named backends
+    None,
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Default, Deserialize, Clone)]
 pub struct SmartHomeConfig {
     pub platform: SmartHomePlatform,
+    #[serde(default)]
     pub smartthings: SmartThingsConfig,
+    #[serde(default)]
+    pub hue: HueConfig,
+    #[serde(default)]
+    pub home_assistant: HomeAssistantConfig,
+    #[serde(default)]
+    pub zigbee2mqtt: Zigbee2MqttConfig,
+    #[serde(default)]
+    pub lifx: LifxConfig,
+    #[serde(default)]
+    pub wled: WledConfig,
+    #[serde(default)]
+    pub shelly: ShellyConfig,
+    #[serde(default)]
+    pub tasmota: TasmotaConfig,
+    #[serde(default)]
+    pub deconz: DeconzConfig,
+    #[serde(default)]
+    pub yeelight: YeelightConfig,
+    #[serde(default)]
+    pub kasa: KasaConfig,
+    #[serde(default)]
+    pub nanoleaf: NanoleafConfig,
+    #[serde(default)]
+    pub esphome: EsphomeConfig,
+    #[serde(default)]
+    pub dmx: DmxConfig,
+    #[serde(default)]
+    pub openhab: OpenhabConfig,
+    #[serde(default)]
+    pub govee: GoveeConfig,
+    #[serde(default)]
+    pub dirigera: DirigeraConfig,
+    #[serde(default)]
+    pub hubitat: HubitatConfig,
+    #[serde(default)]
+    pub knx: KnxConfig,
+    #[serde(default)]
+    pub elgato: ElgatoConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub plugin: PluginConfig,
+    #[serde(default)]
+    pub simulated: SimulatedConfig,
+    #[serde(default)]
+    pub tuya: TuyaConfig,
+    /// Additional backends that Lights can select by name
+    #[serde(default)]
+    pub backends: Vec<NamedBackendConfig>,
+}
+
+/// A backend configured like `smart_home`, with a name
+#[derive(Deserialize, Clone)]
+pub struct NamedBackendConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub smart_home: SmartHomeConfig,
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Default, Deserialize, Clone)]
 pub struct SmartThingsConfig {
     pub api_token: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Default, Deserialize, Clone)]
+pub struct HueConfig {
+    /// Host name or IP address of the Hue bridge
+    pub bridge_address: Option<String>,
+    pub application_key: Option<String>,
+    /// PEM file of the CA that signed the bridge certificate
+    pub ca_certificate_file: Option<String>,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+}
+
+#[derive(Default, Deserialize, Clone)]
+pub struct HomeAssistantConfig {
+    /// Home Assistant URL, e.g. http://homeassistant.local:8123
+    pub base_url: Option<String>,
+    /// Long-lived access token
+    pub access_token: Option<String>,
+    /// Subscribe to state changes over the websocket API instead of polling
+    #[serde(default)]
+    pub websocket_events: bool,
+}
+
+#[derive(Default, Deserialize, Clone)]
+pub struct MqttConfig {
+    /// Broker URL, mqtt://host:port or mqtts://host:port for TLS
+    pub broker_url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// PEM file of the CA that signed the broker certificate. The system
+    /// CAs are used if not set.
+    pub ca_certificate_file: Option<String>,
+    /// Defaults to a platform specific ID, followed by the backend name for
+    /// named backends
+    pub client_id: Option<String>,
+    /// Name of the backend, which is appended to the default client ID so
+    /// that backends on the same broker don't disconnect each other
+    #[serde(skip)]
+    pub backend_name: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Zigbee2MqttConfig {
+    pub mqtt: MqttConfig,
+    /// Zigbee2MQTT `mqtt.base_topic` setting
+    pub base_topic: String,
+}
+
+impl Default for Zigbee2MqttConfig {
+    fn default() -> Self {
+        Self {
+            mqtt: Default::default(),
+            base_topic: "zigbee2mqtt".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LifxConfig {
+    /// Address discovery broadcasts are sent to
+    pub broadcast_address: String,
+    pub port: u16,
+    /// How long to wait for bulbs to answer, in milliseconds
+    pub timeout_ms: u64,
+    /// Bulbs with a fixed address, which don't need to be discovered
+    pub devices: Vec<LifxDeviceConfig>,
+}
+
+impl Default for LifxConfig {
+    fn default() -> Self {
+        Self {
+            broadcast_address: "255.255.255.255".to_string(),
+            port: 56700,
+            timeout_ms: 1000,
+            devices: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct LifxDeviceConfig {
+    /// MAC address of the bulb
+    pub id: String,
+    /// IP address, optionally with a port
+    pub address: String,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct WledConfig {
+    /// Controllers that don't answer within this time are reported offline
+    pub timeout_ms: u64,
+}
+
+impl Default for WledConfig {
+    fn default() -> Self {
+        Self { timeout_ms: 3000 }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ShellyConfig {
+    /// Password of devices with authentication enabled
+    pub password: Option<String>,
+    /// Devices that don't answer within this time are reported offline
+    pub timeout_ms: u64,
+}
+
+impl Default for ShellyConfig {
+    fn default() -> Self {
+        Self {
+            password: None,
+            timeout_ms: 3000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct TasmotaConfig {
+    /// Broker of devices controlled over MQTT
+    pub mqtt: MqttConfig,
+    /// Web admin password of devices controlled over HTTP
+    pub password: Option<String>,
+    /// Devices that don't answer within this time are reported offline
+    pub timeout_ms: u64,
+    pub devices: Vec<TasmotaDeviceConfig>,
+}
+
+impl Default for TasmotaConfig {
+    fn default() -> Self {
+        Self {
+            mqtt: Default::default(),
+            password: None,
+            timeout_ms: 3000,
+            devices: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum TasmotaTransport {
+    Http,
+    Mqtt,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TasmotaDeviceConfig {
+    /// Device ID used in Light resources
+    pub id: String,
+    pub transport: TasmotaTransport,
+    /// Host name or IP address, required for HTTP
+    pub host: Option<String>,
+    /// MQTT topic of the device, defaults to the ID
+    pub topic: Option<String>,
+}
+
+#[derive(Default, Deserialize, Clone)]
+pub struct DeconzConfig {
+    /// deCONZ REST API URL, e.g. http://phoscon.local
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    /// Listen to state changes over the websocket API instead of polling
+    #[serde(default)]
+    pub websocket_events: bool,
+    /// Websocket port, read from the gateway configuration if not set
+    pub websocket_port: Option<u16>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct YeelightConfig {
+    /// Bulbs that don't answer within this time are reported offline. Also
+    /// used as the discovery time.
+    pub timeout_ms: u64,
+    /// Commands sent to a bulb per minute. Bulbs reject commands over their
+    /// quota of 60 per minute.
+    pub commands_per_minute: usize,
+}
+
+impl Default for YeelightConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 3000,
+            commands_per_minute: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct KasaConfig {
+    /// Address discovery broadcasts are sent to
+    pub broadcast_address: String,
+    /// Devices that don't answer within this time are reported offline. Also
+    /// used as the discovery time.
+    pub timeout_ms: u64,
+}
+
+impl Default for KasaConfig {
+    fn default() -> Self {
+        Self {
+            broadcast_address: "255.255.255.255".to_string(),
+            timeout_ms: 3000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct NanoleafConfig {
+    /// Devices that don't answer within this time are reported offline
+    pub timeout_ms: u64,
+    pub devices: Vec<NanoleafDeviceConfig>,
+}
+
+impl Default for NanoleafConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 3000,
+            devices: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct NanoleafDeviceConfig {
+    /// Host name or IP address of the controller, optionally with a port
+    pub id: String,
+    /// Token from pairing with the controller
+    pub auth_token: String,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct EsphomeConfig {
+    /// Devices that don't connect within this time are reported offline
+    pub timeout_ms: u64,
+    /// API encryption key of devices that are not listed in `devices`
+    pub encryption_key: Option<String>,
+    /// API password of devices that are not listed in `devices`
+    pub password: Option<String>,
+    pub devices: Vec<EsphomeDeviceConfig>,
+}
+
+impl Default for EsphomeConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 3000,
+            encryption_key: None,
+            password: None,
+            devices: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EsphomeDeviceConfig {
+    /// Host name or IP address of the device, optionally with a port
+    pub id: String,
+    /// Base64 `api.encryption.key` of the device, if it uses encryption
+    pub encryption_key: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct DmxConfig {
+    pub protocol: DmxProtocol,
+    /// Node the packets are sent to. Art-Net is broadcast and sACN is sent to
+    /// the multicast address of each universe if not set.
+    pub destination: Option<String>,
+    /// Frames sent to each universe per second
+    pub frame_rate: u32,
+    /// Source name of sACN packets
+    pub source_name: String,
+    pub fixtures: Vec<DmxFixtureConfig>,
+}
+
+impl Default for DmxConfig {
+    fn default() -> Self {
+        Self {
+            protocol: DmxProtocol::ArtNet,
+            destination: None,
+            frame_rate: 30,
+            source_name: "light-operator".to_string(),
+            fixtures: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DmxProtocol {
+    ArtNet,
+    Sacn,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DmxChannelLayout {
+    /// Intensity
+    Dimmer,
+    /// Red, green, blue
+    Rgb,
+    /// Red, green, blue, white
+    Rgbw,
+    /// Warm white, cold white
+    Cct,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DmxFixtureConfig {
+    /// Device ID used in Light resources
+    pub id: String,
+    /// Art-Net port address or sACN universe
+    pub universe: u16,
+    /// First channel of the fixture, 1-512
+    pub address: u16,
+    pub layout: DmxChannelLayout,
+    /// Color temperature of the warm white channel of CCT fixtures,
+    /// 2700 K if not set
+    pub min_kelvin: Option<u16>,
+    /// Color temperature of the cold white channel of CCT fixtures,
+    /// 6500 K if not set
+    pub max_kelvin: Option<u16>,
+}
+
+#[derive(Default, Deserialize, Clone)]
+pub struct OpenhabConfig {
+    /// openHAB URL, e.g. http://openhab.local:8080
+    pub base_url: Option<String>,
+    /// API token, not needed if openHAB allows anonymous access
+    pub api_token: Option<String>,
+    /// Listen to item state changes over the SSE event stream instead of
+    /// polling
+    #[serde(default)]
+    pub sse_events: bool,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct GoveeConfig {
+    /// Devices that don't answer within this time are reported offline. Also
+    /// used as the discovery time.
+    pub timeout_ms: u64,
+}
+
+impl Default for GoveeConfig {
+    fn default() -> Self {
+        Self { timeout_ms: 2000 }
+    }
+}
+
+#[derive(Default, Deserialize, Clone)]
+pub struct DirigeraConfig {
+    /// Host name or IP address of the hub, optionally with a port
+    pub hub_address: Option<String>,
+    /// Token from pairing with the hub
+    pub access_token: Option<String>,
+    /// SHA-256 fingerprint of the hub certificate in hex, optionally with
+    /// colons between bytes
+    pub certificate_sha256: Option<String>,
+}
+
+#[derive(Default, Deserialize, Clone)]
+pub struct HubitatConfig {
+    /// Hub URL, e.g. http://hubitat.local
+    pub base_url: Option<String>,
+    /// ID of the Maker API app instance
+    pub app_id: Option<String>,
+    /// Access token of the Maker API app instance
+    pub access_token: Option<String>,
+    /// URL of the event receiver on the health check server, as reachable
+    /// from the hub. Device events are posted there instead of polling.
+    pub event_url: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct KnxConfig {
+    /// Host name or IP address of the KNXnet/IP interface, optionally with a
+    /// port
+    pub gateway: Option<String>,
+    /// Lights whose switch status is not answered within this time are
+    /// reported offline
+    pub timeout_ms: u64,
+    pub devices: Vec<KnxDeviceConfig>,
+}
+
+impl Default for KnxConfig {
+    fn default() -> Self {
+        Self {
+            gateway: None,
+            timeout_ms: 2000,
+            devices: Vec::new(),
+        }
+    }
+}
+
+/// Group addresses of a light, e.g. `1/2/3`. Status addresses are read
+/// instead of the addresses that values are written to, when set.
+#[derive(Deserialize, Clone)]
+pub struct KnxDeviceConfig {
+    pub id: String,
+    /// DPT 1.001
+    pub switch: String,
+    pub switch_status: Option<String>,
+    /// DPT 5.001
+    pub brightness: Option<String>,
+    pub brightness_status: Option<String>,
+    /// DPT 7.600
+    pub color_temperature: Option<String>,
+    pub color_temperature_status: Option<String>,
+    /// DPT 232.600
+    pub rgb: Option<String>,
+    pub rgb_status: Option<String>,
+    /// DPT 5.003, used with `saturation` when there is no RGB address
+    pub hue: Option<String>,
+    pub hue_status: Option<String>,
+    /// DPT 5.001
+    pub saturation: Option<String>,
+    pub saturation_status: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ElgatoConfig {
+    /// Lights that don't answer within this time are reported offline
+    pub timeout_ms: u64,
+}
+
+impl Default for ElgatoConfig {
+    fn default() -> Self {
+        Self { timeout_ms: 3000 }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Devices that don't answer within this time are reported offline
+    pub timeout_ms: u64,
+    pub profiles: Vec<HttpProfileConfig>,
+    pub devices: Vec<HttpDeviceConfig>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 3000,
+            profiles: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+}
+
+/// Requests for controlling one kind of device. Operations without a
+/// request are not supported by the devices.
+#[derive(Deserialize, Clone)]
+pub struct HttpProfileConfig {
+    pub name: String,
+    /// Headers sent with every request, e.g. `authorization`. Values are
+    /// templates.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub basic_auth: Option<HttpBasicAuthConfig>,
+    /// Values that correspond to 100 % brightness, 360 degree hue and full
+    /// saturation in requests and responses. 100 by default.
+    pub brightness_max: Option<f64>,
+    pub hue_max: Option<f64>,
+    pub saturation_max: Option<f64>,
+    pub status: HttpStatusConfig,
+    pub switch_on: Option<HttpRequestConfig>,
+    pub switch_off: Option<HttpRequestConfig>,
+    pub brightness: Option<HttpRequestConfig>,
+    pub color_temperature: Option<HttpRequestConfig>,
+    pub color: Option<HttpRequestConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HttpBasicAuthConfig {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+/// Request template. The URL, body and header values can contain
+/// `{{placeholders}}`.
+#[derive(Deserialize, Clone)]
+pub struct HttpRequestConfig {
+    /// GET by default
+    pub method: Option<String>,
+    pub url: String,
+    /// Sent as JSON unless a `content-type` header is set
+    pub body: Option<String>,
+}
+
+/// Status request, and JSON paths of the values in its response, e.g.
+/// `$.state.on`
+#[derive(Deserialize, Clone)]
+pub struct HttpStatusConfig {
+    pub request: HttpRequestConfig,
+    /// The device is reported offline when this is false
+    pub online: Option<String>,
+    pub switched_on: String,
+    pub brightness: Option<String>,
+    pub color_temperature: Option<String>,
+    /// Used instead of `color_temperature` for devices that report mireds
+    pub color_temperature_mired: Option<String>,
+    pub hue: Option<String>,
+    pub saturation: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HttpDeviceConfig {
+    pub id: String,
+    /// Name of the profile used for the device
+    pub profile: String,
+    /// Values of template placeholders, e.g. the device address. Names are
+    /// case-insensitive.
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PluginConfig {
+    /// Executable that implements the plugin protocol
+    pub command: Option<String>,
+    pub args: Vec<String>,
+    /// Calls that the plugin doesn't answer within this time fail
+    pub timeout_ms: u64,
+}
+
+impl Default for PluginConfig {
+    fn default() -> Self {
+        Self {
+            command: None,
+            args: Vec::new(),
+            timeout_ms: 10000,
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Clone)]
+pub struct SimulatedConfig {
+    #[serde(default)]
+    pub lights: Vec<SimulatedLightConfig>,
+    /// Average time between random changes to each light, as if someone
+    /// used the app. Lights don't change randomly when this is not set.
+    pub random_change_interval_seconds: Option<u64>,
+    /// Seed for the random changes, for repeatable runs
+    pub seed: Option<u64>,
+}
+
+/// A virtual light and its initial state. Lights only have the capabilities
+/// that they have an initial value for.
+#[derive(Deserialize, Clone)]
+pub struct SimulatedLightConfig {
+    pub id: String,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub switched_on: bool,
+    pub brightness: Option<u8>,
+    pub color_temperature: Option<u16>,
+    /// Color temperatures are rounded to multiples of this, 100 K by default
+    pub color_temperature_step: Option<u16>,
+    /// Supported color temperatures, 2700-6500 K by default
+    pub min_color_temperature: Option<u16>,
+    pub max_color_temperature: Option<u16>,
+    pub hue: Option<u8>,
+    pub saturation: Option<u8>,
+    /// The light goes offline for `offline_seconds` at the end of every
+    /// period of this length
+    pub offline_every_seconds: Option<u64>,
+    #[serde(default)]
+    pub offline_seconds: u64,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct TuyaConfig {
+    /// Lights that don't answer within this time are reported offline
+    pub timeout_ms: u64,
+    pub devices: Vec<TuyaDeviceConfig>,
+}
+
+impl Default for TuyaConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 3000,
+            devices: Vec::new(),
+        }
+    }
+}
+
+/// A Tuya or Smart Life light controlled over the local network. The device
+/// ID and local key can be looked up with the Tuya IoT platform or tinytuya.
+#[derive(Deserialize, Clone)]
+pub struct TuyaDeviceConfig {
+    pub id: String,
+    /// 16 character key that the device encrypts messages with
+    pub local_key: String,
+    /// IP address of the device, optionally with a port
+    pub address: String,
+    /// Protocol version, `3.3` (default), `3.4` or `3.5`
+    pub version: Option<String>,
+    /// Color temperatures at the ends of the `temp_value` range, 2700-6500 K
+    /// by default
+    pub min_color_temperature: Option<u16>,
+    pub max_color_temperature: Option<u16>,
+}
+
+#[derive(Deserialize, Clone)]
 pub struct ControllerConfig {
     pub sync_interval_seconds: u64,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct LogConfig {
     pub filters: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Config {
     pub smart_home: SmartHomeConfig,
     pub controller: ControllerConfig,
@@ -35,7 +733,41 @@ pub struct Config {
     pub health_check: HealthCheckConfig,
 }
 
-#[derive(Deserialize)]
+impl Config {
+    /// Configuration of the named backend, with `smart_home` replaced by the
+    /// backend's settings
+    pub fn for_backend(&self, name: &str) -> Option<Config> {
+        let backend = self.smart_home.backends.iter().find(|b| b.name == name)?;
+        let mut smart_home = backend.smart_home.clone();
+        for mqtt in [
+            &mut smart_home.zigbee2mqtt.mqtt,
+            &mut smart_home.tasmota.mqtt,
+        ] {
+            mqtt.backend_name = Some(name.to_string());
+        }
+        Some(Config {
+            smart_home,
+            controller: self.controller.clone(),
+            log: self.log.clone(),
+            health_check: self.health_check.clone(),
+        })
+    }
+}
+
+/// Configuration with the given `smart_home` section, for tests
+#[cfg(test)]
+pub fn test_config(smart_home: serde_json::Value) -> std::sync::Arc<Config> {
+    let config = serde_json::from_value(serde_json::json!({
+        "smart_home": smart_home,
+        "controller": { "sync_interval_seconds": 60 },
+        "log": { "filters": "" },
+        "health_check": { "enable_server": false, "port": 8080 },
+    }))
+    .unwrap();
+    std::sync::Arc::new(config)
+}
+
+#[derive(Deserialize, Clone)]
 pub struct HealthCheckConfig {
     pub enable_server: bool,
     pub port: u16
@@ -4,9 +4,18 @@ use anyhow::{Context, Error};
 use axum::{routing::get, Router};
 
 use crate::config::Config;
+use crate::smarthome::Backends;
 
-pub async fn run(config: Arc<Config>) -> Result<(), Error> {
-    let app = Router::new().route("/healthz", get(())); // Always succeed
+pub async fn run(config: Arc<Config>, backends: Arc<Backends>) -> Result<(), Error> {
+    let mut app = Router::new().route("/healthz", get(())); // Always succeed
+    for (name, api) in backends.iter() {
+        // Routes of named backends are prefixed so that they don't collide
+        match (name, api.webhook_routes()) {
+            (None, Some(routes)) => app = app.merge(routes),
+            (Some(name), Some(routes)) => app = app.nest(&format!("/backends/{name}"), routes),
+            (_, None) => (),
+        }
+    }
 
     let addr = SocketAddr::from(([0, 0, 0, 0], config.health_check.port));
     let listener = tokio::net::TcpListener::bind(&addr).await?;
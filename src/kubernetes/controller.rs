@@ -7,7 +7,7 @@ use k8s_openapi::{
 };
 use kube::{
     api::{Patch, PatchParams},
-    runtime::{controller::Action, Controller},
+    runtime::{controller::Action, reflector::ObjectRef, Controller},
     Api, Client, ResourceExt,
 };
 use serde_json::json;
@@ -15,7 +15,7 @@ use serde_json::json;
 use crate::{
     config::Config,
     kubernetes::crd::{self, Color},
-    smarthome::{self, LightStatus, SmartHomeApi},
+    smarthome::{self, Backends, DeviceEvent, LightStatus},
 };
 
 use super::crd::Light;
@@ -33,24 +33,55 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub struct Context {
     config: Arc<Config>,
-    smart_home_api: Arc<dyn SmartHomeApi>,
+    backends: Arc<Backends>,
     kube_client: Client,
 }
 
 pub async fn run(
     config: Arc<Config>,
-    smart_home_api: Arc<dyn SmartHomeApi + Send + Sync + 'static>,
+    backends: Arc<Backends>,
 ) -> Result<(), kube::Error> {
     let client = Client::try_default().await?;
     let lights = Api::<Light>::all(client.clone());
 
+    // Events of each backend, tagged with the backend name
+    let device_events: Vec<_> = backends
+        .iter()
+        .filter_map(|(name, api)| {
+            let name = name.map(str::to_string);
+            let events = api.device_events()?;
+            Some(events.map(move |event| (name.clone(), event)))
+        })
+        .collect();
+
     let context = Arc::new(Context {
         config,
-        smart_home_api,
+        backends,
         kube_client: client,
     });
 
-    Controller::new(lights.clone(), Default::default())
+    let mut controller = Controller::new(lights.clone(), Default::default());
+
+    if !device_events.is_empty() {
+        // Reconcile the lights of devices whose state changed outside of the operator
+        let store = controller.store();
+        let triggers = futures::stream::select_all(device_events).flat_map(move |(backend, event)| {
+            let refs: Vec<_> = store
+                .state()
+                .into_iter()
+                .filter(|light| light.spec.backend == backend)
+                .filter(|light| match &event {
+                    DeviceEvent::Changed(id) => &light.spec.device_id == id,
+                    DeviceEvent::Resync => true,
+                })
+                .map(|light| ObjectRef::from_obj(light.as_ref()))
+                .collect();
+            futures::stream::iter(refs)
+        });
+        controller = controller.reconcile_on(triggers);
+    }
+
+    controller
         .run(reconcile, error_policy, context)
         .for_each(|_| futures::future::ready(()))
         .await;
@@ -63,10 +94,6 @@ pub async fn reconcile(light: Arc<Light>, ctx: Arc<Context>) -> Result<Action, E
     let name = light.name_any();
     tracing::info!("Reconciling {}/{}", &ns, &name);
 
-    // Get status
-    let id = &light.spec.device_id;
-    let status_res = ctx.smart_home_api.get_light_status(id).await;
-
     let lights: Api<Light> = Api::namespaced(ctx.kube_client.clone(), &ns);
 
     let mut conds = light
@@ -74,17 +101,43 @@ pub async fn reconcile(light: Arc<Light>, ctx: Arc<Context>) -> Result<Action, E
         .clone()
         .map(|s| s.conditions)
         .unwrap_or_default();
+    ensure_condition(&mut conds, "UnknownBackend", light.metadata.generation);
     ensure_condition(&mut conds, "InvalidDevice", light.metadata.generation);
     ensure_condition(&mut conds, "Ready", light.metadata.generation);
 
+    let backend = light.spec.backend.as_deref();
+    let Some(smart_home_api) = ctx.backends.get(backend) else {
+        // Backends only change with the configuration, so there is nothing to
+        // retry until the Light is changed
+        tracing::warn!("Light refers to unknown backend {backend:?}");
+        let backend_cond = unknown_backend_condition(Some(true), "BackendNotConfigured", Some("Backend is not configured"), light.metadata.generation);
+        update_conditions(&mut conds, backend_cond);
+        let ready_cond = ready_condition(Some(false), "UnknownBackend", None, light.metadata.generation);
+        update_conditions(&mut conds, ready_cond);
+
+        patch_conditions(conds, lights, &name).await?;
+        return Ok(Action::await_change());
+    };
+    let backend_cond = unknown_backend_condition(Some(false), "BackendOk", None, light.metadata.generation);
+    update_conditions(&mut conds, backend_cond);
+
+    // Get status
+    let id = &light.spec.device_id;
+    let status_res = smart_home_api.get_light_status(id).await;
+
     let status = match status_res {
         Ok(s) => s,
+        // Says nothing about the device ID, so the conditions stay as they are
+        Err(he @ smarthome::Error::Busy(_)) => return Err(Error::SmartHomeApi(he)),
         Err(he) => {
             let invalid = match he {
                 smarthome::Error::Configuration(_) => None,
                 smarthome::Error::InvalidId(_) => Some("IdIsInvalid".to_string()),
                 smarthome::Error::RequestFailed(_) => None,
                 smarthome::Error::UnknownDeviceId => Some("DeviceNotFound".to_string()),
+                smarthome::Error::Communication(_) => None,
+                smarthome::Error::UnsupportedCapability(_) => None,
+                smarthome::Error::Busy(_) => None,
             };
 
             let invalid_cond = match invalid {
@@ -105,10 +158,15 @@ pub async fn reconcile(light: Arc<Light>, ctx: Arc<Context>) -> Result<Action, E
 
     if let LightStatus::Online(light_options) = status {
         let mut changes_made = false;
+        // A running effect hides the declared color, so it is set again
+        let effect_active = light_options.effect.is_some();
+        if let Some(effect) = &light_options.effect {
+            tracing::info!("Light is showing effect {effect}");
+        }
         if let Some(target_brightness) = light.spec.brightness {
             if Some(target_brightness) != light_options.brightness {
                 tracing::info!("Setting light brightness to {target_brightness}");
-                ctx.smart_home_api
+                smart_home_api
                     .set_brightness(id, target_brightness)
                     .await?;
                 changes_made = true;
@@ -117,21 +175,23 @@ pub async fn reconcile(light: Arc<Light>, ctx: Arc<Context>) -> Result<Action, E
 
         match light.spec.color.as_ref() {
             Some(&Color::ColorTemperature(target_temp)) => {
-                if Some(target_temp) != light_options.color_temperature {
+                let differs = Some(target_temp) != light_options.color_temperature;
+                if effect_active || differs {
                     tracing::info!("Setting color temperature to {target_temp} K");
-                    ctx.smart_home_api
+                    smart_home_api
                         .set_color_temperature(id, target_temp)
                         .await?;
                     changes_made = true;
                 }
             }
             Some(Color::HueSaturation(target_hue_sat)) => {
-                if Some(target_hue_sat.hue) != light_options.color.as_ref().map(|x| x.hue)
+                let differs = Some(target_hue_sat.hue)
+                    != light_options.color.as_ref().map(|x| x.hue)
                     || Some(target_hue_sat.saturation)
-                        != light_options.color.as_ref().map(|x| x.saturation)
-                {
+                        != light_options.color.as_ref().map(|x| x.saturation);
+                if effect_active || differs {
                     tracing::info!("Setting color to {target_hue_sat}");
-                    ctx.smart_home_api
+                    smart_home_api
                         .set_color(id, target_hue_sat.hue, target_hue_sat.saturation)
                         .await?;
                     changes_made = true;
@@ -142,7 +202,7 @@ pub async fn reconcile(light: Arc<Light>, ctx: Arc<Context>) -> Result<Action, E
 
         if light_options.switched_on != light.spec.state.is_switched_on() || changes_made {
             tracing::info!("Setting light switched on status to {:?}", light.spec.state);
-            ctx.smart_home_api
+            smart_home_api
                 .set_switched_on(id, light.spec.state.into())
                 .await?;
         }
@@ -156,12 +216,22 @@ pub async fn reconcile(light: Arc<Light>, ctx: Arc<Context>) -> Result<Action, E
 
     patch_conditions(conds, lights, &name).await?;
 
+    if smart_home_api.events_connected() {
+        // Changes are pushed by the smart home platform, no need to poll
+        return Ok(Action::await_change());
+    }
+
     Ok(Action::requeue(Duration::from_secs(
         ctx.config.controller.sync_interval_seconds,
     )))
 }
 
 pub fn error_policy(_light: Arc<Light>, err: &Error, _ctx: Arc<Context>) -> Action {
+    if let Error::SmartHomeApi(smarthome::Error::Busy(retry_after)) = err {
+        tracing::info!("Device is busy, reconciling again in {retry_after:?}");
+        return Action::requeue(*retry_after);
+    }
+
     let err_ref: &(dyn std::error::Error + Send + Sync) = err;
     tracing::error!(error = err_ref, "Reconciler error");
     Action::requeue(Duration::from_secs(5))
@@ -218,6 +288,15 @@ fn invalid_device_condition(
     create_condition("InvalidDevice", status, reason, message.map(|m| m.into()), generation)
 }
 
+fn unknown_backend_condition(
+    status: Option<bool>,
+    reason: impl Into<String>,
+    message: Option<&str>,
+    generation: Option<i64>,
+) -> Condition {
+    create_condition("UnknownBackend", status, reason, message.map(|m| m.into()), generation)
+}
+
 fn ready_condition(
     status: Option<bool>,
     reason: impl Into<String>,
@@ -260,4 +339,148 @@ fn ensure_condition(
     if !conditions.iter().any(|c| c.type_ == _type) {
         update_conditions(conditions, create_condition(_type, None, "Unknown", None, generation))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use hyper::Body;
+    use kube::core::ObjectMeta;
+
+    use crate::kubernetes::crd::{HueSaturationColor, LightSpec, LightState};
+
+    use crate::config::test_config;
+
+    use super::*;
+
+    fn context(kube_client: Client) -> Arc<Context> {
+        let config = test_config(json!({
+            "platform": "Simulated",
+            "simulated": {
+                "lights": [
+                    { "id": "1", "brightness": 20, "color_temperature": 3000, "hue": 10 },
+                    { "id": "2", "offline_every_seconds": 3600, "offline_seconds": 3600 },
+                ],
+            },
+        }));
+
+        Arc::new(Context {
+            backends: Arc::new(Backends::new(config.clone()).unwrap()),
+            config,
+            kube_client,
+        })
+    }
+
+    fn light(spec: LightSpec) -> Light {
+        let mut light = Light::new("lamp", spec);
+        light.metadata = ObjectMeta {
+            name: Some("lamp".to_string()),
+            namespace: Some("default".to_string()),
+            generation: Some(1),
+            ..Default::default()
+        };
+        light
+    }
+
+    /// A Kubernetes API that answers every request with `light`, recording
+    /// the status patches
+    fn kube_client(light: &Light) -> (Client, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let patches = Arc::new(Mutex::new(Vec::new()));
+        let response = serde_json::to_vec(light).unwrap();
+
+        let recorded = patches.clone();
+        let service = tower::service_fn(move |request: http::Request<Body>| {
+            let recorded = recorded.clone();
+            let response = response.clone();
+            async move {
+                assert_eq!(request.method(), http::Method::PATCH);
+                assert_eq!(
+                    request.uri().path(),
+                    "/apis/light-operator.lkoskela.com/v1alpha1/namespaces/default/lights/lamp/status"
+                );
+                let body = hyper::body::to_bytes(request.into_body()).await?;
+                recorded.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+                Ok::<_, hyper::Error>(http::Response::new(Body::from(response)))
+            }
+        });
+
+        (Client::new(service, "default"), patches)
+    }
+
+    fn condition<'a>(patch: &'a serde_json::Value, type_: &str) -> &'a serde_json::Value {
+        patch["status"]["conditions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["type"] == type_)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reconcile_sets_simulated_light() {
+        let light = light(LightSpec {
+            device_id: "1".to_string(),
+            backend: None,
+            state: LightState::SwitchedOn,
+            color: Some(Color::HueSaturation(HueSaturationColor {
+                hue: 40,
+                saturation: 50,
+            })),
+            brightness: Some(80),
+        });
+        let (client, patches) = kube_client(&light);
+        let ctx = context(client);
+
+        let action = reconcile(Arc::new(light.clone()), ctx.clone()).await.unwrap();
+        assert_eq!(action, Action::requeue(Duration::from_secs(60)));
+
+        let api = ctx.backends.get(None).unwrap();
+        let LightStatus::Online(options) = api.get_light_status("1").await.unwrap() else {
+            panic!("light is offline");
+        };
+        assert!(options.switched_on);
+        assert_eq!(options.brightness, Some(80));
+        assert_eq!(options.color.map(|c| (c.hue, c.saturation)), Some((40, 50)));
+        assert_eq!(options.color_temperature, None);
+
+        let patches = patches.lock().unwrap();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(condition(&patches[0], "Ready")["status"], "True");
+        assert_eq!(condition(&patches[0], "Ready")["reason"], "DeviceOnline");
+        assert_eq!(condition(&patches[0], "InvalidDevice")["status"], "False");
+        assert_eq!(condition(&patches[0], "UnknownBackend")["status"], "False");
+    }
+
+    #[tokio::test]
+    async fn reconcile_reports_offline_and_unknown_simulated_lights() {
+        let mut light = light(LightSpec {
+            device_id: "2".to_string(),
+            backend: None,
+            state: LightState::SwitchedOn,
+            color: None,
+            brightness: None,
+        });
+        let (client, patches) = kube_client(&light);
+        let ctx = context(client);
+
+        reconcile(Arc::new(light.clone()), ctx.clone()).await.unwrap();
+        assert_eq!(condition(&patches.lock().unwrap()[0], "Ready")["reason"], "DeviceOffline");
+
+        light.spec.device_id = "3".to_string();
+        let res = reconcile(Arc::new(light.clone()), ctx.clone()).await;
+        assert!(matches!(
+            res,
+            Err(Error::SmartHomeApi(smarthome::Error::UnknownDeviceId))
+        ));
+        light.spec.backend = Some("upstairs".to_string());
+        let action = reconcile(Arc::new(light), ctx).await.unwrap();
+        assert_eq!(action, Action::await_change());
+
+        let patches = patches.lock().unwrap();
+        assert_eq!(condition(&patches[1], "InvalidDevice")["reason"], "DeviceNotFound");
+        assert_eq!(condition(&patches[1], "Ready")["status"], "False");
+        assert_eq!(condition(&patches[2], "UnknownBackend")["reason"], "BackendNotConfigured");
+        assert_eq!(condition(&patches[2], "Ready")["reason"], "UnknownBackend");
+    }
+}
@@ -71,6 +71,9 @@ impl LightState {
 pub struct LightSpec {
     /// Device id
     pub device_id: String,
+    /// Name of the backend in smart_home.backends that controls the device.
+    /// The default backend is used when not set.
+    pub backend: Option<String>,
     /// Is the light on or off
     pub state: LightState,
 
@@ -0,0 +1,417 @@
+//! Conversions between the hue/saturation percentages used by the Light resources
+//! and the color models used by the different smart home platforms
+
+/// A point in the CIE 1931 xy chromaticity space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Xy {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// Triangle of the colors a bulb is able to reproduce, given as the xy
+/// coordinates of its red, green and blue primaries
+#[derive(Debug, Clone, Copy)]
+pub struct Gamut {
+    pub red: Xy,
+    pub green: Xy,
+    pub blue: Xy,
+}
+
+/// Convert hue and saturation percentages (0-100) to RGB components (0.0-1.0)
+/// at full brightness
+pub fn hs_to_rgb(hue: u8, saturation: u8) -> (f64, f64, f64) {
+    let h = (f64::from(hue.min(100)) * 3.6) % 360.0 / 60.0;
+    let s = f64::from(saturation.min(100)) / 100.0;
+
+    let c = s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let m = 1.0 - c;
+
+    let (r, g, b) = match h as u8 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    (r + m, g + m, b + m)
+}
+
+/// Convert RGB components (0.0-1.0) to hue and saturation percentages (0-100).
+/// The brightness of the color is ignored.
+pub fn rgb_to_hs(r: f64, g: f64, b: f64) -> (u8, u8) {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    if max <= 0.0 || delta <= f64::EPSILON {
+        return (0, 0);
+    }
+
+    let hue_deg = if max == r {
+        60.0 * (((g - b) / delta).rem_euclid(6.0))
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+
+    let hue = (hue_deg / 3.6).round() as u8 % 100;
+    let saturation = (delta / max * 100.0).round() as u8;
+
+    (hue, saturation.min(100))
+}
+
+/// Convert hue and saturation percentages to CIE xy coordinates. If a gamut is
+/// given, the result is moved to the closest point the bulb can reproduce.
+pub fn hs_to_xy(hue: u8, saturation: u8, gamut: Option<&Gamut>) -> Xy {
+    let (r, g, b) = hs_to_rgb(hue, saturation);
+    let (r, g, b) = (srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b));
+
+    // sRGB (D65) to XYZ
+    let x = 0.4124 * r + 0.3576 * g + 0.1805 * b;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = 0.0193 * r + 0.1192 * g + 0.9505 * b;
+
+    let sum = x + y + z;
+    let xy = if sum <= 0.0 {
+        // D65 white point
+        Xy {
+            x: 0.3127,
+            y: 0.3290,
+        }
+    } else {
+        Xy {
+            x: x / sum,
+            y: y / sum,
+        }
+    };
+
+    match gamut {
+        Some(gamut) => clamp_to_gamut(xy, gamut),
+        None => xy,
+    }
+}
+
+/// Convert CIE xy coordinates to hue and saturation percentages
+pub fn xy_to_hs(xy: Xy) -> (u8, u8) {
+    if xy.y <= 0.0 {
+        return (0, 0);
+    }
+
+    let y = 1.0;
+    let x = xy.x / xy.y;
+    let z = (1.0 - xy.x - xy.y) / xy.y;
+
+    // XYZ to linear sRGB (D65)
+    let r = 3.2406 * x - 1.5372 * y - 0.4986 * z;
+    let g = -0.9689 * x + 1.8758 * y + 0.0415 * z;
+    let b = 0.0557 * x - 0.2040 * y + 1.0570 * z;
+
+    let (r, g, b) = (r.max(0.0), g.max(0.0), b.max(0.0));
+    let max = r.max(g).max(b);
+    if max <= 0.0 {
+        return (0, 0);
+    }
+
+    rgb_to_hs(
+        linear_to_srgb(r / max),
+        linear_to_srgb(g / max),
+        linear_to_srgb(b / max),
+    )
+}
+
+/// Approximate the RGB components (0.0-1.0) of a black body at the given
+/// color temperature, for devices that only have RGB channels
+pub fn kelvin_to_rgb(kelvin: u16) -> (f64, f64, f64) {
+    // Tanner Helland's curve fit of the black body spectrum, valid for 1000-40000 K
+    let t = f64::from(kelvin.clamp(1000, 40000)) / 100.0;
+
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.698_727_446 * (t - 60.0).powf(-0.133_204_759_2)
+    };
+    let g = if t <= 66.0 {
+        99.470_802_586_1 * t.ln() - 161.119_568_166_1
+    } else {
+        288.122_169_528_3 * (t - 60.0).powf(-0.075_514_849_2)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.517_731_223_1 * (t - 10.0).ln() - 305.044_792_730_7
+    };
+
+    let normalize = |c: f64| (c / 255.0).clamp(0.0, 1.0);
+    (normalize(r), normalize(g), normalize(b))
+}
+
+/// Convert RGB components (0.0-1.0) to 8-bit values
+pub fn to_rgb8((r, g, b): (f64, f64, f64)) -> [u8; 3] {
+    let to_u8 = |c: f64| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    [to_u8(r), to_u8(g), to_u8(b)]
+}
+
+/// Convert 8-bit RGB values to components (0.0-1.0)
+pub fn from_rgb8([r, g, b]: [u8; 3]) -> (f64, f64, f64) {
+    let from_u8 = |c: u8| f64::from(c) / 255.0;
+    (from_u8(r), from_u8(g), from_u8(b))
+}
+
+/// Convert a color temperature in Kelvin to mireds (micro reciprocal degrees)
+pub fn kelvin_to_mired(kelvin: u16) -> u16 {
+    (1_000_000.0 / f64::from(kelvin.max(1))).round() as u16
+}
+
+/// Convert a color temperature in mireds to Kelvin
+pub fn mired_to_kelvin(mired: u16) -> u16 {
+    (1_000_000.0 / f64::from(mired.max(1)))
+        .round()
+        .min(f64::from(u16::MAX)) as u16
+}
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn clamp_to_gamut(p: Xy, gamut: &Gamut) -> Xy {
+    if is_in_triangle(p, gamut) {
+        return p;
+    }
+
+    [
+        closest_point_on_segment(p, gamut.red, gamut.green),
+        closest_point_on_segment(p, gamut.green, gamut.blue),
+        closest_point_on_segment(p, gamut.blue, gamut.red),
+    ]
+    .into_iter()
+    .min_by(|a, b| distance(p, *a).total_cmp(&distance(p, *b)))
+    .unwrap_or(p)
+}
+
+fn is_in_triangle(p: Xy, gamut: &Gamut) -> bool {
+    let cross = |a: Xy, b: Xy, c: Xy| (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x);
+
+    let d1 = cross(gamut.red, gamut.green, p);
+    let d2 = cross(gamut.green, gamut.blue, p);
+    let d3 = cross(gamut.blue, gamut.red, p);
+
+    let has_neg = d1 < 0.0 || d2 < 0.0 || d3 < 0.0;
+    let has_pos = d1 > 0.0 || d2 > 0.0 || d3 > 0.0;
+    !(has_neg && has_pos)
+}
+
+fn closest_point_on_segment(p: Xy, a: Xy, b: Xy) -> Xy {
+    let (abx, aby) = (b.x - a.x, b.y - a.y);
+    let len_sq = abx * abx + aby * aby;
+    if len_sq <= 0.0 {
+        return a;
+    }
+
+    let t = (((p.x - a.x) * abx + (p.y - a.y) * aby) / len_sq).clamp(0.0, 1.0);
+    Xy {
+        x: a.x + t * abx,
+        y: a.y + t * aby,
+    }
+}
+
+fn distance(a: Xy, b: Xy) -> f64 {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gamut C of recent Hue bulbs
+    const GAMUT_C: Gamut = Gamut {
+        red: Xy {
+            x: 0.6915,
+            y: 0.3083,
+        },
+        green: Xy { x: 0.17, y: 0.7 },
+        blue: Xy {
+            x: 0.1532,
+            y: 0.0475,
+        },
+    };
+
+    fn assert_close(actual: (f64, f64, f64), expected: (f64, f64, f64)) {
+        for (a, e) in [
+            (actual.0, expected.0),
+            (actual.1, expected.1),
+            (actual.2, expected.2),
+        ] {
+            assert!((a - e).abs() < 1e-9, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn converts_hue_saturation_to_rgb() {
+        assert_close(hs_to_rgb(0, 100), (1.0, 0.0, 0.0));
+        assert_close(hs_to_rgb(50, 100), (0.0, 1.0, 1.0));
+        assert_close(hs_to_rgb(0, 50), (1.0, 0.5, 0.5));
+        // Hue 100 is a full turn back to red
+        assert_close(hs_to_rgb(100, 100), hs_to_rgb(0, 100));
+        // Values above 100 are treated as 100
+        assert_close(hs_to_rgb(200, 200), hs_to_rgb(100, 100));
+    }
+
+    #[test]
+    fn unsaturated_colors_are_white() {
+        for hue in 0..=100 {
+            assert_close(hs_to_rgb(hue, 0), (1.0, 1.0, 1.0));
+        }
+        assert_eq!(rgb_to_hs(1.0, 1.0, 1.0), (0, 0));
+        assert_eq!(rgb_to_hs(0.3, 0.3, 0.3), (0, 0));
+        assert_eq!(rgb_to_hs(0.0, 0.0, 0.0), (0, 0));
+    }
+
+    #[test]
+    fn round_trips_hue_saturation_through_rgb() {
+        for hue in 0..=100 {
+            for saturation in 1..=100 {
+                let (r, g, b) = hs_to_rgb(hue, saturation);
+                assert_eq!(
+                    rgb_to_hs(r, g, b),
+                    (hue % 100, saturation),
+                    "hue {hue}, saturation {saturation}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_brightness_of_rgb() {
+        assert_eq!(rgb_to_hs(0.5, 0.0, 0.0), (0, 100));
+        assert_eq!(rgb_to_hs(0.0, 0.25, 0.25), (50, 100));
+    }
+
+    #[test]
+    fn converts_rgb8() {
+        assert_eq!(to_rgb8((1.0, 0.5, 0.0)), [255, 128, 0]);
+        assert_eq!(to_rgb8((1.5, -0.5, 0.0)), [255, 0, 0]);
+        for value in 0..=u8::MAX {
+            assert_eq!(to_rgb8(from_rgb8([value, 0, value])), [value, 0, value]);
+        }
+    }
+
+    #[test]
+    fn round_trips_saturated_colors_through_rgb8() {
+        for hue in 0..100 {
+            let (r, g, b) = from_rgb8(to_rgb8(hs_to_rgb(hue, 100)));
+            assert_eq!(rgb_to_hs(r, g, b), (hue, 100), "hue {hue}");
+        }
+    }
+
+    #[test]
+    fn converts_kelvin_to_rgb() {
+        assert_close(kelvin_to_rgb(6600), (1.0, 1.0, 1.0));
+        assert_eq!(to_rgb8(kelvin_to_rgb(2700)), [255, 167, 87]);
+
+        // Warm colors have no blue, cold colors are bluish
+        let (_, _, b) = kelvin_to_rgb(1500);
+        assert_eq!(b, 0.0);
+        let (r, _, b) = kelvin_to_rgb(10000);
+        assert!(r < b);
+
+        // Outside the curve fit the ends of the range are used
+        assert_close(kelvin_to_rgb(0), kelvin_to_rgb(1000));
+        assert_close(kelvin_to_rgb(u16::MAX), kelvin_to_rgb(40000));
+    }
+
+    #[test]
+    fn converts_mireds() {
+        assert_eq!(kelvin_to_mired(2700), 370);
+        assert_eq!(kelvin_to_mired(6500), 154);
+        assert_eq!(mired_to_kelvin(370), 2703);
+        assert_eq!(mired_to_kelvin(154), 6494);
+
+        // Nothing divides by zero or overflows
+        assert_eq!(kelvin_to_mired(0), u16::MAX);
+        assert_eq!(mired_to_kelvin(0), u16::MAX);
+        assert_eq!(mired_to_kelvin(1), u16::MAX);
+
+        // Mireds are coarser than Kelvin, but a round trip stays within one mired
+        for kelvin in (1000..=10000).step_by(50) {
+            let mired = kelvin_to_mired(kelvin);
+            assert_eq!(kelvin_to_mired(mired_to_kelvin(mired)), mired, "{kelvin} K");
+        }
+    }
+
+    #[test]
+    fn converts_white_to_white_point() {
+        let xy = hs_to_xy(30, 0, None);
+        assert!((xy.x - 0.3127).abs() < 0.001, "{xy:?}");
+        assert!((xy.y - 0.3290).abs() < 0.001, "{xy:?}");
+        let (_, saturation) = xy_to_hs(xy);
+        assert!(saturation <= 1, "{saturation}");
+    }
+
+    #[test]
+    fn round_trips_hue_saturation_through_xy() {
+        for hue in 0..100 {
+            for saturation in [20, 50, 100] {
+                let (h, s) = xy_to_hs(hs_to_xy(hue, saturation, None));
+                let hue_diff = (i16::from(h) - i16::from(hue)).rem_euclid(100);
+                assert!(hue_diff.min(100 - hue_diff) <= 1, "hue {hue} -> {h}");
+                assert!(
+                    s.abs_diff(saturation) <= 1,
+                    "saturation {saturation} -> {s}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_xy() {
+        assert_eq!(xy_to_hs(Xy { x: 0.3, y: 0.0 }), (0, 0));
+        assert_eq!(xy_to_hs(Xy { x: 0.3, y: -0.1 }), (0, 0));
+    }
+
+    #[test]
+    fn clamps_colors_to_gamut() {
+        // Colors inside the gamut are unchanged
+        let inside = hs_to_xy(0, 50, None);
+        assert_eq!(hs_to_xy(0, 50, Some(&GAMUT_C)), inside);
+
+        // A point beyond a corner is moved to the corner
+        assert_eq!(
+            clamp_to_gamut(Xy { x: 0.75, y: 0.30 }, &GAMUT_C),
+            GAMUT_C.red
+        );
+
+        // A point beyond an edge is moved onto the edge
+        let clamped = clamp_to_gamut(Xy { x: 0.5, y: 0.6 }, &GAMUT_C);
+        let on_edge = closest_point_on_segment(clamped, GAMUT_C.red, GAMUT_C.green);
+        assert!(distance(clamped, on_edge) < 1e-9, "{clamped:?}");
+        assert!(clamped.x < 0.5 && clamped.y < 0.6);
+
+        // Every converted color is reproducible by the bulb
+        for hue in 0..100 {
+            let xy = hs_to_xy(hue, 100, Some(&GAMUT_C));
+            let nudged = Xy {
+                x: xy.x + (0.3127 - xy.x) * 1e-9,
+                y: xy.y + (0.3290 - xy.y) * 1e-9,
+            };
+            assert!(is_in_triangle(nudged, &GAMUT_C), "hue {hue}: {xy:?}");
+        }
+    }
+}
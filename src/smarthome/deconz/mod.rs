@@ -0,0 +1,277 @@
+use std::sync::{Arc, Once};
+
+use api_models::*;
+use async_trait::async_trait;
+use reqwest::{Client, Response, Url};
+
+use crate::config::Config;
+
+use super::{
+    color::{self, Xy},
+    events::EventChannel,
+    requested::RequestedValues,
+    DeviceEventStream, LightOptions, LightStatus, SmartHomeApi,
+};
+
+mod websocket;
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+/// Error type for resources that don't exist
+const ERROR_RESOURCE_NOT_AVAILABLE: u32 = 3;
+
+pub struct Deconz {
+    _config: Arc<Config>,
+    client: Client,
+    base_url: Url,
+    requested: RequestedValues,
+    websocket_events: bool,
+    websocket_port: Option<u16>,
+    events: Arc<EventChannel>,
+    start_listener: Once,
+}
+
+impl Deconz {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let deconz_config = &config.smart_home.deconz;
+        let Some(base_url) = &deconz_config.base_url else {
+            return Err(super::Error::Configuration(
+                "deCONZ URL not configured".to_string(),
+            ));
+        };
+        let Some(api_key) = &deconz_config.api_key else {
+            return Err(super::Error::Configuration(
+                "deCONZ API key not configured".to_string(),
+            ));
+        };
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .build()
+            .unwrap();
+
+        let base_url = format!("{}/api/{api_key}/", base_url.trim_end_matches('/'))
+            .parse()
+            .map_err(|_| super::Error::Configuration(format!("Invalid deCONZ URL `{base_url}`")))?;
+
+        Ok(Self {
+            websocket_events: deconz_config.websocket_events,
+            websocket_port: deconz_config.websocket_port,
+            _config: config,
+            client,
+            base_url,
+            requested: Default::default(),
+            events: Default::default(),
+            start_listener: Once::new(),
+        })
+    }
+
+    /// Device IDs are the numeric light IDs of the gateway, e.g. `3`
+    fn validate_device_id(id: &str) -> super::Result<()> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(super::Error::InvalidId(id.to_string()));
+        }
+        Ok(())
+    }
+
+    fn error_from_status(res: Response) -> super::Result<Response> {
+        if res.status() == 404 {
+            return Err(super::Error::UnknownDeviceId);
+        }
+
+        Ok(res.error_for_status()?)
+    }
+
+    async fn get_light(&self, id: &str) -> super::Result<Light> {
+        Self::validate_device_id(id)?;
+
+        let url = self.base_url.join(&format!("lights/{id}")).unwrap();
+
+        let res = self.client.get(url).send().await?;
+        Ok(Self::error_from_status(res)?.json().await?)
+    }
+
+    async fn update_state(&self, id: &str, body: serde_json::Value) -> super::Result<()> {
+        Self::validate_device_id(id)?;
+
+        tracing::debug!(
+            device_id = id,
+            state = body.to_string(),
+            "Updating light state"
+        );
+
+        let url = self.base_url.join(&format!("lights/{id}/state")).unwrap();
+
+        let res = self.client.put(url).json(&body).send().await?;
+        let results: Vec<UpdateResult> = Self::error_from_status(res)?.json().await?;
+
+        match results.into_iter().find_map(|r| r.error) {
+            Some(e) if e.type_ == ERROR_RESOURCE_NOT_AVAILABLE => {
+                Err(super::Error::UnknownDeviceId)
+            }
+            Some(e) => Err(super::Error::Communication(format!(
+                "{} ({})",
+                e.description, e.type_
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Deconz {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let light = self.get_light(id).await?;
+
+        tracing::debug!("Got status {light:#?}");
+
+        let state = light.state;
+        if state.reachable == Some(false) {
+            return Ok(LightStatus::Offline);
+        }
+
+        let brightness = state
+            .bri
+            .map(|b| (f64::from(b) * 100.0 / 255.0).round() as u8);
+
+        let color_temperature = state
+            .ct
+            .filter(|_| state.colormode.as_deref() == Some("ct"))
+            .map(|mired| {
+                self.requested
+                    .color_temperature(id, |k| color::kelvin_to_mired(k) == mired)
+                    .unwrap_or_else(|| color::mired_to_kelvin(mired))
+            });
+
+        let color = match (state.colormode.as_deref(), state.hue, state.sat, state.xy) {
+            _ if color_temperature.is_some() => None,
+            (Some("hs"), Some(hue), Some(sat), _) => Some((
+                (f64::from(hue) * 100.0 / 65536.0).round() as u8 % 100,
+                (f64::from(sat) * 100.0 / 255.0).round() as u8,
+            )),
+            (Some("xy"), _, _, Some([x, y])) => Some(color::xy_to_hs(Xy { x, y })),
+            _ => None,
+        }
+        .map(|(hue, saturation)| {
+            let (hue, saturation) = self
+                .requested
+                .color(id, |rh, rs| {
+                    rh.abs_diff(hue) <= 1 && rs.abs_diff(saturation) <= 1
+                })
+                .unwrap_or((hue, saturation));
+            super::Color { hue, saturation }
+        });
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: state.on.unwrap_or(false),
+            brightness,
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        self.update_state(id, serde_json::json!({ "on": switched_on }))
+            .await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let bri = (f64::from(brightness.clamp(0, 100)) * 255.0 / 100.0).round() as u8;
+        self.update_state(id, serde_json::json!({ "bri": bri }))
+            .await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let light = self.get_light(id).await?;
+
+        let mut mired = color::kelvin_to_mired(temp);
+        if let (Some(min), Some(max)) = (light.ctmin, light.ctmax) {
+            mired = mired.clamp(min, max.max(min));
+        }
+        self.update_state(id, serde_json::json!({ "ct": mired }))
+            .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let body = serde_json::json!({
+            "hue": (f64::from(hue) * 65536.0 / 100.0).round().min(65535.0) as u16,
+            "sat": (f64::from(saturation.clamp(0, 100)) * 255.0 / 100.0).round() as u8,
+        });
+        self.update_state(id, body).await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    fn device_events(&self) -> Option<DeviceEventStream> {
+        if !self.websocket_events {
+            return None;
+        }
+
+        let stream = self.events.subscribe();
+        self.start_listener.call_once(|| {
+            tokio::spawn(websocket::listen(
+                self.client.clone(),
+                self.base_url.clone(),
+                self.websocket_port,
+                self.events.clone(),
+            ));
+        });
+        Some(stream)
+    }
+
+    fn events_connected(&self) -> bool {
+        self.events.is_connected()
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug, Default)]
+    #[serde(default)]
+    pub struct LightState {
+        pub on: Option<bool>,
+        pub bri: Option<u8>,
+        /// Color temperature in mireds
+        pub ct: Option<u16>,
+        pub hue: Option<u16>,
+        pub sat: Option<u8>,
+        pub xy: Option<[f64; 2]>,
+        /// `hs`, `xy` or `ct`
+        pub colormode: Option<String>,
+        pub reachable: Option<bool>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Light {
+        pub state: LightState,
+        pub ctmin: Option<u16>,
+        pub ctmax: Option<u16>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct UpdateError {
+        #[serde(rename = "type")]
+        pub type_: u32,
+        pub description: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct UpdateResult {
+        pub error: Option<UpdateError>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct GatewayConfig {
+        pub websocketport: u16,
+    }
+}
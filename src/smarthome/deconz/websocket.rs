@@ -0,0 +1,129 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use futures::{SinkExt, StreamExt};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::smarthome::events::{Backoff, EventChannel};
+
+use super::api_models::GatewayConfig;
+
+/// Send a ping if nothing has been received for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Listen to light events from the deCONZ websocket and publish the changed
+/// light IDs. Reconnects with backoff until the program exits.
+pub(super) async fn listen(
+    client: Client,
+    base_url: Url,
+    port: Option<u16>,
+    events: Arc<EventChannel>,
+) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+    loop {
+        match connect_and_listen(&client, &base_url, port, &events, &mut backoff).await {
+            Ok(()) => tracing::info!("deCONZ websocket connection closed"),
+            Err(e) => {
+                let err_ref: &(dyn std::error::Error + Send + Sync) = e.as_ref();
+                tracing::warn!(error = err_ref, "deCONZ websocket connection failed");
+            }
+        }
+        events.set_connected(false);
+
+        let delay = backoff.next_delay();
+        tracing::debug!("Reconnecting to deCONZ websocket in {delay:?}");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// The websocket is served on its own port, which the gateway reports in
+/// its configuration
+async fn websocket_url(client: &Client, base_url: &Url, port: Option<u16>) -> anyhow::Result<Url> {
+    let port = match port {
+        Some(port) => port,
+        None => {
+            let config: GatewayConfig = client
+                .get(base_url.join("config")?)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+                .context("Reading gateway configuration failed")?;
+            config.websocketport
+        }
+    };
+
+    let mut url = base_url.clone();
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    url.set_scheme(scheme)
+        .and_then(|_| url.set_port(Some(port)))
+        .map_err(|_| anyhow!("Invalid websocket URL"))?;
+    url.set_path("/");
+    Ok(url)
+}
+
+async fn connect_and_listen(
+    client: &Client,
+    base_url: &Url,
+    port: Option<u16>,
+    events: &EventChannel,
+    backoff: &mut Backoff,
+) -> anyhow::Result<()> {
+    let url = websocket_url(client, base_url, port).await?;
+    let (mut ws, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .context("Connecting failed")?;
+
+    tracing::info!("Connected to deCONZ websocket");
+    backoff.reset();
+    events.set_connected(true);
+
+    let mut awaiting_pong = false;
+
+    loop {
+        let msg = match tokio::time::timeout(IDLE_TIMEOUT, ws.next()).await {
+            Ok(Some(msg)) => msg?,
+            Ok(None) => return Ok(()),
+            Err(_) if awaiting_pong => bail!("Ping timed out"),
+            Err(_) => {
+                ws.send(Message::Ping(Vec::new())).await?;
+                awaiting_pong = true;
+                continue;
+            }
+        };
+        awaiting_pong = false;
+
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Close(_) => return Ok(()),
+            // Pings are answered by tungstenite
+            _ => continue,
+        };
+
+        let event: Event =
+            serde_json::from_str(&text).map_err(|e| anyhow!("Invalid message from deCONZ: {e}"))?;
+
+        if event.t == "event" && event.r.as_deref() == Some("lights") {
+            if let Some(id) = event.id {
+                tracing::debug!(device_id = id, event = event.e, "Light changed");
+                events.send_changed(id);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Event {
+    /// Message type, always `event`
+    t: String,
+    /// `changed`, `added`, `deleted` or `scene-called`
+    #[serde(default)]
+    e: String,
+    /// Resource type
+    r: Option<String>,
+    id: Option<String>,
+}
@@ -0,0 +1,439 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use api_models::*;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{distributions::Slice, Rng};
+use reqwest::{header::HeaderMap, Client, Response, StatusCode, Url};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ServerName,
+};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+
+use super::{requested::RequestedValues, DeviceInfo, LightOptions, LightStatus, SmartHomeApi};
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+const PORT: u16 = 8443;
+
+/// Time to wait for the action button of the hub to be pressed when pairing
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Characters allowed in a PKCE code verifier
+const VERIFIER_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
+
+pub struct Dirigera {
+    _config: Arc<Config>,
+    client: Client,
+    base_url: Url,
+    requested: RequestedValues,
+}
+
+impl Dirigera {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let dirigera_config = &config.smart_home.dirigera;
+        let Some(hub_address) = &dirigera_config.hub_address else {
+            return Err(super::Error::Configuration(
+                "DIRIGERA hub address not configured".to_string(),
+            ));
+        };
+        let Some(access_token) = &dirigera_config.access_token else {
+            return Err(super::Error::Configuration(
+                "DIRIGERA access token not configured".to_string(),
+            ));
+        };
+
+        let mut auth_header = HeaderMap::new();
+        auth_header.append(
+            "Authorization",
+            format!("Bearer {access_token}").parse().map_err(|_| {
+                super::Error::Configuration("DIRIGERA access token is invalid".to_string())
+            })?,
+        );
+
+        let client = Self::client(&config, auth_header)?;
+        let base_url = Self::base_url(hub_address)?;
+
+        Ok(Self {
+            _config: config,
+            client,
+            base_url,
+            requested: Default::default(),
+        })
+    }
+
+    /// Client that only accepts the pinned hub certificate. The hub
+    /// certificate is self-signed and not issued for the hub address, so it
+    /// can't be verified against a CA.
+    fn client(config: &Config, headers: HeaderMap) -> super::Result<Client> {
+        let Some(fingerprint) = &config.smart_home.dirigera.certificate_sha256 else {
+            return Err(super::Error::Configuration(
+                "DIRIGERA certificate fingerprint not configured".to_string(),
+            ));
+        };
+        let verifier = PinnedCertificate::new(fingerprint)?;
+
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+
+        Ok(reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_preconfigured_tls(tls_config)
+            .default_headers(headers)
+            .build()
+            .unwrap())
+    }
+
+    /// API base URL of the hub, `https://<address>:8443/v1/`
+    fn base_url(address: &str) -> super::Result<Url> {
+        let invalid =
+            || super::Error::Configuration(format!("Invalid DIRIGERA hub address `{address}`"));
+
+        let mut url: Url = format!("https://{address}/v1/")
+            .parse()
+            .map_err(|_| invalid())?;
+        if url.port().is_none() {
+            url.set_port(Some(PORT)).map_err(|_| invalid())?;
+        }
+        Ok(url)
+    }
+
+    /// Device IDs are UUIDs with a suffix, e.g.
+    /// `5ac8ce0f-6f9c-4f1b-8b6f-0c2e3e8d4b1a_1`
+    fn device_url(&self, id: &str) -> super::Result<Url> {
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+        if !valid {
+            return Err(super::Error::InvalidId(id.to_string()));
+        }
+
+        Ok(self.base_url.join(&format!("devices/{id}")).unwrap())
+    }
+
+    fn error_from_status(res: Response) -> super::Result<Response> {
+        match res.status() {
+            StatusCode::NOT_FOUND => Err(super::Error::UnknownDeviceId),
+            StatusCode::UNAUTHORIZED => Err(super::Error::Configuration(
+                "DIRIGERA access token was not accepted".to_string(),
+            )),
+            _ => Ok(res.error_for_status()?),
+        }
+    }
+
+    async fn get_device(&self, id: &str) -> super::Result<Device> {
+        let url = self.device_url(id)?;
+
+        let res = self.client.get(url).send().await?;
+        Ok(Self::error_from_status(res)?.json().await?)
+    }
+
+    /// Update attributes of a device. Capabilities that the device can't
+    /// receive are rejected before sending anything.
+    async fn set_attributes(
+        &self,
+        id: &str,
+        capability: &'static str,
+        attributes: serde_json::Value,
+    ) -> super::Result<()> {
+        let device = self.get_device(id).await?;
+        if !device
+            .capabilities
+            .can_receive
+            .iter()
+            .any(|c| c == capability)
+        {
+            return Err(super::Error::UnsupportedCapability(capability));
+        }
+
+        let url = self.device_url(id)?;
+        let body = serde_json::json!([{ "attributes": attributes }]);
+
+        tracing::debug!(device_id = id, body = body.to_string(), "Updating device");
+
+        let res = self.client.patch(url).json(&body).send().await?;
+        Self::error_from_status(res)?;
+        Ok(())
+    }
+}
+
+/// Accepts only a certificate with the configured SHA-256 fingerprint
+struct PinnedCertificate {
+    fingerprint: Vec<u8>,
+}
+
+impl PinnedCertificate {
+    /// Parse a fingerprint in hex, optionally with colons between bytes
+    fn new(fingerprint: &str) -> super::Result<Self> {
+        let digits: Vec<u8> = fingerprint
+            .chars()
+            .filter(|&c| c != ':')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<_>>()
+            .filter(|d: &Vec<u8>| d.len() == 64)
+            .ok_or_else(|| {
+                super::Error::Configuration(
+                    "DIRIGERA certificate fingerprint must be a SHA-256 hash in hex".to_string(),
+                )
+            })?;
+
+        Ok(Self {
+            fingerprint: digits.chunks(2).map(|b| b[0] << 4 | b[1]).collect(),
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(&end_entity.0).as_slice() != self.fingerprint {
+            return Err(rustls::Error::General(
+                "Hub certificate does not match the configured fingerprint".to_string(),
+            ));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Create an access token. The action button on the bottom of the hub must
+/// be pressed within 60 seconds after starting.
+pub(super) async fn pair(config: &Config, address: &str) -> super::Result<String> {
+    let client = Dirigera::client(config, HeaderMap::new())?;
+    let base_url = Dirigera::base_url(address)?;
+
+    let verifier: String = rand::thread_rng()
+        .sample_iter(Slice::new(VERIFIER_CHARS).unwrap())
+        .take(128)
+        .map(|&c| char::from(c))
+        .collect();
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+    let mut url = base_url.join("oauth/authorize").unwrap();
+    url.query_pairs_mut()
+        .append_pair("audience", "homesmart.local")
+        .append_pair("response_type", "code")
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    let res = client.get(url).send().await?;
+    let authorization: Authorization = res.error_for_status()?.json().await?;
+
+    // The token is issued once the button has been pressed
+    let url = base_url.join("oauth/token").unwrap();
+    let deadline = Instant::now() + PAIRING_TIMEOUT;
+    loop {
+        let res = client
+            .post(url.clone())
+            .form(&[
+                ("code", authorization.code.as_str()),
+                ("name", env!("CARGO_PKG_NAME")),
+                ("grant_type", "authorization_code"),
+                ("code_verifier", &verifier),
+            ])
+            .send()
+            .await?;
+
+        if res.status().is_success() {
+            let token: Token = res.json().await?;
+            return Ok(token.access_token);
+        }
+        if !res.status().is_client_error() {
+            res.error_for_status()?;
+        }
+        if Instant::now() >= deadline {
+            return Err(super::Error::Communication(
+                "The action button of the hub was not pressed within 60 seconds".to_string(),
+            ));
+        }
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Dirigera {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let device = self.get_device(id).await?;
+
+        tracing::debug!("Got status {device:#?}");
+
+        if !device.is_reachable {
+            return Ok(LightStatus::Offline);
+        }
+
+        let attributes = &device.attributes;
+        let color_mode = attributes.color_mode.as_deref();
+
+        let color_temperature = attributes
+            .color_temperature
+            .filter(|_| color_mode != Some("color"))
+            .map(|kelvin| {
+                let (min, max) = attributes.kelvin_range();
+                self.requested
+                    .color_temperature(id, |k| k.clamp(min, max) == kelvin)
+                    .unwrap_or(kelvin)
+            });
+
+        let color = match (attributes.color_hue, attributes.color_saturation) {
+            (Some(hue), Some(saturation)) if color_temperature.is_none() => {
+                let hue = (hue.rem_euclid(360.0) / 3.6).round() as u8 % 100;
+                let saturation = (saturation * 100.0).round().clamp(0.0, 100.0) as u8;
+                let (hue, saturation) = self
+                    .requested
+                    .color(id, |rh, rs| {
+                        rh.abs_diff(hue) <= 1 && rs.abs_diff(saturation) <= 1
+                    })
+                    .unwrap_or((hue, saturation));
+                Some(super::Color { hue, saturation })
+            }
+            _ => None,
+        };
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: attributes.is_on,
+            brightness: attributes.light_level.map(|l| l.min(100)),
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let attributes = serde_json::json!({ "isOn": switched_on });
+        self.set_attributes(id, "isOn", attributes).await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        // Light level zero is not accepted, lights are switched off instead
+        let attributes = serde_json::json!({ "lightLevel": brightness.clamp(1, 100) });
+        self.set_attributes(id, "lightLevel", attributes).await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let device = self.get_device(id).await?;
+        let (min, max) = device.attributes.kelvin_range();
+
+        let attributes = serde_json::json!({ "colorTemperature": temp.clamp(min, max) });
+        self.set_attributes(id, "colorTemperature", attributes)
+            .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let (hue, saturation) = (hue.min(100), saturation.min(100));
+        let attributes = serde_json::json!({
+            "colorHue": f64::from(hue) * 3.6,
+            "colorSaturation": f64::from(saturation) / 100.0,
+        });
+        self.set_attributes(id, "colorHue", attributes).await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    async fn list_devices(&self) -> super::Result<Vec<DeviceInfo>> {
+        let url = self.base_url.join("devices").unwrap();
+
+        let res = self.client.get(url).send().await?;
+        let devices: Vec<Device> = Self::error_from_status(res)?.json().await?;
+
+        Ok(devices
+            .into_iter()
+            .filter(|d| d.device_type == "light")
+            .map(|d| DeviceInfo {
+                id: d.id,
+                name: d.attributes.custom_name,
+                model: d.attributes.model,
+            })
+            .collect())
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    /// Color temperature range of lights that don't report one
+    const DEFAULT_KELVIN_RANGE: (u16, u16) = (2202, 4000);
+
+    #[derive(Deserialize, Debug)]
+    pub struct Authorization {
+        pub code: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Token {
+        pub access_token: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Device {
+        pub id: String,
+        /// `light`, `outlet`, `sensor`, ...
+        pub device_type: String,
+        pub is_reachable: bool,
+        pub attributes: Attributes,
+        pub capabilities: Capabilities,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Attributes {
+        pub custom_name: Option<String>,
+        pub model: Option<String>,
+        #[serde(default)]
+        pub is_on: bool,
+        /// 1-100
+        pub light_level: Option<u8>,
+        pub color_temperature: Option<u16>,
+        /// The warmest and coldest color temperatures are reported as the
+        /// maximum and minimum respectively
+        pub color_temperature_min: Option<u16>,
+        pub color_temperature_max: Option<u16>,
+        /// Degrees
+        pub color_hue: Option<f64>,
+        /// 0-1
+        pub color_saturation: Option<f64>,
+        /// `color` or `temperature`
+        pub color_mode: Option<String>,
+    }
+
+    impl Attributes {
+        /// Supported color temperature range as (warmest, coldest)
+        pub fn kelvin_range(&self) -> (u16, u16) {
+            match (self.color_temperature_min, self.color_temperature_max) {
+                (Some(a), Some(b)) => (a.min(b), a.max(b)),
+                _ => DEFAULT_KELVIN_RANGE,
+            }
+        }
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Capabilities {
+        #[serde(default)]
+        pub can_receive: Vec<String>,
+    }
+}
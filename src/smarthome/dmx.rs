@@ -0,0 +1,656 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use tokio::net::UdpSocket;
+
+use crate::config::{Config, DmxChannelLayout, DmxFixtureConfig, DmxProtocol};
+
+use super::{color, LightOptions, LightStatus, SmartHomeApi};
+
+const UNIVERSE_SIZE: usize = 512;
+
+/// Color temperatures of the white channels of CCT fixtures that don't
+/// configure them
+const DEFAULT_KELVIN_RANGE: (u16, u16) = (2700, 6500);
+
+/// DMX has no read-back, so the state of each fixture is what was last sent to
+/// it. Frames are built from these states and sent at a fixed rate, because
+/// nodes go dark or hold the last frame when the data stops.
+pub struct Dmx {
+    _config: Arc<Config>,
+    fixtures: HashMap<String, DmxFixtureConfig>,
+    states: Arc<Mutex<HashMap<String, FixtureState>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FixtureState {
+    switched_on: bool,
+    brightness: u8,
+    mode: Option<ColorMode>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ColorMode {
+    Color { hue: u8, saturation: u8 },
+    Temperature(u16),
+}
+
+impl Default for FixtureState {
+    fn default() -> Self {
+        // Universes start out dark
+        Self {
+            switched_on: false,
+            brightness: 100,
+            mode: None,
+        }
+    }
+}
+
+impl Dmx {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let dmx_config = &config.smart_home.dmx;
+
+        if dmx_config.frame_rate == 0 || dmx_config.frame_rate > 44 {
+            return Err(super::Error::Configuration(
+                "DMX frame rate must be between 1 and 44".to_string(),
+            ));
+        }
+
+        let mut fixtures = HashMap::new();
+        for fixture in &dmx_config.fixtures {
+            Self::validate_fixture(dmx_config.protocol, fixture)?;
+            fixtures.insert(fixture.id.clone(), fixture.clone());
+        }
+
+        let destination = match &dmx_config.destination {
+            Some(destination) => Some(destination.parse::<IpAddr>().map_err(|_| {
+                super::Error::Configuration(format!("Invalid DMX destination `{destination}`"))
+            })?),
+            None => None,
+        };
+
+        let states: Arc<Mutex<HashMap<String, FixtureState>>> = Default::default();
+        let sender = Sender {
+            protocol: dmx_config.protocol,
+            destination,
+            cid: component_id(&dmx_config.source_name),
+            source_name: dmx_config.source_name.clone(),
+            fixtures: fixtures.values().cloned().collect(),
+            states: states.clone(),
+            sequences: Default::default(),
+        };
+        let interval = Duration::from_secs(1) / dmx_config.frame_rate;
+        tokio::spawn(sender.run(interval));
+
+        Ok(Self {
+            _config: config,
+            fixtures,
+            states,
+        })
+    }
+
+    fn validate_fixture(protocol: DmxProtocol, fixture: &DmxFixtureConfig) -> super::Result<()> {
+        let invalid = |reason: &str| {
+            super::Error::Configuration(format!("Invalid DMX fixture `{}`: {reason}", fixture.id))
+        };
+
+        let universes = match protocol {
+            DmxProtocol::ArtNet => 0..=0x7fff,
+            DmxProtocol::Sacn => 1..=63999,
+        };
+        if !universes.contains(&fixture.universe) {
+            return Err(invalid("universe is out of range"));
+        }
+
+        let last_channel = usize::from(fixture.address) + fixture.layout.channels() - 1;
+        if fixture.address == 0 || last_channel > UNIVERSE_SIZE {
+            return Err(invalid("channels must be between 1 and 512"));
+        }
+
+        let (min_kelvin, max_kelvin) = kelvin_range(fixture);
+        if fixture.layout == DmxChannelLayout::Cct && min_kelvin >= max_kelvin {
+            return Err(invalid("min_kelvin must be below max_kelvin"));
+        }
+
+        Ok(())
+    }
+
+    fn fixture(&self, id: &str) -> super::Result<&DmxFixtureConfig> {
+        self.fixtures.get(id).ok_or(super::Error::UnknownDeviceId)
+    }
+
+    fn update_state(&self, id: &str, f: impl FnOnce(&mut FixtureState)) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(id.to_string()).or_default();
+        f(state);
+
+        tracing::debug!(device_id = id, state = ?state, "Updated fixture state");
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Dmx {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        self.fixture(id)?;
+        let state = self
+            .states
+            .lock()
+            .unwrap()
+            .get(id)
+            .copied()
+            .unwrap_or_default();
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: state.switched_on,
+            brightness: Some(state.brightness),
+            color_temperature: match state.mode {
+                Some(ColorMode::Temperature(kelvin)) => Some(kelvin),
+                _ => None,
+            },
+            color: match state.mode {
+                Some(ColorMode::Color { hue, saturation }) => {
+                    Some(super::Color { hue, saturation })
+                }
+                _ => None,
+            },
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        self.fixture(id)?;
+        self.update_state(id, |s| s.switched_on = switched_on);
+        Ok(())
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        self.fixture(id)?;
+        self.update_state(id, |s| s.brightness = brightness.clamp(0, 100));
+        Ok(())
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        if self.fixture(id)?.layout == DmxChannelLayout::Dimmer {
+            return Err(super::Error::UnsupportedCapability("color temperature"));
+        }
+
+        self.update_state(id, |s| s.mode = Some(ColorMode::Temperature(temp)));
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        if matches!(
+            self.fixture(id)?.layout,
+            DmxChannelLayout::Dimmer | DmxChannelLayout::Cct
+        ) {
+            return Err(super::Error::UnsupportedCapability("color"));
+        }
+
+        self.update_state(id, |s| {
+            s.mode = Some(ColorMode::Color {
+                hue: hue.min(100),
+                saturation: saturation.min(100),
+            })
+        });
+        Ok(())
+    }
+}
+
+impl DmxChannelLayout {
+    fn channels(self) -> usize {
+        match self {
+            DmxChannelLayout::Dimmer => 1,
+            DmxChannelLayout::Rgb => 3,
+            DmxChannelLayout::Rgbw => 4,
+            DmxChannelLayout::Cct => 2,
+        }
+    }
+}
+
+fn kelvin_range(fixture: &DmxFixtureConfig) -> (u16, u16) {
+    (
+        fixture.min_kelvin.unwrap_or(DEFAULT_KELVIN_RANGE.0),
+        fixture.max_kelvin.unwrap_or(DEFAULT_KELVIN_RANGE.1),
+    )
+}
+
+/// Channel values of the fixture in its state
+fn channel_values(fixture: &DmxFixtureConfig, state: &FixtureState) -> Vec<u8> {
+    let level = if state.switched_on {
+        f64::from(state.brightness.min(100)) / 100.0
+    } else {
+        0.0
+    };
+    let to_u8 = |c: f64| (c.clamp(0.0, 1.0) * level * 255.0).round() as u8;
+
+    let rgb = || match state.mode {
+        Some(ColorMode::Color { hue, saturation }) => color::hs_to_rgb(hue, saturation),
+        Some(ColorMode::Temperature(kelvin)) => color::kelvin_to_rgb(kelvin),
+        None => (1.0, 1.0, 1.0),
+    };
+
+    match fixture.layout {
+        DmxChannelLayout::Dimmer => vec![to_u8(1.0)],
+        DmxChannelLayout::Rgb => {
+            let (r, g, b) = rgb();
+            vec![to_u8(r), to_u8(g), to_u8(b)]
+        }
+        DmxChannelLayout::Rgbw => {
+            // The white channel takes over the part all primaries share
+            let (r, g, b) = rgb();
+            let w = r.min(g).min(b);
+            vec![to_u8(r - w), to_u8(g - w), to_u8(b - w), to_u8(w)]
+        }
+        DmxChannelLayout::Cct => {
+            let (min, max) = kelvin_range(fixture);
+            let (min, max) = (f64::from(min), f64::from(max));
+            let kelvin = match state.mode {
+                Some(ColorMode::Temperature(kelvin)) => f64::from(kelvin),
+                _ => (min + max) / 2.0,
+            };
+            let cold = ((kelvin - min) / (max - min)).clamp(0.0, 1.0);
+            vec![to_u8(1.0 - cold), to_u8(cold)]
+        }
+    }
+}
+
+/// sACN component identifier, derived from the source name so that it stays
+/// the same across restarts
+fn component_id(source_name: &str) -> [u8; 16] {
+    Sha256::digest(source_name.as_bytes())[..16]
+        .try_into()
+        .unwrap()
+}
+
+/// Sends the frames of every universe with fixtures at the frame rate
+struct Sender {
+    protocol: DmxProtocol,
+    destination: Option<IpAddr>,
+    cid: [u8; 16],
+    source_name: String,
+    fixtures: Vec<DmxFixtureConfig>,
+    states: Arc<Mutex<HashMap<String, FixtureState>>>,
+    /// Sequence numbers of the last packets sent by universe
+    sequences: HashMap<u16, u8>,
+}
+
+impl Sender {
+    async fn run(mut self, interval: Duration) {
+        let socket = match self.socket().await {
+            Ok(socket) => socket,
+            Err(e) => {
+                tracing::error!(error = %e, "Opening DMX socket failed");
+                return;
+            }
+        };
+
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+        loop {
+            ticker.tick().await;
+
+            for (universe, frame) in self.frames() {
+                let (packet, address) = self.packet(universe, &frame);
+                if let Err(e) = socket.send_to(&packet, address).await {
+                    tracing::warn!(universe, error = %e, "Sending DMX frame failed");
+                }
+            }
+        }
+    }
+
+    async fn socket(&self) -> std::io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.set_broadcast(true)?;
+        Ok(socket)
+    }
+
+    fn frames(&self) -> BTreeMap<u16, [u8; UNIVERSE_SIZE]> {
+        let states = self.states.lock().unwrap();
+
+        let mut frames = BTreeMap::new();
+        for fixture in &self.fixtures {
+            let frame = frames
+                .entry(fixture.universe)
+                .or_insert([0u8; UNIVERSE_SIZE]);
+            let state = states.get(&fixture.id).copied().unwrap_or_default();
+
+            let start = usize::from(fixture.address) - 1;
+            let values = channel_values(fixture, &state);
+            frame[start..start + values.len()].copy_from_slice(&values);
+        }
+        frames
+    }
+
+    fn packet(&mut self, universe: u16, frame: &[u8]) -> (Vec<u8>, SocketAddr) {
+        // Zero means that sequencing is disabled in Art-Net, and is skipped
+        // in both protocols
+        let sequence = self.sequences.entry(universe).or_insert(0);
+        *sequence = sequence.checked_add(1).unwrap_or(1);
+
+        match self.protocol {
+            DmxProtocol::ArtNet => {
+                let address = self.destination.unwrap_or(IpAddr::V4(Ipv4Addr::BROADCAST));
+                (
+                    protocol::art_dmx(universe, *sequence, frame),
+                    SocketAddr::new(address, protocol::ART_NET_PORT),
+                )
+            }
+            DmxProtocol::Sacn => {
+                let address = self.destination.unwrap_or_else(|| {
+                    let [high, low] = universe.to_be_bytes();
+                    IpAddr::V4(Ipv4Addr::new(239, 255, high, low))
+                });
+                (
+                    protocol::sacn_data(&self.cid, &self.source_name, universe, *sequence, frame),
+                    SocketAddr::new(address, protocol::SACN_PORT),
+                )
+            }
+        }
+    }
+}
+
+mod protocol {
+    pub const ART_NET_PORT: u16 = 6454;
+    pub const SACN_PORT: u16 = 5568;
+
+    const ART_NET_ID: &[u8; 8] = b"Art-Net\0";
+    const OP_DMX: u16 = 0x5000;
+    const ART_NET_VERSION: u16 = 14;
+
+    const ACN_PACKET_ID: &[u8; 12] = b"ASC-E1.17\0\0\0";
+    const VECTOR_ROOT_E131_DATA: u32 = 0x0000_0004;
+    const VECTOR_E131_DATA_PACKET: u32 = 0x0000_0002;
+    const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+    const DEFAULT_PRIORITY: u8 = 100;
+
+    /// ArtDmx packet for a 15-bit port address
+    pub fn art_dmx(universe: u16, sequence: u8, frame: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(18 + frame.len());
+        packet.extend_from_slice(ART_NET_ID);
+        packet.extend_from_slice(&OP_DMX.to_le_bytes());
+        packet.extend_from_slice(&ART_NET_VERSION.to_be_bytes());
+        packet.push(sequence);
+        // Physical input port, informational only
+        packet.push(0);
+        // SubUni and Net
+        packet.extend_from_slice(&(universe & 0x7fff).to_le_bytes());
+        packet.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+        packet.extend_from_slice(frame);
+        packet
+    }
+
+    /// E1.31 data packet with the null start code
+    pub fn sacn_data(
+        cid: &[u8; 16],
+        source_name: &str,
+        universe: u16,
+        sequence: u8,
+        frame: &[u8],
+    ) -> Vec<u8> {
+        let total_len = 126 + frame.len();
+        // PDU lengths are counted from the flags and length field to the end
+        let flags_and_length = |offset: usize| (0x7000 | (total_len - offset) as u16).to_be_bytes();
+
+        let mut packet = Vec::with_capacity(total_len);
+
+        // Root layer
+        packet.extend_from_slice(&0x0010u16.to_be_bytes());
+        packet.extend_from_slice(&0x0000u16.to_be_bytes());
+        packet.extend_from_slice(ACN_PACKET_ID);
+        packet.extend_from_slice(&flags_and_length(16));
+        packet.extend_from_slice(&VECTOR_ROOT_E131_DATA.to_be_bytes());
+        packet.extend_from_slice(cid);
+
+        // Framing layer
+        packet.extend_from_slice(&flags_and_length(38));
+        packet.extend_from_slice(&VECTOR_E131_DATA_PACKET.to_be_bytes());
+        let mut name = [0u8; 64];
+        let name_len = source_name.len().min(63);
+        name[..name_len].copy_from_slice(&source_name.as_bytes()[..name_len]);
+        packet.extend_from_slice(&name);
+        packet.push(DEFAULT_PRIORITY);
+        // Synchronization address, not used
+        packet.extend_from_slice(&0u16.to_be_bytes());
+        packet.push(sequence);
+        // Options
+        packet.push(0);
+        packet.extend_from_slice(&universe.to_be_bytes());
+
+        // DMP layer
+        packet.extend_from_slice(&flags_and_length(115));
+        packet.push(VECTOR_DMP_SET_PROPERTY);
+        // Address and data type
+        packet.push(0xa1);
+        // First property address
+        packet.extend_from_slice(&0u16.to_be_bytes());
+        // Address increment
+        packet.extend_from_slice(&1u16.to_be_bytes());
+        packet.extend_from_slice(&(frame.len() as u16 + 1).to_be_bytes());
+        // Start code
+        packet.push(0);
+        packet.extend_from_slice(frame);
+
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CID: [u8; 16] = [
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e,
+        0x1f,
+    ];
+
+    fn fixture(
+        id: &str,
+        universe: u16,
+        address: u16,
+        layout: DmxChannelLayout,
+    ) -> DmxFixtureConfig {
+        DmxFixtureConfig {
+            id: id.to_string(),
+            universe,
+            address,
+            layout,
+            min_kelvin: None,
+            max_kelvin: None,
+        }
+    }
+
+    fn sender(protocol: DmxProtocol, destination: Option<IpAddr>) -> Sender {
+        Sender {
+            protocol,
+            destination,
+            cid: CID,
+            source_name: "light-operator".to_string(),
+            fixtures: vec![
+                fixture("spot", 1, 1, DmxChannelLayout::Rgb),
+                fixture("strip", 1, 510, DmxChannelLayout::Dimmer),
+                fixture("wash", 2, 5, DmxChannelLayout::Cct),
+            ],
+            states: Default::default(),
+            sequences: Default::default(),
+        }
+    }
+
+    #[test]
+    fn builds_art_dmx_packets() {
+        let packet = protocol::art_dmx(0x0123, 5, &[1, 2, 3, 4]);
+
+        assert_eq!(
+            packet,
+            [
+                b'A', b'r', b't', b'-', b'N', b'e', b't', 0, // ID
+                0x00, 0x50, // OpDmx, little endian
+                0x00, 0x0e, // Protocol version 14
+                0x05, // Sequence
+                0x00, // Physical
+                0x23, 0x01, // SubUni and Net
+                0x00, 0x04, // Length
+                1, 2, 3, 4,
+            ]
+        );
+
+        // Port addresses are 15 bits
+        let packet = protocol::art_dmx(0xffff, 1, &[0; 2]);
+        assert_eq!(&packet[14..16], &[0xff, 0x7f]);
+    }
+
+    #[test]
+    fn builds_e131_packets() {
+        let mut frame = [0u8; UNIVERSE_SIZE];
+        frame[0] = 0xaa;
+        frame[511] = 0x55;
+        let packet = protocol::sacn_data(&CID, "light-operator", 0x0102, 7, &frame);
+
+        assert_eq!(packet.len(), 638);
+
+        // Root layer
+        assert_eq!(&packet[0..4], &[0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(&packet[4..16], b"ASC-E1.17\0\0\0");
+        assert_eq!(&packet[16..18], &[0x72, 0x6e]);
+        assert_eq!(&packet[18..22], &[0, 0, 0, 4]);
+        assert_eq!(&packet[22..38], &CID);
+
+        // Framing layer
+        assert_eq!(&packet[38..40], &[0x72, 0x58]);
+        assert_eq!(&packet[40..44], &[0, 0, 0, 2]);
+        assert_eq!(&packet[44..58], b"light-operator");
+        assert!(packet[58..108].iter().all(|&b| b == 0));
+        assert_eq!(packet[108], 100);
+        assert_eq!(&packet[109..111], &[0, 0]);
+        assert_eq!(packet[111], 7);
+        assert_eq!(packet[112], 0);
+        assert_eq!(&packet[113..115], &[0x01, 0x02]);
+
+        // DMP layer
+        assert_eq!(&packet[115..117], &[0x72, 0x0b]);
+        assert_eq!(&packet[117..125], &[0x02, 0xa1, 0, 0, 0, 1, 0x02, 0x01]);
+        assert_eq!(packet[125], 0);
+        assert_eq!(packet[126], 0xaa);
+        assert_eq!(packet[637], 0x55);
+    }
+
+    #[test]
+    fn truncates_long_source_names() {
+        let name = "x".repeat(100);
+        let packet = protocol::sacn_data(&CID, &name, 1, 1, &[0; UNIVERSE_SIZE]);
+        assert!(packet[44..107].iter().all(|&b| b == b'x'));
+        // The name is always null terminated
+        assert_eq!(packet[107], 0);
+        assert_eq!(packet[108], 100);
+    }
+
+    #[test]
+    fn numbers_packets_per_universe() {
+        let mut art_net_sender = sender(DmxProtocol::ArtNet, None);
+        let frame = [0; UNIVERSE_SIZE];
+
+        let sequence = |packet: &[u8]| packet[12];
+        assert_eq!(sequence(&art_net_sender.packet(1, &frame).0), 1);
+        assert_eq!(sequence(&art_net_sender.packet(1, &frame).0), 2);
+        assert_eq!(sequence(&art_net_sender.packet(2, &frame).0), 1);
+
+        // Zero is skipped when wrapping around
+        art_net_sender.sequences.insert(1, 254);
+        assert_eq!(sequence(&art_net_sender.packet(1, &frame).0), 255);
+        assert_eq!(sequence(&art_net_sender.packet(1, &frame).0), 1);
+
+        let mut sacn_sender = sender(DmxProtocol::Sacn, None);
+        let sequence = |packet: &[u8]| packet[111];
+        assert_eq!(sequence(&sacn_sender.packet(1, &frame).0), 1);
+        assert_eq!(sequence(&sacn_sender.packet(1, &frame).0), 2);
+        assert_eq!(sequence(&sacn_sender.packet(300, &frame).0), 1);
+    }
+
+    #[test]
+    fn sends_to_protocol_addresses() {
+        let frame = [0; UNIVERSE_SIZE];
+
+        let (packet, address) = sender(DmxProtocol::ArtNet, None).packet(3, &frame);
+        assert_eq!(address, "255.255.255.255:6454".parse().unwrap());
+        assert_eq!(&packet[14..16], &[3, 0]);
+
+        // sACN universes have their own multicast groups
+        let (packet, address) = sender(DmxProtocol::Sacn, None).packet(0x0102, &frame);
+        assert_eq!(address, "239.255.1.2:5568".parse().unwrap());
+        assert_eq!(&packet[113..115], &[0x01, 0x02]);
+
+        let node = "192.168.1.50".parse().unwrap();
+        let (_, address) = sender(DmxProtocol::Sacn, Some(node)).packet(1, &frame);
+        assert_eq!(address, "192.168.1.50:5568".parse().unwrap());
+    }
+
+    #[test]
+    fn builds_frames_from_fixture_states() {
+        let sender = sender(DmxProtocol::Sacn, None);
+        {
+            let mut states = sender.states.lock().unwrap();
+            states.insert(
+                "spot".to_string(),
+                FixtureState {
+                    switched_on: true,
+                    brightness: 100,
+                    mode: Some(ColorMode::Color {
+                        hue: 0,
+                        saturation: 100,
+                    }),
+                },
+            );
+            states.insert(
+                "strip".to_string(),
+                FixtureState {
+                    switched_on: true,
+                    brightness: 50,
+                    mode: None,
+                },
+            );
+            states.insert(
+                "wash".to_string(),
+                FixtureState {
+                    switched_on: true,
+                    brightness: 100,
+                    mode: Some(ColorMode::Temperature(6500)),
+                },
+            );
+        }
+
+        let frames = sender.frames();
+        assert_eq!(frames.keys().copied().collect::<Vec<_>>(), [1, 2]);
+
+        let universe = &frames[&1];
+        assert_eq!(&universe[0..3], &[255, 0, 0]);
+        assert_eq!(universe[509], 128);
+        assert!(universe[3..509].iter().all(|&b| b == 0));
+
+        let universe = &frames[&2];
+        assert_eq!(&universe[4..6], &[0, 255]);
+    }
+
+    #[test]
+    fn validates_fixtures() {
+        let valid = fixture("spot", 1, 510, DmxChannelLayout::Rgb);
+        assert!(Dmx::validate_fixture(DmxProtocol::Sacn, &valid).is_ok());
+
+        let past_end = fixture("spot", 1, 511, DmxChannelLayout::Rgb);
+        assert!(Dmx::validate_fixture(DmxProtocol::Sacn, &past_end).is_err());
+        let no_address = fixture("spot", 1, 0, DmxChannelLayout::Dimmer);
+        assert!(Dmx::validate_fixture(DmxProtocol::Sacn, &no_address).is_err());
+
+        // sACN universes start at 1, Art-Net port addresses at 0
+        let universe_zero = fixture("spot", 0, 1, DmxChannelLayout::Dimmer);
+        assert!(Dmx::validate_fixture(DmxProtocol::Sacn, &universe_zero).is_err());
+        assert!(Dmx::validate_fixture(DmxProtocol::ArtNet, &universe_zero).is_ok());
+    }
+}
@@ -0,0 +1,176 @@
+use std::{sync::Arc, time::Duration};
+
+use api_models::*;
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+
+use crate::config::Config;
+
+use super::{color, requested::RequestedValues, LightOptions, LightStatus, SmartHomeApi};
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+const PORT: u16 = 9123;
+
+/// Range of the `temperature` value, which is in mireds (7000-2900 K)
+const MIRED_RANGE: (u16, u16) = (143, 344);
+
+/// Brightness range accepted by the lights
+const BRIGHTNESS_RANGE: (u8, u8) = (3, 100);
+
+pub struct Elgato {
+    _config: Arc<Config>,
+    client: Client,
+    requested: RequestedValues,
+}
+
+impl Elgato {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let timeout = Duration::from_millis(config.smart_home.elgato.timeout_ms);
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .timeout(timeout)
+            .build()
+            .unwrap();
+
+        Ok(Self {
+            _config: config,
+            client,
+            requested: Default::default(),
+        })
+    }
+
+    /// Device IDs are host names or IP addresses of the lights, optionally
+    /// with a port
+    fn lights_url(id: &str) -> super::Result<Url> {
+        let invalid = || super::Error::InvalidId(id.to_string());
+
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'));
+        if !valid {
+            return Err(invalid());
+        }
+
+        let mut url: Url = format!("http://{id}/elgato/lights")
+            .parse()
+            .map_err(|_| invalid())?;
+        if url.port().is_none() {
+            url.set_port(Some(PORT)).map_err(|_| invalid())?;
+        }
+        Ok(url)
+    }
+
+    async fn get_light(&self, id: &str) -> super::Result<Light> {
+        let url = Self::lights_url(id)?;
+        let res = self.client.get(url).send().await?;
+        let lights: Lights = res.error_for_status()?.json().await?;
+
+        lights.lights.into_iter().next().ok_or_else(|| {
+            super::Error::Communication(format!("Elgato device {id} reported no lights"))
+        })
+    }
+
+    async fn update_light(&self, id: &str, light: serde_json::Value) -> super::Result<()> {
+        let url = Self::lights_url(id)?;
+        let body = serde_json::json!({ "numberOfLights": 1, "lights": [light] });
+
+        tracing::debug!(device_id = id, state = body.to_string(), "Updating light");
+
+        let res = self.client.put(url).json(&body).send().await?;
+        res.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Elgato {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let light = match self.get_light(id).await {
+            Ok(light) => light,
+            Err(super::Error::RequestFailed(e)) if e.is_connect() || e.is_timeout() => {
+                tracing::debug!(error = %e, "Elgato light is unreachable");
+                return Ok(LightStatus::Offline);
+            }
+            Err(e) => return Err(e),
+        };
+
+        tracing::debug!("Got status {light:#?}");
+
+        let mired = light.temperature;
+        let color_temperature = self
+            .requested
+            .color_temperature(id, |k| {
+                color::kelvin_to_mired(k).clamp(MIRED_RANGE.0, MIRED_RANGE.1) == mired
+            })
+            .unwrap_or_else(|| color::mired_to_kelvin(mired));
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: light.on == 1,
+            brightness: Some(
+                self.requested
+                    .brightness(id, |b| {
+                        b.clamp(BRIGHTNESS_RANGE.0, BRIGHTNESS_RANGE.1) == light.brightness
+                    })
+                    .unwrap_or(light.brightness.min(100)),
+            ),
+            color_temperature: Some(color_temperature),
+            color: None,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        self.update_light(id, serde_json::json!({ "on": u8::from(switched_on) }))
+            .await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let value = brightness.clamp(BRIGHTNESS_RANGE.0, BRIGHTNESS_RANGE.1);
+        self.update_light(id, serde_json::json!({ "brightness": value }))
+            .await?;
+
+        self.requested.set_brightness(id, brightness);
+        Ok(())
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let mired = color::kelvin_to_mired(temp).clamp(MIRED_RANGE.0, MIRED_RANGE.1);
+        self.update_light(id, serde_json::json!({ "temperature": mired }))
+            .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    /// Key Lights and Ring Lights only have adjustable white
+    async fn set_color(&self, _id: &str, _hue: u8, _saturation: u8) -> super::Result<()> {
+        Err(super::Error::UnsupportedCapability("color"))
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Lights {
+        pub lights: Vec<Light>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Light {
+        /// 0 or 1
+        pub on: u8,
+        /// 3-100
+        pub brightness: u8,
+        /// Mireds, 143-344
+        pub temperature: u16,
+    }
+}
@@ -0,0 +1,704 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context};
+use snow::StatelessTransportState;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::watch,
+};
+
+use crate::smarthome::events::Backoff;
+
+use super::{
+    proto::{self, LightStateResponse, ListEntitiesLightResponse},
+    Shared,
+};
+
+const CLIENT_INFO: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+const NOISE_PROLOGUE: &[u8] = b"NoiseAPIInit\x00\x00";
+
+const PLAINTEXT_INDICATOR: u8 = 0x00;
+const NOISE_INDICATOR: u8 = 0x01;
+
+/// Noise frames have a 16-bit length
+const MAX_NOISE_FRAME: usize = u16::MAX as usize;
+const NOISE_TAG_LEN: usize = 16;
+
+/// Send a ping if nothing has been received for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub(super) struct DeviceSettings {
+    /// `host:port`
+    pub address: String,
+    pub encryption_key: Option<[u8; 32]>,
+    pub password: String,
+}
+
+/// Connection to one ESPHome device, which may have several lights
+pub(super) struct Device {
+    pub host: String,
+    settings: DeviceSettings,
+    state: Mutex<DeviceState>,
+    /// Notified whenever the state changes
+    changed: watch::Sender<()>,
+    writer: tokio::sync::Mutex<Option<FrameWriter>>,
+}
+
+#[derive(Default)]
+pub(super) struct DeviceState {
+    pub connected: bool,
+    /// Whether connecting has been tried at least once
+    pub attempted: bool,
+    /// Light entities by object ID
+    pub lights: HashMap<String, ListEntitiesLightResponse>,
+    /// Latest light states by entity key
+    pub states: HashMap<u32, LightStateResponse>,
+}
+
+impl Device {
+    pub fn new(host: String, settings: DeviceSettings) -> Self {
+        Self {
+            host,
+            settings,
+            state: Default::default(),
+            changed: watch::channel(()).0,
+            writer: Default::default(),
+        }
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, DeviceState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    pub async fn send(&self, msg_type: u16, payload: &[u8]) -> crate::smarthome::Result<()> {
+        let mut writer = self.writer.lock().await;
+        let Some(writer) = writer.as_mut() else {
+            return Err(crate::smarthome::Error::Communication(format!(
+                "Not connected to {}",
+                self.host
+            )));
+        };
+
+        writer
+            .write_message(msg_type, payload)
+            .await
+            .map_err(|e| crate::smarthome::Error::Communication(e.to_string()))
+    }
+
+    fn light_id(&self, key: u32) -> Option<String> {
+        self.state()
+            .lights
+            .values()
+            .find(|l| l.key == key)
+            .map(|l| format!("{}/{}", self.host, l.object_id))
+    }
+
+    fn light_ids(&self) -> Vec<String> {
+        self.state()
+            .lights
+            .keys()
+            .map(|object_id| format!("{}/{object_id}", self.host))
+            .collect()
+    }
+}
+
+/// Keep connected to the device and publish the IDs of lights whose state
+/// changes. Reconnects with backoff until the program exits.
+pub(super) async fn run(device: Arc<Device>, shared: Arc<Shared>, timeout: Duration) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+    loop {
+        match connect_and_listen(&device, &shared, timeout, &mut backoff).await {
+            Ok(()) => tracing::info!(host = device.host, "ESPHome connection closed"),
+            Err(e) => {
+                let err_ref: &(dyn std::error::Error + Send + Sync) = e.as_ref();
+                tracing::warn!(
+                    host = device.host,
+                    error = err_ref,
+                    "ESPHome connection failed"
+                );
+            }
+        }
+
+        *device.writer.lock().await = None;
+        let was_connected = {
+            let mut state = device.state();
+            state.states.clear();
+            state.attempted = true;
+            std::mem::replace(&mut state.connected, false)
+        };
+        device.changed.send_replace(());
+
+        if was_connected {
+            // The lights are now offline
+            for id in device.light_ids() {
+                shared.events.send_changed(id);
+            }
+            shared.update_connected();
+        }
+
+        let delay = backoff.next_delay();
+        tracing::debug!(
+            host = device.host,
+            "Reconnecting to ESPHome device in {delay:?}"
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn connect_and_listen(
+    device: &Device,
+    shared: &Shared,
+    timeout: Duration,
+    backoff: &mut Backoff,
+) -> anyhow::Result<()> {
+    let (mut reader, writer, lights) = tokio::time::timeout(timeout, connect(&device.settings))
+        .await
+        .map_err(|_| anyhow!("Connecting timed out"))??;
+
+    tracing::info!(
+        host = device.host,
+        lights = lights.len(),
+        "Connected to ESPHome device"
+    );
+    backoff.reset();
+
+    *device.writer.lock().await = Some(writer);
+    {
+        let mut state = device.state();
+        state.lights = lights
+            .into_iter()
+            .map(|l| (l.object_id.clone(), l))
+            .collect();
+        state.connected = true;
+        state.attempted = true;
+    }
+    device.changed.send_replace(());
+    shared.update_connected();
+
+    device.send(proto::SUBSCRIBE_STATES_REQUEST, &[]).await?;
+
+    let mut awaiting_pong = false;
+
+    loop {
+        // Only the first byte is read with the idle timeout, so that a frame
+        // is never left half read
+        let indicator = match tokio::time::timeout(IDLE_TIMEOUT, reader.read_indicator()).await {
+            Ok(indicator) => indicator?,
+            Err(_) if awaiting_pong => bail!("Ping timed out"),
+            Err(_) => {
+                device.send(proto::PING_REQUEST, &[]).await?;
+                awaiting_pong = true;
+                continue;
+            }
+        };
+        let (msg_type, payload) = tokio::time::timeout(timeout, reader.read_body(indicator))
+            .await
+            .map_err(|_| anyhow!("Reading message timed out"))??;
+        awaiting_pong = false;
+
+        match msg_type {
+            proto::LIGHT_STATE_RESPONSE => {
+                let light_state = proto::LightStateResponse::decode(&payload)?;
+                let Some(id) = device.light_id(light_state.key) else {
+                    continue;
+                };
+
+                tracing::debug!(device_id = id, "Light changed");
+                device.state().states.insert(light_state.key, light_state);
+                device.changed.send_replace(());
+                shared.events.send_changed(id);
+            }
+            proto::PING_REQUEST => device.send(proto::PING_RESPONSE, &[]).await?,
+            proto::GET_TIME_REQUEST => {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                let response = proto::get_time_response(now.as_secs() as u32);
+                device.send(proto::GET_TIME_RESPONSE, &response).await?;
+            }
+            proto::DISCONNECT_REQUEST => {
+                device.send(proto::DISCONNECT_RESPONSE, &[]).await?;
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Open the connection, log in and list the light entities
+async fn connect(
+    settings: &DeviceSettings,
+) -> anyhow::Result<(FrameReader, FrameWriter, Vec<ListEntitiesLightResponse>)> {
+    let stream = TcpStream::connect(&settings.address)
+        .await
+        .context("Connecting failed")?;
+    stream.set_nodelay(true)?;
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+
+    let noise = match &settings.encryption_key {
+        Some(key) => Some(Arc::new(noise_handshake(&mut read, &mut write, key).await?)),
+        None => None,
+    };
+    let mut reader = FrameReader::new(read, noise.clone());
+    let mut writer = FrameWriter::new(write, noise);
+
+    writer
+        .write_message(proto::HELLO_REQUEST, &proto::hello_request(CLIENT_INFO))
+        .await?;
+    let hello = proto::HelloResponse::decode(&reader.expect(proto::HELLO_RESPONSE).await?)?;
+    tracing::debug!(
+        name = hello.name,
+        server_info = hello.server_info,
+        api_version = format!("{}.{}", hello.api_version_major, hello.api_version_minor),
+        "ESPHome device said hello"
+    );
+    if hello.api_version_major != proto::API_VERSION.0 {
+        bail!(
+            "Unsupported API version {}.{}",
+            hello.api_version_major,
+            hello.api_version_minor
+        );
+    }
+
+    writer
+        .write_message(
+            proto::CONNECT_REQUEST,
+            &proto::connect_request(&settings.password),
+        )
+        .await?;
+    let connect = proto::ConnectResponse::decode(&reader.expect(proto::CONNECT_RESPONSE).await?)?;
+    if connect.invalid_password {
+        bail!("Invalid API password");
+    }
+
+    writer
+        .write_message(proto::LIST_ENTITIES_REQUEST, &[])
+        .await?;
+    let mut lights = Vec::new();
+    loop {
+        let (msg_type, payload) = reader.read_message().await?;
+        match msg_type {
+            proto::LIST_ENTITIES_LIGHT_RESPONSE => {
+                lights.push(proto::ListEntitiesLightResponse::decode(&payload)?)
+            }
+            proto::LIST_ENTITIES_DONE_RESPONSE => break,
+            _ => {}
+        }
+    }
+
+    Ok((reader, writer, lights))
+}
+
+async fn noise_handshake(
+    reader: &mut BufReader<OwnedReadHalf>,
+    writer: &mut OwnedWriteHalf,
+    key: &[u8; 32],
+) -> anyhow::Result<StatelessTransportState> {
+    let mut handshake = snow::Builder::new(NOISE_PARAMS.parse()?)
+        .prologue(NOISE_PROLOGUE)
+        .psk(0, key)
+        .build_initiator()?;
+
+    let mut message = vec![0u8; MAX_NOISE_FRAME];
+    let len = handshake.write_message(&[], &mut message[1..])?;
+
+    // An empty hello frame followed by the first handshake message, which is
+    // prefixed with a zero byte
+    let mut frames = noise_frame(&[])?;
+    frames.extend(noise_frame(&message[..len + 1])?);
+    writer.write_all(&frames).await?;
+
+    let hello = read_noise_frame(reader)
+        .await
+        .context("Reading Noise hello failed, the device may not use encryption")?;
+    if hello.first() != Some(&NOISE_INDICATOR) {
+        bail!("Device does not support Noise encryption");
+    }
+
+    let response = read_noise_frame(reader)
+        .await
+        .context("Reading Noise handshake failed")?;
+    match response.split_first() {
+        Some((0, message)) => {
+            let mut payload = vec![0u8; MAX_NOISE_FRAME];
+            handshake
+                .read_message(message, &mut payload)
+                .context("Noise handshake failed, check the encryption key")?;
+        }
+        Some((_, error)) => bail!("Noise handshake failed: {}", String::from_utf8_lossy(error)),
+        None => bail!("Noise handshake failed: empty response"),
+    }
+
+    Ok(handshake.into_stateless_transport_mode()?)
+}
+
+fn noise_frame(payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let len = u16::try_from(payload.len()).context("Message is too long")?;
+
+    let mut frame = Vec::with_capacity(payload.len() + 3);
+    frame.push(NOISE_INDICATOR);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+async fn read_noise_frame(reader: &mut BufReader<OwnedReadHalf>) -> anyhow::Result<Vec<u8>> {
+    match reader.read_u8().await? {
+        NOISE_INDICATOR => read_noise_frame_body(reader).await,
+        PLAINTEXT_INDICATOR => bail!("Device does not use encryption, remove its encryption key"),
+        indicator => bail!("Invalid frame indicator {indicator:#04x}"),
+    }
+}
+
+async fn read_noise_frame_body(reader: &mut BufReader<OwnedReadHalf>) -> anyhow::Result<Vec<u8>> {
+    let len = reader.read_u16().await?;
+    let mut frame = vec![0u8; len.into()];
+    reader.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+async fn read_varint(reader: &mut BufReader<OwnedReadHalf>) -> anyhow::Result<u64> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = reader.read_u8().await?;
+        bytes.push(byte);
+        if byte & 0x80 == 0 {
+            return Ok(proto::decode_varint(&bytes)?.0);
+        }
+        if bytes.len() >= 10 {
+            bail!("Invalid varint");
+        }
+    }
+}
+
+pub(super) struct FrameReader {
+    stream: BufReader<OwnedReadHalf>,
+    noise: Option<Arc<StatelessTransportState>>,
+    nonce: u64,
+}
+
+impl FrameReader {
+    fn new(stream: BufReader<OwnedReadHalf>, noise: Option<Arc<StatelessTransportState>>) -> Self {
+        Self {
+            stream,
+            noise,
+            nonce: 0,
+        }
+    }
+
+    async fn read_indicator(&mut self) -> anyhow::Result<u8> {
+        match self.stream.read_u8().await {
+            Ok(indicator) => Ok(indicator),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                bail!("Connection closed by the device")
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn read_body(&mut self, indicator: u8) -> anyhow::Result<(u16, Vec<u8>)> {
+        match (&self.noise, indicator) {
+            (None, PLAINTEXT_INDICATOR) => {
+                let len = read_varint(&mut self.stream).await?;
+                let msg_type = read_varint(&mut self.stream).await?;
+                let msg_type = u16::try_from(msg_type).context("Invalid message type")?;
+                if len > MAX_NOISE_FRAME as u64 {
+                    bail!("Message is too long");
+                }
+
+                let mut payload = vec![0u8; len as usize];
+                self.stream.read_exact(&mut payload).await?;
+                Ok((msg_type, payload))
+            }
+            (Some(noise), NOISE_INDICATOR) => {
+                let frame = read_noise_frame_body(&mut self.stream).await?;
+                let mut message = vec![0u8; frame.len()];
+                let len = noise
+                    .read_message(self.nonce, &frame, &mut message)
+                    .context("Decrypting message failed")?;
+                self.nonce += 1;
+
+                if len < 4 {
+                    bail!("Encrypted message is too short");
+                }
+                let msg_type = u16::from_be_bytes([message[0], message[1]]);
+                let payload_len = usize::from(u16::from_be_bytes([message[2], message[3]]));
+                if payload_len > len - 4 {
+                    bail!("Encrypted message is truncated");
+                }
+                message.truncate(4 + payload_len);
+                message.drain(..4);
+                Ok((msg_type, message))
+            }
+            (None, NOISE_INDICATOR) => bail!("Device requires an encryption key"),
+            (Some(_), PLAINTEXT_INDICATOR) => {
+                bail!("Device does not use encryption, remove its encryption key")
+            }
+            _ => bail!("Invalid frame indicator {indicator:#04x}"),
+        }
+    }
+
+    async fn read_message(&mut self) -> anyhow::Result<(u16, Vec<u8>)> {
+        let indicator = self.read_indicator().await?;
+        self.read_body(indicator).await
+    }
+
+    /// Read messages until one of the type arrives, returning its payload
+    async fn expect(&mut self, expected_type: u16) -> anyhow::Result<Vec<u8>> {
+        loop {
+            let (msg_type, payload) = self.read_message().await?;
+            if msg_type == expected_type {
+                return Ok(payload);
+            }
+            if msg_type == proto::DISCONNECT_REQUEST {
+                bail!("Device closed the connection");
+            }
+        }
+    }
+}
+
+pub(super) struct FrameWriter {
+    stream: OwnedWriteHalf,
+    noise: Option<Arc<StatelessTransportState>>,
+    nonce: u64,
+}
+
+impl FrameWriter {
+    fn new(stream: OwnedWriteHalf, noise: Option<Arc<StatelessTransportState>>) -> Self {
+        Self {
+            stream,
+            noise,
+            nonce: 0,
+        }
+    }
+
+    async fn write_message(&mut self, msg_type: u16, payload: &[u8]) -> anyhow::Result<()> {
+        let frame = match &self.noise {
+            None => {
+                let mut frame = vec![PLAINTEXT_INDICATOR];
+                proto::encode_varint(&mut frame, payload.len() as u64);
+                proto::encode_varint(&mut frame, msg_type.into());
+                frame.extend_from_slice(payload);
+                frame
+            }
+            Some(noise) => {
+                let payload_len = u16::try_from(payload.len()).context("Message is too long")?;
+
+                let mut message = Vec::with_capacity(payload.len() + 4);
+                message.extend_from_slice(&msg_type.to_be_bytes());
+                message.extend_from_slice(&payload_len.to_be_bytes());
+                message.extend_from_slice(payload);
+
+                let mut encrypted = vec![0u8; message.len() + NOISE_TAG_LEN];
+                let len = noise
+                    .write_message(self.nonce, &message, &mut encrypted)
+                    .context("Encrypting message failed")?;
+                self.nonce += 1;
+
+                noise_frame(&encrypted[..len])?
+            }
+        };
+
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    /// A connected client and the device end of the connection
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, device) = tokio::join!(client, listener.accept());
+        (client.unwrap(), device.unwrap().0)
+    }
+
+    /// Noise handshake of an ESPHome device stand-in, answering with an error
+    /// frame like ESPHome if the client uses another key
+    async fn device_handshake(
+        device: &mut TcpStream,
+        key: &[u8; 32],
+    ) -> Option<StatelessTransportState> {
+        let mut hello = [0; 3];
+        device.read_exact(&mut hello).await.unwrap();
+        assert_eq!(hello, [NOISE_INDICATOR, 0, 0]);
+
+        assert_eq!(device.read_u8().await.unwrap(), NOISE_INDICATOR);
+        let len = device.read_u16().await.unwrap();
+        let mut frame = vec![0; len.into()];
+        device.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame[0], 0);
+
+        let mut handshake = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .prologue(NOISE_PROLOGUE)
+            .psk(0, key)
+            .build_responder()
+            .unwrap();
+
+        // Protocol version and device name
+        let mut response = noise_frame(b"\x01desk\x00").unwrap();
+        let mut buf = vec![0; MAX_NOISE_FRAME];
+        if handshake.read_message(&frame[1..], &mut buf).is_err() {
+            response.extend(noise_frame(b"\x01Handshake MAC failure").unwrap());
+            device.write_all(&response).await.unwrap();
+            return None;
+        }
+
+        let len = handshake.write_message(&[], &mut buf[1..]).unwrap();
+        buf[0] = 0;
+        response.extend(noise_frame(&buf[..len + 1]).unwrap());
+        device.write_all(&response).await.unwrap();
+
+        Some(handshake.into_stateless_transport_mode().unwrap())
+    }
+
+    #[tokio::test]
+    async fn writes_and_reads_plaintext_frames() {
+        let (client, mut device) = connection().await;
+        let (read, write) = client.into_split();
+        let mut reader = FrameReader::new(BufReader::new(read), None);
+        let mut writer = FrameWriter::new(write, None);
+
+        writer
+            .write_message(proto::LIGHT_COMMAND_REQUEST, &[0x10, 0x01])
+            .await
+            .unwrap();
+        writer.write_message(300, &[0xaa; 130]).await.unwrap();
+
+        // Indicator, payload length and message type as varints, payload
+        let mut frame = [0; 5];
+        device.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, [0x00, 0x02, 0x20, 0x10, 0x01]);
+        let mut frame = [0; 5 + 130];
+        device.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame[..5], [0x00, 0x82, 0x01, 0xac, 0x02]);
+        assert!(frame[5..].iter().all(|&b| b == 0xaa));
+
+        device
+            .write_all(&[0x00, 0x02, 0x18, 0x10, 0x01, 0x00, 0x00, 0x07])
+            .await
+            .unwrap();
+        assert_eq!(
+            reader.read_message().await.unwrap(),
+            (proto::LIGHT_STATE_RESPONSE, vec![0x10, 0x01])
+        );
+        assert_eq!(
+            reader.read_message().await.unwrap(),
+            (proto::PING_REQUEST, vec![])
+        );
+
+        // Encrypted frames need a key
+        device.write_all(&[NOISE_INDICATOR, 0, 0]).await.unwrap();
+        assert!(reader.read_message().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_and_reads_noise_frames() {
+        let (client, mut device) = connection().await;
+        let (read, mut write) = client.into_split();
+        let mut read = BufReader::new(read);
+
+        let (client_noise, device_noise) = tokio::join!(
+            noise_handshake(&mut read, &mut write, &KEY),
+            device_handshake(&mut device, &KEY)
+        );
+        let client_noise = Arc::new(client_noise.unwrap());
+        let device_noise = device_noise.unwrap();
+
+        let mut reader = FrameReader::new(read, Some(client_noise.clone()));
+        let mut writer = FrameWriter::new(write, Some(client_noise));
+
+        for nonce in 0..2 {
+            writer
+                .write_message(proto::LIGHT_COMMAND_REQUEST, &[0x10, 0x01])
+                .await
+                .unwrap();
+
+            // Indicator and length of the encrypted message type, payload
+            // length and payload
+            assert_eq!(device.read_u8().await.unwrap(), NOISE_INDICATOR);
+            let len = device.read_u16().await.unwrap();
+            assert_eq!(usize::from(len), 4 + 2 + NOISE_TAG_LEN);
+            let mut frame = vec![0; len.into()];
+            device.read_exact(&mut frame).await.unwrap();
+            let mut message = vec![0; frame.len()];
+            let len = device_noise
+                .read_message(nonce, &frame, &mut message)
+                .unwrap();
+            assert_eq!(message[..len], [0x00, 0x20, 0x00, 0x02, 0x10, 0x01]);
+        }
+
+        let mut encrypted = vec![0; 64];
+        let len = device_noise
+            .write_message(0, &[0x00, 0x18, 0x00, 0x02, 0x10, 0x00], &mut encrypted)
+            .unwrap();
+        device
+            .write_all(&noise_frame(&encrypted[..len]).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            reader.read_message().await.unwrap(),
+            (proto::LIGHT_STATE_RESPONSE, vec![0x10, 0x00])
+        );
+
+        // Plaintext frames are not accepted once encrypted
+        device.write_all(&[0x00, 0x00, 0x07]).await.unwrap();
+        assert!(reader.read_message().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reports_noise_handshake_errors() {
+        let (client, mut device) = connection().await;
+        let (read, mut write) = client.into_split();
+        let mut read = BufReader::new(read);
+
+        let (client_noise, device_noise) = tokio::join!(
+            noise_handshake(&mut read, &mut write, &[8; 32]),
+            device_handshake(&mut device, &KEY)
+        );
+        assert!(device_noise.is_none());
+        let error = client_noise.err().unwrap().to_string();
+        assert!(error.contains("Handshake MAC failure"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn reports_devices_without_encryption() {
+        let (client, mut device) = connection().await;
+        let (read, mut write) = client.into_split();
+        let mut read = BufReader::new(read);
+
+        // Plaintext devices answer the hello with a plaintext frame
+        device.write_all(&[0x00, 0x00, 0x02]).await.unwrap();
+        let error = noise_handshake(&mut read, &mut write, &KEY)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            format!("{error:#}").contains("does not use encryption"),
+            "{error:#}"
+        );
+    }
+}
@@ -0,0 +1,592 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use base64::Engine;
+use tokio::time::Instant;
+
+use crate::config::Config;
+
+use self::{
+    connection::{Device, DeviceSettings, DeviceState},
+    proto::{color_mode, LightCommandRequest, LightStateResponse, ListEntitiesLightResponse},
+};
+
+use super::{
+    color, events::EventChannel, requested::RequestedValues, DeviceEventStream, DeviceInfo,
+    LightOptions, LightStatus, SmartHomeApi,
+};
+
+mod connection;
+mod proto;
+
+const PORT: u16 = 6053;
+
+/// Color modes that drive white channels
+const WHITE_MODES: u32 =
+    color_mode::WHITE | color_mode::COLOR_TEMPERATURE | color_mode::COLD_WARM_WHITE;
+const COLOR_TEMPERATURE_MODES: u32 = color_mode::COLOR_TEMPERATURE | color_mode::COLD_WARM_WHITE;
+
+/// `ColorMode.ON_OFF`, lights without brightness
+const MODE_ON_OFF: u32 = 1;
+
+/// Effect name that stops the running effect
+const NO_EFFECT: &str = "None";
+
+pub struct Esphome {
+    config: Arc<Config>,
+    timeout: Duration,
+    shared: Arc<Shared>,
+    requested: RequestedValues,
+}
+
+/// State shared with the device connections
+#[derive(Default)]
+struct Shared {
+    /// Devices that have been connected to, by host
+    devices: Mutex<HashMap<String, Arc<Device>>>,
+    events: EventChannel,
+}
+
+impl Shared {
+    /// Events are only complete while every device is connected
+    fn update_connected(&self) {
+        let devices = self.devices.lock().unwrap();
+        let connected = !devices.is_empty() && devices.values().all(|d| d.state().connected);
+        self.events.set_connected(connected);
+    }
+}
+
+impl Esphome {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let esphome_config = &config.smart_home.esphome;
+
+        // Report invalid keys at startup rather than on first use
+        Self::decode_key(esphome_config.encryption_key.as_deref())?;
+        for device in &esphome_config.devices {
+            Self::decode_key(device.encryption_key.as_deref())?;
+        }
+
+        Ok(Self {
+            timeout: Duration::from_millis(esphome_config.timeout_ms),
+            shared: Default::default(),
+            requested: Default::default(),
+            config,
+        })
+    }
+
+    /// Device IDs are `<host>/<object_id>`, e.g. `desk-strip.lan/desk_strip`
+    fn parse_device_id(id: &str) -> super::Result<(&str, &str)> {
+        let invalid = || super::Error::InvalidId(id.to_string());
+
+        let (host, object_id) = id.rsplit_once('/').ok_or_else(invalid)?;
+        let valid_host = !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'));
+        let valid_object_id = !object_id.is_empty()
+            && object_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'));
+        if !valid_host || !valid_object_id {
+            return Err(invalid());
+        }
+
+        Ok((host, object_id))
+    }
+
+    /// Encryption keys are the base64 `api.encryption.key` of the device
+    fn decode_key(key: Option<&str>) -> super::Result<Option<[u8; 32]>> {
+        let Some(key) = key else {
+            return Ok(None);
+        };
+
+        base64::engine::general_purpose::STANDARD
+            .decode(key)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .map(Some)
+            .ok_or_else(|| {
+                super::Error::Configuration(
+                    "ESPHome encryption key must be 32 bytes in base64".to_string(),
+                )
+            })
+    }
+
+    fn settings(&self, host: &str) -> super::Result<DeviceSettings> {
+        let esphome_config = &self.config.smart_home.esphome;
+
+        // Listed devices use their own credentials instead of the defaults
+        let (encryption_key, password) = match esphome_config.devices.iter().find(|d| d.id == host)
+        {
+            Some(device) => (&device.encryption_key, &device.password),
+            None => (&esphome_config.encryption_key, &esphome_config.password),
+        };
+
+        let has_port = host.parse::<SocketAddr>().is_ok()
+            || host
+                .rsplit_once(':')
+                .is_some_and(|(h, port)| !h.contains(':') && port.parse::<u16>().is_ok());
+        let address = if has_port {
+            host.to_string()
+        } else if host.parse::<Ipv6Addr>().is_ok() {
+            format!("[{host}]:{PORT}")
+        } else {
+            format!("{host}:{PORT}")
+        };
+
+        Ok(DeviceSettings {
+            address,
+            encryption_key: Self::decode_key(encryption_key.as_deref())?,
+            password: password.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Connection to the device, which is opened on first use and kept open
+    fn device(&self, host: &str) -> super::Result<Arc<Device>> {
+        let mut devices = self.shared.devices.lock().unwrap();
+        if let Some(device) = devices.get(host) {
+            return Ok(device.clone());
+        }
+
+        let device = Arc::new(Device::new(host.to_string(), self.settings(host)?));
+        devices.insert(host.to_string(), device.clone());
+        tokio::spawn(connection::run(
+            device.clone(),
+            self.shared.clone(),
+            self.timeout,
+        ));
+        Ok(device)
+    }
+
+    /// Wait until `f` returns a value for the device state, or the timeout
+    /// expires
+    async fn wait_for<T>(
+        &self,
+        device: &Device,
+        f: impl Fn(&DeviceState) -> Option<T>,
+    ) -> Option<T> {
+        let deadline = Instant::now() + self.timeout;
+        let mut changed = device.subscribe();
+
+        loop {
+            if let Some(value) = f(&device.state()) {
+                return Some(value);
+            }
+            match tokio::time::timeout_at(deadline, changed.changed()).await {
+                Ok(Ok(())) => continue,
+                _ => return None,
+            }
+        }
+    }
+
+    /// The light entity with the ID, once the device is connected
+    async fn light(&self, id: &str) -> super::Result<(Arc<Device>, ListEntitiesLightResponse)> {
+        let (host, object_id) = Self::parse_device_id(id)?;
+        let device = self.device(host)?;
+
+        let light = self
+            .wait_for(&device, |state| {
+                if !state.connected {
+                    return state.attempted.then_some(None);
+                }
+                Some(state.lights.get(object_id).cloned())
+            })
+            .await
+            .flatten();
+
+        match light {
+            Some(light) => Ok((device, light)),
+            None if device.state().connected => Err(super::Error::UnknownDeviceId),
+            None => Err(super::Error::Communication(format!(
+                "Not connected to {host}"
+            ))),
+        }
+    }
+
+    async fn send_command(
+        &self,
+        id: &str,
+        device: &Device,
+        command: LightCommandRequest,
+    ) -> super::Result<()> {
+        tracing::debug!(device_id = id, ?command, "Sending light command");
+
+        device
+            .send(proto::LIGHT_COMMAND_REQUEST, &command.encode())
+            .await
+    }
+
+    fn light_options(
+        &self,
+        id: &str,
+        light: &ListEntitiesLightResponse,
+        state: &LightStateResponse,
+    ) -> LightOptions {
+        let mode = state.color_mode;
+
+        // RGBW lights only show the color while the white channel is off
+        let color_active =
+            mode & color_mode::RGB != 0 && (mode & WHITE_MODES == 0 || state.white == 0.0);
+        let color_temperature_active = !color_active && mode & COLOR_TEMPERATURE_MODES != 0;
+
+        let color_temperature =
+            (color_temperature_active && state.color_temperature > 0.0).then(|| {
+                let mireds = state.color_temperature.round() as u16;
+                self.requested
+                    .color_temperature(id, |k| clamp_mireds(light, k) == mireds)
+                    .unwrap_or_else(|| color::mired_to_kelvin(mireds))
+            });
+
+        let color = color_active.then(|| {
+            let rgb = color::to_rgb8((state.red.into(), state.green.into(), state.blue.into()));
+            let (hue, saturation) = self
+                .requested
+                .color(id, |h, s| color::to_rgb8(color::hs_to_rgb(h, s)) == rgb)
+                .unwrap_or_else(|| {
+                    let (r, g, b) = color::from_rgb8(rgb);
+                    color::rgb_to_hs(r, g, b)
+                });
+            super::Color { hue, saturation }
+        });
+
+        let effect = Some(state.effect.clone()).filter(|e| !e.is_empty() && e != "None");
+
+        LightOptions {
+            switched_on: state.state,
+            brightness: (mode != MODE_ON_OFF)
+                .then(|| (state.brightness * 100.0).round().clamp(0.0, 100.0) as u8),
+            color_temperature,
+            color,
+            effect,
+        }
+    }
+}
+
+/// Mireds sent to the light for a color temperature in Kelvin
+fn clamp_mireds(light: &ListEntitiesLightResponse, kelvin: u16) -> u16 {
+    let mireds = color::kelvin_to_mired(kelvin);
+    if light.max_mireds > light.min_mireds {
+        mireds.clamp(
+            light.min_mireds.round() as u16,
+            light.max_mireds.round() as u16,
+        )
+    } else {
+        mireds
+    }
+}
+
+/// The supported color mode with the capability and the fewest other
+/// channels, so that e.g. an RGBW light shows a color on the RGB channels only
+fn best_color_mode(light: &ListEntitiesLightResponse, capability: u32, avoid: u32) -> Option<u32> {
+    light
+        .supported_color_modes
+        .iter()
+        .copied()
+        .filter(|mode| mode & capability != 0)
+        .min_by_key(|mode| (mode & avoid != 0, mode.count_ones()))
+}
+
+#[async_trait]
+impl SmartHomeApi for Esphome {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let (host, object_id) = Self::parse_device_id(id)?;
+        let device = self.device(host)?;
+
+        enum Lookup {
+            Offline,
+            Unknown,
+            Found(ListEntitiesLightResponse, LightStateResponse),
+        }
+
+        let lookup = self
+            .wait_for(&device, |state| {
+                if !state.connected {
+                    return state.attempted.then_some(Lookup::Offline);
+                }
+                let Some(light) = state.lights.get(object_id) else {
+                    return Some(Lookup::Unknown);
+                };
+                let light_state = state.states.get(&light.key)?;
+                Some(Lookup::Found(light.clone(), light_state.clone()))
+            })
+            .await;
+
+        match lookup {
+            Some(Lookup::Found(light, state)) => {
+                tracing::debug!("Got status {state:#?}");
+                Ok(LightStatus::Online(self.light_options(id, &light, &state)))
+            }
+            Some(Lookup::Unknown) => Err(super::Error::UnknownDeviceId),
+            None if device.state().connected => Err(super::Error::Communication(format!(
+                "No state received for {id}"
+            ))),
+            Some(Lookup::Offline) | None => {
+                tracing::debug!(device_id = id, "ESPHome device is not connected");
+                Ok(LightStatus::Offline)
+            }
+        }
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let (device, light) = self.light(id).await?;
+        let command = LightCommandRequest {
+            key: light.key,
+            state: Some(switched_on),
+            ..Default::default()
+        };
+        self.send_command(id, &device, command).await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let (device, light) = self.light(id).await?;
+        let command = LightCommandRequest {
+            key: light.key,
+            brightness: Some(f32::from(brightness.clamp(0, 100)) / 100.0),
+            ..Default::default()
+        };
+        self.send_command(id, &device, command).await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let (device, light) = self.light(id).await?;
+        let mode = best_color_mode(&light, COLOR_TEMPERATURE_MODES, color_mode::RGB)
+            .ok_or(super::Error::UnsupportedCapability("color temperature"))?;
+
+        let has_rgb = mode & color_mode::RGB != 0;
+        let command = LightCommandRequest {
+            key: light.key,
+            color_mode: Some(mode),
+            color_temperature: Some(clamp_mireds(&light, temp).into()),
+            // Turn the RGB channels off in combined modes
+            white: has_rgb.then_some(1.0),
+            color_brightness: has_rgb.then_some(0.0),
+            // A running effect would hide the color temperature
+            effect: Some(NO_EFFECT.to_string()),
+            ..Default::default()
+        };
+        self.send_command(id, &device, command).await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let (device, light) = self.light(id).await?;
+        let mode = best_color_mode(&light, color_mode::RGB, WHITE_MODES)
+            .ok_or(super::Error::UnsupportedCapability("color"))?;
+
+        let (r, g, b) = color::hs_to_rgb(hue, saturation);
+        let has_white = mode & WHITE_MODES != 0;
+        let command = LightCommandRequest {
+            key: light.key,
+            color_mode: Some(mode),
+            rgb: Some((r as f32, g as f32, b as f32)),
+            // Turn the white channels off in combined modes
+            white: has_white.then_some(0.0),
+            color_brightness: has_white.then_some(1.0),
+            effect: Some(NO_EFFECT.to_string()),
+            ..Default::default()
+        };
+        self.send_command(id, &device, command).await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    fn device_events(&self) -> Option<DeviceEventStream> {
+        Some(self.shared.events.subscribe())
+    }
+
+    fn events_connected(&self) -> bool {
+        self.shared.events.is_connected()
+    }
+
+    /// Lights of the devices listed in the configuration
+    async fn list_devices(&self) -> super::Result<Vec<DeviceInfo>> {
+        let mut lights = Vec::new();
+
+        for device_config in &self.config.smart_home.esphome.devices {
+            let device = self.device(&device_config.id)?;
+            let device_lights = self
+                .wait_for(&device, |state| {
+                    state
+                        .connected
+                        .then(|| state.lights.values().cloned().collect::<Vec<_>>())
+                })
+                .await;
+
+            let Some(device_lights) = device_lights else {
+                tracing::warn!(host = device.host, "ESPHome device did not connect in time");
+                continue;
+            };
+
+            lights.extend(device_lights.into_iter().map(|l| DeviceInfo {
+                id: format!("{}/{}", device.host, l.object_id),
+                name: Some(l.name),
+                model: None,
+            }));
+        }
+
+        Ok(lights)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::test_config;
+
+    use super::*;
+
+    /// `ColorMode` values of api.proto
+    const MODE_BRIGHTNESS: u32 = 3;
+    const MODE_WHITE: u32 = 7;
+    const MODE_COLOR_TEMPERATURE: u32 = 11;
+    const MODE_COLD_WARM_WHITE: u32 = 19;
+    const MODE_RGB: u32 = 35;
+    const MODE_RGB_WHITE: u32 = 39;
+    const MODE_RGB_COLOR_TEMPERATURE: u32 = 47;
+    const MODE_RGB_COLD_WARM_WHITE: u32 = 51;
+
+    fn esphome() -> Esphome {
+        let config = test_config(serde_json::json!({ "platform": "Esphome" }));
+        Esphome::new(config).unwrap()
+    }
+
+    fn light(supported_color_modes: &[u32]) -> ListEntitiesLightResponse {
+        ListEntitiesLightResponse {
+            object_id: "desk".to_string(),
+            min_mireds: 153.0,
+            max_mireds: 500.0,
+            supported_color_modes: supported_color_modes.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn mode_flags_match_api_proto() {
+        assert_eq!(MODE_WHITE & WHITE_MODES, color_mode::WHITE);
+        assert_eq!(
+            MODE_COLOR_TEMPERATURE & COLOR_TEMPERATURE_MODES,
+            color_mode::COLOR_TEMPERATURE
+        );
+        assert_eq!(
+            MODE_COLD_WARM_WHITE & COLOR_TEMPERATURE_MODES,
+            color_mode::COLD_WARM_WHITE
+        );
+        assert_eq!(MODE_RGB & WHITE_MODES, 0);
+        assert_eq!(MODE_RGB & color_mode::RGB, color_mode::RGB);
+        assert_eq!(MODE_BRIGHTNESS & (WHITE_MODES | color_mode::RGB), 0);
+    }
+
+    #[test]
+    fn picks_modes_with_fewest_other_channels() {
+        let rgbw = light(&[MODE_RGB_WHITE, MODE_RGB, MODE_RGB_COLOR_TEMPERATURE]);
+        assert_eq!(
+            best_color_mode(&rgbw, color_mode::RGB, WHITE_MODES),
+            Some(MODE_RGB)
+        );
+
+        let rgbct = light(&[MODE_RGB_COLOR_TEMPERATURE, MODE_RGB_COLD_WARM_WHITE]);
+        assert_eq!(
+            best_color_mode(&rgbct, COLOR_TEMPERATURE_MODES, color_mode::RGB),
+            Some(MODE_RGB_COLD_WARM_WHITE)
+        );
+
+        let ct = light(&[MODE_COLOR_TEMPERATURE, MODE_RGB_COLOR_TEMPERATURE]);
+        assert_eq!(
+            best_color_mode(&ct, COLOR_TEMPERATURE_MODES, color_mode::RGB),
+            Some(MODE_COLOR_TEMPERATURE)
+        );
+
+        let white = light(&[MODE_WHITE, MODE_BRIGHTNESS]);
+        assert_eq!(
+            best_color_mode(&white, COLOR_TEMPERATURE_MODES, color_mode::RGB),
+            None
+        );
+        assert_eq!(best_color_mode(&white, color_mode::RGB, WHITE_MODES), None);
+    }
+
+    #[test]
+    fn reports_color_of_rgb_modes() {
+        let esphome = esphome();
+        let light = light(&[MODE_RGB_WHITE]);
+        let mut state = LightStateResponse {
+            state: true,
+            brightness: 0.5,
+            color_mode: MODE_RGB_WHITE,
+            red: 0.0,
+            green: 0.0,
+            blue: 1.0,
+            white: 0.0,
+            effect: "None".to_string(),
+            ..Default::default()
+        };
+
+        let options = esphome.light_options("desk.lan/desk", &light, &state);
+        assert!(options.switched_on);
+        assert_eq!(options.brightness, Some(50));
+        assert_eq!(options.color_temperature, None);
+        let color = options.color.unwrap();
+        assert_eq!((color.hue, color.saturation), (67, 100));
+        assert_eq!(options.effect, None);
+
+        // The color is not shown while the white channel is on
+        state.white = 1.0;
+        let options = esphome.light_options("desk.lan/desk", &light, &state);
+        assert!(options.color.is_none());
+        assert!(options.color_temperature.is_none());
+    }
+
+    #[test]
+    fn reports_color_temperature_of_white_modes() {
+        let esphome = esphome();
+        let light = light(&[MODE_RGB_COLD_WARM_WHITE]);
+        let state = LightStateResponse {
+            state: false,
+            brightness: 1.0,
+            color_mode: MODE_RGB_COLD_WARM_WHITE,
+            white: 1.0,
+            color_temperature: 370.0,
+            effect: "Flicker".to_string(),
+            ..Default::default()
+        };
+
+        let options = esphome.light_options("desk.lan/desk", &light, &state);
+        assert!(!options.switched_on);
+        assert_eq!(options.color_temperature, Some(2703));
+        assert!(options.color.is_none());
+        assert_eq!(options.effect.as_deref(), Some("Flicker"));
+
+        // Requests outside the range of the light are reported as requested
+        esphome
+            .requested
+            .set_color_temperature("desk.lan/desk", 1500);
+        let state = LightStateResponse {
+            color_temperature: 500.0,
+            ..state
+        };
+        let options = esphome.light_options("desk.lan/desk", &light, &state);
+        assert_eq!(options.color_temperature, Some(1500));
+    }
+
+    #[test]
+    fn reports_no_brightness_for_on_off_lights() {
+        let esphome = esphome();
+        let state = LightStateResponse {
+            state: true,
+            color_mode: MODE_ON_OFF,
+            ..Default::default()
+        };
+        let options = esphome.light_options("desk.lan/desk", &light(&[MODE_ON_OFF]), &state);
+        assert_eq!(options.brightness, None);
+        assert!(options.color.is_none());
+        assert!(options.color_temperature.is_none());
+    }
+}
@@ -0,0 +1,592 @@
+//! Encoding of the native API messages that are used. The messages are
+//! protobuf, but only a handful of them are needed, so they are encoded by
+//! hand instead of generating code from `api.proto`.
+
+use std::fmt;
+
+pub const HELLO_REQUEST: u16 = 1;
+pub const HELLO_RESPONSE: u16 = 2;
+pub const CONNECT_REQUEST: u16 = 3;
+pub const CONNECT_RESPONSE: u16 = 4;
+pub const DISCONNECT_REQUEST: u16 = 5;
+pub const DISCONNECT_RESPONSE: u16 = 6;
+pub const PING_REQUEST: u16 = 7;
+pub const PING_RESPONSE: u16 = 8;
+pub const LIST_ENTITIES_REQUEST: u16 = 11;
+pub const LIST_ENTITIES_LIGHT_RESPONSE: u16 = 15;
+pub const LIST_ENTITIES_DONE_RESPONSE: u16 = 19;
+pub const SUBSCRIBE_STATES_REQUEST: u16 = 20;
+pub const LIGHT_STATE_RESPONSE: u16 = 24;
+pub const LIGHT_COMMAND_REQUEST: u16 = 32;
+pub const GET_TIME_REQUEST: u16 = 36;
+pub const GET_TIME_RESPONSE: u16 = 37;
+
+/// API version this client implements
+pub const API_VERSION: (u32, u32) = (1, 9);
+
+/// Capability flags that make up the `ColorMode` enum values
+pub mod color_mode {
+    pub const WHITE: u32 = 1 << 2;
+    pub const COLOR_TEMPERATURE: u32 = 1 << 3;
+    pub const COLD_WARM_WHITE: u32 = 1 << 4;
+    pub const RGB: u32 = 1 << 5;
+}
+
+#[derive(Debug)]
+pub struct DecodeError(&'static str);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid protobuf message: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn encode_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Read a varint from the start of `buf`, returning it and its length
+pub fn decode_varint(buf: &[u8]) -> Result<(u64, usize), DecodeError> {
+    let mut value = 0u64;
+    for (i, byte) in buf.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(DecodeError("truncated varint"))
+}
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn key(&mut self, field: u32, wire_type: u8) {
+        encode_varint(&mut self.buf, u64::from(field << 3 | u32::from(wire_type)));
+    }
+
+    fn uint32(&mut self, field: u32, value: u32) {
+        self.key(field, 0);
+        encode_varint(&mut self.buf, value.into());
+    }
+
+    fn bool(&mut self, field: u32, value: bool) {
+        self.uint32(field, value.into());
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) {
+        self.key(field, 5);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn float(&mut self, field: u32, value: f32) {
+        self.fixed32(field, value.to_bits());
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.key(field, 2);
+        encode_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    LengthDelimited(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl Value<'_> {
+    fn as_u32(&self) -> u32 {
+        match self {
+            Value::Varint(v) | Value::Fixed64(v) => *v as u32,
+            Value::Fixed32(v) => *v,
+            Value::LengthDelimited(_) => 0,
+        }
+    }
+
+    fn as_bool(&self) -> bool {
+        self.as_u32() != 0
+    }
+
+    fn as_f32(&self) -> f32 {
+        match self {
+            Value::Fixed32(v) => f32::from_bits(*v),
+            _ => 0.0,
+        }
+    }
+
+    fn as_string(&self) -> String {
+        match self {
+            Value::LengthDelimited(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            _ => String::new(),
+        }
+    }
+
+    /// Repeated varint fields may be packed or not
+    fn as_varints(&self) -> Result<Vec<u32>, DecodeError> {
+        match self {
+            Value::LengthDelimited(mut bytes) => {
+                let mut values = Vec::new();
+                while !bytes.is_empty() {
+                    let (value, len) = decode_varint(bytes)?;
+                    values.push(value as u32);
+                    bytes = &bytes[len..];
+                }
+                Ok(values)
+            }
+            value => Ok(vec![value.as_u32()]),
+        }
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+    if buf.len() < len {
+        return Err(DecodeError("truncated field"));
+    }
+    let (value, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(value)
+}
+
+/// Call `f` with the number and value of each field in the message
+fn decode_fields<'a>(
+    mut buf: &'a [u8],
+    mut f: impl FnMut(u32, Value<'a>) -> Result<(), DecodeError>,
+) -> Result<(), DecodeError> {
+    while !buf.is_empty() {
+        let (key, len) = decode_varint(buf)?;
+        buf = &buf[len..];
+
+        let value = match key & 0x7 {
+            0 => {
+                let (value, len) = decode_varint(buf)?;
+                buf = &buf[len..];
+                Value::Varint(value)
+            }
+            1 => Value::Fixed64(u64::from_le_bytes(take(&mut buf, 8)?.try_into().unwrap())),
+            2 => {
+                let (value_len, len) = decode_varint(buf)?;
+                buf = &buf[len..];
+                Value::LengthDelimited(take(&mut buf, value_len as usize)?)
+            }
+            5 => Value::Fixed32(u32::from_le_bytes(take(&mut buf, 4)?.try_into().unwrap())),
+            _ => return Err(DecodeError("unsupported wire type")),
+        };
+
+        f((key >> 3) as u32, value)?;
+    }
+    Ok(())
+}
+
+pub fn hello_request(client_info: &str) -> Vec<u8> {
+    let mut e = Encoder::default();
+    e.string(1, client_info);
+    e.uint32(2, API_VERSION.0);
+    e.uint32(3, API_VERSION.1);
+    e.buf
+}
+
+pub fn connect_request(password: &str) -> Vec<u8> {
+    let mut e = Encoder::default();
+    if !password.is_empty() {
+        e.string(1, password);
+    }
+    e.buf
+}
+
+pub fn get_time_response(epoch_seconds: u32) -> Vec<u8> {
+    let mut e = Encoder::default();
+    e.fixed32(1, epoch_seconds);
+    e.buf
+}
+
+#[derive(Debug, Default)]
+pub struct HelloResponse {
+    pub api_version_major: u32,
+    pub api_version_minor: u32,
+    pub server_info: String,
+    pub name: String,
+}
+
+impl HelloResponse {
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut msg = Self::default();
+        decode_fields(buf, |field, value| {
+            match field {
+                1 => msg.api_version_major = value.as_u32(),
+                2 => msg.api_version_minor = value.as_u32(),
+                3 => msg.server_info = value.as_string(),
+                4 => msg.name = value.as_string(),
+                _ => {}
+            }
+            Ok(())
+        })?;
+        Ok(msg)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectResponse {
+    pub invalid_password: bool,
+}
+
+impl ConnectResponse {
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut msg = Self::default();
+        decode_fields(buf, |field, value| {
+            if field == 1 {
+                msg.invalid_password = value.as_bool();
+            }
+            Ok(())
+        })?;
+        Ok(msg)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ListEntitiesLightResponse {
+    pub object_id: String,
+    pub key: u32,
+    pub name: String,
+    pub min_mireds: f32,
+    pub max_mireds: f32,
+    pub supported_color_modes: Vec<u32>,
+}
+
+impl ListEntitiesLightResponse {
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut msg = Self::default();
+        decode_fields(buf, |field, value| {
+            match field {
+                1 => msg.object_id = value.as_string(),
+                2 => msg.key = value.as_u32(),
+                3 => msg.name = value.as_string(),
+                9 => msg.min_mireds = value.as_f32(),
+                10 => msg.max_mireds = value.as_f32(),
+                12 => msg.supported_color_modes.extend(value.as_varints()?),
+                _ => {}
+            }
+            Ok(())
+        })?;
+        Ok(msg)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LightStateResponse {
+    pub key: u32,
+    pub state: bool,
+    pub brightness: f32,
+    pub color_mode: u32,
+    pub color_brightness: f32,
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+    pub white: f32,
+    /// Mireds
+    pub color_temperature: f32,
+    pub effect: String,
+}
+
+impl LightStateResponse {
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut msg = Self::default();
+        decode_fields(buf, |field, value| {
+            match field {
+                1 => msg.key = value.as_u32(),
+                2 => msg.state = value.as_bool(),
+                3 => msg.brightness = value.as_f32(),
+                4 => msg.red = value.as_f32(),
+                5 => msg.green = value.as_f32(),
+                6 => msg.blue = value.as_f32(),
+                7 => msg.white = value.as_f32(),
+                8 => msg.color_temperature = value.as_f32(),
+                9 => msg.effect = value.as_string(),
+                10 => msg.color_brightness = value.as_f32(),
+                11 => msg.color_mode = value.as_u32(),
+                _ => {}
+            }
+            Ok(())
+        })?;
+        Ok(msg)
+    }
+}
+
+/// Fields left as `None` are not changed
+#[derive(Debug, Default)]
+pub struct LightCommandRequest {
+    pub key: u32,
+    pub state: Option<bool>,
+    pub brightness: Option<f32>,
+    pub color_mode: Option<u32>,
+    pub color_brightness: Option<f32>,
+    pub rgb: Option<(f32, f32, f32)>,
+    pub white: Option<f32>,
+    /// Mireds
+    pub color_temperature: Option<f32>,
+    pub effect: Option<String>,
+}
+
+impl LightCommandRequest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::default();
+        e.fixed32(1, self.key);
+        if let Some(state) = self.state {
+            e.bool(2, true);
+            e.bool(3, state);
+        }
+        if let Some(brightness) = self.brightness {
+            e.bool(4, true);
+            e.float(5, brightness);
+        }
+        if let Some((red, green, blue)) = self.rgb {
+            e.bool(6, true);
+            e.float(7, red);
+            e.float(8, green);
+            e.float(9, blue);
+        }
+        if let Some(white) = self.white {
+            e.bool(10, true);
+            e.float(11, white);
+        }
+        if let Some(color_temperature) = self.color_temperature {
+            e.bool(12, true);
+            e.float(13, color_temperature);
+        }
+        if let Some(effect) = &self.effect {
+            e.bool(18, true);
+            e.string(19, effect);
+        }
+        if let Some(color_brightness) = self.color_brightness {
+            e.bool(20, true);
+            e.float(21, color_brightness);
+        }
+        if let Some(color_mode) = self.color_mode {
+            e.bool(22, true);
+            e.uint32(23, color_mode);
+        }
+        e.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `LightStateResponse` of an RGB light, with the field numbers of
+    /// api.proto
+    const LIGHT_STATE: &[u8] = &[
+        0x0d, 0x78, 0x56, 0x34, 0x12, // key (fixed32) = 0x12345678
+        0x10, 0x01, // state = true
+        0x1d, 0x00, 0x00, 0x00, 0x3f, // brightness = 0.5
+        0x25, 0x00, 0x00, 0x80, 0x3f, // red = 1.0
+        0x2d, 0x00, 0x00, 0x00, 0x00, // green = 0.0
+        0x35, 0x00, 0x00, 0x80, 0x3e, // blue = 0.25
+        0x3d, 0x00, 0x00, 0x00, 0x00, // white = 0.0
+        0x45, 0x00, 0x00, 0xb9, 0x43, // color_temperature = 370.0
+        0x4a, 0x07, b'R', b'a', b'i', b'n', b'b', b'o', b'w', // effect
+        0x55, 0x00, 0x00, 0x80, 0x3f, // color_brightness = 1.0
+        0x58, 0x23, // color_mode = COLOR_MODE_RGB
+        0x65, 0x00, 0x00, 0x00, 0x00, // cold_white, not used
+    ];
+
+    /// `LightCommandRequest` setting everything but the color temperature
+    const LIGHT_COMMAND: &[u8] = &[
+        0x0d, 0x78, 0x56, 0x34, 0x12, // key (fixed32) = 0x12345678
+        0x10, 0x01, 0x18, 0x01, // has_state, state = true
+        0x20, 0x01, 0x2d, 0x00, 0x00, 0x00, 0x3f, // has_brightness, brightness = 0.5
+        0x30, 0x01, // has_rgb
+        0x3d, 0x00, 0x00, 0x80, 0x3f, // red = 1.0
+        0x45, 0x00, 0x00, 0x00, 0x00, // green = 0.0
+        0x4d, 0x00, 0x00, 0x80, 0x3e, // blue = 0.25
+        0x50, 0x01, 0x5d, 0x00, 0x00, 0x00, 0x00, // has_white, white = 0.0
+        0x90, 0x01, 0x01, // has_effect
+        0x9a, 0x01, 0x04, b'N', b'o', b'n', b'e', // effect = "None"
+        0xa0, 0x01, 0x01, // has_color_brightness
+        0xad, 0x01, 0x00, 0x00, 0x80, 0x3f, // color_brightness = 1.0
+        0xb0, 0x01, 0x01, 0xb8, 0x01, 0x23, // has_color_mode, color_mode = RGB
+    ];
+
+    #[test]
+    fn encodes_varints() {
+        for (value, bytes) in [
+            (0, &[0x00][..]),
+            (1, &[0x01]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (300, &[0xac, 0x02]),
+            (u64::from(u32::MAX), &[0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ] {
+            let mut buf = Vec::new();
+            encode_varint(&mut buf, value);
+            assert_eq!(buf, bytes, "{value}");
+
+            let mut with_rest = buf.clone();
+            with_rest.push(0x55);
+            assert_eq!(decode_varint(&with_rest).unwrap(), (value, bytes.len()));
+        }
+
+        assert!(decode_varint(&[]).is_err());
+        assert!(decode_varint(&[0x80, 0x80]).is_err());
+        assert!(decode_varint(&[0xff; 11]).is_err());
+    }
+
+    #[test]
+    fn decodes_light_state() {
+        let state = LightStateResponse::decode(LIGHT_STATE).unwrap();
+        assert_eq!(state.key, 0x12345678);
+        assert!(state.state);
+        assert_eq!(state.brightness, 0.5);
+        assert_eq!((state.red, state.green, state.blue), (1.0, 0.0, 0.25));
+        assert_eq!(state.white, 0.0);
+        assert_eq!(state.color_temperature, 370.0);
+        assert_eq!(state.effect, "Rainbow");
+        assert_eq!(state.color_brightness, 1.0);
+        assert_eq!(state.color_mode, 35);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        assert!(LightStateResponse::decode(&LIGHT_STATE[..3]).is_err());
+        assert!(LightStateResponse::decode(&LIGHT_STATE[..40]).is_err());
+        // Wire type 3, deprecated groups
+        assert!(LightStateResponse::decode(&[0x0b]).is_err());
+    }
+
+    #[test]
+    fn encodes_light_commands() {
+        let command = LightCommandRequest {
+            key: 0x12345678,
+            state: Some(true),
+            brightness: Some(0.5),
+            color_mode: Some(color_mode::RGB | 0b11),
+            color_brightness: Some(1.0),
+            rgb: Some((1.0, 0.0, 0.25)),
+            white: Some(0.0),
+            color_temperature: None,
+            effect: Some("None".to_string()),
+        };
+        assert_eq!(command.encode(), LIGHT_COMMAND);
+
+        let command = LightCommandRequest {
+            key: 0x12345678,
+            state: Some(false),
+            color_temperature: Some(370.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            command.encode(),
+            [
+                0x0d, 0x78, 0x56, 0x34, 0x12, // key
+                0x10, 0x01, 0x18, 0x00, // has_state, state = false
+                0x60, 0x01, 0x6d, 0x00, 0x00, 0xb9, 0x43, // color_temperature = 370.0
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_light_commands() {
+        let mut fields = Vec::new();
+        decode_fields(LIGHT_COMMAND, |field, value| {
+            let value = match value {
+                Value::Fixed32(v) if field != 1 => f32::from_bits(v).to_string(),
+                Value::LengthDelimited(_) => value.as_string(),
+                value => value.as_u32().to_string(),
+            };
+            fields.push((field, value));
+            Ok(())
+        })
+        .unwrap();
+
+        let fields: Vec<_> = fields.iter().map(|(f, v)| (*f, v.as_str())).collect();
+        assert_eq!(
+            fields,
+            [
+                (1, "305419896"),
+                (2, "1"),
+                (3, "1"),
+                (4, "1"),
+                (5, "0.5"),
+                (6, "1"),
+                (7, "1"),
+                (8, "0"),
+                (9, "0.25"),
+                (10, "1"),
+                (11, "0"),
+                (18, "1"),
+                (19, "None"),
+                (20, "1"),
+                (21, "1"),
+                (22, "1"),
+                (23, "35"),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_light_entities() {
+        let mut entity = vec![
+            0x0a, 0x04, b'd', b'e', b's', b'k', // object_id
+            0x15, 0x0f, 0x00, 0x00, 0x00, // key (fixed32) = 15
+            0x1a, 0x04, b'D', b'e', b's', b'k', // name
+            0x4d, 0x00, 0x00, 0x19, 0x43, // min_mireds = 153.0
+            0x55, 0x00, 0x00, 0xfa, 0x43, // max_mireds = 500.0
+        ];
+
+        // Packed supported_color_modes
+        let mut packed = entity.clone();
+        packed.extend([0x62, 0x02, 0x23, 0x2f]);
+        let light = ListEntitiesLightResponse::decode(&packed).unwrap();
+        assert_eq!(light.object_id, "desk");
+        assert_eq!(light.key, 15);
+        assert_eq!(light.name, "Desk");
+        assert_eq!((light.min_mireds, light.max_mireds), (153.0, 500.0));
+        assert_eq!(light.supported_color_modes, [35, 47]);
+
+        // Unpacked supported_color_modes
+        entity.extend([0x60, 0x23, 0x60, 0x2f]);
+        let light = ListEntitiesLightResponse::decode(&entity).unwrap();
+        assert_eq!(light.supported_color_modes, [35, 47]);
+    }
+
+    #[test]
+    fn encodes_handshake_messages() {
+        let mut expected = vec![0x0a, 0x04, b't', b'e', b's', b't'];
+        expected.extend([0x10, 0x01, 0x18, 0x09]);
+        assert_eq!(hello_request("test"), expected);
+
+        let hello = HelloResponse::decode(&[
+            0x08, 0x01, 0x10, 0x0a, // API 1.10
+            0x1a, 0x02, b'f', b'w', // server_info
+            0x22, 0x04, b'd', b'e', b's', b'k', // name
+        ])
+        .unwrap();
+        assert_eq!((hello.api_version_major, hello.api_version_minor), (1, 10));
+        assert_eq!(hello.server_info, "fw");
+        assert_eq!(hello.name, "desk");
+
+        assert!(connect_request("").is_empty());
+        assert_eq!(connect_request("pw"), [0x0a, 0x02, b'p', b'w']);
+        assert!(
+            ConnectResponse::decode(&[0x08, 0x01])
+                .unwrap()
+                .invalid_password
+        );
+        assert!(!ConnectResponse::decode(&[]).unwrap().invalid_password);
+
+        assert_eq!(
+            get_time_response(0x01020304),
+            [0x0d, 0x04, 0x03, 0x02, 0x01]
+        );
+    }
+}
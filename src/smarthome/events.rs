@@ -0,0 +1,92 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::{DeviceEvent, DeviceEventStream};
+
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Fan-out of device events from a backend's event connection to the
+/// controller, together with the state of that connection
+pub struct EventChannel {
+    sender: broadcast::Sender<DeviceEvent>,
+    connected: AtomicBool,
+}
+
+impl EventChannel {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            sender,
+            connected: AtomicBool::new(false),
+        }
+    }
+
+    pub fn send_changed(&self, id: impl Into<String>) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.sender.send(DeviceEvent::Changed(id.into()));
+    }
+
+    /// Record the state of the event connection. Every change requests a
+    /// resync, so that devices are checked when the controller switches
+    /// between relying on events and polling.
+    pub fn set_connected(&self, connected: bool) {
+        if self.connected.swap(connected, Ordering::SeqCst) != connected {
+            let _ = self.sender.send(DeviceEvent::Resync);
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    pub fn subscribe(&self) -> DeviceEventStream {
+        let receiver = self.sender.subscribe();
+        futures::stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((event, receiver)),
+                // Events were dropped, so everything needs to be checked
+                Err(RecvError::Lagged(_)) => Some((DeviceEvent::Resync, receiver)),
+                Err(RecvError::Closed) => None,
+            }
+        })
+        .boxed()
+    }
+}
+
+impl Default for EventChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Exponential backoff for reconnecting event connections
+pub struct Backoff {
+    current: Duration,
+    initial: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            current: initial,
+            initial,
+            max,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use api_models::*;
+use async_trait::async_trait;
+use tokio::{net::UdpSocket, sync::oneshot};
+
+use crate::config::Config;
+
+use super::{
+    color, requested::RequestedValues, DeviceInfo, LightOptions, LightStatus, SmartHomeApi,
+};
+
+const SCAN_ADDRESS: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 4001);
+const COMMAND_PORT: u16 = 4003;
+/// Devices always send their responses to this port
+const RESPONSE_PORT: u16 = 4002;
+
+/// Color temperature range accepted by `colorwc`
+const KELVIN_RANGE: (u16, u16) = (2000, 9000);
+
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+pub struct Govee {
+    _config: Arc<Config>,
+    socket: Arc<UdpSocket>,
+    timeout: Duration,
+    responses: Arc<Responses>,
+    requested: RequestedValues,
+}
+
+/// Responses received on the response port, which is shared by all requests
+#[derive(Default)]
+struct Responses {
+    /// Devices that answered a scan, by upper case device ID
+    discovered: Mutex<HashMap<String, ScanData>>,
+    /// Requests waiting for a `devStatus` response, by device address
+    status_waiters: Mutex<HashMap<IpAddr, Vec<oneshot::Sender<DevStatus>>>>,
+}
+
+impl Govee {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let timeout = Duration::from_millis(config.smart_home.govee.timeout_ms);
+
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, RESPONSE_PORT))
+            .and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)
+            })
+            .map_err(|e| {
+                super::Error::Configuration(format!(
+                    "Could not listen on Govee response port {RESPONSE_PORT}: {e}"
+                ))
+            })?;
+        let socket = Arc::new(socket);
+
+        let responses: Arc<Responses> = Default::default();
+        tokio::spawn(receive(socket.clone(), responses.clone()));
+
+        Ok(Self {
+            _config: config,
+            socket,
+            timeout,
+            responses,
+            requested: Default::default(),
+        })
+    }
+
+    /// Device IDs are either IPv4 addresses or device IDs reported by
+    /// discovery, e.g. `1F:80:C5:32:32:36:72:4E`. The latter would also be
+    /// valid IPv6 addresses, which Govee devices don't use.
+    fn is_device_id(id: &str) -> super::Result<bool> {
+        let is_device_id = id.split(':').count() == 8
+            && id
+                .split(':')
+                .all(|b| b.len() == 2 && b.chars().all(|c| c.is_ascii_hexdigit()));
+        if is_device_id {
+            return Ok(true);
+        }
+
+        if id.parse::<Ipv4Addr>().is_ok() {
+            return Ok(false);
+        }
+        Err(super::Error::InvalidId(id.to_string()))
+    }
+
+    /// Address of the device, or `None` if a device with the ID was not
+    /// discovered
+    async fn address(&self, id: &str) -> super::Result<Option<IpAddr>> {
+        if !Self::is_device_id(id)? {
+            return Ok(id.parse().ok());
+        }
+
+        let id = id.to_ascii_uppercase();
+        if let Some(address) = self.discovered_address(&id) {
+            return Ok(Some(address));
+        }
+
+        self.discover().await?;
+        Ok(self.discovered_address(&id))
+    }
+
+    fn discovered_address(&self, id: &str) -> Option<IpAddr> {
+        let discovered = self.responses.discovered.lock().unwrap();
+        discovered.get(id).and_then(|d| d.ip.parse().ok())
+    }
+
+    /// Find devices by sending a scan request to the multicast group
+    async fn discover(&self) -> super::Result<Vec<ScanData>> {
+        tracing::debug!("Discovering Govee devices");
+
+        let request = serde_json::json!({
+            "msg": { "cmd": "scan", "data": { "account_topic": "reserve" } }
+        });
+        self.socket
+            .send_to(request.to_string().as_bytes(), SCAN_ADDRESS)
+            .await?;
+
+        // Responses are collected by the receiver
+        tokio::time::sleep(self.timeout).await;
+
+        Ok(self
+            .responses
+            .discovered
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    async fn send(&self, address: IpAddr, cmd: &str, data: serde_json::Value) -> super::Result<()> {
+        let request = serde_json::json!({ "msg": { "cmd": cmd, "data": data } });
+
+        tracing::debug!(%address, request = request.to_string(), "Sending request");
+
+        self.socket
+            .send_to(
+                request.to_string().as_bytes(),
+                SocketAddr::new(address, COMMAND_PORT),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Request the state of the device, or `None` if it did not answer in time
+    async fn dev_status(&self, address: IpAddr) -> super::Result<Option<DevStatus>> {
+        let (sender, receiver) = oneshot::channel();
+        self.responses
+            .status_waiters
+            .lock()
+            .unwrap()
+            .entry(address)
+            .or_default()
+            .push(sender);
+
+        self.send(address, "devStatus", serde_json::json!({}))
+            .await?;
+
+        match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(status)) => Ok(Some(status)),
+            _ => {
+                // Drop the waiters of the requests that timed out
+                let mut waiters = self.responses.status_waiters.lock().unwrap();
+                if let Some(senders) = waiters.get_mut(&address) {
+                    senders.retain(|s| !s.is_closed());
+                    if senders.is_empty() {
+                        waiters.remove(&address);
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    async fn control(&self, id: &str, cmd: &str, data: serde_json::Value) -> super::Result<()> {
+        let Some(address) = self.address(id).await? else {
+            return Err(super::Error::UnknownDeviceId);
+        };
+        self.send(address, cmd, data).await
+    }
+
+    fn forget(&self, address: IpAddr) {
+        let address = address.to_string();
+        self.responses
+            .discovered
+            .lock()
+            .unwrap()
+            .retain(|_, d| d.ip != address);
+    }
+}
+
+/// Receive responses until the program exits
+async fn receive(socket: Arc<UdpSocket>, responses: Arc<Responses>) {
+    let mut buf = vec![0; MAX_RESPONSE_SIZE];
+
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::warn!(error = %e, "Receiving Govee response failed");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let response = match serde_json::from_slice::<Response>(&buf[..len]) {
+            Ok(response) => response.msg,
+            Err(e) => {
+                tracing::debug!(address = %from.ip(), error = %e, "Ignoring invalid Govee response");
+                continue;
+            }
+        };
+
+        match response {
+            Message::Scan(data) => {
+                tracing::debug!(
+                    id = data.device,
+                    address = data.ip,
+                    "Discovered Govee device"
+                );
+                responses
+                    .discovered
+                    .lock()
+                    .unwrap()
+                    .insert(data.device.to_ascii_uppercase(), data);
+            }
+            Message::DevStatus(status) => {
+                let senders = responses
+                    .status_waiters
+                    .lock()
+                    .unwrap()
+                    .remove(&from.ip())
+                    .unwrap_or_default();
+                for sender in senders {
+                    let _ = sender.send(status.clone());
+                }
+            }
+            Message::Other => {}
+        }
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Govee {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let Some(address) = self.address(id).await? else {
+            return Ok(LightStatus::Offline);
+        };
+
+        let Some(status) = self.dev_status(address).await? else {
+            tracing::debug!(%address, "Govee device did not answer");
+            self.forget(address);
+            return Ok(LightStatus::Offline);
+        };
+
+        tracing::debug!("Got status {status:#?}");
+
+        // The color temperature is zero while the device shows an RGB color
+        let color_temperature = (status.color_tem_in_kelvin > 0).then(|| {
+            let kelvin = status.color_tem_in_kelvin;
+            self.requested
+                .color_temperature(id, |k| k.clamp(KELVIN_RANGE.0, KELVIN_RANGE.1) == kelvin)
+                .unwrap_or(kelvin)
+        });
+
+        let color = color_temperature.is_none().then(|| {
+            let rgb = [status.color.r, status.color.g, status.color.b];
+            let (hue, saturation) = self
+                .requested
+                .color(id, |h, s| color::to_rgb8(color::hs_to_rgb(h, s)) == rgb)
+                .unwrap_or_else(|| {
+                    let (r, g, b) = color::from_rgb8(rgb);
+                    color::rgb_to_hs(r, g, b)
+                });
+            super::Color { hue, saturation }
+        });
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: status.on_off == 1,
+            brightness: Some(status.brightness.min(100)),
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let data = serde_json::json!({ "value": u8::from(switched_on) });
+        self.control(id, "turn", data).await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let data = serde_json::json!({ "value": brightness.clamp(0, 100) });
+        self.control(id, "brightness", data).await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let data = serde_json::json!({
+            "color": { "r": 0, "g": 0, "b": 0 },
+            "colorTemInKelvin": temp.clamp(KELVIN_RANGE.0, KELVIN_RANGE.1),
+        });
+        self.control(id, "colorwc", data).await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let [r, g, b] = color::to_rgb8(color::hs_to_rgb(hue, saturation));
+        let data = serde_json::json!({
+            "color": { "r": r, "g": g, "b": b },
+            "colorTemInKelvin": 0,
+        });
+        self.control(id, "colorwc", data).await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    async fn list_devices(&self) -> super::Result<Vec<DeviceInfo>> {
+        let devices = self.discover().await?;
+        Ok(devices
+            .into_iter()
+            .map(|d| DeviceInfo {
+                id: d.device.to_ascii_uppercase(),
+                name: Some(d.ip),
+                model: Some(d.sku),
+            })
+            .collect())
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    pub struct Response {
+        pub msg: Message,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(tag = "cmd", content = "data")]
+    pub enum Message {
+        #[serde(rename = "scan")]
+        Scan(ScanData),
+        #[serde(rename = "devStatus")]
+        DevStatus(DevStatus),
+        #[serde(other)]
+        Other,
+    }
+
+    #[derive(Deserialize, Debug, Clone)]
+    pub struct ScanData {
+        pub ip: String,
+        /// Device ID, e.g. `1F:80:C5:32:32:36:72:4E`
+        pub device: String,
+        pub sku: String,
+    }
+
+    #[derive(Deserialize, Debug, Clone, Copy)]
+    pub struct Rgb {
+        pub r: u8,
+        pub g: u8,
+        pub b: u8,
+    }
+
+    #[derive(Deserialize, Debug, Clone)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DevStatus {
+        pub on_off: u8,
+        pub brightness: u8,
+        pub color: Rgb,
+        #[serde(default)]
+        pub color_tem_in_kelvin: u16,
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Once},
+};
+
+use api_models::*;
+use async_trait::async_trait;
+use reqwest::{header::HeaderMap, Client, Response, Url};
+
+use crate::config::Config;
+
+use super::{
+    color, events::EventChannel, requested::RequestedValues, DeviceEventStream, LightOptions,
+    LightStatus, SmartHomeApi,
+};
+
+mod websocket;
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+pub struct HomeAssistant {
+    _config: Arc<Config>,
+    client: Client,
+    base_url: Url,
+    requested: RequestedValues,
+    /// Values set while a light is off. They are sent when the light is
+    /// switched on, because `light.turn_on` with them would switch it on.
+    pending: Mutex<HashMap<String, Pending>>,
+    access_token: String,
+    websocket_events: bool,
+    events: Arc<EventChannel>,
+    start_listener: Once,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+struct Pending {
+    brightness: Option<u8>,
+    color_temperature: Option<u16>,
+    color: Option<(u8, u8)>,
+}
+
+impl HomeAssistant {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let ha_config = &config.smart_home.home_assistant;
+        let Some(base_url) = &ha_config.base_url else {
+            return Err(super::Error::Configuration(
+                "Home Assistant URL not configured".to_string(),
+            ));
+        };
+        let Some(access_token) = &ha_config.access_token else {
+            return Err(super::Error::Configuration(
+                "Home Assistant access token not configured".to_string(),
+            ));
+        };
+
+        let mut auth_header = HeaderMap::new();
+        auth_header.append(
+            "Authorization",
+            format!("Bearer {}", access_token).parse().map_err(|_| {
+                super::Error::Configuration("Home Assistant access token is invalid".to_string())
+            })?,
+        );
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .default_headers(auth_header)
+            .build()
+            .unwrap();
+
+        let base_url = format!("{}/api/", base_url.trim_end_matches('/'))
+            .parse()
+            .map_err(|_| {
+                super::Error::Configuration(format!("Invalid Home Assistant URL `{base_url}`"))
+            })?;
+
+        Ok(Self {
+            access_token: access_token.clone(),
+            websocket_events: ha_config.websocket_events,
+            _config: config,
+            client,
+            base_url,
+            requested: Default::default(),
+            pending: Default::default(),
+            events: Default::default(),
+            start_listener: Once::new(),
+        })
+    }
+
+    fn websocket_url(&self) -> Url {
+        let mut url = self.base_url.join("websocket").unwrap();
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        url.set_scheme(scheme).unwrap();
+        url
+    }
+
+    /// Device IDs are light entity IDs, e.g. `light.kitchen`
+    fn validate_device_id(id: &str) -> super::Result<()> {
+        let valid = id.strip_prefix("light.").is_some_and(|object_id| {
+            !object_id.is_empty()
+                && object_id
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        });
+
+        if !valid {
+            return Err(super::Error::InvalidId(id.to_string()));
+        }
+        Ok(())
+    }
+
+    fn error_from_status(res: Response) -> super::Result<Response> {
+        if res.status() == 404 {
+            return Err(super::Error::UnknownDeviceId);
+        }
+
+        Ok(res.error_for_status()?)
+    }
+
+    async fn call_light_service(
+        &self,
+        id: &str,
+        service: &str,
+        mut data: serde_json::Value,
+    ) -> super::Result<()> {
+        Self::validate_device_id(id)?;
+
+        data["entity_id"] = id.into();
+        tracing::debug!(
+            device_id = id,
+            service,
+            data = data.to_string(),
+            "Calling service"
+        );
+
+        let url = self
+            .base_url
+            .join(&format!("services/light/{service}"))
+            .unwrap();
+
+        let res = self.client.post(url).json(&data).send().await?;
+
+        Self::error_from_status(res)?;
+        Ok(())
+    }
+
+    async fn get_state(&self, id: &str) -> super::Result<EntityState> {
+        Self::validate_device_id(id)?;
+
+        let url = self
+            .base_url
+            .join(&format!("states/{id}"))
+            .map_err(|_| super::Error::InvalidId(id.to_string()))?;
+
+        let res = self.client.get(url).send().await?;
+        Ok(Self::error_from_status(res)?.json().await?)
+    }
+
+    /// Whether the light is off, so values should be kept pending instead
+    /// of being sent
+    async fn is_off(&self, id: &str) -> super::Result<bool> {
+        Ok(self.get_state(id).await?.state == "off")
+    }
+
+    fn update_pending(&self, id: &str, f: impl FnOnce(&mut Pending)) {
+        f(self
+            .pending
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_default());
+    }
+
+    fn take_pending(&self, id: &str) -> Pending {
+        self.pending.lock().unwrap().remove(id).unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for HomeAssistant {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let body = self.get_state(id).await?;
+
+        tracing::debug!("Got status {body:#?}");
+
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .get(id)
+            .copied()
+            .unwrap_or_default();
+
+        Ok(body.into_light_status(id, &self.requested, pending))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        if !switched_on {
+            return self
+                .call_light_service(id, "turn_off", serde_json::json!({}))
+                .await;
+        }
+
+        let pending = self.take_pending(id);
+        let mut data = serde_json::json!({});
+        if let Some(brightness) = pending.brightness {
+            data["brightness_pct"] = brightness.into();
+        }
+        if let Some(temp) = pending.color_temperature {
+            data["color_temp_kelvin"] = temp.into();
+        }
+        if let Some((hue, saturation)) = pending.color {
+            data["hs_color"] = serde_json::json!([f64::from(hue) * 3.6, saturation]);
+        }
+        self.call_light_service(id, "turn_on", data).await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let brightness = brightness.clamp(0, 100);
+        if self.is_off(id).await? {
+            self.update_pending(id, |p| p.brightness = Some(brightness));
+            return Ok(());
+        }
+
+        self.update_pending(id, |p| p.brightness = None);
+        let data = serde_json::json!({ "brightness_pct": brightness });
+        self.call_light_service(id, "turn_on", data).await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        if self.is_off(id).await? {
+            self.update_pending(id, |p| {
+                p.color_temperature = Some(temp);
+                p.color = None;
+            });
+        } else {
+            self.update_pending(id, |p| p.color_temperature = None);
+            let data = serde_json::json!({ "color_temp_kelvin": temp });
+            self.call_light_service(id, "turn_on", data).await?;
+        }
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        if self.is_off(id).await? {
+            self.update_pending(id, |p| {
+                p.color = Some((hue, saturation));
+                p.color_temperature = None;
+            });
+        } else {
+            self.update_pending(id, |p| p.color = None);
+            let data = serde_json::json!({
+                "hs_color": [f64::from(hue) * 3.6, saturation]
+            });
+            self.call_light_service(id, "turn_on", data).await?;
+        }
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    fn device_events(&self) -> Option<DeviceEventStream> {
+        if !self.websocket_events {
+            return None;
+        }
+
+        let stream = self.events.subscribe();
+        self.start_listener.call_once(|| {
+            tokio::spawn(websocket::listen(
+                self.websocket_url(),
+                self.access_token.clone(),
+                self.events.clone(),
+            ));
+        });
+        Some(stream)
+    }
+
+    fn events_connected(&self) -> bool {
+        self.events.is_connected()
+    }
+}
+
+impl EntityState {
+    /// Home Assistant doesn't report brightness or color while a light is
+    /// off, so the values pending for it are reported instead
+    fn into_light_status(
+        self,
+        id: &str,
+        requested: &RequestedValues,
+        pending: Pending,
+    ) -> LightStatus {
+        let switched_on = match self.state.as_str() {
+            "on" => true,
+            "off" => false,
+            // "unavailable" or "unknown"
+            _ => return LightStatus::Offline,
+        };
+
+        let attrs = self.attributes;
+
+        let mut brightness = attrs
+            .brightness
+            .map(|b| (b.clamp(0.0, 255.0) * 100.0 / 255.0).round() as u8);
+
+        let mut color_temperature = attrs
+            .color_temp_kelvin
+            .filter(|_| attrs.color_mode.as_deref() == Some("color_temp"))
+            .map(|k| {
+                requested
+                    .color_temperature(id, |r| {
+                        color::kelvin_to_mired(r) == color::kelvin_to_mired(k)
+                    })
+                    .unwrap_or(k)
+            });
+
+        let mut color = attrs
+            .hs_color
+            .filter(|_| color_temperature.is_none())
+            .map(|[h, s]| {
+                let hue = (h / 3.6).round() as u8 % 100;
+                let saturation = s.round().clamp(0.0, 100.0) as u8;
+                let (hue, saturation) = requested
+                    .color(id, |rh, rs| {
+                        rh.abs_diff(hue) <= 1 && rs.abs_diff(saturation) <= 1
+                    })
+                    .unwrap_or((hue, saturation));
+
+                super::Color { hue, saturation }
+            });
+
+        if !switched_on {
+            brightness = pending.brightness.or(brightness);
+            if let Some(temp) = pending.color_temperature {
+                color_temperature = Some(temp);
+                color = None;
+            }
+            if let Some((hue, saturation)) = pending.color {
+                color = Some(super::Color { hue, saturation });
+                color_temperature = None;
+            }
+        }
+
+        LightStatus::Online(LightOptions {
+            switched_on,
+            brightness,
+            color_temperature,
+            color,
+            effect: None,
+        })
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug, Default)]
+    #[serde(default)]
+    pub struct LightAttributes {
+        pub brightness: Option<f64>,
+        pub color_mode: Option<String>,
+        pub color_temp_kelvin: Option<u16>,
+        pub hs_color: Option<[f64; 2]>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct EntityState {
+        pub state: String,
+        #[serde(default)]
+        pub attributes: LightAttributes,
+    }
+}
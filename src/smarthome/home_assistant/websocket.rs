@@ -0,0 +1,156 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use futures::{SinkExt, StreamExt};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::smarthome::events::{Backoff, EventChannel};
+
+/// Send a ping if nothing has been received for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SUBSCRIBE_ID: u64 = 1;
+
+/// Listen to `state_changed` events from the Home Assistant websocket API and
+/// publish changes of light entities. Reconnects with backoff until the
+/// program exits.
+pub(super) async fn listen(url: Url, access_token: String, events: Arc<EventChannel>) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+    loop {
+        match connect_and_listen(&url, &access_token, &events, &mut backoff).await {
+            Ok(()) => tracing::info!("Home Assistant websocket connection closed"),
+            Err(e) => {
+                let err_ref: &(dyn std::error::Error + Send + Sync) = e.as_ref();
+                tracing::warn!(
+                    error = err_ref,
+                    "Home Assistant websocket connection failed"
+                );
+            }
+        }
+        events.set_connected(false);
+
+        let delay = backoff.next_delay();
+        tracing::debug!("Reconnecting to Home Assistant websocket in {delay:?}");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn connect_and_listen(
+    url: &Url,
+    access_token: &str,
+    events: &EventChannel,
+    backoff: &mut Backoff,
+) -> anyhow::Result<()> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .context("Connecting failed")?;
+
+    // Authentication phase
+    let Some(msg) = next_message(&mut ws).await? else {
+        return Ok(());
+    };
+    if msg.type_ != "auth_required" {
+        bail!("Unexpected message `{}` before authentication", msg.type_);
+    }
+
+    let auth = json!({ "type": "auth", "access_token": access_token });
+    ws.send(Message::text(auth.to_string())).await?;
+
+    match next_message(&mut ws).await? {
+        Some(msg) if msg.type_ == "auth_ok" => (),
+        Some(msg) if msg.type_ == "auth_invalid" => bail!("Authentication failed"),
+        Some(msg) => bail!("Unexpected message `{}` during authentication", msg.type_),
+        None => return Ok(()),
+    }
+
+    let subscribe = json!({
+        "id": SUBSCRIBE_ID,
+        "type": "subscribe_events",
+        "event_type": "state_changed",
+    });
+    ws.send(Message::text(subscribe.to_string())).await?;
+
+    let mut next_id = SUBSCRIBE_ID + 1;
+    let mut awaiting_pong = false;
+
+    loop {
+        let msg = match tokio::time::timeout(IDLE_TIMEOUT, next_message(&mut ws)).await {
+            Ok(msg) => msg?,
+            Err(_) if awaiting_pong => bail!("Ping timed out"),
+            Err(_) => {
+                let ping = json!({ "id": next_id, "type": "ping" });
+                next_id += 1;
+                ws.send(Message::text(ping.to_string())).await?;
+                awaiting_pong = true;
+                continue;
+            }
+        };
+        let Some(msg) = msg else {
+            return Ok(());
+        };
+        awaiting_pong = false;
+
+        match msg.type_.as_str() {
+            "result" if msg.id == Some(SUBSCRIBE_ID) => {
+                if !msg.success.unwrap_or(false) {
+                    bail!("Subscribing to state changes failed");
+                }
+                tracing::info!("Subscribed to Home Assistant state changes");
+                backoff.reset();
+                events.set_connected(true);
+            }
+            "event" => {
+                let Some(entity_id) = msg.event.and_then(|e| e.data.entity_id) else {
+                    continue;
+                };
+                if entity_id.starts_with("light.") {
+                    tracing::debug!(device_id = entity_id, "Light state changed");
+                    events.send_changed(entity_id);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+async fn next_message<S>(ws: &mut S) -> anyhow::Result<Option<ServerMessage>>
+where
+    S: futures::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    while let Some(msg) = ws.next().await {
+        match msg? {
+            Message::Text(text) => {
+                return serde_json::from_str(&text)
+                    .map(Some)
+                    .map_err(|e| anyhow!("Invalid message from Home Assistant: {e}"));
+            }
+            Message::Close(_) => return Ok(None),
+            // Pings are answered by tungstenite
+            _ => continue,
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerMessage {
+    #[serde(rename = "type")]
+    type_: String,
+    id: Option<u64>,
+    success: Option<bool>,
+    event: Option<Event>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Event {
+    data: EventData,
+}
+
+#[derive(Deserialize, Debug)]
+struct EventData {
+    entity_id: Option<String>,
+}
@@ -0,0 +1,438 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use reqwest::{Client, Method, Response};
+use serde_json::Value;
+
+use crate::config::{Config, HttpProfileConfig, HttpRequestConfig};
+
+use self::template::{JsonPath, Template};
+
+use super::{
+    color, requested::RequestedValues, DeviceInfo, LightOptions, LightStatus, SmartHomeApi,
+};
+
+mod template;
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+pub struct Http {
+    _config: Arc<Config>,
+    client: Client,
+    devices: HashMap<String, Device>,
+    requested: RequestedValues,
+}
+
+struct Device {
+    profile: Arc<Profile>,
+    /// Placeholder values by lower case name
+    variables: HashMap<String, String>,
+}
+
+struct Profile {
+    name: String,
+    headers: Vec<(String, Template)>,
+    basic_auth: Option<(Template, Option<Template>)>,
+    brightness_max: f64,
+    hue_max: f64,
+    saturation_max: f64,
+    status: Request,
+    paths: StatusPaths,
+    switch_on: Option<Request>,
+    switch_off: Option<Request>,
+    brightness: Option<Request>,
+    color_temperature: Option<Request>,
+    color: Option<Request>,
+}
+
+struct StatusPaths {
+    online: Option<JsonPath>,
+    switched_on: JsonPath,
+    brightness: Option<JsonPath>,
+    color_temperature: Option<JsonPath>,
+    color_temperature_mired: Option<JsonPath>,
+    hue: Option<JsonPath>,
+    saturation: Option<JsonPath>,
+}
+
+struct Request {
+    method: Method,
+    url: Template,
+    body: Option<Template>,
+}
+
+impl Profile {
+    fn from_config(profile: &HttpProfileConfig) -> super::Result<Self> {
+        let invalid = |what: &str, value: &str| {
+            super::Error::Configuration(format!(
+                "Invalid {what} `{value}` in HTTP profile {}",
+                profile.name
+            ))
+        };
+        let template = |text: &str| Template::parse(text).ok_or_else(|| invalid("template", text));
+        let path = |path: &str| JsonPath::parse(path).ok_or_else(|| invalid("JSON path", path));
+        let optional_path = |p: &Option<String>| p.as_deref().map(path).transpose();
+        let request = |request: &HttpRequestConfig| {
+            let method = request.method.as_deref().unwrap_or("GET");
+            Ok::<_, super::Error>(Request {
+                method: Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| invalid("method", method))?,
+                url: template(&request.url)?,
+                body: request.body.as_deref().map(template).transpose()?,
+            })
+        };
+        let optional_request = |r: &Option<HttpRequestConfig>| r.as_ref().map(request).transpose();
+        let max = |what: &str, value: Option<f64>| {
+            let value = value.unwrap_or(100.0);
+            if value > 0.0 && value.is_finite() {
+                Ok(value)
+            } else {
+                Err(invalid(what, &value.to_string()))
+            }
+        };
+
+        let status = &profile.status;
+        Ok(Self {
+            name: profile.name.clone(),
+            headers: profile
+                .headers
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), template(value)?)))
+                .collect::<super::Result<_>>()?,
+            basic_auth: profile
+                .basic_auth
+                .as_ref()
+                .map(|auth| {
+                    Ok::<_, super::Error>((
+                        template(&auth.username)?,
+                        auth.password.as_deref().map(template).transpose()?,
+                    ))
+                })
+                .transpose()?,
+            brightness_max: max("brightness_max", profile.brightness_max)?,
+            hue_max: max("hue_max", profile.hue_max)?,
+            saturation_max: max("saturation_max", profile.saturation_max)?,
+            status: request(&status.request)?,
+            paths: StatusPaths {
+                online: optional_path(&status.online)?,
+                switched_on: path(&status.switched_on)?,
+                brightness: optional_path(&status.brightness)?,
+                color_temperature: optional_path(&status.color_temperature)?,
+                color_temperature_mired: optional_path(&status.color_temperature_mired)?,
+                hue: optional_path(&status.hue)?,
+                saturation: optional_path(&status.saturation)?,
+            },
+            switch_on: optional_request(&profile.switch_on)?,
+            switch_off: optional_request(&profile.switch_off)?,
+            brightness: optional_request(&profile.brightness)?,
+            color_temperature: optional_request(&profile.color_temperature)?,
+            color: optional_request(&profile.color)?,
+        })
+    }
+}
+
+/// Scale a value between 0 and 100 to 0-`max` for a request
+fn scale_to(value: u8, max: f64) -> String {
+    ((f64::from(value) * max / 100.0).round() as i64).to_string()
+}
+
+/// Scale a value between 0 and `max` from a response to 0-100
+fn scale_from(value: f64, max: f64) -> u8 {
+    (value * 100.0 / max).round().clamp(0.0, 100.0) as u8
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_bool(value: &Value) -> bool {
+    match value {
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|n| n != 0.0),
+        Value::String(s) => ["on", "true", "1", "yes"]
+            .iter()
+            .any(|t| s.trim().eq_ignore_ascii_case(t)),
+        _ => false,
+    }
+}
+
+impl Http {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let http_config = &config.smart_home.http;
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .timeout(Duration::from_millis(http_config.timeout_ms))
+            .build()
+            .unwrap();
+
+        let profiles = http_config
+            .profiles
+            .iter()
+            .map(|p| Ok((p.name.clone(), Arc::new(Profile::from_config(p)?))))
+            .collect::<super::Result<HashMap<_, _>>>()?;
+
+        let devices = http_config
+            .devices
+            .iter()
+            .map(|d| {
+                let profile = profiles.get(&d.profile).ok_or_else(|| {
+                    super::Error::Configuration(format!(
+                        "Unknown HTTP profile `{}` for device {}",
+                        d.profile, d.id
+                    ))
+                })?;
+                let device = Device {
+                    profile: profile.clone(),
+                    variables: d
+                        .variables
+                        .iter()
+                        .map(|(name, value)| (name.to_lowercase(), value.clone()))
+                        .collect(),
+                };
+                Ok((d.id.clone(), device))
+            })
+            .collect::<super::Result<_>>()?;
+
+        Ok(Self {
+            _config: config,
+            client,
+            devices,
+            requested: Default::default(),
+        })
+    }
+
+    /// Device IDs are the IDs of devices listed in the configuration
+    fn device(&self, id: &str) -> super::Result<&Device> {
+        self.devices.get(id).ok_or(super::Error::UnknownDeviceId)
+    }
+
+    /// Render the request for the device and send it. Placeholders get their
+    /// values from `values`, the device ID (`id`) and the device variables.
+    async fn send(
+        &self,
+        id: &str,
+        request: &Request,
+        values: &[(&str, String)],
+    ) -> super::Result<Response> {
+        let device = self.device(id)?;
+        let lookup = |name: &str| {
+            values
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.clone())
+                .or_else(|| (name == "id").then(|| id.to_string()))
+                .or_else(|| device.variables.get(&name.to_lowercase()).cloned())
+        };
+        let render = |template: &Template| {
+            template.render(lookup).map_err(|name| {
+                super::Error::Configuration(format!(
+                    "No value for placeholder `{name}` of device {id}"
+                ))
+            })
+        };
+
+        let url = render(&request.url)?;
+        let url: reqwest::Url = url.parse().map_err(|_| {
+            super::Error::Configuration(format!("Invalid URL `{url}` for device {id}"))
+        })?;
+
+        let mut builder = self.client.request(request.method.clone(), url);
+        let profile = &device.profile;
+        for (name, value) in &profile.headers {
+            builder = builder.header(name, render(value)?);
+        }
+        if let Some((username, password)) = &profile.basic_auth {
+            let password = password.as_ref().map(render).transpose()?;
+            builder = builder.basic_auth(render(username)?, password);
+        }
+        if let Some(body) = &request.body {
+            let body = render(body)?;
+            tracing::debug!(device_id = id, body, "Sending request");
+            if !profile
+                .headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+            {
+                builder = builder.header("Content-Type", "application/json");
+            }
+            builder = builder.body(body);
+        }
+
+        let res = builder.send().await?;
+        Ok(res.error_for_status()?)
+    }
+
+    async fn send_operation(
+        &self,
+        id: &str,
+        request: Option<&Request>,
+        capability: &'static str,
+        values: &[(&str, String)],
+    ) -> super::Result<()> {
+        let Some(request) = request else {
+            return Err(super::Error::UnsupportedCapability(capability));
+        };
+        self.send(id, request, values).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Http {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let profile = &self.device(id)?.profile;
+        let res = match self.send(id, &profile.status, &[]).await {
+            Ok(res) => res,
+            Err(super::Error::RequestFailed(e)) if e.is_connect() || e.is_timeout() => {
+                tracing::debug!(error = %e, "Device is unreachable");
+                return Ok(LightStatus::Offline);
+            }
+            Err(e) => return Err(e),
+        };
+        let status: Value = res.json().await?;
+
+        tracing::debug!("Got status {status:#?}");
+
+        let paths = &profile.paths;
+        let find = |path: &Option<JsonPath>| path.as_ref().and_then(|p| p.find(&status));
+
+        if find(&paths.online).is_some_and(|online| !as_bool(online)) {
+            return Ok(LightStatus::Offline);
+        }
+
+        let switched_on = paths.switched_on.find(&status).ok_or_else(|| {
+            super::Error::Communication(format!("Status of device {id} has no switch state"))
+        })?;
+
+        let brightness = find(&paths.brightness)
+            .and_then(as_number)
+            .map(|b| scale_from(b, profile.brightness_max));
+
+        let color_temperature = match (
+            find(&paths.color_temperature).and_then(as_number),
+            find(&paths.color_temperature_mired).and_then(as_number),
+        ) {
+            (Some(kelvin), _) => {
+                let kelvin = kelvin.round() as u16;
+                Some(
+                    self.requested
+                        .color_temperature(id, |k| k.abs_diff(kelvin) <= 1)
+                        .unwrap_or(kelvin),
+                )
+            }
+            (None, Some(mired)) => {
+                let mired = mired.round() as u16;
+                Some(
+                    self.requested
+                        .color_temperature(id, |k| color::kelvin_to_mired(k) == mired)
+                        .unwrap_or_else(|| color::mired_to_kelvin(mired)),
+                )
+            }
+            (None, None) => None,
+        };
+
+        let color = match (
+            find(&paths.hue).and_then(as_number),
+            find(&paths.saturation).and_then(as_number),
+        ) {
+            (Some(hue), Some(saturation)) => {
+                let hue = scale_from(hue, profile.hue_max) % 100;
+                let saturation = scale_from(saturation, profile.saturation_max);
+                let (hue, saturation) = self
+                    .requested
+                    .color(id, |rh, rs| {
+                        rh.abs_diff(hue) <= 1 && rs.abs_diff(saturation) <= 1
+                    })
+                    .unwrap_or((hue, saturation));
+                Some(super::Color { hue, saturation })
+            }
+            _ => None,
+        };
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: as_bool(switched_on),
+            brightness,
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let profile = &self.device(id)?.profile;
+        let request = if switched_on {
+            &profile.switch_on
+        } else {
+            &profile.switch_off
+        };
+        self.send_operation(id, request.as_ref(), "switching", &[])
+            .await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let profile = &self.device(id)?.profile;
+        let values = [(
+            "brightness",
+            scale_to(brightness.min(100), profile.brightness_max),
+        )];
+        self.send_operation(id, profile.brightness.as_ref(), "brightness", &values)
+            .await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let profile = &self.device(id)?.profile;
+        let values = [
+            ("kelvin", temp.to_string()),
+            ("mired", color::kelvin_to_mired(temp).to_string()),
+        ];
+        self.send_operation(
+            id,
+            profile.color_temperature.as_ref(),
+            "color temperature",
+            &values,
+        )
+        .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let profile = &self.device(id)?.profile;
+        let (hue, saturation) = (hue.min(100), saturation.min(100));
+        let [red, green, blue] = color::to_rgb8(color::hs_to_rgb(hue, saturation));
+        let values = [
+            ("hue", scale_to(hue, profile.hue_max)),
+            ("saturation", scale_to(saturation, profile.saturation_max)),
+            ("red", red.to_string()),
+            ("green", green.to_string()),
+            ("blue", blue.to_string()),
+        ];
+        self.send_operation(id, profile.color.as_ref(), "color", &values)
+            .await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    async fn list_devices(&self) -> super::Result<Vec<DeviceInfo>> {
+        Ok(self
+            .devices
+            .iter()
+            .map(|(id, device)| DeviceInfo {
+                id: id.clone(),
+                name: None,
+                model: Some(device.profile.name.clone()),
+            })
+            .collect())
+    }
+}
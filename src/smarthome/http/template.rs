@@ -0,0 +1,173 @@
+use serde_json::Value;
+
+/// Text with `{{name}}` placeholders
+#[derive(Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Literal(String),
+    Placeholder(String),
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = Vec::new();
+        let mut rest = text;
+
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..].find("}}")? + start;
+            let name = rest[start + 2..end].trim();
+            if name.is_empty() {
+                return None;
+            }
+            parts.push(Part::Placeholder(name.to_string()));
+            rest = &rest[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Some(Self { parts })
+    }
+
+    /// Replace the placeholders with the values returned by `lookup`. Fails
+    /// with the name of the first placeholder that has no value.
+    pub fn render(&self, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => rendered.push_str(text),
+                Part::Placeholder(name) => {
+                    rendered.push_str(&lookup(name).ok_or_else(|| name.clone())?);
+                }
+            }
+        }
+        Ok(rendered)
+    }
+}
+
+/// Path to a value in a JSON document, e.g. `$.lights[0].on` or
+/// `$["state"]["on"]`
+#[derive(Debug)]
+pub struct JsonPath {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Option<Self> {
+        let mut rest = path.trim().strip_prefix('$')?;
+        let mut segments = Vec::new();
+
+        while !rest.is_empty() {
+            if let Some(after_dot) = rest.strip_prefix('.') {
+                let end = after_dot.find(['.', '[']).unwrap_or(after_dot.len());
+                if end == 0 {
+                    return None;
+                }
+                segments.push(Segment::Key(after_dot[..end].to_string()));
+                rest = &after_dot[end..];
+            } else if let Some(after_bracket) = rest.strip_prefix('[') {
+                let end = after_bracket.find(']')?;
+                let inner = after_bracket[..end].trim();
+                let quoted = inner
+                    .strip_prefix('"')
+                    .and_then(|i| i.strip_suffix('"'))
+                    .or_else(|| inner.strip_prefix('\'').and_then(|i| i.strip_suffix('\'')));
+                match quoted {
+                    Some(key) => segments.push(Segment::Key(key.to_string())),
+                    None => segments.push(Segment::Index(inner.parse().ok()?)),
+                }
+                rest = &after_bracket[end + 1..];
+            } else {
+                return None;
+            }
+        }
+
+        Some(Self { segments })
+    }
+
+    pub fn find<'a>(&self, document: &'a Value) -> Option<&'a Value> {
+        self.segments
+            .iter()
+            .try_fold(document, |value, segment| match segment {
+                Segment::Key(key) => value.get(key),
+                Segment::Index(index) => value.get(index),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "id" => Some("desk".to_string()),
+            "brightness" => Some("80".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let template = Template::parse("http://{{ id }}/set?b={{brightness}}&x=1").unwrap();
+        assert_eq!(template.render(lookup).unwrap(), "http://desk/set?b=80&x=1");
+    }
+
+    #[test]
+    fn renders_text_without_placeholders() {
+        let template = Template::parse("{\"on\": true}").unwrap();
+        assert_eq!(template.render(lookup).unwrap(), "{\"on\": true}");
+        assert_eq!(Template::parse("").unwrap().render(lookup).unwrap(), "");
+    }
+
+    #[test]
+    fn fails_with_missing_value() {
+        let template = Template::parse("{{id}}/{{hue}}").unwrap();
+        assert_eq!(template.render(lookup).unwrap_err(), "hue");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(Template::parse("{{id").is_none());
+        assert!(Template::parse("a{{ }}b").is_none());
+    }
+
+    #[test]
+    fn finds_values_by_path() {
+        let document = json!({
+            "lights": [{ "on": true }, { "on": false, "state": { "bri": 42 } }],
+            "odd key": "x",
+        });
+        let find = |path: &str| JsonPath::parse(path).unwrap().find(&document).cloned();
+
+        assert_eq!(find("$"), Some(document.clone()));
+        assert_eq!(find("$.lights[0].on"), Some(json!(true)));
+        assert_eq!(find("$.lights[1].state.bri"), Some(json!(42)));
+        assert_eq!(find("$[\"lights\"][1]['on']"), Some(json!(false)));
+        assert_eq!(find("$[\"odd key\"]"), Some(json!("x")));
+        assert_eq!(find("$.lights[2]"), None);
+        assert_eq!(find("$.missing.on"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        for path in ["lights", "$..on", "$.", "$[0", "$[x]", "$lights"] {
+            assert!(JsonPath::parse(path).is_none(), "{path}");
+        }
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    sync::{Arc, Once},
+    time::Duration,
+};
+
+use api_models::*;
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::post,
+    Json, Router,
+};
+use rand::Rng;
+use reqwest::{Client, Response, Url};
+
+use crate::config::Config;
+
+use super::{
+    events::{Backoff, EventChannel},
+    requested::RequestedValues,
+    DeviceEventStream, DeviceInfo, LightOptions, LightStatus, SmartHomeApi,
+};
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+/// Path of the event receiver on the health check server, followed by the
+/// secret token that is added to the registered event URL
+const EVENTS_PATH: &str = "/hubitat/events/:token";
+
+pub struct Hubitat {
+    _config: Arc<Config>,
+    client: Client,
+    base_url: Url,
+    access_token: String,
+    requested: RequestedValues,
+    event_url: Option<String>,
+    event_receiver: Arc<EventReceiver>,
+    register_events: Once,
+}
+
+/// State of the event receiver. Events are only accepted with the token, so
+/// that others who can reach the health check server can't post them.
+struct EventReceiver {
+    events: EventChannel,
+    token: String,
+}
+
+impl Hubitat {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let hubitat_config = &config.smart_home.hubitat;
+        let Some(base_url) = &hubitat_config.base_url else {
+            return Err(super::Error::Configuration(
+                "Hubitat URL not configured".to_string(),
+            ));
+        };
+        let Some(app_id) = &hubitat_config.app_id else {
+            return Err(super::Error::Configuration(
+                "Hubitat Maker API app ID not configured".to_string(),
+            ));
+        };
+        let Some(access_token) = &hubitat_config.access_token else {
+            return Err(super::Error::Configuration(
+                "Hubitat access token not configured".to_string(),
+            ));
+        };
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .build()
+            .unwrap();
+
+        let base_url = format!("{}/apps/api/{app_id}/", base_url.trim_end_matches('/'))
+            .parse()
+            .map_err(|_| {
+                super::Error::Configuration(format!("Invalid Hubitat URL `{base_url}`"))
+            })?;
+
+        // Events can only be received on the health check server
+        let event_url = hubitat_config.event_url.clone().filter(|_| {
+            if !config.health_check.enable_server {
+                tracing::warn!(
+                    "Hubitat event URL is ignored because the health check server is disabled"
+                );
+            }
+            config.health_check.enable_server
+        });
+
+        let event_receiver = EventReceiver {
+            events: EventChannel::new(),
+            token: format!("{:032x}", rand::thread_rng().gen::<u128>()),
+        };
+
+        Ok(Self {
+            access_token: access_token.clone(),
+            event_url,
+            _config: config,
+            client,
+            base_url,
+            requested: Default::default(),
+            event_receiver: Arc::new(event_receiver),
+            register_events: Once::new(),
+        })
+    }
+
+    /// Maker API URL with the access token
+    fn url(&self, path: &str) -> Url {
+        let mut url = self.base_url.join(path).unwrap();
+        url.query_pairs_mut()
+            .append_pair("access_token", &self.access_token);
+        url
+    }
+
+    /// Device IDs are numeric Hubitat device IDs, e.g. `42`
+    fn validate_device_id(id: &str) -> super::Result<()> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(super::Error::InvalidId(id.to_string()));
+        }
+        Ok(())
+    }
+
+    fn error_from_status(res: Response) -> super::Result<Response> {
+        if res.status() == 404 {
+            return Err(super::Error::UnknownDeviceId);
+        }
+        if res.status() == 401 {
+            return Err(super::Error::Configuration(
+                "Hubitat access token was not accepted".to_string(),
+            ));
+        }
+
+        Ok(res.error_for_status()?)
+    }
+
+    async fn get_device(&self, id: &str) -> super::Result<Device> {
+        Self::validate_device_id(id)?;
+
+        let res = self
+            .client
+            .get(self.url(&format!("devices/{id}")))
+            .send()
+            .await?;
+        Ok(Self::error_from_status(res)?.json().await?)
+    }
+
+    /// Run a device command, after checking that the device has it
+    async fn send_command(
+        &self,
+        id: &str,
+        command: &str,
+        value: Option<String>,
+        capability: &'static str,
+    ) -> super::Result<()> {
+        let device = self.get_device(id).await?;
+        if !device.commands.iter().any(|c| c == command) {
+            return Err(super::Error::UnsupportedCapability(capability));
+        }
+
+        tracing::debug!(device_id = id, command, value, "Sending device command");
+
+        let mut url = self.url(&format!("devices/{id}/"));
+        {
+            let mut path = url.path_segments_mut().unwrap();
+            path.pop_if_empty().push(command);
+            if let Some(value) = &value {
+                path.push(value);
+            }
+        }
+
+        let res = self.client.get(url).send().await?;
+        Self::error_from_status(res)?;
+        Ok(())
+    }
+}
+
+/// Set the URL that the Maker API posts device events to, with the token of
+/// the receiver, retrying until it succeeds
+async fn register_event_url(
+    client: Client,
+    mut url: Url,
+    event_url: String,
+    receiver: Arc<EventReceiver>,
+) {
+    let event_url = format!("{}/{}", event_url.trim_end_matches('/'), receiver.token);
+    url.path_segments_mut()
+        .unwrap()
+        .pop_if_empty()
+        .push("postURL")
+        .push(&event_url);
+
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        let res = client
+            .get(url.clone())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+        match res {
+            Ok(_) => {
+                tracing::info!("Registered Hubitat event URL");
+                receiver.events.set_connected(true);
+                return;
+            }
+            Err(e) => {
+                let delay = backoff.next_delay();
+                tracing::warn!(error = %e, "Registering Hubitat event URL failed, retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+async fn receive_event(
+    State(receiver): State<Arc<EventReceiver>>,
+    Path(token): Path<String>,
+    Json(event): Json<EventPost>,
+) -> StatusCode {
+    if token != receiver.token {
+        return StatusCode::NOT_FOUND;
+    }
+
+    tracing::debug!("Received event {event:?}");
+
+    // Hub events such as mode changes don't concern any device
+    if let Some(id) = event.content.device_id() {
+        receiver.events.send_changed(id);
+    }
+    StatusCode::OK
+}
+
+#[async_trait]
+impl SmartHomeApi for Hubitat {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let device = self.get_device(id).await?;
+
+        tracing::debug!("Got status {device:#?}");
+
+        if device.attribute_str("healthStatus") == Some("offline") {
+            return Ok(LightStatus::Offline);
+        }
+
+        // `CT` or `RGB`, not reported by every driver
+        let color_mode = device.attribute_str("colorMode");
+
+        let color = match (
+            device.attribute_number("hue"),
+            device.attribute_number("saturation"),
+        ) {
+            (Some(hue), Some(saturation)) if color_mode != Some("CT") => {
+                let hue = hue.round().clamp(0.0, 100.0) as u8 % 100;
+                let saturation = saturation.round().clamp(0.0, 100.0) as u8;
+                let (hue, saturation) = self
+                    .requested
+                    .color(id, |rh, rs| {
+                        rh.abs_diff(hue) <= 1 && rs.abs_diff(saturation) <= 1
+                    })
+                    .unwrap_or((hue, saturation));
+                Some(super::Color { hue, saturation })
+            }
+            _ => None,
+        };
+
+        let color_temperature = device
+            .attribute_number("colorTemperature")
+            .filter(|_| color_mode.map_or(color.is_none(), |m| m == "CT"))
+            .map(|ct| {
+                let ct = ct.round() as u16;
+                self.requested
+                    .color_temperature(id, |k| k.abs_diff(ct) <= 1)
+                    .unwrap_or(ct)
+            });
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: device.attribute_str("switch") == Some("on"),
+            brightness: device
+                .attribute_number("level")
+                .map(|l| l.round().clamp(0.0, 100.0) as u8),
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let command = if switched_on { "on" } else { "off" };
+        self.send_command(id, command, None, "switching").await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let value = brightness.clamp(0, 100).to_string();
+        self.send_command(id, "setLevel", Some(value), "brightness")
+            .await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        self.send_command(
+            id,
+            "setColorTemperature",
+            Some(temp.to_string()),
+            "color temperature",
+        )
+        .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        // Hubitat uses the same 0-100 scale for hue as the Light resources
+        let (hue, saturation) = (hue.min(100), saturation.min(100));
+        let value = serde_json::json!({ "hue": hue, "saturation": saturation });
+        self.send_command(id, "setColor", Some(value.to_string()), "color")
+            .await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    fn device_events(&self) -> Option<DeviceEventStream> {
+        let event_url = self.event_url.as_ref()?;
+
+        let stream = self.event_receiver.events.subscribe();
+        self.register_events.call_once(|| {
+            tokio::spawn(register_event_url(
+                self.client.clone(),
+                self.url(""),
+                event_url.clone(),
+                self.event_receiver.clone(),
+            ));
+        });
+        Some(stream)
+    }
+
+    fn events_connected(&self) -> bool {
+        self.event_receiver.events.is_connected()
+    }
+
+    fn webhook_routes(&self) -> Option<Router> {
+        self.event_url.as_ref()?;
+
+        Some(
+            Router::new()
+                .route(EVENTS_PATH, post(receive_event))
+                .with_state(self.event_receiver.clone()),
+        )
+    }
+
+    async fn list_devices(&self) -> super::Result<Vec<DeviceInfo>> {
+        let res = self.client.get(self.url("devices")).send().await?;
+        let devices: Vec<DeviceSummary> = Self::error_from_status(res)?.json().await?;
+
+        Ok(devices
+            .into_iter()
+            .map(|d| DeviceInfo {
+                id: d.id,
+                name: d.label.or(d.name),
+                model: d.type_,
+            })
+            .collect())
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    pub struct DeviceSummary {
+        pub id: String,
+        pub name: Option<String>,
+        pub label: Option<String>,
+        /// Driver name
+        #[serde(rename = "type")]
+        pub type_: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Device {
+        #[serde(default)]
+        pub attributes: Vec<Attribute>,
+        #[serde(default)]
+        pub commands: Vec<String>,
+    }
+
+    impl Device {
+        fn attribute(&self, name: &str) -> Option<&serde_json::Value> {
+            self.attributes
+                .iter()
+                .find(|a| a.name == name)
+                .map(|a| &a.current_value)
+        }
+
+        pub fn attribute_str(&self, name: &str) -> Option<&str> {
+            self.attribute(name).and_then(|v| v.as_str())
+        }
+
+        /// Numbers are reported as strings by some drivers
+        pub fn attribute_number(&self, name: &str) -> Option<f64> {
+            match self.attribute(name)? {
+                serde_json::Value::Number(n) => n.as_f64(),
+                serde_json::Value::String(s) => s.parse().ok(),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Attribute {
+        pub name: String,
+        #[serde(default)]
+        pub current_value: serde_json::Value,
+    }
+
+    /// Body of the requests sent to the event URL
+    #[derive(Deserialize, Debug)]
+    pub struct EventPost {
+        pub content: Event,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Event {
+        device_id: serde_json::Value,
+    }
+
+    impl Event {
+        /// Device IDs are sent as strings or numbers depending on the
+        /// firmware version
+        pub fn device_id(&self) -> Option<String> {
+            match &self.device_id {
+                serde_json::Value::String(s) => Some(s.clone()),
+                serde_json::Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            }
+        }
+    }
+}
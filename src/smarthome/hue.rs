@@ -0,0 +1,350 @@
+use std::sync::Arc;
+
+use api_models::*;
+use async_trait::async_trait;
+use reqwest::{header::HeaderMap, Client, Response, Url};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+use super::{
+    color::{self, Gamut, Xy},
+    requested::RequestedValues,
+    LightOptions, LightStatus, SmartHomeApi,
+};
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+/// Mirek range supported by most Hue white ambiance bulbs, used when the
+/// bridge does not report one for the light
+const DEFAULT_MIREK_RANGE: (u16, u16) = (153, 500);
+
+/// The bridge reports xy coordinates with four decimals
+const XY_TOLERANCE: f64 = 0.0005;
+
+pub struct Hue {
+    _config: Arc<Config>,
+    client: Client,
+    base_url: Url,
+    requested: RequestedValues,
+}
+
+impl Hue {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let hue_config = &config.smart_home.hue;
+        let Some(bridge_address) = &hue_config.bridge_address else {
+            return Err(super::Error::Configuration(
+                "Hue bridge address not configured".to_string(),
+            ));
+        };
+        let Some(application_key) = &hue_config.application_key else {
+            return Err(super::Error::Configuration(
+                "Hue application key not configured".to_string(),
+            ));
+        };
+
+        let mut auth_header = HeaderMap::new();
+        auth_header.append(
+            "hue-application-key",
+            application_key.parse().map_err(|_| {
+                super::Error::Configuration("Hue application key is invalid".to_string())
+            })?,
+        );
+
+        let mut client_builder = reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .default_headers(auth_header)
+            .danger_accept_invalid_certs(hue_config.accept_invalid_certs);
+
+        if let Some(ca_file) = &hue_config.ca_certificate_file {
+            let pem = std::fs::read(ca_file).map_err(|e| {
+                super::Error::Configuration(format!("Could not read Hue CA certificate: {e}"))
+            })?;
+            let cert = reqwest::Certificate::from_pem(&pem).map_err(|e| {
+                super::Error::Configuration(format!("Invalid Hue CA certificate: {e}"))
+            })?;
+            client_builder = client_builder.add_root_certificate(cert);
+        }
+
+        let client = client_builder.build().unwrap();
+
+        let base_url = format!("https://{bridge_address}/clip/v2/resource/")
+            .parse()
+            .map_err(|_| {
+                super::Error::Configuration(format!(
+                    "Invalid Hue bridge address `{bridge_address}`"
+                ))
+            })?;
+
+        Ok(Self {
+            _config: config,
+            client,
+            base_url,
+            requested: Default::default(),
+        })
+    }
+
+    fn validate_device_id(id: &str) -> super::Result<()> {
+        Uuid::try_parse(id).map_err(|_| super::Error::InvalidId(id.to_string()))?;
+        Ok(())
+    }
+
+    fn error_from_status(res: Response) -> super::Result<Response> {
+        if res.status() == 404 {
+            return Err(super::Error::UnknownDeviceId);
+        }
+
+        Ok(res.error_for_status()?)
+    }
+
+    async fn get_light(&self, id: &str) -> super::Result<LightResource> {
+        Self::validate_device_id(id)?;
+
+        let url = self
+            .base_url
+            .join(&format!("light/{id}"))
+            .map_err(|_| super::Error::InvalidId(id.to_string()))?;
+
+        let res = self.client.get(url).send().await?;
+        let body: ResourceResponse<LightResource> = Self::error_from_status(res)?.json().await?;
+
+        body.data
+            .into_iter()
+            .next()
+            .ok_or(super::Error::UnknownDeviceId)
+    }
+
+    async fn is_connected(&self, device_id: &str) -> super::Result<bool> {
+        let url = self.base_url.join("zigbee_connectivity").unwrap();
+
+        let res = self.client.get(url).send().await?;
+        let body: ResourceResponse<ZigbeeConnectivity> =
+            Self::error_from_status(res)?.json().await?;
+
+        let connected = body
+            .data
+            .iter()
+            .find(|c| c.owner.rid == device_id)
+            .map(|c| c.status == ZigbeeConnectivityStatus::Connected)
+            // Lights that are not zigbee devices (e.g. groups) don't have a
+            // connectivity resource
+            .unwrap_or(true);
+
+        Ok(connected)
+    }
+
+    async fn update_light(&self, id: &str, body: serde_json::Value) -> super::Result<()> {
+        Self::validate_device_id(id)?;
+
+        tracing::debug!(device_id = id, command = body.to_string(), "Updating light");
+
+        let url = self
+            .base_url
+            .join(&format!("light/{id}"))
+            .map_err(|_| super::Error::InvalidId(id.to_string()))?;
+
+        let res = self.client.put(url).json(&body).send().await?;
+
+        Self::error_from_status(res)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Hue {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let light = self.get_light(id).await?;
+
+        tracing::debug!("Got status {light:#?}");
+
+        if !self.is_connected(&light.owner.rid).await? {
+            return Ok(LightStatus::Offline);
+        }
+
+        let brightness = light
+            .dimming
+            .map(|d| d.brightness.round().clamp(0.0, 100.0) as u8);
+
+        let color_temperature = light
+            .color_temperature
+            .as_ref()
+            .filter(|ct| ct.mirek_valid)
+            .and_then(|ct| ct.mirek)
+            .map(|mirek| {
+                self.requested
+                    .color_temperature(id, |k| color::kelvin_to_mired(k) == mirek)
+                    .unwrap_or_else(|| color::mired_to_kelvin(mirek))
+            });
+
+        let color = match (&color_temperature, &light.color) {
+            (None, Some(c)) => {
+                let gamut = c.gamut.as_ref().map(Gamut::from);
+                let xy = Xy::from(&c.xy);
+                let (hue, saturation) = self
+                    .requested
+                    .color(id, |h, s| {
+                        let expected = color::hs_to_xy(h, s, gamut.as_ref());
+                        (expected.x - xy.x).abs() <= XY_TOLERANCE
+                            && (expected.y - xy.y).abs() <= XY_TOLERANCE
+                    })
+                    .unwrap_or_else(|| color::xy_to_hs(xy));
+
+                Some(super::Color { hue, saturation })
+            }
+            _ => None,
+        };
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: light.on.on,
+            brightness,
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let body = serde_json::json!({ "on": { "on": switched_on } });
+        self.update_light(id, body).await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let body = serde_json::json!({ "dimming": { "brightness": brightness.clamp(0, 100) } });
+        self.update_light(id, body).await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let light = self.get_light(id).await?;
+        let (min, max) = light
+            .color_temperature
+            .and_then(|ct| ct.mirek_schema)
+            .map(|s| (s.mirek_minimum, s.mirek_maximum))
+            .unwrap_or(DEFAULT_MIREK_RANGE);
+
+        let mirek = color::kelvin_to_mired(temp).clamp(min, max);
+        let body = serde_json::json!({ "color_temperature": { "mirek": mirek } });
+        self.update_light(id, body).await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let light = self.get_light(id).await?;
+        let gamut = light.color.and_then(|c| c.gamut).map(|g| Gamut::from(&g));
+
+        let xy = color::hs_to_xy(hue, saturation, gamut.as_ref());
+        let body = serde_json::json!({ "color": { "xy": { "x": xy.x, "y": xy.y } } });
+        self.update_light(id, body).await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+}
+
+impl From<&XyValue> for Xy {
+    fn from(value: &XyValue) -> Self {
+        Xy {
+            x: value.x,
+            y: value.y,
+        }
+    }
+}
+
+impl From<&ColorGamut> for Gamut {
+    fn from(value: &ColorGamut) -> Self {
+        Gamut {
+            red: (&value.red).into(),
+            green: (&value.green).into(),
+            blue: (&value.blue).into(),
+        }
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    pub struct ResourceResponse<T> {
+        #[serde(default = "Vec::new")]
+        pub data: Vec<T>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ResourceIdentifier {
+        pub rid: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct On {
+        pub on: bool,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Dimming {
+        pub brightness: f64,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct MirekSchema {
+        pub mirek_minimum: u16,
+        pub mirek_maximum: u16,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ColorTemperature {
+        pub mirek: Option<u16>,
+        #[serde(default)]
+        pub mirek_valid: bool,
+        pub mirek_schema: Option<MirekSchema>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct XyValue {
+        pub x: f64,
+        pub y: f64,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ColorGamut {
+        pub red: XyValue,
+        pub green: XyValue,
+        pub blue: XyValue,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Color {
+        pub xy: XyValue,
+        pub gamut: Option<ColorGamut>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct LightResource {
+        pub owner: ResourceIdentifier,
+        pub on: On,
+        pub dimming: Option<Dimming>,
+        pub color_temperature: Option<ColorTemperature>,
+        pub color: Option<Color>,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ZigbeeConnectivityStatus {
+        Connected,
+        Disconnected,
+        ConnectivityIssue,
+        UnidirectionalIncoming,
+        #[serde(other)]
+        Unknown,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ZigbeeConnectivity {
+        pub owner: ResourceIdentifier,
+        pub status: ZigbeeConnectivityStatus,
+    }
+}
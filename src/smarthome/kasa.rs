@@ -0,0 +1,789 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use api_models::*;
+use async_trait::async_trait;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+    time::Instant,
+};
+
+use crate::config::Config;
+
+use super::{requested::RequestedValues, DeviceInfo, LightOptions, LightStatus, SmartHomeApi};
+
+const PORT: u16 = 9999;
+
+const LIGHTING_SERVICE: &str = "smartlife.iot.smartbulb.lightingservice";
+const DIMMER_SERVICE: &str = "smartlife.iot.dimmer";
+
+/// Error codes for unsupported modules and methods
+const ERROR_MODULE_NOT_SUPPORTED: i32 = -1;
+const ERROR_METHOD_NOT_SUPPORTED: i32 = -2;
+
+/// Responses are a few kilobytes at most
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// Color temperatures supported by bulb models, by model prefix. The first
+/// match is used.
+const COLOR_TEMPERATURE_RANGES: [(&str, (u16, u16)); 10] = [
+    ("KL120(US)", (2700, 5000)),
+    ("KL120", (2700, 6500)),
+    ("LB120", (2700, 6500)),
+    ("KL125", (2500, 6500)),
+    ("KL135", (2500, 6500)),
+    ("KB130", (2500, 9000)),
+    ("KL130", (2500, 9000)),
+    ("LB130", (2500, 9000)),
+    ("LB230", (2500, 9000)),
+    ("KL430", (2500, 9000)),
+];
+const DEFAULT_COLOR_TEMPERATURE_RANGE: (u16, u16) = (2700, 6500);
+
+pub struct Kasa {
+    _config: Arc<Config>,
+    broadcast_address: SocketAddr,
+    timeout: Duration,
+    /// Addresses of discovered devices by device ID
+    discovered: Mutex<HashMap<String, IpAddr>>,
+    /// Kinds of the devices that have been seen by address
+    kinds: Mutex<HashMap<IpAddr, DeviceKind>>,
+    /// Color temperature ranges of the devices that have been seen by address
+    color_temperature_ranges: Mutex<HashMap<IpAddr, (u16, u16)>>,
+    requested: RequestedValues,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DeviceKind {
+    Bulb,
+    Dimmer,
+    Plug,
+}
+
+/// Whether the device could be reached and answered the request
+enum RequestError {
+    Unreachable,
+    Failed(super::Error),
+}
+
+impl From<RequestError> for super::Error {
+    fn from(value: RequestError) -> Self {
+        match value {
+            RequestError::Unreachable => {
+                super::Error::Communication("Device is unreachable".to_string())
+            }
+            RequestError::Failed(e) => e,
+        }
+    }
+}
+
+impl Kasa {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let kasa_config = &config.smart_home.kasa;
+
+        let broadcast_ip: IpAddr = kasa_config.broadcast_address.parse().map_err(|_| {
+            super::Error::Configuration(format!(
+                "Invalid Kasa broadcast address `{}`",
+                kasa_config.broadcast_address
+            ))
+        })?;
+
+        Ok(Self {
+            broadcast_address: SocketAddr::new(broadcast_ip, PORT),
+            timeout: Duration::from_millis(kasa_config.timeout_ms),
+            discovered: Default::default(),
+            kinds: Default::default(),
+            color_temperature_ranges: Default::default(),
+            requested: Default::default(),
+            _config: config,
+        })
+    }
+
+    /// Device IDs are either IP addresses or device IDs reported by
+    /// discovery, e.g. `8006A1B2C3D4E5F60718293A4B5C6D7E8F901234`
+    fn is_device_id(id: &str) -> super::Result<bool> {
+        if id.parse::<IpAddr>().is_ok() {
+            return Ok(false);
+        }
+        if id.len() >= 32 && id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(true);
+        }
+        Err(super::Error::InvalidId(id.to_string()))
+    }
+
+    /// Address of the device, or `None` if a device with the ID was not
+    /// discovered
+    async fn address(&self, id: &str) -> super::Result<Option<IpAddr>> {
+        if !Self::is_device_id(id)? {
+            return Ok(id.parse().ok());
+        }
+
+        let id = id.to_ascii_uppercase();
+        if let Some(address) = self.discovered.lock().unwrap().get(&id) {
+            return Ok(Some(*address));
+        }
+
+        self.discover().await?;
+        Ok(self.discovered.lock().unwrap().get(&id).copied())
+    }
+
+    /// Find devices by broadcasting a `get_sysinfo` request
+    async fn discover(&self) -> super::Result<Vec<(IpAddr, SysInfo)>> {
+        tracing::debug!("Discovering Kasa devices");
+
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        socket.set_broadcast(true)?;
+
+        let request = serde_json::json!({ "system": { "get_sysinfo": {} } });
+        socket
+            .send_to(
+                &protocol::encrypt(request.to_string().as_bytes()),
+                self.broadcast_address,
+            )
+            .await?;
+
+        let mut devices = Vec::new();
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = vec![0; MAX_RESPONSE_SIZE];
+        while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, from) = res?;
+            let Ok(response) = serde_json::from_slice::<Response>(&protocol::decrypt(&buf[..len]))
+            else {
+                continue;
+            };
+            let Some(sysinfo) = response.system.and_then(|s| s.get_sysinfo) else {
+                continue;
+            };
+
+            tracing::debug!(id = sysinfo.device_id, address = %from.ip(), "Discovered Kasa device");
+            self.discovered
+                .lock()
+                .unwrap()
+                .insert(sysinfo.device_id.to_ascii_uppercase(), from.ip());
+            self.remember(from.ip(), &sysinfo);
+            devices.push((from.ip(), sysinfo));
+        }
+
+        Ok(devices)
+    }
+
+    /// Send a request over TCP and return the response
+    async fn request(
+        &self,
+        address: IpAddr,
+        request: serde_json::Value,
+    ) -> Result<serde_json::Value, RequestError> {
+        tracing::debug!(%address, request = request.to_string(), "Sending request");
+
+        let response = tokio::time::timeout(self.timeout, async {
+            let mut stream = TcpStream::connect((address, PORT)).await?;
+            stream
+                .write_all(&protocol::encode_tcp(request.to_string().as_bytes()))
+                .await?;
+            protocol::read_tcp(&mut stream).await
+        })
+        .await
+        .map_err(|_| RequestError::Unreachable)?
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::HostUnreachable
+            | std::io::ErrorKind::NetworkUnreachable => RequestError::Unreachable,
+            _ => RequestError::Failed(e.into()),
+        })?;
+
+        serde_json::from_slice(&response).map_err(|e| {
+            RequestError::Failed(super::Error::Communication(format!(
+                "Invalid response: {e}"
+            )))
+        })
+    }
+
+    async fn get_sysinfo(&self, address: IpAddr) -> Result<SysInfo, RequestError> {
+        let request = serde_json::json!({ "system": { "get_sysinfo": {} } });
+        let response = self.request(address, request).await?;
+        let sysinfo = parse_response(response)?
+            .system
+            .and_then(|s| s.get_sysinfo)
+            .ok_or_else(|| {
+                RequestError::Failed(super::Error::Communication(
+                    "Missing system information".to_string(),
+                ))
+            })?;
+
+        self.remember(address, &sysinfo);
+        Ok(sysinfo)
+    }
+
+    /// Keep what is needed for controlling the device
+    fn remember(&self, address: IpAddr, sysinfo: &SysInfo) {
+        self.kinds.lock().unwrap().insert(address, sysinfo.kind());
+        self.color_temperature_ranges
+            .lock()
+            .unwrap()
+            .insert(address, sysinfo.color_temperature_range());
+    }
+
+    async fn kind(&self, address: IpAddr) -> Result<DeviceKind, RequestError> {
+        if let Some(kind) = self.kinds.lock().unwrap().get(&address) {
+            return Ok(*kind);
+        }
+        Ok(self.get_sysinfo(address).await?.kind())
+    }
+
+    async fn color_temperature_range(&self, address: IpAddr) -> Result<(u16, u16), RequestError> {
+        if let Some(range) = self.color_temperature_ranges.lock().unwrap().get(&address) {
+            return Ok(*range);
+        }
+        Ok(self.get_sysinfo(address).await?.color_temperature_range())
+    }
+
+    /// Send a request to a bulb, dimmer or plug, checking that the device is
+    /// of a kind that supports it
+    async fn control(
+        &self,
+        id: &str,
+        capability: &'static str,
+        request: impl FnOnce(DeviceKind) -> Option<serde_json::Value>,
+    ) -> super::Result<()> {
+        let Some(address) = self.address(id).await? else {
+            return Err(super::Error::UnknownDeviceId);
+        };
+        let kind = self.kind(address).await?;
+        let Some(request) = request(kind) else {
+            return Err(super::Error::UnsupportedCapability(capability));
+        };
+
+        let response = self.request(address, request).await?;
+        match error_code(&response) {
+            Some((ERROR_MODULE_NOT_SUPPORTED | ERROR_METHOD_NOT_SUPPORTED, _)) => {
+                Err(super::Error::UnsupportedCapability(capability))
+            }
+            Some((code, message)) => {
+                Err(super::Error::Communication(format!("{message} ({code})")))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn transition_light_state(state: serde_json::Value) -> serde_json::Value {
+        let mut state = state;
+        state["transition_period"] = 0.into();
+        state["ignore_default"] = 1.into();
+        serde_json::json!({ LIGHTING_SERVICE: { "transition_light_state": state } })
+    }
+}
+
+impl SysInfo {
+    fn kind(&self) -> DeviceKind {
+        let mic_type = self.mic_type.as_deref().or(self.type_.as_deref());
+        if mic_type == Some("IOT.SMARTBULB") {
+            DeviceKind::Bulb
+        } else if self.brightness.is_some() {
+            DeviceKind::Dimmer
+        } else {
+            DeviceKind::Plug
+        }
+    }
+
+    fn color_temperature_range(&self) -> (u16, u16) {
+        COLOR_TEMPERATURE_RANGES
+            .iter()
+            .find(|(model, _)| self.model.starts_with(model))
+            .map_or(DEFAULT_COLOR_TEMPERATURE_RANGE, |(_, range)| *range)
+    }
+}
+
+fn parse_response(response: serde_json::Value) -> Result<Response, RequestError> {
+    serde_json::from_value(response).map_err(|e| {
+        RequestError::Failed(super::Error::Communication(format!(
+            "Invalid response: {e}"
+        )))
+    })
+}
+
+/// The first error reported by a module or method of a response
+fn error_code(response: &serde_json::Value) -> Option<(i32, String)> {
+    let modules = response.as_object()?;
+    let methods = modules
+        .values()
+        .filter_map(|m| m.as_object())
+        .flat_map(|m| m.values());
+
+    modules.values().chain(methods).find_map(|result| {
+        let code = result.get("err_code")?.as_i64()?;
+        (code != 0).then(|| {
+            let message = result
+                .get("err_msg")
+                .and_then(|m| m.as_str())
+                .unwrap_or("Request failed");
+            (code as i32, message.to_string())
+        })
+    })
+}
+
+fn hue_from_degrees(degrees: u16) -> u8 {
+    (f64::from(degrees % 360) / 3.6).round() as u8 % 100
+}
+
+fn hue_to_degrees(hue: u8) -> u16 {
+    (f64::from(hue) * 3.6).round() as u16 % 360
+}
+
+#[async_trait]
+impl SmartHomeApi for Kasa {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let Some(address) = self.address(id).await? else {
+            return Ok(LightStatus::Offline);
+        };
+
+        let status = async {
+            if self.kind(address).await? != DeviceKind::Bulb {
+                let sysinfo = self.get_sysinfo(address).await?;
+                return Ok(LightOptions {
+                    switched_on: sysinfo.relay_state == Some(1),
+                    brightness: sysinfo.brightness,
+                    color_temperature: None,
+                    color: None,
+                    effect: None,
+                });
+            }
+
+            let request = serde_json::json!({ LIGHTING_SERVICE: { "get_light_state": {} } });
+            let response = self.request(address, request).await?;
+            let state = parse_response(response)?
+                .lighting_service
+                .and_then(|s| s.get_light_state)
+                .ok_or_else(|| {
+                    RequestError::Failed(super::Error::Communication(
+                        "Missing light state".to_string(),
+                    ))
+                })?;
+
+            tracing::debug!("Got status {state:#?}");
+
+            // Switched off bulbs report the state they turn on to separately
+            let values = state.dft_on_state.as_ref().unwrap_or(&state.values);
+
+            let (min, max) = self.color_temperature_range(address).await?;
+            let color_temperature = values.color_temp.filter(|&ct| ct != 0).map(|ct| {
+                self.requested
+                    .color_temperature(id, |k| k.clamp(min, max) == ct)
+                    .unwrap_or(ct)
+            });
+
+            let color = match (values.hue, values.saturation) {
+                (Some(hue), Some(saturation)) if color_temperature.is_none() => {
+                    let hue = hue_from_degrees(hue);
+                    let (hue, saturation) = self
+                        .requested
+                        .color(id, |rh, rs| rh.abs_diff(hue) <= 1 && rs == saturation)
+                        .unwrap_or((hue, saturation));
+                    Some(super::Color { hue, saturation })
+                }
+                _ => None,
+            };
+
+            Ok(LightOptions {
+                switched_on: state.on_off == 1,
+                brightness: values.brightness,
+                color_temperature,
+                color,
+                effect: None,
+            })
+        }
+        .await;
+
+        match status {
+            Ok(options) => Ok(LightStatus::Online(options)),
+            Err(RequestError::Unreachable) => {
+                tracing::debug!(%address, "Kasa device is unreachable");
+                if Self::is_device_id(id)? {
+                    // The address may have changed
+                    self.discovered
+                        .lock()
+                        .unwrap()
+                        .remove(&id.to_ascii_uppercase());
+                }
+                Ok(LightStatus::Offline)
+            }
+            Err(RequestError::Failed(e)) => Err(e),
+        }
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let state = u8::from(switched_on);
+        self.control(id, "switching", |kind| match kind {
+            DeviceKind::Bulb => Some(Self::transition_light_state(
+                serde_json::json!({ "on_off": state }),
+            )),
+            DeviceKind::Dimmer | DeviceKind::Plug => Some(serde_json::json!({
+                "system": { "set_relay_state": { "state": state } }
+            })),
+        })
+        .await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let brightness = brightness.clamp(0, 100);
+        self.control(id, "brightness", |kind| match kind {
+            DeviceKind::Bulb => Some(Self::transition_light_state(
+                serde_json::json!({ "brightness": brightness }),
+            )),
+            DeviceKind::Dimmer => Some(serde_json::json!({
+                DIMMER_SERVICE: { "set_brightness": { "brightness": brightness } }
+            })),
+            DeviceKind::Plug => None,
+        })
+        .await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let Some(address) = self.address(id).await? else {
+            return Err(super::Error::UnknownDeviceId);
+        };
+        let (min, max) = self.color_temperature_range(address).await?;
+        let color_temp = temp.clamp(min, max);
+
+        self.control(id, "color temperature", |kind| {
+            (kind == DeviceKind::Bulb).then(|| {
+                Self::transition_light_state(serde_json::json!({ "color_temp": color_temp }))
+            })
+        })
+        .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let state = serde_json::json!({
+            "hue": hue_to_degrees(hue),
+            "saturation": saturation.clamp(0, 100),
+            // Switches from color temperature mode to color mode
+            "color_temp": 0,
+        });
+        self.control(id, "color", |kind| {
+            (kind == DeviceKind::Bulb).then(|| Self::transition_light_state(state))
+        })
+        .await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    async fn list_devices(&self) -> super::Result<Vec<DeviceInfo>> {
+        let devices = self.discover().await?;
+        Ok(devices
+            .into_iter()
+            .map(|(address, sysinfo)| DeviceInfo {
+                id: sysinfo.device_id,
+                name: Some(format!("{} ({address})", sysinfo.alias)),
+                model: Some(sysinfo.model),
+            })
+            .collect())
+    }
+}
+
+/// The Kasa local protocol: JSON obfuscated with an autokey XOR cipher. TCP
+/// messages are prefixed with their length as a big endian u32, UDP
+/// messages are not.
+mod protocol {
+    use tokio::io::{AsyncRead, AsyncReadExt};
+
+    use super::MAX_RESPONSE_SIZE;
+
+    const INITIAL_KEY: u8 = 171;
+
+    pub fn encrypt(plain: &[u8]) -> Vec<u8> {
+        let mut key = INITIAL_KEY;
+        plain
+            .iter()
+            .map(|&b| {
+                key ^= b;
+                key
+            })
+            .collect()
+    }
+
+    pub fn decrypt(cipher: &[u8]) -> Vec<u8> {
+        let mut key = INITIAL_KEY;
+        cipher
+            .iter()
+            .map(|&b| {
+                let plain = key ^ b;
+                key = b;
+                plain
+            })
+            .collect()
+    }
+
+    /// Encrypt a message and prefix it with its length
+    pub fn encode_tcp(plain: &[u8]) -> Vec<u8> {
+        let payload = encrypt(plain);
+        let mut packet = (payload.len() as u32).to_be_bytes().to_vec();
+        packet.extend(payload);
+        packet
+    }
+
+    /// Read a length prefixed message and decrypt it
+    pub async fn read_tcp(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Vec<u8>> {
+        let len = reader.read_u32().await? as usize;
+        if len > MAX_RESPONSE_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Response is too large",
+            ));
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+        Ok(decrypt(&payload))
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    pub struct SysInfo {
+        #[serde(rename = "deviceId")]
+        pub device_id: String,
+        #[serde(default)]
+        pub alias: String,
+        #[serde(default)]
+        pub model: String,
+        /// `IOT.SMARTBULB` for bulbs, `IOT.SMARTPLUGSWITCH` for plugs and
+        /// switches
+        pub mic_type: Option<String>,
+        /// Same as `mic_type`, reported instead of it or as well by some
+        /// firmware
+        #[serde(rename = "type")]
+        pub type_: Option<String>,
+        pub relay_state: Option<u8>,
+        /// Only reported by dimmers
+        pub brightness: Option<u8>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct SystemResponse {
+        pub get_sysinfo: Option<SysInfo>,
+    }
+
+    #[derive(Deserialize, Debug, Default)]
+    #[serde(default)]
+    pub struct LightValues {
+        pub hue: Option<u16>,
+        pub saturation: Option<u8>,
+        /// Kelvin, or 0 in color mode
+        pub color_temp: Option<u16>,
+        pub brightness: Option<u8>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct LightState {
+        pub on_off: u8,
+        #[serde(flatten)]
+        pub values: LightValues,
+        pub dft_on_state: Option<LightValues>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct LightingServiceResponse {
+        pub get_light_state: Option<LightState>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Response {
+        pub system: Option<SystemResponse>,
+        #[serde(rename = "smartlife.iot.smartbulb.lightingservice")]
+        pub lighting_service: Option<LightingServiceResponse>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GET_SYSINFO: &[u8] = br#"{"system":{"get_sysinfo":{}}}"#;
+
+    /// `GET_SYSINFO` as sent over TCP
+    const GET_SYSINFO_TCP: [u8; 33] = [
+        0x00, 0x00, 0x00, 0x1d, 0xd0, 0xf2, 0x81, 0xf8, 0x8b, 0xff, 0x9a, 0xf7, 0xd5, 0xef, 0x94,
+        0xb6, 0xd1, 0xb4, 0xc0, 0x9f, 0xec, 0x95, 0xe6, 0x8f, 0xe1, 0x87, 0xe8, 0xca, 0xf0, 0x8b,
+        0xf6, 0x8b, 0xf6,
+    ];
+
+    fn sysinfo(value: serde_json::Value) -> SysInfo {
+        let response: Response =
+            serde_json::from_value(serde_json::json!({ "system": { "get_sysinfo": value } }))
+                .unwrap();
+        response.system.unwrap().get_sysinfo.unwrap()
+    }
+
+    #[test]
+    fn encrypts_messages() {
+        assert_eq!(protocol::encrypt(GET_SYSINFO), &GET_SYSINFO_TCP[4..]);
+        assert_eq!(protocol::decrypt(&GET_SYSINFO_TCP[4..]), GET_SYSINFO);
+        assert_eq!(protocol::encode_tcp(GET_SYSINFO), GET_SYSINFO_TCP);
+
+        let response = br#"{"system":{"get_sysinfo":{"deviceId":"8006","err_code":0}}}"#;
+        assert_eq!(
+            protocol::decrypt(&protocol::encrypt(response)),
+            response.to_vec()
+        );
+        assert!(protocol::encrypt(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_length_prefixed_messages() {
+        let mut reader = &GET_SYSINFO_TCP[..];
+        assert_eq!(protocol::read_tcp(&mut reader).await.unwrap(), GET_SYSINFO);
+
+        // Truncated message
+        let mut reader = &GET_SYSINFO_TCP[..20];
+        assert!(protocol::read_tcp(&mut reader).await.is_err());
+
+        let mut too_large = ((MAX_RESPONSE_SIZE + 1) as u32).to_be_bytes().to_vec();
+        too_large.extend([0; 16]);
+        let error = protocol::read_tcp(&mut &too_large[..]).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parses_bulb_sysinfo() {
+        let bulb = sysinfo(serde_json::json!({
+            "deviceId": "8012A1B2C3D4E5F60718293A4B5C6D7E8F901234",
+            "alias": "Living room",
+            "model": "KL130(EU)",
+            "mic_type": "IOT.SMARTBULB",
+            "is_color": 1,
+            "light_state": { "on_off": 1 },
+            "err_code": 0,
+        }));
+        assert_eq!(bulb.device_id, "8012A1B2C3D4E5F60718293A4B5C6D7E8F901234");
+        assert_eq!(bulb.alias, "Living room");
+        assert_eq!(bulb.kind(), DeviceKind::Bulb);
+
+        // Some firmware reports `type` instead of or as well as `mic_type`
+        let bulb = sysinfo(serde_json::json!({
+            "deviceId": "8012",
+            "type": "IOT.SMARTBULB",
+        }));
+        assert_eq!(bulb.kind(), DeviceKind::Bulb);
+        let bulb = sysinfo(serde_json::json!({
+            "deviceId": "8012",
+            "mic_type": "IOT.SMARTBULB",
+            "type": "IOT.SMARTBULB",
+        }));
+        assert_eq!(bulb.kind(), DeviceKind::Bulb);
+    }
+
+    #[test]
+    fn knows_color_temperature_ranges() {
+        let range = |model: &str| {
+            sysinfo(serde_json::json!({ "deviceId": "8012", "model": model }))
+                .color_temperature_range()
+        };
+        assert_eq!(range("KL130(EU)"), (2500, 9000));
+        assert_eq!(range("LB130(US)"), (2500, 9000));
+        assert_eq!(range("KL120(US)"), (2700, 5000));
+        assert_eq!(range("KL120(EU)"), (2700, 6500));
+        assert_eq!(range("KL125(US)"), (2500, 6500));
+        assert_eq!(range("KL50(US)"), DEFAULT_COLOR_TEMPERATURE_RANGE);
+        assert_eq!(range(""), DEFAULT_COLOR_TEMPERATURE_RANGE);
+    }
+
+    #[test]
+    fn parses_plug_and_dimmer_sysinfo() {
+        let plug = sysinfo(serde_json::json!({
+            "deviceId": "8006A1B2C3D4E5F60718293A4B5C6D7E8F901234",
+            "alias": "Lamp",
+            "model": "HS100(EU)",
+            "type": "IOT.SMARTPLUGSWITCH",
+            "relay_state": 1,
+            "err_code": 0,
+        }));
+        assert_eq!(plug.kind(), DeviceKind::Plug);
+        assert_eq!(plug.relay_state, Some(1));
+        assert_eq!(plug.brightness, None);
+
+        let dimmer = sysinfo(serde_json::json!({
+            "deviceId": "8006A1B2C3D4E5F60718293A4B5C6D7E8F905678",
+            "model": "HS220(US)",
+            "mic_type": "IOT.SMARTPLUGSWITCH",
+            "type": "IOT.SMARTPLUGSWITCH",
+            "relay_state": 0,
+            "brightness": 35,
+        }));
+        assert_eq!(dimmer.kind(), DeviceKind::Dimmer);
+        assert_eq!(dimmer.relay_state, Some(0));
+        assert_eq!(dimmer.brightness, Some(35));
+    }
+
+    #[test]
+    fn parses_light_state() {
+        let response: Response = serde_json::from_value(serde_json::json!({
+            LIGHTING_SERVICE: {
+                "get_light_state": {
+                    "on_off": 0,
+                    "dft_on_state": {
+                        "mode": "normal",
+                        "hue": 120,
+                        "saturation": 80,
+                        "color_temp": 0,
+                        "brightness": 60,
+                    },
+                    "err_code": 0,
+                },
+            },
+        }))
+        .unwrap();
+        let state = response.lighting_service.unwrap().get_light_state.unwrap();
+        assert_eq!(state.on_off, 0);
+        assert_eq!(state.values.brightness, None);
+        let on_state = state.dft_on_state.unwrap();
+        assert_eq!(on_state.hue, Some(120));
+        assert_eq!(on_state.saturation, Some(80));
+        assert_eq!(on_state.color_temp, Some(0));
+        assert_eq!(on_state.brightness, Some(60));
+    }
+
+    #[test]
+    fn finds_error_codes() {
+        assert_eq!(
+            error_code(&serde_json::json!({
+                "system": { "set_relay_state": { "err_code": 0 } }
+            })),
+            None
+        );
+        assert_eq!(
+            error_code(&serde_json::json!({
+                LIGHTING_SERVICE: { "err_code": -1, "err_msg": "module not support" }
+            })),
+            Some((ERROR_MODULE_NOT_SUPPORTED, "module not support".to_string()))
+        );
+        assert_eq!(
+            error_code(&serde_json::json!({
+                "system": { "set_brightness": { "err_code": -2 } }
+            })),
+            Some((ERROR_METHOD_NOT_SUPPORTED, "Request failed".to_string()))
+        );
+    }
+
+    #[test]
+    fn converts_hues() {
+        assert_eq!(hue_to_degrees(0), 0);
+        assert_eq!(hue_to_degrees(50), 180);
+        assert_eq!(hue_to_degrees(100), 0);
+        assert_eq!(hue_from_degrees(359), 0);
+        for hue in 0..100 {
+            assert_eq!(hue_from_degrees(hue_to_degrees(hue)), hue);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+
+use crate::config::{Config, KnxDeviceConfig};
+
+use self::tunnel::{GroupValue, Tunnel};
+
+use super::{color, requested::RequestedValues, LightOptions, LightStatus, SmartHomeApi};
+
+mod tunnel;
+
+pub struct Knx {
+    _config: Arc<Config>,
+    tunnel: Tunnel,
+    devices: HashMap<String, GroupAddresses>,
+    requested: RequestedValues,
+}
+
+/// Group addresses of a light. Values are read from the status addresses,
+/// or from the addresses they are written to if there is no status address.
+struct GroupAddresses {
+    switch: u16,
+    switch_status: Option<u16>,
+    brightness: Option<u16>,
+    brightness_status: Option<u16>,
+    color_temperature: Option<u16>,
+    color_temperature_status: Option<u16>,
+    rgb: Option<u16>,
+    rgb_status: Option<u16>,
+    hue: Option<u16>,
+    hue_status: Option<u16>,
+    saturation: Option<u16>,
+    saturation_status: Option<u16>,
+}
+
+impl GroupAddresses {
+    fn from_config(device: &KnxDeviceConfig) -> super::Result<Self> {
+        let parse_one = |address: &str| {
+            parse_group_address(address).ok_or_else(|| {
+                super::Error::Configuration(format!(
+                    "Invalid KNX group address `{address}` for {}",
+                    device.id
+                ))
+            })
+        };
+        let parse = |address: &Option<String>| address.as_deref().map(parse_one).transpose();
+
+        Ok(Self {
+            switch: parse_one(&device.switch)?,
+            switch_status: parse(&device.switch_status)?,
+            brightness: parse(&device.brightness)?,
+            brightness_status: parse(&device.brightness_status)?,
+            color_temperature: parse(&device.color_temperature)?,
+            color_temperature_status: parse(&device.color_temperature_status)?,
+            rgb: parse(&device.rgb)?,
+            rgb_status: parse(&device.rgb_status)?,
+            hue: parse(&device.hue)?,
+            hue_status: parse(&device.hue_status)?,
+            saturation: parse(&device.saturation)?,
+            saturation_status: parse(&device.saturation_status)?,
+        })
+    }
+}
+
+/// Parse a group address in three-level (`1/2/3`), two-level (`1/515`) or
+/// free (`2563`) notation
+fn parse_group_address(address: &str) -> Option<u16> {
+    let parts = address
+        .split('/')
+        .map(|p| p.trim().parse::<u16>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    match parts[..] {
+        [main, middle, sub] if main < 32 && middle < 8 && sub < 256 => {
+            Some(main << 11 | middle << 8 | sub)
+        }
+        [main, sub] if main < 32 && sub < 2048 => Some(main << 11 | sub),
+        [address] => Some(address),
+        _ => None,
+    }
+}
+
+/// DPT 5.001 percentage, 0-255 on the bus
+fn percent_to_dpt5(percent: u8) -> u8 {
+    (f64::from(percent.min(100)) * 2.55).round() as u8
+}
+
+fn dpt5_to_percent(value: u8) -> u8 {
+    (f64::from(value) / 2.55).round() as u8
+}
+
+impl Knx {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let knx_config = &config.smart_home.knx;
+        let Some(gateway) = &knx_config.gateway else {
+            return Err(super::Error::Configuration(
+                "KNX gateway not configured".to_string(),
+            ));
+        };
+
+        let devices = knx_config
+            .devices
+            .iter()
+            .map(|d| Ok((d.id.clone(), GroupAddresses::from_config(d)?)))
+            .collect::<super::Result<_>>()?;
+
+        let tunnel = Tunnel::new(
+            gateway.clone(),
+            Duration::from_millis(knx_config.timeout_ms),
+        );
+
+        Ok(Self {
+            _config: config,
+            tunnel,
+            devices,
+            requested: Default::default(),
+        })
+    }
+
+    /// Device IDs are the IDs of devices listed in the configuration
+    fn device(&self, id: &str) -> super::Result<&GroupAddresses> {
+        self.devices.get(id).ok_or(super::Error::UnknownDeviceId)
+    }
+
+    /// Read the status of an optional value. Values that are not answered
+    /// are left out.
+    async fn read(
+        &self,
+        address: Option<u16>,
+        status_address: Option<u16>,
+    ) -> super::Result<Option<GroupValue>> {
+        match status_address.or(address) {
+            Some(address) => self.tunnel.read(address).await,
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Knx {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let device = self.device(id)?;
+
+        // A light is offline when its switch status can't be read
+        let switch = match self
+            .tunnel
+            .read(device.switch_status.unwrap_or(device.switch))
+            .await
+        {
+            Ok(Some(switch)) => switch,
+            Ok(None) => return Ok(LightStatus::Offline),
+            Err(super::Error::Communication(e)) => {
+                tracing::debug!(error = e, "KNX gateway is unreachable");
+                return Ok(LightStatus::Offline);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let brightness = self
+            .read(device.brightness, device.brightness_status)
+            .await?
+            .and_then(|v| v.first_octet())
+            .map(dpt5_to_percent);
+
+        let color_temperature = self
+            .read(device.color_temperature, device.color_temperature_status)
+            .await?
+            .and_then(|v| match *v.data() {
+                [hi, lo] => Some(u16::from_be_bytes([hi, lo])),
+                _ => None,
+            });
+
+        let rgb = self
+            .read(device.rgb, device.rgb_status)
+            .await?
+            .and_then(|v| <[u8; 3]>::try_from(v.data()).ok());
+        let color = match rgb {
+            Some(rgb) => self
+                .requested
+                .color(id, |h, s| color::to_rgb8(color::hs_to_rgb(h, s)) == rgb)
+                .or_else(|| {
+                    let (r, g, b) = color::from_rgb8(rgb);
+                    Some(color::rgb_to_hs(r, g, b))
+                }),
+            None => {
+                let hue = self
+                    .read(device.hue, device.hue_status)
+                    .await?
+                    .and_then(|v| v.first_octet());
+                let saturation = self
+                    .read(device.saturation, device.saturation_status)
+                    .await?
+                    .and_then(|v| v.first_octet());
+                // DPT 5.003 angle, 0-255 for 0-360 degrees
+                hue.zip(saturation)
+                    .map(|(h, s)| (dpt5_to_percent(h) % 100, dpt5_to_percent(s)))
+            }
+        };
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: switch.first_octet().is_some_and(|v| v & 0x01 != 0),
+            brightness,
+            color_temperature,
+            color: color.map(|(hue, saturation)| super::Color { hue, saturation }),
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let device = self.device(id)?;
+        self.tunnel
+            .write(device.switch, GroupValue::Small(u8::from(switched_on)))
+            .await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let Some(address) = self.device(id)?.brightness else {
+            return Err(super::Error::UnsupportedCapability("brightness"));
+        };
+        let value = GroupValue::Data(vec![percent_to_dpt5(brightness)]);
+        self.tunnel.write(address, value).await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let Some(address) = self.device(id)?.color_temperature else {
+            return Err(super::Error::UnsupportedCapability("color temperature"));
+        };
+        // DPT 7.600, absolute color temperature in Kelvin
+        let value = GroupValue::Data(temp.to_be_bytes().to_vec());
+        self.tunnel.write(address, value).await
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let device = self.device(id)?;
+        match (device.rgb, device.hue, device.saturation) {
+            (Some(address), _, _) => {
+                // DPT 232.600
+                let rgb = color::to_rgb8(color::hs_to_rgb(hue, saturation));
+                self.tunnel
+                    .write(address, GroupValue::Data(rgb.to_vec()))
+                    .await?;
+            }
+            (None, Some(hue_address), Some(saturation_address)) => {
+                let hue_value = GroupValue::Data(vec![percent_to_dpt5(hue % 100)]);
+                let saturation_value = GroupValue::Data(vec![percent_to_dpt5(saturation)]);
+                self.tunnel.write(hue_address, hue_value).await?;
+                self.tunnel
+                    .write(saturation_address, saturation_value)
+                    .await?;
+            }
+            _ => return Err(super::Error::UnsupportedCapability("color")),
+        }
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_group_addresses() {
+        assert_eq!(parse_group_address("1/2/3"), Some(0x0a03));
+        assert_eq!(parse_group_address("31/7/255"), Some(0xffff));
+        assert_eq!(parse_group_address("1/515"), Some(0x0a03));
+        assert_eq!(parse_group_address("2563"), Some(0x0a03));
+        assert_eq!(parse_group_address(" 1 / 2 / 3 "), Some(0x0a03));
+    }
+
+    #[test]
+    fn rejects_invalid_group_addresses() {
+        for address in [
+            "", "32/0/0", "1/8/0", "1/2/256", "1/2048", "1/2/3/4", "a/b/c",
+        ] {
+            assert_eq!(parse_group_address(address), None, "{address}");
+        }
+    }
+
+    #[test]
+    fn converts_dpt5_percentages() {
+        assert_eq!(percent_to_dpt5(0), 0);
+        assert_eq!(percent_to_dpt5(50), 127);
+        assert_eq!(percent_to_dpt5(100), 255);
+        assert_eq!(percent_to_dpt5(120), 255);
+        for percent in 0..=100 {
+            assert_eq!(dpt5_to_percent(percent_to_dpt5(percent)), percent);
+        }
+    }
+}
@@ -0,0 +1,742 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    time::{sleep_until, timeout, Instant},
+};
+
+use crate::smarthome::{Error, Result};
+
+const DEFAULT_PORT: u16 = 3671;
+
+const HEADER_LEN: usize = 6;
+const PROTOCOL_VERSION: u8 = 0x10;
+
+const CONNECT_REQUEST: u16 = 0x0205;
+const CONNECT_RESPONSE: u16 = 0x0206;
+const CONNECTIONSTATE_REQUEST: u16 = 0x0207;
+const CONNECTIONSTATE_RESPONSE: u16 = 0x0208;
+const DISCONNECT_REQUEST: u16 = 0x0209;
+const DISCONNECT_RESPONSE: u16 = 0x020A;
+const TUNNELLING_REQUEST: u16 = 0x0420;
+const TUNNELLING_ACK: u16 = 0x0421;
+
+/// Tunnel connection on the link layer
+const CRI_TUNNEL_LINK_LAYER: [u8; 4] = [0x04, 0x04, 0x02, 0x00];
+
+const L_DATA_REQ: u8 = 0x11;
+const L_DATA_CON: u8 = 0x2E;
+const L_DATA_IND: u8 = 0x29;
+
+/// Standard frame, no repetition, broadcast, low priority
+const CONTROL_1: u8 = 0xBC;
+/// Group destination address, hop count 6
+const CONTROL_2: u8 = 0xE0;
+
+const APCI_GROUP_VALUE_READ: u16 = 0x000;
+const APCI_GROUP_VALUE_RESPONSE: u16 = 0x040;
+const APCI_GROUP_VALUE_WRITE: u16 = 0x080;
+const APCI_MASK: u16 = 0x3C0;
+
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(3);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Unanswered heartbeats before the connection is considered lost
+const HEARTBEAT_ATTEMPTS: u32 = 3;
+
+/// Data of a group value telegram. Values of up to 6 bits, like DPT 1
+/// switches, are sent in the APCI octet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupValue {
+    Small(u8),
+    Data(Vec<u8>),
+}
+
+impl GroupValue {
+    /// First octet of the value, however it was encoded
+    pub fn first_octet(&self) -> Option<u8> {
+        match self {
+            GroupValue::Small(value) => Some(*value),
+            GroupValue::Data(data) => data.first().copied(),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match self {
+            GroupValue::Small(_) => &[],
+            GroupValue::Data(data) => data,
+        }
+    }
+}
+
+/// KNXnet/IP tunnelling connection to an IP interface or router. The
+/// connection is opened when first needed and again after it is lost.
+pub struct Tunnel {
+    gateway: String,
+    timeout: Duration,
+    connection: tokio::sync::Mutex<Option<Arc<Connection>>>,
+}
+
+impl Tunnel {
+    /// `gateway` is `host` or `host:port`
+    pub fn new(gateway: String, timeout: Duration) -> Self {
+        Self {
+            gateway,
+            timeout,
+            connection: Default::default(),
+        }
+    }
+
+    async fn connection(&self) -> Result<Arc<Connection>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(existing) = connection.as_ref().filter(|c| c.is_alive()) {
+            return Ok(existing.clone());
+        }
+
+        let new = Connection::open(&self.gateway, self.timeout)
+            .await
+            .map_err(|e| {
+                let reason = match e {
+                    Error::Communication(reason) => reason,
+                    e => e.to_string(),
+                };
+                Error::Communication(format!(
+                    "Connecting to KNX gateway {} failed: {reason}",
+                    self.gateway
+                ))
+            })?;
+        tokio::spawn(new.clone().run());
+
+        *connection = Some(new.clone());
+        Ok(new)
+    }
+
+    pub async fn write(&self, address: u16, value: GroupValue) -> Result<()> {
+        let connection = self.connection().await?;
+        connection
+            .send_group_value(address, APCI_GROUP_VALUE_WRITE, &value)
+            .await
+    }
+
+    /// Send a GroupValueRead and wait for the response. Returns `None` if no
+    /// device answered in time.
+    pub async fn read(&self, address: u16) -> Result<Option<GroupValue>> {
+        let connection = self.connection().await?;
+
+        let (sender, receiver) = oneshot::channel();
+        connection
+            .readers
+            .lock()
+            .unwrap()
+            .entry(address)
+            .or_default()
+            .push(sender);
+
+        connection
+            .send_group_value(address, APCI_GROUP_VALUE_READ, &GroupValue::Small(0))
+            .await?;
+
+        match timeout(self.timeout, receiver).await {
+            Ok(Ok(value)) => Ok(Some(value)),
+            _ => {
+                let mut readers = connection.readers.lock().unwrap();
+                if let Some(senders) = readers.get_mut(&address) {
+                    senders.retain(|s| !s.is_closed());
+                    if senders.is_empty() {
+                        readers.remove(&address);
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+struct Connection {
+    socket: UdpSocket,
+    channel: u8,
+    alive: AtomicBool,
+    /// Sequence number of the next tunnelling request. Held while a request
+    /// is waiting for its acknowledgement and confirmation, as only one may
+    /// be outstanding.
+    send_sequence: tokio::sync::Mutex<u8>,
+    ack: Mutex<Option<(u8, oneshot::Sender<u8>)>>,
+    /// Notified with whether the bus confirmed the last sent frame
+    confirmation: Mutex<Option<oneshot::Sender<bool>>>,
+    /// Waiting reads by group address
+    readers: Mutex<HashMap<u16, Vec<oneshot::Sender<GroupValue>>>>,
+}
+
+/// What the receive loop should do after handling a frame
+enum Handled {
+    Continue,
+    ConnectionState(u8),
+    Disconnected,
+}
+
+impl Connection {
+    async fn open(gateway: &str, connect_timeout: Duration) -> Result<Arc<Self>> {
+        let address = if gateway.contains(':') {
+            gateway.to_string()
+        } else {
+            format!("{gateway}:{DEFAULT_PORT}")
+        };
+        let address: SocketAddr = tokio::net::lookup_host(&address)
+            .await?
+            .find(|a| a.is_ipv4())
+            .ok_or_else(|| Error::Communication("No IPv4 address found".to_string()))?;
+
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        socket.connect(address).await?;
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&nat_hpai());
+        body.extend_from_slice(&nat_hpai());
+        body.extend_from_slice(&CRI_TUNNEL_LINK_LAYER);
+        socket.send(&frame(CONNECT_REQUEST, &body)).await?;
+
+        let mut buf = [0; 512];
+        let deadline = Instant::now() + connect_timeout;
+        let (channel, status) = loop {
+            let len = tokio::time::timeout_at(deadline, socket.recv(&mut buf))
+                .await
+                .map_err(|_| Error::Communication("No response".to_string()))??;
+            if let Some((CONNECT_RESPONSE, body)) = parse_frame(&buf[..len]) {
+                if body.len() < 2 {
+                    return Err(Error::Communication("Invalid connect response".to_string()));
+                }
+                break (body[0], body[1]);
+            }
+        };
+        if status != 0 {
+            return Err(Error::Communication(format!(
+                "Connection refused with status {status:#04x}"
+            )));
+        }
+
+        tracing::debug!(%address, channel, "Opened KNX tunnelling connection");
+
+        Ok(Arc::new(Self {
+            socket,
+            channel,
+            alive: AtomicBool::new(true),
+            send_sequence: Default::default(),
+            ack: Default::default(),
+            confirmation: Default::default(),
+            readers: Default::default(),
+        }))
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    /// Receive frames and send heartbeats until the connection is lost
+    async fn run(self: Arc<Self>) {
+        let mut buf = [0; 512];
+        let mut receive_sequence: u8 = 0;
+        let mut next_heartbeat = Instant::now() + HEARTBEAT_INTERVAL;
+        let mut unanswered_heartbeats = 0;
+
+        loop {
+            tokio::select! {
+                res = self.socket.recv(&mut buf) => {
+                    let len = match res {
+                        Ok(len) => len,
+                        Err(e) => {
+                            tracing::warn!(error = %e, "Receiving from KNX gateway failed");
+                            break;
+                        }
+                    };
+                    match self.handle_frame(&buf[..len], &mut receive_sequence).await {
+                        Handled::Continue => {}
+                        Handled::ConnectionState(0) => {
+                            unanswered_heartbeats = 0;
+                            next_heartbeat = Instant::now() + HEARTBEAT_INTERVAL;
+                        }
+                        Handled::ConnectionState(status) => {
+                            tracing::warn!("KNX gateway reported connection state {status:#04x}");
+                            break;
+                        }
+                        Handled::Disconnected => {
+                            tracing::info!("KNX gateway closed the tunnelling connection");
+                            break;
+                        }
+                    }
+                }
+                _ = sleep_until(next_heartbeat) => {
+                    if unanswered_heartbeats == HEARTBEAT_ATTEMPTS {
+                        tracing::warn!("KNX gateway stopped answering heartbeats");
+                        self.send_disconnect().await;
+                        break;
+                    }
+                    unanswered_heartbeats += 1;
+                    let body = [self.channel, 0, 8, 1, 0, 0, 0, 0, 0, 0];
+                    let _ = self.socket.send(&frame(CONNECTIONSTATE_REQUEST, &body)).await;
+                    next_heartbeat = Instant::now() + HEARTBEAT_TIMEOUT;
+                }
+            }
+        }
+
+        self.alive.store(false, Ordering::SeqCst);
+        // Pending reads fail immediately instead of timing out
+        self.readers.lock().unwrap().clear();
+    }
+
+    async fn handle_frame(&self, frame: &[u8], receive_sequence: &mut u8) -> Handled {
+        let Some((service, body)) = parse_frame(frame) else {
+            return Handled::Continue;
+        };
+
+        match service {
+            TUNNELLING_REQUEST if body.len() >= 4 && body[1] == self.channel => {
+                // The connection header length is not trusted
+                let Some(cemi) = body.get(usize::from(body[0])..) else {
+                    return Handled::Continue;
+                };
+                let sequence = body[2];
+                if sequence != *receive_sequence && sequence != receive_sequence.wrapping_sub(1) {
+                    return Handled::Continue;
+                }
+
+                let ack = [4, self.channel, sequence, 0];
+                let _ = self.socket.send(&self::frame(TUNNELLING_ACK, &ack)).await;
+
+                // Repeated requests are only acknowledged again
+                if sequence == *receive_sequence {
+                    *receive_sequence = receive_sequence.wrapping_add(1);
+                    self.handle_cemi(cemi);
+                }
+                Handled::Continue
+            }
+            TUNNELLING_ACK if body.len() >= 4 && body[1] == self.channel => {
+                let mut ack = self.ack.lock().unwrap();
+                if ack
+                    .as_ref()
+                    .is_some_and(|(sequence, _)| *sequence == body[2])
+                {
+                    let (_, sender) = ack.take().unwrap();
+                    let _ = sender.send(body[3]);
+                }
+                Handled::Continue
+            }
+            CONNECTIONSTATE_RESPONSE if body.len() >= 2 && body[0] == self.channel => {
+                Handled::ConnectionState(body[1])
+            }
+            DISCONNECT_REQUEST if !body.is_empty() && body[0] == self.channel => {
+                let _ = self
+                    .socket
+                    .send(&self::frame(DISCONNECT_RESPONSE, &[self.channel, 0]))
+                    .await;
+                Handled::Disconnected
+            }
+            _ => Handled::Continue,
+        }
+    }
+
+    fn handle_cemi(&self, cemi: &[u8]) {
+        let Some(telegram) = parse_cemi(cemi) else {
+            return;
+        };
+
+        match telegram.message_code {
+            L_DATA_CON => {
+                if let Some(sender) = self.confirmation.lock().unwrap().take() {
+                    let _ = sender.send(telegram.confirmed);
+                }
+            }
+            L_DATA_IND
+                if telegram.apci == APCI_GROUP_VALUE_RESPONSE
+                    || telegram.apci == APCI_GROUP_VALUE_WRITE =>
+            {
+                tracing::trace!(
+                    address = telegram.destination,
+                    value = ?telegram.value,
+                    "Received group value"
+                );
+                let readers = self.readers.lock().unwrap().remove(&telegram.destination);
+                for sender in readers.unwrap_or_default() {
+                    let _ = sender.send(telegram.value.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn send_group_value(&self, address: u16, apci: u16, value: &GroupValue) -> Result<()> {
+        let cemi = group_value_cemi(L_DATA_REQ, address, apci, value);
+
+        // Held until the confirmation, so that it can't be mixed up with the
+        // confirmation of another telegram
+        let mut sequence = self.send_sequence.lock().await;
+
+        let (confirmation, confirmed) = oneshot::channel();
+        *self.confirmation.lock().unwrap() = Some(confirmation);
+        self.send_tunnelling_request(&mut sequence, &cemi).await?;
+
+        match timeout(CONFIRMATION_TIMEOUT, confirmed).await {
+            Ok(Ok(true)) => Ok(()),
+            Ok(Ok(false)) => Err(Error::Communication(format!(
+                "KNX bus did not confirm the telegram to {}",
+                format_group_address(address)
+            ))),
+            _ => Err(Error::Communication(
+                "KNX gateway did not confirm the telegram".to_string(),
+            )),
+        }
+    }
+
+    /// Send a request and wait for its acknowledgement, repeating it once.
+    /// The connection is closed if it is not acknowledged.
+    async fn send_tunnelling_request(&self, sequence: &mut u8, cemi: &[u8]) -> Result<()> {
+        let mut body = vec![4, self.channel, *sequence, 0];
+        body.extend_from_slice(cemi);
+        let request = frame(TUNNELLING_REQUEST, &body);
+
+        for _ in 0..2 {
+            let (sender, receiver) = oneshot::channel();
+            *self.ack.lock().unwrap() = Some((*sequence, sender));
+            self.socket.send(&request).await?;
+
+            match timeout(ACK_TIMEOUT, receiver).await {
+                Ok(Ok(0)) => {
+                    *sequence = sequence.wrapping_add(1);
+                    return Ok(());
+                }
+                Ok(Ok(status)) => {
+                    return Err(Error::Communication(format!(
+                        "KNX gateway rejected the request with status {status:#04x}"
+                    )))
+                }
+                _ => continue,
+            }
+        }
+
+        self.send_disconnect().await;
+        self.alive.store(false, Ordering::SeqCst);
+        Err(Error::Communication(
+            "KNX gateway did not acknowledge the request".to_string(),
+        ))
+    }
+
+    async fn send_disconnect(&self) {
+        let body = [self.channel, 0, 8, 1, 0, 0, 0, 0, 0, 0];
+        let _ = self.socket.send(&frame(DISCONNECT_REQUEST, &body)).await;
+    }
+}
+
+/// Host protocol address information of 0.0.0.0:0, which makes the gateway
+/// answer to the address that requests come from
+fn nat_hpai() -> [u8; 8] {
+    [8, 1, 0, 0, 0, 0, 0, 0]
+}
+
+fn frame(service: u16, body: &[u8]) -> Vec<u8> {
+    let len = (HEADER_LEN + body.len()) as u16;
+    let mut frame = vec![HEADER_LEN as u8, PROTOCOL_VERSION];
+    frame.extend_from_slice(&service.to_be_bytes());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+fn parse_frame(frame: &[u8]) -> Option<(u16, &[u8])> {
+    if frame.len() < HEADER_LEN
+        || usize::from(frame[0]) != HEADER_LEN
+        || frame[1] != PROTOCOL_VERSION
+    {
+        return None;
+    }
+    let service = u16::from_be_bytes([frame[2], frame[3]]);
+    let len = usize::from(u16::from_be_bytes([frame[4], frame[5]]));
+    frame.get(HEADER_LEN..len).map(|body| (service, body))
+}
+
+/// cEMI frame of a group value telegram, with the source address left to
+/// the gateway
+fn group_value_cemi(message_code: u8, address: u16, apci: u16, value: &GroupValue) -> Vec<u8> {
+    let mut cemi = vec![message_code, 0, CONTROL_1, CONTROL_2, 0, 0];
+    cemi.extend_from_slice(&address.to_be_bytes());
+    match value {
+        GroupValue::Small(small) => {
+            cemi.push(1);
+            cemi.extend_from_slice(&(apci | u16::from(small & 0x3F)).to_be_bytes());
+        }
+        GroupValue::Data(data) => {
+            cemi.push(1 + data.len() as u8);
+            cemi.extend_from_slice(&apci.to_be_bytes());
+            cemi.extend_from_slice(data);
+        }
+    }
+    cemi
+}
+
+struct Telegram {
+    message_code: u8,
+    /// Whether the frame was sent on the bus, for confirmations
+    confirmed: bool,
+    destination: u16,
+    apci: u16,
+    value: GroupValue,
+}
+
+fn parse_cemi(cemi: &[u8]) -> Option<Telegram> {
+    let message_code = *cemi.first()?;
+    let data = cemi.get(2 + usize::from(*cemi.get(1)?)..)?;
+    let &[control_1, control_2, _, _, dest_hi, dest_lo, len, ref npdu @ ..] = data else {
+        return None;
+    };
+
+    // Only group addressed telegrams are of interest
+    if control_2 & 0x80 == 0 || npdu.len() < 2 || npdu.len() != usize::from(len) + 1 {
+        return None;
+    }
+
+    let tpci_apci = u16::from_be_bytes([npdu[0], npdu[1]]);
+    let value = if len == 1 {
+        GroupValue::Small(npdu[1] & 0x3F)
+    } else {
+        GroupValue::Data(npdu[2..].to_vec())
+    };
+
+    Some(Telegram {
+        message_code,
+        confirmed: control_1 & 0x01 == 0,
+        destination: u16::from_be_bytes([dest_hi, dest_lo]),
+        apci: tpci_apci & APCI_MASK,
+        value,
+    })
+}
+
+/// Group address in three-level notation, e.g. `1/2/3`
+pub fn format_group_address(address: u16) -> String {
+    format!(
+        "{}/{}/{}",
+        address >> 11,
+        (address >> 8) & 0x07,
+        address & 0xFF
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    const CHANNEL: u8 = 7;
+
+    /// KNXnet/IP gateway stand-in that accepts tunnelling connections,
+    /// acknowledges and confirms every telegram, and answers reads of the
+    /// addresses in `responses`. Received cEMI frames are passed on to the
+    /// test.
+    async fn gateway(
+        responses: HashMap<u16, GroupValue>,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let mut sequence: u8 = 0;
+            let mut send_request = |cemi: &[u8]| {
+                let mut body = vec![4, CHANNEL, sequence, 0];
+                body.extend_from_slice(cemi);
+                sequence = sequence.wrapping_add(1);
+                frame(TUNNELLING_REQUEST, &body)
+            };
+
+            loop {
+                let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+                let Some((service, body)) = parse_frame(&buf[..len]) else {
+                    continue;
+                };
+
+                match service {
+                    CONNECT_REQUEST => {
+                        let mut response = vec![CHANNEL, 0];
+                        response.extend_from_slice(&nat_hpai());
+                        response.extend_from_slice(&[4, 4, 0x11, 0x05]);
+                        let response = frame(CONNECT_RESPONSE, &response);
+                        socket.send_to(&response, client).await.unwrap();
+
+                        // Malformed requests must not break the connection
+                        let malformed = frame(TUNNELLING_REQUEST, &[200, CHANNEL, 0, 0]);
+                        socket.send_to(&malformed, client).await.unwrap();
+                    }
+                    TUNNELLING_REQUEST => {
+                        let ack = frame(TUNNELLING_ACK, &[4, CHANNEL, body[2], 0]);
+                        socket.send_to(&ack, client).await.unwrap();
+
+                        let cemi = body[4..].to_vec();
+                        let mut confirmation = cemi.clone();
+                        confirmation[0] = L_DATA_CON;
+                        socket
+                            .send_to(&send_request(&confirmation), client)
+                            .await
+                            .unwrap();
+
+                        let telegram = parse_cemi(&cemi).unwrap();
+                        if telegram.apci == APCI_GROUP_VALUE_READ {
+                            if let Some(value) = responses.get(&telegram.destination) {
+                                let response = group_value_cemi(
+                                    L_DATA_IND,
+                                    telegram.destination,
+                                    APCI_GROUP_VALUE_RESPONSE,
+                                    value,
+                                );
+                                socket
+                                    .send_to(&send_request(&response), client)
+                                    .await
+                                    .unwrap();
+                            }
+                        }
+                        sender.send(cemi).unwrap();
+                    }
+                    // Acknowledgements from the client
+                    _ => {}
+                }
+            }
+        });
+
+        (address, receiver)
+    }
+
+    #[tokio::test]
+    async fn writes_group_values() {
+        let (address, mut received) = gateway(HashMap::new()).await;
+        let tunnel = Tunnel::new(address.to_string(), Duration::from_secs(1));
+
+        tunnel.write(0x0901, GroupValue::Small(1)).await.unwrap();
+        tunnel
+            .write(0x0a02, GroupValue::Data(vec![0x12, 0x34]))
+            .await
+            .unwrap();
+
+        let switch = parse_cemi(&received.recv().await.unwrap()).unwrap();
+        assert_eq!(switch.message_code, L_DATA_REQ);
+        assert_eq!(switch.destination, 0x0901);
+        assert_eq!(switch.apci, APCI_GROUP_VALUE_WRITE);
+        assert_eq!(switch.value, GroupValue::Small(1));
+
+        let data = parse_cemi(&received.recv().await.unwrap()).unwrap();
+        assert_eq!(data.destination, 0x0a02);
+        assert_eq!(data.apci, APCI_GROUP_VALUE_WRITE);
+        assert_eq!(data.value, GroupValue::Data(vec![0x12, 0x34]));
+    }
+
+    #[tokio::test]
+    async fn reads_group_values() {
+        let responses = HashMap::from([
+            (0x0902, GroupValue::Small(1)),
+            (0x0a03, GroupValue::Data(vec![0x80])),
+        ]);
+        let (address, mut received) = gateway(responses).await;
+        let tunnel = Tunnel::new(address.to_string(), Duration::from_millis(500));
+
+        assert_eq!(
+            tunnel.read(0x0902).await.unwrap(),
+            Some(GroupValue::Small(1))
+        );
+        assert_eq!(
+            tunnel.read(0x0a03).await.unwrap(),
+            Some(GroupValue::Data(vec![0x80]))
+        );
+        // No device answers
+        assert_eq!(tunnel.read(0x0b04).await.unwrap(), None);
+
+        let read = parse_cemi(&received.recv().await.unwrap()).unwrap();
+        assert_eq!(read.destination, 0x0902);
+        assert_eq!(read.apci, APCI_GROUP_VALUE_READ);
+    }
+
+    #[tokio::test]
+    async fn fails_without_gateway() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let tunnel = Tunnel::new(address.to_string(), Duration::from_millis(100));
+
+        assert!(matches!(
+            tunnel.read(0x0901).await,
+            Err(Error::Communication(_))
+        ));
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frame = frame(TUNNELLING_ACK, &[4, CHANNEL, 3, 0]);
+        assert_eq!(
+            frame,
+            [0x06, 0x10, 0x04, 0x21, 0x00, 0x0a, 4, CHANNEL, 3, 0]
+        );
+        assert_eq!(
+            parse_frame(&frame),
+            Some((TUNNELLING_ACK, &[4, CHANNEL, 3, 0][..]))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        // Wrong header length, wrong version, short and overlong lengths
+        assert_eq!(parse_frame(&[0x05, 0x10, 0x04, 0x21, 0x00, 0x06]), None);
+        assert_eq!(parse_frame(&[0x06, 0x20, 0x04, 0x21, 0x00, 0x06]), None);
+        assert_eq!(parse_frame(&[0x06, 0x10, 0x04]), None);
+        assert_eq!(parse_frame(&[0x06, 0x10, 0x04, 0x21, 0x00, 0x02]), None);
+        assert_eq!(parse_frame(&[0x06, 0x10, 0x04, 0x21, 0x00, 0x0a, 4]), None);
+    }
+
+    #[test]
+    fn parses_cemi() {
+        // GroupValueResponse with 2 bytes to 1/2/3, with additional info
+        let cemi = [
+            L_DATA_IND, 2, 0xAA, 0xBB, 0xBC, 0xE0, 0x11, 0x05, 0x0a, 0x03, 3, 0x00, 0x40, 0x0c,
+            0x1c,
+        ];
+        let telegram = parse_cemi(&cemi).unwrap();
+        assert_eq!(telegram.message_code, L_DATA_IND);
+        assert_eq!(telegram.destination, 0x0a03);
+        assert_eq!(telegram.apci, APCI_GROUP_VALUE_RESPONSE);
+        assert_eq!(telegram.value, GroupValue::Data(vec![0x0c, 0x1c]));
+
+        let small = group_value_cemi(
+            L_DATA_CON,
+            0x0901,
+            APCI_GROUP_VALUE_WRITE,
+            &GroupValue::Small(1),
+        );
+        let telegram = parse_cemi(&small).unwrap();
+        assert!(telegram.confirmed);
+        assert_eq!(telegram.apci, APCI_GROUP_VALUE_WRITE);
+        assert_eq!(telegram.value, GroupValue::Small(1));
+    }
+
+    #[test]
+    fn rejects_invalid_cemi() {
+        // Truncated, individually addressed and with a wrong length
+        assert!(parse_cemi(&[L_DATA_IND, 0, 0xBC]).is_none());
+        assert!(parse_cemi(&[L_DATA_IND, 9, 0xBC, 0xE0]).is_none());
+        assert!(
+            parse_cemi(&[L_DATA_IND, 0, 0xBC, 0x60, 0, 0, 0x09, 0x01, 1, 0x00, 0x81]).is_none()
+        );
+        assert!(
+            parse_cemi(&[L_DATA_IND, 0, 0xBC, 0xE0, 0, 0, 0x09, 0x01, 2, 0x00, 0x81]).is_none()
+        );
+    }
+
+    #[test]
+    fn formats_group_addresses() {
+        assert_eq!(format_group_address(0x0a03), "1/2/3");
+        assert_eq!(format_group_address(0xffff), "31/7/255");
+    }
+}
@@ -0,0 +1,826 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use protocol::*;
+use tokio::{net::UdpSocket, time::Instant};
+
+use crate::config::Config;
+
+use super::{requested::RequestedValues, LightOptions, LightStatus, SmartHomeApi};
+
+type Mac = [u8; 6];
+
+/// Every request is sent this many times before giving up, as UDP packets
+/// to bulbs get lost quite often
+const ATTEMPTS: u32 = 3;
+
+/// Color temperature range supported by LIFX bulbs
+const KELVIN_RANGE: (u16, u16) = (1500, 9000);
+
+/// Identifies the responses to our requests
+const SOURCE: u32 = 0x4c4f_5052;
+
+pub struct Lifx {
+    _config: Arc<Config>,
+    broadcast_address: SocketAddr,
+    port: u16,
+    timeout: Duration,
+    /// Bulbs configured with a fixed address, which are never rediscovered
+    static_devices: HashSet<Mac>,
+    addresses: Mutex<HashMap<Mac, SocketAddr>>,
+    sequence: AtomicU8,
+    requested: RequestedValues,
+}
+
+impl Lifx {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let lifx_config = &config.smart_home.lifx;
+
+        let broadcast_ip: IpAddr = lifx_config.broadcast_address.parse().map_err(|_| {
+            super::Error::Configuration(format!(
+                "Invalid LIFX broadcast address `{}`",
+                lifx_config.broadcast_address
+            ))
+        })?;
+
+        let mut addresses = HashMap::new();
+        for device in &lifx_config.devices {
+            let mac = Self::parse_device_id(&device.id).map_err(|_| {
+                super::Error::Configuration(format!("Invalid LIFX device ID `{}`", device.id))
+            })?;
+            let address = device
+                .address
+                .parse()
+                .or_else(|_| {
+                    device
+                        .address
+                        .parse::<IpAddr>()
+                        .map(|ip| SocketAddr::new(ip, lifx_config.port))
+                })
+                .map_err(|_| {
+                    super::Error::Configuration(format!(
+                        "Invalid LIFX device address `{}`",
+                        device.address
+                    ))
+                })?;
+            addresses.insert(mac, address);
+        }
+
+        Ok(Self {
+            broadcast_address: SocketAddr::new(broadcast_ip, lifx_config.port),
+            port: lifx_config.port,
+            timeout: Duration::from_millis(lifx_config.timeout_ms),
+            static_devices: addresses.keys().copied().collect(),
+            addresses: Mutex::new(addresses),
+            sequence: AtomicU8::new(0),
+            requested: Default::default(),
+            _config: config,
+        })
+    }
+
+    /// Device IDs are bulb MAC addresses, e.g. `d0:73:d5:01:02:03`
+    fn parse_device_id(id: &str) -> super::Result<Mac> {
+        let invalid = || super::Error::InvalidId(id.to_string());
+
+        let parts: Vec<_> = id.split([':', '-']).collect();
+        if parts.len() != 6 {
+            return Err(invalid());
+        }
+
+        let mut mac = [0; 6];
+        for (byte, part) in mac.iter_mut().zip(parts) {
+            if part.len() != 2 {
+                return Err(invalid());
+            }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        Ok(mac)
+    }
+
+    /// Find bulbs on the network with a broadcast GetService message
+    async fn discover(&self) -> super::Result<()> {
+        tracing::debug!("Discovering LIFX bulbs");
+
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        socket.set_broadcast(true)?;
+
+        let header = Header {
+            target: [0; 6],
+            tagged: true,
+            ack_required: false,
+            res_required: true,
+            source: SOURCE,
+            sequence: self.next_sequence(),
+            message_type: GET_SERVICE,
+        };
+        socket
+            .send_to(&encode(&header, &[]), self.broadcast_address)
+            .await?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0; 1024];
+        while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, from) = res?;
+            let Some((header, payload)) = decode(&buf[..len]) else {
+                continue;
+            };
+            if header.message_type != STATE_SERVICE || header.source != SOURCE {
+                continue;
+            }
+            let Some((SERVICE_UDP, port)) = decode_state_service(payload) else {
+                continue;
+            };
+
+            tracing::debug!(mac = ?header.target, address = %from.ip(), "Discovered LIFX bulb");
+            let port = u16::try_from(port).unwrap_or(self.port);
+            self.addresses
+                .lock()
+                .unwrap()
+                .insert(header.target, SocketAddr::new(from.ip(), port));
+        }
+
+        Ok(())
+    }
+
+    async fn address(&self, mac: &Mac) -> super::Result<Option<SocketAddr>> {
+        if let Some(address) = self.addresses.lock().unwrap().get(mac) {
+            return Ok(Some(*address));
+        }
+
+        self.discover().await?;
+        Ok(self.addresses.lock().unwrap().get(mac).copied())
+    }
+
+    /// Forget the address of a bulb that stopped answering, in case it has
+    /// changed
+    fn forget(&self, mac: &Mac) {
+        if !self.static_devices.contains(mac) {
+            self.addresses.lock().unwrap().remove(mac);
+        }
+    }
+
+    fn next_sequence(&self) -> u8 {
+        self.sequence.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Send a message and wait for a response of the given type. Returns
+    /// `None` if the bulb does not answer.
+    async fn send(
+        &self,
+        mac: &Mac,
+        message_type: u16,
+        payload: &[u8],
+        response_type: u16,
+    ) -> super::Result<Option<Vec<u8>>> {
+        let Some(address) = self.address(mac).await? else {
+            return Ok(None);
+        };
+
+        let sequence = self.next_sequence();
+        let header = Header {
+            target: *mac,
+            tagged: false,
+            ack_required: response_type == ACKNOWLEDGEMENT,
+            res_required: response_type != ACKNOWLEDGEMENT,
+            source: SOURCE,
+            sequence,
+            message_type,
+        };
+        let packet = encode(&header, payload);
+
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        let mut buf = [0; 1024];
+
+        for _ in 0..ATTEMPTS {
+            socket.send_to(&packet, address).await?;
+
+            let deadline = Instant::now() + self.timeout / ATTEMPTS;
+            while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+            {
+                let (len, _) = res?;
+                let Some((header, payload)) = decode(&buf[..len]) else {
+                    continue;
+                };
+                if header.source == SOURCE
+                    && header.sequence == sequence
+                    && header.message_type == response_type
+                {
+                    return Ok(Some(payload.to_vec()));
+                }
+            }
+        }
+
+        self.forget(mac);
+        Ok(None)
+    }
+
+    async fn get_state(&self, mac: &Mac) -> super::Result<Option<LightState>> {
+        let Some(payload) = self.send(mac, LIGHT_GET, &[], LIGHT_STATE).await? else {
+            return Ok(None);
+        };
+
+        LightState::decode(&payload)
+            .map(Some)
+            .ok_or_else(|| super::Error::Communication("Invalid LightState message".to_string()))
+    }
+
+    async fn send_acknowledged(
+        &self,
+        mac: &Mac,
+        message_type: u16,
+        payload: &[u8],
+    ) -> super::Result<()> {
+        self.send(mac, message_type, payload, ACKNOWLEDGEMENT)
+            .await?
+            .ok_or_else(|| super::Error::Communication("Bulb did not respond".to_string()))?;
+        Ok(())
+    }
+
+    /// Change the color of a bulb. SetColor always sets all of hue,
+    /// saturation, brightness and Kelvin, so the current color is read first.
+    async fn update_color(&self, id: &str, update: impl FnOnce(&mut Hsbk)) -> super::Result<()> {
+        let mac = Self::parse_device_id(id)?;
+        let Some(state) = self.get_state(&mac).await? else {
+            return Err(super::Error::Communication(
+                "Bulb did not respond".to_string(),
+            ));
+        };
+
+        let mut color = state.color;
+        update(&mut color);
+
+        tracing::debug!(device_id = id, ?color, "Setting color");
+        self.send_acknowledged(&mac, LIGHT_SET_COLOR, &set_color_payload(&color))
+            .await
+    }
+}
+
+fn percent_to_u16(value: u8) -> u16 {
+    (f64::from(value.min(100)) * f64::from(u16::MAX) / 100.0).round() as u16
+}
+
+fn u16_to_percent(value: u16) -> u8 {
+    (f64::from(value) * 100.0 / f64::from(u16::MAX)).round() as u8
+}
+
+#[async_trait]
+impl SmartHomeApi for Lifx {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        let mac = Self::parse_device_id(id)?;
+
+        tracing::debug!("Getting status for device {id}");
+
+        let Some(state) = self.get_state(&mac).await? else {
+            return Ok(LightStatus::Offline);
+        };
+
+        tracing::debug!("Got status {state:#?}");
+
+        let hsbk = state.color;
+        // A color without saturation looks white to the bulb, so look for a
+        // requested color first
+        let requested_color = self.requested.color(id, |hue, saturation| {
+            percent_to_u16(hue % 100) == hsbk.hue && percent_to_u16(saturation) == hsbk.saturation
+        });
+        let (color_temperature, color) = if let Some((hue, saturation)) = requested_color {
+            (None, Some(super::Color { hue, saturation }))
+        } else if hsbk.saturation == 0 {
+            let kelvin = self
+                .requested
+                .color_temperature(id, |k| {
+                    k.clamp(KELVIN_RANGE.0, KELVIN_RANGE.1) == hsbk.kelvin
+                })
+                .unwrap_or(hsbk.kelvin);
+            (Some(kelvin), None)
+        } else {
+            let hue = u16_to_percent(hsbk.hue) % 100;
+            let saturation = u16_to_percent(hsbk.saturation);
+            (None, Some(super::Color { hue, saturation }))
+        };
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: state.power > 0,
+            brightness: Some(u16_to_percent(hsbk.brightness)),
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let mac = Self::parse_device_id(id)?;
+        self.send_acknowledged(&mac, SET_POWER, &set_power_payload(switched_on))
+            .await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        self.update_color(id, |c| c.brightness = percent_to_u16(brightness))
+            .await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        self.update_color(id, |c| {
+            c.saturation = 0;
+            c.kelvin = temp.clamp(KELVIN_RANGE.0, KELVIN_RANGE.1);
+        })
+        .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        self.update_color(id, |c| {
+            c.hue = percent_to_u16(hue % 100);
+            c.saturation = percent_to_u16(saturation);
+        })
+        .await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+}
+
+/// The LIFX LAN binary protocol. All numbers are little endian.
+mod protocol {
+    pub const GET_SERVICE: u16 = 2;
+    pub const STATE_SERVICE: u16 = 3;
+    pub const SET_POWER: u16 = 21;
+    pub const ACKNOWLEDGEMENT: u16 = 45;
+    pub const LIGHT_GET: u16 = 101;
+    pub const LIGHT_SET_COLOR: u16 = 102;
+    pub const LIGHT_STATE: u16 = 107;
+
+    pub const SERVICE_UDP: u8 = 1;
+
+    const HEADER_SIZE: usize = 36;
+    const PROTOCOL: u16 = 1024;
+    const ADDRESSABLE: u16 = 1 << 12;
+    const TAGGED: u16 = 1 << 13;
+
+    #[derive(Debug)]
+    pub struct Header {
+        pub target: [u8; 6],
+        pub tagged: bool,
+        pub ack_required: bool,
+        pub res_required: bool,
+        pub source: u32,
+        pub sequence: u8,
+        pub message_type: u16,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct Hsbk {
+        pub hue: u16,
+        pub saturation: u16,
+        pub brightness: u16,
+        pub kelvin: u16,
+    }
+
+    #[derive(Debug)]
+    pub struct LightState {
+        pub color: Hsbk,
+        pub power: u16,
+    }
+
+    pub fn encode(header: &Header, payload: &[u8]) -> Vec<u8> {
+        let size = (HEADER_SIZE + payload.len()) as u16;
+        let mut protocol = PROTOCOL | ADDRESSABLE;
+        if header.tagged {
+            protocol |= TAGGED;
+        }
+        let flags = u8::from(header.res_required) | u8::from(header.ack_required) << 1;
+
+        let mut packet = Vec::with_capacity(size.into());
+        // Frame
+        packet.extend(size.to_le_bytes());
+        packet.extend(protocol.to_le_bytes());
+        packet.extend(header.source.to_le_bytes());
+        // Frame address
+        packet.extend(header.target);
+        packet.extend([0; 2]);
+        packet.extend([0; 6]);
+        packet.push(flags);
+        packet.push(header.sequence);
+        // Protocol header
+        packet.extend([0; 8]);
+        packet.extend(header.message_type.to_le_bytes());
+        packet.extend([0; 2]);
+
+        packet.extend(payload);
+        packet
+    }
+
+    pub fn decode(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let size = u16_at(packet, 0)? as usize;
+        let protocol = u16_at(packet, 2)?;
+        if protocol & 0x0fff != PROTOCOL || size > packet.len() || size < HEADER_SIZE {
+            return None;
+        }
+
+        let header = Header {
+            target: packet[8..14].try_into().ok()?,
+            tagged: protocol & TAGGED != 0,
+            res_required: packet[22] & 1 != 0,
+            ack_required: packet[22] & 2 != 0,
+            source: u32::from_le_bytes(packet[4..8].try_into().ok()?),
+            sequence: packet[23],
+            message_type: u16_at(packet, 32)?,
+        };
+        Some((header, &packet[HEADER_SIZE..size]))
+    }
+
+    /// Returns the service type and port
+    pub fn decode_state_service(payload: &[u8]) -> Option<(u8, u32)> {
+        let service = *payload.first()?;
+        let port = u32::from_le_bytes(payload.get(1..5)?.try_into().ok()?);
+        Some((service, port))
+    }
+
+    impl LightState {
+        pub fn decode(payload: &[u8]) -> Option<Self> {
+            Some(Self {
+                color: Hsbk {
+                    hue: u16_at(payload, 0)?,
+                    saturation: u16_at(payload, 2)?,
+                    brightness: u16_at(payload, 4)?,
+                    kelvin: u16_at(payload, 6)?,
+                },
+                power: u16_at(payload, 10)?,
+            })
+        }
+    }
+
+    pub fn set_color_payload(color: &Hsbk) -> Vec<u8> {
+        let mut payload = vec![0];
+        payload.extend(color.hue.to_le_bytes());
+        payload.extend(color.saturation.to_le_bytes());
+        payload.extend(color.brightness.to_le_bytes());
+        payload.extend(color.kelvin.to_le_bytes());
+        // Transition duration in milliseconds
+        payload.extend(0u32.to_le_bytes());
+        payload
+    }
+
+    pub fn set_power_payload(switched_on: bool) -> Vec<u8> {
+        let level: u16 = if switched_on { u16::MAX } else { 0 };
+        level.to_le_bytes().to_vec()
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+        Some(u16::from_le_bytes(
+            bytes.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::test_config;
+
+    use super::*;
+
+    const MAC: Mac = [0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03];
+
+    fn state_payload(state: &LightState) -> Vec<u8> {
+        let mut payload = set_color_payload(&state.color)[1..9].to_vec();
+        payload.extend([0; 2]);
+        payload.extend(state.power.to_le_bytes());
+        // Label and reserved bytes
+        payload.extend([0; 40]);
+        payload
+    }
+
+    /// LIFX bulb stand-in that answers discovery, reports its state and
+    /// acknowledges SetColor and SetPower
+    async fn bulb(state: LightState) -> (SocketAddr, Arc<Mutex<LightState>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(state));
+
+        let bulb_state = state.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let Some((request, payload)) = decode(&buf[..len]) else {
+                    continue;
+                };
+                if !request.tagged && request.target != MAC {
+                    continue;
+                }
+
+                let (message_type, response) = match request.message_type {
+                    GET_SERVICE => {
+                        let mut response = vec![SERVICE_UDP];
+                        response.extend(u32::from(address.port()).to_le_bytes());
+                        (STATE_SERVICE, response)
+                    }
+                    LIGHT_GET => (LIGHT_STATE, state_payload(&bulb_state.lock().unwrap())),
+                    LIGHT_SET_COLOR => {
+                        let value =
+                            |offset| u16::from_le_bytes([payload[offset], payload[offset + 1]]);
+                        bulb_state.lock().unwrap().color = Hsbk {
+                            hue: value(1),
+                            saturation: value(3),
+                            brightness: value(5),
+                            kelvin: value(7),
+                        };
+                        (ACKNOWLEDGEMENT, Vec::new())
+                    }
+                    SET_POWER => {
+                        bulb_state.lock().unwrap().power =
+                            u16::from_le_bytes([payload[0], payload[1]]);
+                        (ACKNOWLEDGEMENT, Vec::new())
+                    }
+                    _ => continue,
+                };
+
+                let header = Header {
+                    target: MAC,
+                    tagged: false,
+                    ack_required: false,
+                    res_required: false,
+                    source: request.source,
+                    sequence: request.sequence,
+                    message_type,
+                };
+                socket
+                    .send_to(&encode(&header, &response), from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (address, state)
+    }
+
+    fn lifx(bulb: SocketAddr) -> Lifx {
+        let config = test_config(serde_json::json!({
+            "platform": "Lifx",
+            "lifx": {
+                "broadcast_address": bulb.ip().to_string(),
+                "port": bulb.port(),
+                "timeout_ms": 300,
+            },
+        }));
+        Lifx::new(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn discovers_and_controls_bulbs() {
+        let (address, state) = bulb(LightState {
+            color: Hsbk {
+                hue: 0,
+                saturation: 0,
+                brightness: u16::MAX,
+                kelvin: 2700,
+            },
+            power: 0,
+        })
+        .await;
+        let lifx = lifx(address);
+        let id = "d0:73:d5:01:02:03";
+
+        let LightStatus::Online(options) = lifx.get_light_status(id).await.unwrap() else {
+            panic!("Bulb is offline");
+        };
+        assert!(!options.switched_on);
+        assert_eq!(options.brightness, Some(100));
+        assert_eq!(options.color_temperature, Some(2700));
+        assert!(options.color.is_none());
+
+        lifx.set_color(id, 50, 80).await.unwrap();
+        lifx.set_brightness(id, 40).await.unwrap();
+        lifx.set_switched_on(id, true).await.unwrap();
+
+        let LightStatus::Online(options) = lifx.get_light_status(id).await.unwrap() else {
+            panic!("Bulb is offline");
+        };
+        assert!(options.switched_on);
+        assert_eq!(options.brightness, Some(40));
+        assert_eq!(options.color_temperature, None);
+        let color = options.color.unwrap();
+        assert_eq!((color.hue, color.saturation), (50, 80));
+
+        lifx.set_color_temperature(id, 12000).await.unwrap();
+        let color = state.lock().unwrap().color;
+        assert_eq!(color.saturation, 0);
+        assert_eq!(color.kelvin, KELVIN_RANGE.1);
+        assert_eq!(color.brightness, percent_to_u16(40));
+
+        // The out of range request is reported until the bulb is changed
+        let LightStatus::Online(options) = lifx.get_light_status(id).await.unwrap() else {
+            panic!("Bulb is offline");
+        };
+        assert_eq!(options.color_temperature, Some(12000));
+
+        state.lock().unwrap().color.kelvin = 4000;
+        let LightStatus::Online(options) = lifx.get_light_status(id).await.unwrap() else {
+            panic!("Bulb is offline");
+        };
+        assert_eq!(options.color_temperature, Some(4000));
+    }
+
+    #[tokio::test]
+    async fn reports_unsaturated_colors_as_requested() {
+        let (address, state) = bulb(LightState {
+            color: Hsbk {
+                hue: 0,
+                saturation: 0,
+                brightness: u16::MAX,
+                kelvin: 2700,
+            },
+            power: 1,
+        })
+        .await;
+        let lifx = lifx(address);
+        let id = "d0:73:d5:01:02:03";
+
+        lifx.set_color(id, 30, 0).await.unwrap();
+        let LightStatus::Online(options) = lifx.get_light_status(id).await.unwrap() else {
+            panic!("Bulb is offline");
+        };
+        assert_eq!(options.color_temperature, None);
+        let color = options.color.unwrap();
+        assert_eq!((color.hue, color.saturation), (30, 0));
+
+        // Once changed elsewhere, the white bulb is reported by its kelvin
+        state.lock().unwrap().color.hue = 0;
+        let LightStatus::Online(options) = lifx.get_light_status(id).await.unwrap() else {
+            panic!("Bulb is offline");
+        };
+        assert_eq!(options.color_temperature, Some(2700));
+        assert!(options.color.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_bulbs_offline() {
+        let (address, _) = bulb(LightState {
+            color: Hsbk {
+                hue: 0,
+                saturation: 0,
+                brightness: 0,
+                kelvin: 2700,
+            },
+            power: 0,
+        })
+        .await;
+        let lifx = lifx(address);
+
+        assert!(matches!(
+            lifx.get_light_status("d0:73:d5:0a:0b:0c").await.unwrap(),
+            LightStatus::Offline
+        ));
+        assert!(lifx
+            .set_switched_on("d0:73:d5:0a:0b:0c", true)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn parses_device_ids() {
+        assert_eq!(Lifx::parse_device_id("d0:73:d5:01:02:03").unwrap(), MAC);
+        assert_eq!(Lifx::parse_device_id("D0-73-D5-01-02-03").unwrap(), MAC);
+        for id in [
+            "",
+            "d0:73:d5:01:02",
+            "d0:73:d5:01:02:03:04",
+            "d0:73:d5:01:2:003",
+            "g0:73:d5:01:02:03",
+        ] {
+            assert!(Lifx::parse_device_id(id).is_err(), "{id}");
+        }
+    }
+
+    #[test]
+    fn encodes_headers() {
+        let header = Header {
+            target: MAC,
+            tagged: false,
+            ack_required: true,
+            res_required: false,
+            source: SOURCE,
+            sequence: 7,
+            message_type: SET_POWER,
+        };
+        let packet = encode(&header, &set_power_payload(true));
+
+        assert_eq!(packet.len(), 38);
+        assert_eq!(&packet[0..4], &[38, 0, 0x00, 0x14]);
+        assert_eq!(&packet[8..14], &MAC);
+        assert_eq!(packet[22], 0b10);
+        assert_eq!(packet[23], 7);
+        assert_eq!(&packet[32..34], &[21, 0]);
+        assert_eq!(&packet[36..], &[0xff, 0xff]);
+
+        let (decoded, payload) = decode(&packet).unwrap();
+        assert_eq!(decoded.target, MAC);
+        assert!(!decoded.tagged);
+        assert!(decoded.ack_required);
+        assert!(!decoded.res_required);
+        assert_eq!(decoded.source, SOURCE);
+        assert_eq!(decoded.sequence, 7);
+        assert_eq!(decoded.message_type, SET_POWER);
+        assert_eq!(payload, &[0xff, 0xff]);
+    }
+
+    #[test]
+    fn encodes_tagged_headers() {
+        let header = Header {
+            target: [0; 6],
+            tagged: true,
+            ack_required: false,
+            res_required: true,
+            source: SOURCE,
+            sequence: 0,
+            message_type: GET_SERVICE,
+        };
+        let packet = encode(&header, &[]);
+
+        assert_eq!(&packet[0..4], &[36, 0, 0x00, 0x34]);
+        let (decoded, payload) = decode(&packet).unwrap();
+        assert!(decoded.tagged);
+        assert!(decoded.res_required);
+        assert!(payload.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        let header = Header {
+            target: MAC,
+            tagged: false,
+            ack_required: false,
+            res_required: true,
+            source: SOURCE,
+            sequence: 0,
+            message_type: LIGHT_GET,
+        };
+        let packet = encode(&header, &[1, 2]);
+
+        // Too short for a header
+        assert!(decode(&packet[..35]).is_none());
+        // Truncated payload
+        assert!(decode(&packet[..37]).is_none());
+
+        let mut wrong_protocol = packet.clone();
+        wrong_protocol[2] = 0x01;
+        assert!(decode(&wrong_protocol).is_none());
+
+        let mut too_small = packet;
+        too_small[0] = 30;
+        assert!(decode(&too_small).is_none());
+    }
+
+    #[test]
+    fn decodes_light_state() {
+        let state = LightState {
+            color: Hsbk {
+                hue: 0x1234,
+                saturation: 0xffff,
+                brightness: 0x8000,
+                kelvin: 3500,
+            },
+            power: 0xffff,
+        };
+        let decoded = LightState::decode(&state_payload(&state)).unwrap();
+        assert_eq!(decoded.color.hue, 0x1234);
+        assert_eq!(decoded.color.saturation, 0xffff);
+        assert_eq!(decoded.color.brightness, 0x8000);
+        assert_eq!(decoded.color.kelvin, 3500);
+        assert_eq!(decoded.power, 0xffff);
+
+        assert!(LightState::decode(&[0; 11]).is_none());
+    }
+
+    #[test]
+    fn decodes_state_service() {
+        assert_eq!(
+            decode_state_service(&[SERVICE_UDP, 0x7c, 0xdd, 0, 0]),
+            Some((SERVICE_UDP, 56700))
+        );
+        assert_eq!(decode_state_service(&[SERVICE_UDP, 0x7c]), None);
+    }
+
+    #[test]
+    fn converts_percentages() {
+        assert_eq!(percent_to_u16(0), 0);
+        assert_eq!(percent_to_u16(100), u16::MAX);
+        assert_eq!(percent_to_u16(150), u16::MAX);
+        for percent in 0..=100 {
+            assert_eq!(u16_to_percent(percent_to_u16(percent)), percent);
+        }
+    }
+}
@@ -1,12 +1,47 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
+use axum::Router;
+use futures::stream::BoxStream;
 
 use crate::config::{Config, SmartHomePlatform};
 
-use self::smartthings::SmartThings;
+use self::{
+    deconz::Deconz, dirigera::Dirigera, dmx::Dmx, elgato::Elgato, esphome::Esphome, govee::Govee,
+    home_assistant::HomeAssistant, http::Http, hubitat::Hubitat, hue::Hue, kasa::Kasa, knx::Knx,
+    lifx::Lifx, nanoleaf::Nanoleaf, openhab::Openhab, plugin::Plugin, shelly::Shelly,
+    simulated::Simulated, smartthings::SmartThings, tasmota::Tasmota, tuya::Tuya, wled::Wled,
+    yeelight::Yeelight, zigbee2mqtt::Zigbee2Mqtt,
+};
 
+mod color;
+mod deconz;
+mod dirigera;
+mod dmx;
+mod elgato;
+mod esphome;
+mod events;
+mod govee;
+mod home_assistant;
+mod http;
+mod hubitat;
+mod hue;
+mod kasa;
+mod knx;
+mod lifx;
+mod mqtt;
+mod nanoleaf;
+mod openhab;
+mod plugin;
+mod requested;
+mod shelly;
+mod simulated;
 mod smartthings;
+mod tasmota;
+mod tuya;
+mod wled;
+mod yeelight;
+mod zigbee2mqtt;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -21,6 +56,23 @@ pub enum Error {
 
     #[error("Device was not found")]
     UnknownDeviceId,
+
+    #[error("Device communication failed: {0}")]
+    Communication(String),
+
+    #[error("Device does not support {0}")]
+    UnsupportedCapability(&'static str),
+
+    /// The device can't take more commands for now, e.g. because of a
+    /// command quota. Not a failure, the Light is reconciled again later.
+    #[error("Device is busy for {0:?}")]
+    Busy(Duration),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Communication(value.to_string())
+    }
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -43,6 +95,28 @@ pub struct LightOptions {
     pub brightness: Option<u8>,
     pub color_temperature: Option<u16>,
     pub color: Option<Color>,
+    /// Name of the effect the light is showing instead of a plain color
+    pub effect: Option<String>,
+}
+
+/// Notification that a device may no longer be in its reconciled state
+#[derive(Clone, Debug)]
+pub enum DeviceEvent {
+    /// The state of the device with this ID changed
+    Changed(String),
+    /// Events may have been missed, so all devices should be checked
+    Resync,
+}
+
+pub type DeviceEventStream = BoxStream<'static, DeviceEvent>;
+
+/// A device found by a backend that can discover devices
+#[derive(Debug)]
+pub struct DeviceInfo {
+    /// ID to use in Light resources
+    pub id: String,
+    pub name: Option<String>,
+    pub model: Option<String>,
 }
 
 #[async_trait]
@@ -56,6 +130,28 @@ pub trait SmartHomeApi: Send + Sync {
     async fn set_color_temperature(&self, id: &str, color_temp: u16) -> Result<()>;
 
     async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> Result<()>;
+
+    /// Device state changes pushed by the platform, if the backend supports it
+    fn device_events(&self) -> Option<DeviceEventStream> {
+        None
+    }
+
+    /// Whether device events are currently being received. While they are not,
+    /// the controller polls the devices instead.
+    fn events_connected(&self) -> bool {
+        false
+    }
+
+    /// Routes to serve on the health check server, e.g. to receive events
+    /// that the platform posts to the operator
+    fn webhook_routes(&self) -> Option<Router> {
+        None
+    }
+
+    /// Devices that can be controlled, if the backend can discover them
+    async fn list_devices(&self) -> Result<Vec<DeviceInfo>> {
+        Err(Error::UnsupportedCapability("device listing"))
+    }
 }
 
 pub fn get_smart_home_api(config: Arc<Config>) -> Result<Arc<dyn SmartHomeApi + Send + Sync>> {
@@ -65,5 +161,232 @@ pub fn get_smart_home_api(config: Arc<Config>) -> Result<Arc<dyn SmartHomeApi +
             let arc_smartthings = thing_res.map(Arc::new)?;
             Ok(arc_smartthings)
         }
+        SmartHomePlatform::Hue => {
+            let hue = Hue::new(config)?;
+            Ok(Arc::new(hue))
+        }
+        SmartHomePlatform::HomeAssistant => {
+            let home_assistant = HomeAssistant::new(config)?;
+            Ok(Arc::new(home_assistant))
+        }
+        SmartHomePlatform::Zigbee2Mqtt => {
+            let zigbee2mqtt = Zigbee2Mqtt::new(config)?;
+            Ok(Arc::new(zigbee2mqtt))
+        }
+        SmartHomePlatform::Lifx => {
+            let lifx = Lifx::new(config)?;
+            Ok(Arc::new(lifx))
+        }
+        SmartHomePlatform::Wled => {
+            let wled = Wled::new(config)?;
+            Ok(Arc::new(wled))
+        }
+        SmartHomePlatform::Shelly => {
+            let shelly = Shelly::new(config)?;
+            Ok(Arc::new(shelly))
+        }
+        SmartHomePlatform::Tasmota => {
+            let tasmota = Tasmota::new(config)?;
+            Ok(Arc::new(tasmota))
+        }
+        SmartHomePlatform::Deconz => {
+            let deconz = Deconz::new(config)?;
+            Ok(Arc::new(deconz))
+        }
+        SmartHomePlatform::Yeelight => {
+            let yeelight = Yeelight::new(config)?;
+            Ok(Arc::new(yeelight))
+        }
+        SmartHomePlatform::Kasa => {
+            let kasa = Kasa::new(config)?;
+            Ok(Arc::new(kasa))
+        }
+        SmartHomePlatform::Nanoleaf => {
+            let nanoleaf = Nanoleaf::new(config)?;
+            Ok(Arc::new(nanoleaf))
+        }
+        SmartHomePlatform::Esphome => {
+            let esphome = Esphome::new(config)?;
+            Ok(Arc::new(esphome))
+        }
+        SmartHomePlatform::Dmx => {
+            let dmx = Dmx::new(config)?;
+            Ok(Arc::new(dmx))
+        }
+        SmartHomePlatform::Openhab => {
+            let openhab = Openhab::new(config)?;
+            Ok(Arc::new(openhab))
+        }
+        SmartHomePlatform::Govee => {
+            let govee = Govee::new(config)?;
+            Ok(Arc::new(govee))
+        }
+        SmartHomePlatform::Dirigera => {
+            let dirigera = Dirigera::new(config)?;
+            Ok(Arc::new(dirigera))
+        }
+        SmartHomePlatform::Hubitat => {
+            let hubitat = Hubitat::new(config)?;
+            Ok(Arc::new(hubitat))
+        }
+        SmartHomePlatform::Knx => {
+            let knx = Knx::new(config)?;
+            Ok(Arc::new(knx))
+        }
+        SmartHomePlatform::Elgato => {
+            let elgato = Elgato::new(config)?;
+            Ok(Arc::new(elgato))
+        }
+        SmartHomePlatform::Http => {
+            let http = Http::new(config)?;
+            Ok(Arc::new(http))
+        }
+        SmartHomePlatform::Plugin => {
+            let plugin = Plugin::new(config)?;
+            Ok(Arc::new(plugin))
+        }
+        SmartHomePlatform::Simulated => {
+            let simulated = Simulated::new(config)?;
+            Ok(Arc::new(simulated))
+        }
+        SmartHomePlatform::Tuya => {
+            let tuya = Tuya::new(config)?;
+            Ok(Arc::new(tuya))
+        }
+        SmartHomePlatform::None => Err(Error::Configuration(
+            "No smart home platform configured".to_string(),
+        )),
+    }
+}
+
+/// The configured backends: the default one selected with
+/// `smart_home.platform`, unless it is `None`, and the named ones in
+/// `smart_home.backends`
+pub struct Backends {
+    default: Option<Arc<dyn SmartHomeApi + Send + Sync>>,
+    named: HashMap<String, Arc<dyn SmartHomeApi + Send + Sync>>,
+}
+
+impl Backends {
+    pub fn new(config: Arc<Config>) -> Result<Self> {
+        // Govee devices answer to a fixed port, which only one backend can
+        // listen on
+        let govee_backends = std::iter::once(&config.smart_home)
+            .chain(config.smart_home.backends.iter().map(|b| &b.smart_home))
+            .filter(|s| matches!(s.platform, SmartHomePlatform::Govee))
+            .count();
+        if govee_backends > 1 {
+            return Err(Error::Configuration(
+                "Only one Govee backend can be configured".to_string(),
+            ));
+        }
+
+        let default = match config.smart_home.platform {
+            SmartHomePlatform::None => None,
+            _ => Some(get_smart_home_api(config.clone())?),
+        };
+
+        let mut named = HashMap::new();
+        for backend in &config.smart_home.backends {
+            let name = &backend.name;
+            if name.is_empty() || named.contains_key(name) {
+                return Err(Error::Configuration(format!(
+                    "Backend names must be unique and not empty, got `{name}`"
+                )));
+            }
+            // Names are used in the paths of webhook routes
+            if !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(Error::Configuration(format!(
+                    "Backend names may only contain letters, digits, `-` and `_`, got `{name}`"
+                )));
+            }
+            let backend_config = config.for_backend(name).unwrap();
+            let api = get_smart_home_api(Arc::new(backend_config)).map_err(|e| {
+                Error::Configuration(format!("Initializing backend `{name}` failed: {e}"))
+            })?;
+            named.insert(name.clone(), api);
+        }
+
+        Ok(Self { default, named })
+    }
+
+    /// The backend with the name, or the default backend if there is no name
+    pub fn get(&self, name: Option<&str>) -> Option<&Arc<dyn SmartHomeApi + Send + Sync>> {
+        match name {
+            Some(name) => self.named.get(name),
+            None => self.default.as_ref(),
+        }
+    }
+
+    /// All backends with their names. The default backend has no name.
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = (Option<&str>, &Arc<dyn SmartHomeApi + Send + Sync>)> {
+        self.default.iter().map(|api| (None, api)).chain(
+            self.named
+                .iter()
+                .map(|(name, api)| (Some(name.as_str()), api)),
+        )
+    }
+}
+
+/// Pair with a device or bridge at the address, returning the credentials to
+/// configure for it
+pub async fn pair(config: Arc<Config>, address: &str) -> Result<String> {
+    match config.smart_home.platform {
+        SmartHomePlatform::Nanoleaf => nanoleaf::pair(&config, address).await,
+        SmartHomePlatform::Dirigera => dirigera::pair(&config, address).await,
+        _ => Err(Error::UnsupportedCapability("pairing")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::test_config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_several_govee_backends() {
+        let config = test_config(serde_json::json!({
+            "platform": "Govee",
+            "backends": [
+                { "name": "downstairs", "platform": "Govee" },
+            ],
+        }));
+
+        assert!(matches!(
+            Backends::new(config),
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_backend_names() {
+        for name in ["", "up/stairs", ":id", "*rest", "kitchen lights", "kü"] {
+            let config = test_config(serde_json::json!({
+                "platform": "None",
+                "backends": [{ "name": name, "platform": "Simulated" }],
+            }));
+            assert!(
+                matches!(Backends::new(config), Err(Error::Configuration(_))),
+                "{name}"
+            );
+        }
+
+        let config = test_config(serde_json::json!({
+            "platform": "None",
+            "backends": [
+                { "name": "Upstairs_2", "platform": "Simulated" },
+                { "name": "down-stairs", "platform": "Simulated" },
+            ],
+        }));
+        let backends = Backends::new(config).unwrap();
+        assert!(backends.get(Some("Upstairs_2")).is_some());
+        assert!(backends.get(Some("down-stairs")).is_some());
+        assert!(backends.get(None).is_none());
     }
 }
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use reqwest::Url;
+use rumqttc::{AsyncClient, ClientError, EventLoop, MqttOptions, Transport};
+
+use crate::config::MqttConfig;
+
+const CHANNEL_CAPACITY: usize = 100;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Create an MQTT client from the configuration. The returned event loop
+/// must be polled for the client to make any progress.
+pub fn create_client(
+    config: &MqttConfig,
+    default_client_id: &str,
+) -> super::Result<(AsyncClient, EventLoop)> {
+    let Some(broker_url) = &config.broker_url else {
+        return Err(super::Error::Configuration(
+            "MQTT broker URL not configured".to_string(),
+        ));
+    };
+
+    let invalid_url =
+        || super::Error::Configuration(format!("Invalid MQTT broker URL `{broker_url}`"));
+
+    let url: Url = broker_url.parse().map_err(|_| invalid_url())?;
+    let tls = match url.scheme() {
+        "mqtt" | "tcp" => false,
+        "mqtts" | "ssl" => true,
+        _ => return Err(invalid_url()),
+    };
+    let host = url.host_str().ok_or_else(invalid_url)?;
+    let port = url.port().unwrap_or(if tls { 8883 } else { 1883 });
+
+    let client_id = match (&config.client_id, &config.backend_name) {
+        (Some(client_id), _) => client_id.clone(),
+        (None, Some(backend)) => format!("{default_client_id}-{backend}"),
+        (None, None) => default_client_id.to_string(),
+    };
+
+    let mut options = MqttOptions::new(client_id, host, port);
+    options.set_keep_alive(KEEP_ALIVE);
+
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+
+    if tls {
+        let transport = match &config.ca_certificate_file {
+            Some(ca_file) => {
+                let ca = std::fs::read(ca_file).map_err(|e| {
+                    super::Error::Configuration(format!("Could not read MQTT CA certificate: {e}"))
+                })?;
+                Transport::tls(ca, None, None)
+            }
+            None => Transport::tls_with_default_config(),
+        };
+        options.set_transport(transport);
+    }
+
+    Ok(AsyncClient::new(options, CHANNEL_CAPACITY))
+}
+
+impl From<ClientError> for super::Error {
+    fn from(value: ClientError) -> Self {
+        super::Error::Communication(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::test_config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn named_backends_get_their_own_client_id() {
+        let config = test_config(serde_json::json!({
+            "platform": "Zigbee2Mqtt",
+            "zigbee2mqtt": { "mqtt": { "broker_url": "mqtt://127.0.0.1:1" } },
+            "backends": [
+                {
+                    "name": "upstairs",
+                    "platform": "Zigbee2Mqtt",
+                    "zigbee2mqtt": { "mqtt": { "broker_url": "mqtt://127.0.0.1:1" } },
+                },
+                {
+                    "name": "garage",
+                    "platform": "Zigbee2Mqtt",
+                    "zigbee2mqtt": {
+                        "mqtt": { "broker_url": "mqtt://127.0.0.1:1", "client_id": "garage-lights" },
+                    },
+                },
+            ],
+        }));
+        let client_id = |config: &MqttConfig| {
+            let (_, event_loop) = create_client(config, "light-operator").unwrap();
+            event_loop.mqtt_options.client_id()
+        };
+
+        assert_eq!(
+            client_id(&config.smart_home.zigbee2mqtt.mqtt),
+            "light-operator"
+        );
+        let upstairs = config.for_backend("upstairs").unwrap();
+        assert_eq!(
+            client_id(&upstairs.smart_home.zigbee2mqtt.mqtt),
+            "light-operator-upstairs"
+        );
+        let garage = config.for_backend("garage").unwrap();
+        assert_eq!(
+            client_id(&garage.smart_home.zigbee2mqtt.mqtt),
+            "garage-lights"
+        );
+    }
+}
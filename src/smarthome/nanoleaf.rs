@@ -0,0 +1,248 @@
+use std::{sync::Arc, time::Duration};
+
+use api_models::*;
+use async_trait::async_trait;
+use reqwest::{Client, Response, StatusCode, Url};
+
+use crate::config::Config;
+
+use super::{requested::RequestedValues, LightOptions, LightStatus, SmartHomeApi};
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+const PORT: u16 = 16021;
+
+/// Color temperature range supported by Nanoleaf controllers
+const KELVIN_RANGE: (u16, u16) = (1200, 6500);
+
+/// Effect selected when the panels show a plain color
+const SOLID_EFFECT: &str = "*Solid*";
+
+pub struct Nanoleaf {
+    config: Arc<Config>,
+    client: Client,
+    requested: RequestedValues,
+}
+
+impl Nanoleaf {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let client = Self::client(&config)?;
+
+        Ok(Self {
+            config,
+            client,
+            requested: Default::default(),
+        })
+    }
+
+    fn client(config: &Config) -> super::Result<Client> {
+        let timeout = Duration::from_millis(config.smart_home.nanoleaf.timeout_ms);
+
+        Ok(reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .timeout(timeout)
+            .build()
+            .unwrap())
+    }
+
+    /// API base URL of a controller, `http://<address>:16021/api/v1/`
+    fn base_url(address: &str) -> super::Result<Url> {
+        let invalid = || super::Error::InvalidId(address.to_string());
+
+        let valid = !address.is_empty()
+            && address
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'));
+        if !valid {
+            return Err(invalid());
+        }
+
+        let mut url: Url = format!("http://{address}/api/v1/")
+            .parse()
+            .map_err(|_| invalid())?;
+        if url.port().is_none() {
+            url.set_port(Some(PORT)).map_err(|_| invalid())?;
+        }
+        Ok(url)
+    }
+
+    /// Device IDs are controller host names or IP addresses, which must be
+    /// listed in the configuration with their auth tokens
+    fn device_url(&self, id: &str, path: &str) -> super::Result<Url> {
+        let base_url = Self::base_url(id)?;
+
+        let device = self
+            .config
+            .smart_home
+            .nanoleaf
+            .devices
+            .iter()
+            .find(|d| d.id == id)
+            .ok_or(super::Error::UnknownDeviceId)?;
+
+        base_url
+            .join(&format!("{}/{path}", device.auth_token))
+            .map_err(|_| {
+                super::Error::Configuration(format!("Invalid Nanoleaf auth token for {id}"))
+            })
+    }
+
+    fn error_from_status(res: Response) -> super::Result<Response> {
+        if res.status() == StatusCode::UNAUTHORIZED {
+            return Err(super::Error::Configuration(
+                "Nanoleaf auth token was not accepted".to_string(),
+            ));
+        }
+
+        Ok(res.error_for_status()?)
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, id: &str, path: &str) -> super::Result<T> {
+        let url = self.device_url(id, path)?;
+        let res = self.client.get(url).send().await?;
+        Ok(Self::error_from_status(res)?.json().await?)
+    }
+
+    async fn update_state(&self, id: &str, body: serde_json::Value) -> super::Result<()> {
+        let url = self.device_url(id, "state")?;
+
+        tracing::debug!(device_id = id, state = body.to_string(), "Updating state");
+
+        let res = self.client.put(url).json(&body).send().await?;
+        Self::error_from_status(res)?;
+        Ok(())
+    }
+}
+
+/// Create an auth token. The controller must be in pairing mode, which is
+/// entered by holding its power button for 5-7 seconds.
+pub(super) async fn pair(config: &Config, address: &str) -> super::Result<String> {
+    let client = Nanoleaf::client(config)?;
+    let url = Nanoleaf::base_url(address)?.join("new").unwrap();
+
+    let res = client.post(url).send().await?;
+    if res.status() == StatusCode::FORBIDDEN {
+        return Err(super::Error::Communication(
+            "Controller is not in pairing mode. Hold its power button for 5-7 seconds and try again within 30 seconds.".to_string(),
+        ));
+    }
+
+    let body: NewUser = res.error_for_status()?.json().await?;
+    Ok(body.auth_token)
+}
+
+#[async_trait]
+impl SmartHomeApi for Nanoleaf {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let state: State = match self.get(id, "state").await {
+            Ok(state) => state,
+            Err(super::Error::RequestFailed(e)) if e.is_connect() || e.is_timeout() => {
+                tracing::debug!(error = %e, "Nanoleaf controller is unreachable");
+                return Ok(LightStatus::Offline);
+            }
+            Err(e) => return Err(e),
+        };
+
+        tracing::debug!("Got status {state:#?}");
+
+        let effect = if state.color_mode == "effect" {
+            let selected: String = self.get(id, "effects/select").await?;
+            Some(selected).filter(|e| e != SOLID_EFFECT)
+        } else {
+            None
+        };
+
+        let color_temperature = (state.color_mode == "ct").then(|| {
+            let ct = state.ct.value;
+            self.requested
+                .color_temperature(id, |k| k.clamp(KELVIN_RANGE.0, KELVIN_RANGE.1) == ct)
+                .unwrap_or(ct)
+        });
+
+        let color = (state.color_mode != "ct").then(|| {
+            let hue = (f64::from(state.hue.value % 360) / 3.6).round() as u8 % 100;
+            let saturation = state.sat.value.min(100) as u8;
+            let (hue, saturation) = self
+                .requested
+                .color(id, |rh, rs| rh.abs_diff(hue) <= 1 && rs == saturation)
+                .unwrap_or((hue, saturation));
+            super::Color { hue, saturation }
+        });
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: state.on.value,
+            brightness: Some(state.brightness.value.min(100) as u8),
+            color_temperature,
+            color,
+            effect,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        self.update_state(id, serde_json::json!({ "on": { "value": switched_on } }))
+            .await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let body = serde_json::json!({ "brightness": { "value": brightness.clamp(0, 100) } });
+        self.update_state(id, body).await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let ct = temp.clamp(KELVIN_RANGE.0, KELVIN_RANGE.1);
+        self.update_state(id, serde_json::json!({ "ct": { "value": ct } }))
+            .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        // Setting a color also stops any running effect
+        let body = serde_json::json!({
+            "hue": { "value": (f64::from(hue) * 3.6).round() as u16 % 360 },
+            "sat": { "value": saturation.clamp(0, 100) },
+        });
+        self.update_state(id, body).await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    pub struct BoolValue {
+        pub value: bool,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct IntValue {
+        pub value: u16,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct State {
+        pub on: BoolValue,
+        pub brightness: IntValue,
+        /// Degrees
+        pub hue: IntValue,
+        pub sat: IntValue,
+        /// Kelvin
+        pub ct: IntValue,
+        /// `hs`, `ct` or `effect`
+        pub color_mode: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct NewUser {
+        pub auth_token: String,
+    }
+}
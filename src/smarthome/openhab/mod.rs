@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Once},
+};
+
+use api_models::*;
+use async_trait::async_trait;
+use reqwest::{header::HeaderMap, Client, Response, Url};
+
+use crate::config::Config;
+
+use super::{
+    events::EventChannel, requested::RequestedValues, DeviceEventStream, LightOptions, LightStatus,
+    SmartHomeApi,
+};
+
+mod sse;
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+pub struct Openhab {
+    _config: Arc<Config>,
+    client: Client,
+    base_url: Url,
+    requested: RequestedValues,
+    /// Values to apply when an item that is off is switched on. openHAB
+    /// items are off when their brightness is zero, so setting the
+    /// brightness or color would switch them on.
+    pending: Mutex<HashMap<String, Pending>>,
+    sse_events: bool,
+    events: Arc<EventChannel>,
+    start_listener: Once,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+struct Pending {
+    brightness: Option<u8>,
+    color: Option<(u8, u8)>,
+}
+
+/// State of an item, parsed according to its type
+#[derive(Debug)]
+enum ItemState {
+    Switch {
+        on: bool,
+    },
+    Dimmer {
+        brightness: f64,
+    },
+    /// Hue in degrees, saturation and brightness in percent
+    Color {
+        hue: f64,
+        saturation: f64,
+        brightness: f64,
+    },
+    /// `NULL` or `UNDEF`, e.g. because the thing is offline
+    Undefined,
+}
+
+impl Openhab {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let openhab_config = &config.smart_home.openhab;
+        let Some(base_url) = &openhab_config.base_url else {
+            return Err(super::Error::Configuration(
+                "openHAB URL not configured".to_string(),
+            ));
+        };
+
+        let mut headers = HeaderMap::new();
+        if let Some(api_token) = &openhab_config.api_token {
+            headers.append(
+                "Authorization",
+                format!("Bearer {api_token}").parse().map_err(|_| {
+                    super::Error::Configuration("openHAB API token is invalid".to_string())
+                })?,
+            );
+        }
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .default_headers(headers)
+            .build()
+            .unwrap();
+
+        let base_url = format!("{}/rest/", base_url.trim_end_matches('/'))
+            .parse()
+            .map_err(|_| {
+                super::Error::Configuration(format!("Invalid openHAB URL `{base_url}`"))
+            })?;
+
+        Ok(Self {
+            sse_events: openhab_config.sse_events,
+            _config: config,
+            client,
+            base_url,
+            requested: Default::default(),
+            pending: Default::default(),
+            events: Default::default(),
+            start_listener: Once::new(),
+        })
+    }
+
+    /// Device IDs are item names, e.g. `Kitchen_Light`
+    fn item_url(&self, id: &str) -> super::Result<Url> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(super::Error::InvalidId(id.to_string()));
+        }
+
+        Ok(self.base_url.join(&format!("items/{id}")).unwrap())
+    }
+
+    fn error_from_status(res: Response) -> super::Result<Response> {
+        if res.status() == 404 {
+            return Err(super::Error::UnknownDeviceId);
+        }
+
+        Ok(res.error_for_status()?)
+    }
+
+    async fn get_item_state(&self, id: &str) -> super::Result<ItemState> {
+        let url = self.item_url(id)?;
+
+        let res = self.client.get(url).send().await?;
+        let item: Item = Self::error_from_status(res)?.json().await?;
+
+        tracing::debug!("Got status {item:#?}");
+
+        parse_state(&item)
+    }
+
+    async fn send_command(&self, id: &str, command: String) -> super::Result<()> {
+        let url = self.item_url(id)?;
+
+        tracing::debug!(device_id = id, command, "Sending item command");
+
+        let res = self
+            .client
+            .post(url)
+            .header("Content-Type", "text/plain")
+            .body(command)
+            .send()
+            .await?;
+        Self::error_from_status(res)?;
+        Ok(())
+    }
+
+    fn update_pending(&self, id: &str, f: impl FnOnce(&mut Pending)) {
+        f(self
+            .pending
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_default());
+    }
+
+    fn take_pending(&self, id: &str) -> Pending {
+        self.pending.lock().unwrap().remove(id).unwrap_or_default()
+    }
+}
+
+fn parse_state(item: &Item) -> super::Result<ItemState> {
+    if item.state == "NULL" || item.state == "UNDEF" {
+        return Ok(ItemState::Undefined);
+    }
+
+    let invalid = || {
+        super::Error::Communication(format!(
+            "Invalid state `{}` of item {}",
+            item.state, item.name
+        ))
+    };
+    let parse = |value: &str| value.trim().parse::<f64>().map_err(|_| invalid());
+
+    // Groups have the state of their base item type
+    let item_type = match (item.type_.as_str(), &item.group_type) {
+        ("Group", Some(group_type)) => group_type.as_str(),
+        (item_type, _) => item_type,
+    };
+
+    match item_type {
+        "Switch" => Ok(ItemState::Switch {
+            on: item.state == "ON",
+        }),
+        "Dimmer" => Ok(ItemState::Dimmer {
+            brightness: parse(&item.state)?,
+        }),
+        "Color" => {
+            let mut parts = item.state.split(',');
+            let (Some(hue), Some(saturation), Some(brightness), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid());
+            };
+            Ok(ItemState::Color {
+                hue: parse(hue)?,
+                saturation: parse(saturation)?,
+                brightness: parse(brightness)?,
+            })
+        }
+        _ => Err(super::Error::UnsupportedCapability("light control")),
+    }
+}
+
+fn percent(value: f64) -> u8 {
+    value.round().clamp(0.0, 100.0) as u8
+}
+
+#[async_trait]
+impl SmartHomeApi for Openhab {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let state = self.get_item_state(id).await?;
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .get(id)
+            .copied()
+            .unwrap_or_default();
+
+        let (switched_on, brightness, color) = match state {
+            ItemState::Undefined => return Ok(LightStatus::Offline),
+            ItemState::Switch { on } => (on, None, None),
+            ItemState::Dimmer { brightness } => {
+                let on = brightness > 0.0;
+                (
+                    on,
+                    on.then(|| percent(brightness)).or(pending.brightness),
+                    None,
+                )
+            }
+            ItemState::Color {
+                hue,
+                saturation,
+                brightness,
+            } => {
+                let on = brightness > 0.0;
+                let color = pending.color.filter(|_| !on).unwrap_or_else(|| {
+                    let hue = (hue.rem_euclid(360.0) / 3.6).round() as u8 % 100;
+                    let saturation = percent(saturation);
+                    self.requested
+                        .color(id, |rh, rs| {
+                            rh.abs_diff(hue) <= 1 && rs.abs_diff(saturation) <= 1
+                        })
+                        .unwrap_or((hue, saturation))
+                });
+                let color = super::Color {
+                    hue: color.0,
+                    saturation: color.1,
+                };
+                (
+                    on,
+                    on.then(|| percent(brightness)).or(pending.brightness),
+                    Some(color),
+                )
+            }
+        };
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on,
+            brightness,
+            color_temperature: None,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        if !switched_on {
+            return self.send_command(id, "OFF".to_string()).await;
+        }
+
+        let state = self.get_item_state(id).await?;
+        let pending = self.take_pending(id);
+        let command = match state {
+            ItemState::Color {
+                hue, saturation, ..
+            } if pending.brightness.is_some() || pending.color.is_some() => {
+                let (hue, saturation) = pending
+                    .color
+                    .map(|(h, s)| (f64::from(h) * 3.6, f64::from(s)))
+                    .unwrap_or((hue, saturation));
+                format!(
+                    "{hue:.1},{saturation},{}",
+                    pending.brightness.unwrap_or(100)
+                )
+            }
+            ItemState::Dimmer { .. } if pending.brightness.is_some() => {
+                pending.brightness.unwrap().to_string()
+            }
+            _ => "ON".to_string(),
+        };
+
+        self.send_command(id, command).await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let brightness = brightness.clamp(0, 100);
+        match self.get_item_state(id).await? {
+            ItemState::Switch { .. } => Err(super::Error::UnsupportedCapability("brightness")),
+            ItemState::Dimmer {
+                brightness: current,
+            }
+            | ItemState::Color {
+                brightness: current,
+                ..
+            } if current > 0.0 => {
+                self.update_pending(id, |p| p.brightness = None);
+                self.send_command(id, brightness.to_string()).await
+            }
+            _ => {
+                self.update_pending(id, |p| p.brightness = Some(brightness));
+                Ok(())
+            }
+        }
+    }
+
+    async fn set_color_temperature(&self, _id: &str, _temp: u16) -> super::Result<()> {
+        Err(super::Error::UnsupportedCapability("color temperature"))
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let (hue, saturation) = (hue.min(100), saturation.min(100));
+        match self.get_item_state(id).await? {
+            ItemState::Color { brightness, .. } if brightness > 0.0 => {
+                self.update_pending(id, |p| p.color = None);
+                let command = format!(
+                    "{:.1},{saturation},{}",
+                    f64::from(hue) * 3.6,
+                    percent(brightness)
+                );
+                self.send_command(id, command).await?;
+            }
+            ItemState::Color { .. } => {
+                self.update_pending(id, |p| p.color = Some((hue, saturation)));
+            }
+            _ => return Err(super::Error::UnsupportedCapability("color")),
+        }
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    fn device_events(&self) -> Option<DeviceEventStream> {
+        if !self.sse_events {
+            return None;
+        }
+
+        let stream = self.events.subscribe();
+        self.start_listener.call_once(|| {
+            tokio::spawn(sse::listen(
+                self.client.clone(),
+                self.base_url.clone(),
+                self.events.clone(),
+            ));
+        });
+        Some(stream)
+    }
+
+    fn events_connected(&self) -> bool {
+        self.events.is_connected()
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Item {
+        pub name: String,
+        /// `Switch`, `Dimmer`, `Color`, `Group`, ...
+        #[serde(rename = "type")]
+        pub type_: String,
+        /// Base item type of groups
+        pub group_type: Option<String>,
+        /// e.g. `ON`, `42` or `120,100,42`
+        pub state: String,
+    }
+}
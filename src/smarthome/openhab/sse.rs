@@ -0,0 +1,102 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+
+use crate::smarthome::events::{Backoff, EventChannel};
+
+/// Item state changes. Group member changes have the member name as an extra
+/// path segment.
+const TOPICS: &str = "openhab/items/*/statechanged,openhab/items/*/*/statechanged";
+
+/// openHAB sends an `ALIVE` event every 10 seconds, so a stream that has
+/// been silent for this long is assumed to be broken
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Listen to item state changes from the openHAB event stream and publish the
+/// changed item names. Reconnects with backoff until the program exits.
+pub(super) async fn listen(client: Client, base_url: Url, events: Arc<EventChannel>) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+    loop {
+        match connect_and_listen(&client, &base_url, &events, &mut backoff).await {
+            Ok(()) => tracing::info!("openHAB event stream closed"),
+            Err(e) => {
+                let err_ref: &(dyn std::error::Error + Send + Sync) = e.as_ref();
+                tracing::warn!(error = err_ref, "openHAB event stream failed");
+            }
+        }
+        events.set_connected(false);
+
+        let delay = backoff.next_delay();
+        tracing::debug!("Reconnecting to openHAB event stream in {delay:?}");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn connect_and_listen(
+    client: &Client,
+    base_url: &Url,
+    events: &EventChannel,
+    backoff: &mut Backoff,
+) -> anyhow::Result<()> {
+    let mut url = base_url.join("events")?;
+    url.query_pairs_mut().append_pair("topics", TOPICS);
+
+    let mut res = client
+        .get(url)
+        .header("Accept", "text/event-stream")
+        .send()
+        .await
+        .context("Connecting failed")?
+        .error_for_status()?;
+
+    tracing::info!("Connected to openHAB event stream");
+    backoff.reset();
+    events.set_connected(true);
+
+    let mut buf = Vec::new();
+    loop {
+        let chunk = tokio::time::timeout(IDLE_TIMEOUT, res.chunk())
+            .await
+            .map_err(|_| anyhow!("No events received in {IDLE_TIMEOUT:?}"))??;
+        let Some(chunk) = chunk else {
+            break;
+        };
+
+        buf.extend(chunk.iter().filter(|&&b| b != b'\r'));
+
+        // Events are separated by empty lines
+        while let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+            let event: Vec<u8> = buf.drain(..end + 2).collect();
+            let data: String = String::from_utf8_lossy(&event)
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(str::trim_start)
+                .collect();
+            if data.is_empty() {
+                continue;
+            }
+
+            let event: Event = serde_json::from_str(&data)
+                .map_err(|e| anyhow!("Invalid event from openHAB: {e}"))?;
+            if let Some(item) = event.topic.split('/').nth(2) {
+                tracing::debug!(device_id = item, event = event.type_, "Item changed");
+                events.send_changed(item);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct Event {
+    /// e.g. `openhab/items/Kitchen_Light/statechanged`, missing from `ALIVE`
+    /// events
+    #[serde(default)]
+    topic: String,
+    #[serde(rename = "type", default)]
+    type_: String,
+}
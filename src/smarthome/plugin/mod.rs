@@ -0,0 +1,288 @@
+use std::{sync::Arc, time::Duration};
+
+use api_models::*;
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+
+use self::process::{PluginProcess, PluginSettings, RpcError};
+
+use super::{DeviceEventStream, DeviceInfo, LightOptions, LightStatus, SmartHomeApi};
+
+mod process;
+
+/// Error codes that plugins answer with to fail a call with a specific
+/// `smarthome::Error`. Other codes are reported as communication errors.
+mod error_code {
+    /// JSON-RPC "Method not found"
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const UNSUPPORTED_CAPABILITY: i64 = 1;
+    pub const UNKNOWN_DEVICE: i64 = 2;
+    pub const INVALID_ID: i64 = 3;
+    pub const COMMUNICATION: i64 = 4;
+    pub const CONFIGURATION: i64 = 5;
+}
+
+/// Backend implemented by an external program that is called with JSON-RPC
+/// over its stdin and stdout
+pub struct Plugin {
+    _config: Arc<Config>,
+    process: Arc<PluginProcess>,
+}
+
+impl Plugin {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let plugin_config = &config.smart_home.plugin;
+        let Some(command) = &plugin_config.command else {
+            return Err(super::Error::Configuration(
+                "Plugin command not configured".to_string(),
+            ));
+        };
+
+        let process = PluginProcess::new(PluginSettings {
+            command: command.clone(),
+            args: plugin_config.args.clone(),
+            timeout: Duration::from_millis(plugin_config.timeout_ms),
+        });
+
+        Ok(Self {
+            _config: config,
+            process: Arc::new(process),
+        })
+    }
+
+    /// Call a method of the plugin for the device with the ID, if any.
+    /// `capability` is reported if the plugin doesn't support the method.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        id: Option<&str>,
+        capability: &'static str,
+        params: Value,
+    ) -> super::Result<T> {
+        let result = self
+            .process
+            .call(method, params)
+            .await?
+            .map_err(|e| Self::map_error(e, id, capability))?;
+
+        serde_json::from_value(result).map_err(|e| {
+            super::Error::Communication(format!("Invalid {method} result from plugin: {e}"))
+        })
+    }
+
+    fn map_error(error: RpcError, id: Option<&str>, capability: &'static str) -> super::Error {
+        match error.code {
+            error_code::METHOD_NOT_FOUND | error_code::UNSUPPORTED_CAPABILITY => {
+                super::Error::UnsupportedCapability(capability)
+            }
+            error_code::UNKNOWN_DEVICE => super::Error::UnknownDeviceId,
+            error_code::INVALID_ID => super::Error::InvalidId(id.unwrap_or_default().to_string()),
+            error_code::COMMUNICATION => super::Error::Communication(error.message),
+            error_code::CONFIGURATION => super::Error::Configuration(error.message),
+            code => super::Error::Communication(format!(
+                "Plugin failed with error {code}: {}",
+                error.message
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Plugin {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let status: Status = self
+            .call("getLightStatus", Some(id), "status", json!({ "id": id }))
+            .await?;
+
+        tracing::debug!("Got status {status:#?}");
+
+        if !status.online {
+            return Ok(LightStatus::Offline);
+        }
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: status.switched_on,
+            brightness: status.brightness.map(|b| b.min(100)),
+            color_temperature: status.color_temperature,
+            color: status.color.map(|c| super::Color {
+                hue: c.hue % 100,
+                saturation: c.saturation.min(100),
+            }),
+            effect: status.effect,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let params = json!({ "id": id, "switchedOn": switched_on });
+        self.call::<Value>("setSwitchedOn", Some(id), "switching", params)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let params = json!({ "id": id, "brightness": brightness.min(100) });
+        self.call::<Value>("setBrightness", Some(id), "brightness", params)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let params = json!({ "id": id, "colorTemperature": temp });
+        self.call::<Value>("setColorTemperature", Some(id), "color temperature", params)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let params = json!({ "id": id, "hue": hue % 100, "saturation": saturation.min(100) });
+        self.call::<Value>("setColor", Some(id), "color", params)
+            .await?;
+        Ok(())
+    }
+
+    fn device_events(&self) -> Option<DeviceEventStream> {
+        let stream = self.process.events.subscribe();
+        self.process.start();
+        Some(stream)
+    }
+
+    fn events_connected(&self) -> bool {
+        self.process.events.is_connected()
+    }
+
+    async fn list_devices(&self) -> super::Result<Vec<DeviceInfo>> {
+        let devices: Vec<Device> = self
+            .call("listDevices", None, "device listing", json!({}))
+            .await?;
+
+        Ok(devices
+            .into_iter()
+            .map(|d| DeviceInfo {
+                id: d.id,
+                name: d.name,
+                model: d.model,
+            })
+            .collect())
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Status {
+        pub online: bool,
+        #[serde(default)]
+        pub switched_on: bool,
+        /// 0-100
+        pub brightness: Option<u8>,
+        /// Kelvin
+        pub color_temperature: Option<u16>,
+        pub color: Option<Color>,
+        pub effect: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Color {
+        /// 0-100
+        pub hue: u8,
+        /// 0-100
+        pub saturation: u8,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Device {
+        pub id: String,
+        pub name: Option<String>,
+        pub model: Option<String>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::test_config;
+
+    use super::*;
+
+    fn map_error(code: i64) -> super::super::Error {
+        let error = RpcError {
+            code,
+            message: "Failed".to_string(),
+        };
+        Plugin::map_error(error, Some("light-1"), "color")
+    }
+
+    #[test]
+    fn error_codes_are_mapped() {
+        use super::super::Error;
+
+        assert!(matches!(
+            map_error(-32601),
+            Error::UnsupportedCapability("color")
+        ));
+        assert!(matches!(
+            map_error(1),
+            Error::UnsupportedCapability("color")
+        ));
+        assert!(matches!(map_error(2), Error::UnknownDeviceId));
+        assert!(matches!(map_error(3), Error::InvalidId(id) if id == "light-1"));
+        assert!(matches!(map_error(4), Error::Communication(m) if m == "Failed"));
+        assert!(matches!(map_error(5), Error::Configuration(m) if m == "Failed"));
+        assert!(matches!(
+            map_error(-32000),
+            Error::Communication(m) if m == "Plugin failed with error -32000: Failed"
+        ));
+
+        let error = RpcError {
+            code: error_code::INVALID_ID,
+            message: "Failed".to_string(),
+        };
+        assert!(matches!(
+            Plugin::map_error(error, None, "device listing"),
+            Error::InvalidId(id) if id.is_empty()
+        ));
+    }
+
+    #[tokio::test]
+    async fn light_status_is_read_from_plugin() {
+        // Answers initialize and getLightStatus, and fails everything else
+        // with an unknown device
+        let script = r#"
+            while read -r line; do
+                id=$(echo "$line" | sed 's/.*"id":\([0-9][0-9]*\),"jsonrpc".*/\1/')
+                case "$line" in
+                    *'"method":"initialize"'*)
+                        echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"protocolVersion\":1}}" ;;
+                    *'"method":"getLightStatus"'*)
+                        echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"online\":true,\"switchedOn\":true,\"brightness\":150,\"color\":{\"hue\":120,\"saturation\":50}}}" ;;
+                    *)
+                        echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"error\":{\"code\":2,\"message\":\"Unknown\"}}" ;;
+                esac
+            done
+        "#;
+        let config = test_config(json!({
+            "platform": "Plugin",
+            "plugin": { "command": "sh", "args": ["-c", script], "timeout_ms": 2000 },
+        }));
+        let plugin = Plugin::new(config).unwrap();
+
+        let LightStatus::Online(options) = plugin.get_light_status("1").await.unwrap() else {
+            panic!("light is offline");
+        };
+        assert!(options.switched_on);
+        assert_eq!(options.brightness, Some(100));
+        assert_eq!(options.color.map(|c| (c.hue, c.saturation)), Some((20, 50)));
+        assert_eq!(options.color_temperature, None);
+
+        assert!(matches!(
+            plugin.set_brightness("1", 50).await,
+            Err(super::super::Error::UnknownDeviceId)
+        ));
+    }
+}
@@ -0,0 +1,571 @@
+use std::{
+    collections::HashMap,
+    process::{ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, Once,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{ChildStdin, ChildStdout, Command},
+    sync::{oneshot, watch, Notify},
+    time::Instant,
+};
+
+use crate::smarthome::events::{Backoff, EventChannel};
+
+/// Version of the protocol spoken with plugins, sent in the `initialize`
+/// request. Plugins answer with the version they implement.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const CLIENT_INFO: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// A plugin that has run for this long is considered healthy, so the restart
+/// backoff starts over when it exits
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// A plugin that leaves this many calls in a row unanswered is considered
+/// stuck and restarted
+const MAX_CONSECUTIVE_TIMEOUTS: u32 = 3;
+
+pub(super) struct PluginSettings {
+    pub command: String,
+    pub args: Vec<String>,
+    pub timeout: Duration,
+}
+
+/// A plugin process, started on first use and restarted whenever it exits
+pub(super) struct PluginProcess {
+    settings: PluginSettings,
+    state: watch::Sender<ProcessState>,
+    pub events: EventChannel,
+    started: Once,
+}
+
+#[derive(Clone)]
+enum ProcessState {
+    Starting,
+    Running(Arc<Connection>),
+    Failed(String),
+    /// The plugin speaks another protocol version, so it is not restarted
+    Incompatible(String),
+}
+
+/// Error object of a JSON-RPC response
+#[derive(Deserialize, Debug)]
+pub(super) struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Message {
+    id: Option<u64>,
+    method: Option<String>,
+    #[serde(default)]
+    params: Value,
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct InitializeResult {
+    protocol_version: u32,
+    name: Option<String>,
+    /// Whether the plugin sends `deviceChanged` notifications
+    #[serde(default)]
+    events: bool,
+}
+
+/// Requests sent to a running plugin and waiting for their responses
+struct Connection {
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Result<Value, RpcError>>>>,
+    next_id: AtomicU64,
+    /// Calls that timed out since the plugin last answered
+    timeouts: AtomicU32,
+    /// Notified when too many calls in a row have timed out
+    stuck: Notify,
+}
+
+impl Connection {
+    async fn request(
+        &self,
+        method: &str,
+        params: Value,
+        deadline: Instant,
+    ) -> crate::smarthome::Result<Result<Value, RpcError>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let mut line = request.to_string();
+        line.push('\n');
+
+        let written = {
+            let mut stdin = self.stdin.lock().await;
+            match stdin.write_all(line.as_bytes()).await {
+                Ok(()) => stdin.flush().await,
+                Err(e) => Err(e),
+            }
+        };
+        if let Err(e) = written {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(crate::smarthome::Error::Communication(format!(
+                "Sending {method} to plugin failed: {e}"
+            )));
+        }
+
+        match tokio::time::timeout_at(deadline, receiver).await {
+            Ok(Ok(response)) => {
+                self.timeouts.store(0, Ordering::Relaxed);
+                Ok(response)
+            }
+            Ok(Err(_)) => Err(crate::smarthome::Error::Communication(format!(
+                "Plugin exited before answering {method}"
+            ))),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                if self.timeouts.fetch_add(1, Ordering::Relaxed) + 1 >= MAX_CONSECUTIVE_TIMEOUTS {
+                    self.stuck.notify_one();
+                }
+                Err(crate::smarthome::Error::Communication(format!(
+                    "Plugin did not answer {method} in time"
+                )))
+            }
+        }
+    }
+}
+
+impl PluginProcess {
+    pub fn new(settings: PluginSettings) -> Self {
+        Self {
+            settings,
+            state: watch::channel(ProcessState::Starting).0,
+            events: EventChannel::new(),
+            started: Once::new(),
+        }
+    }
+
+    /// Start supervising the plugin process if that has not been done yet
+    pub fn start(self: &Arc<Self>) {
+        self.started.call_once(|| {
+            tokio::spawn(run(self.clone()));
+        });
+    }
+
+    /// Call a method of the plugin. The outer error is set when the call
+    /// could not be made, the inner one when the plugin answered with an
+    /// error.
+    pub async fn call(
+        self: &Arc<Self>,
+        method: &str,
+        params: Value,
+    ) -> crate::smarthome::Result<Result<Value, RpcError>> {
+        self.start();
+
+        let deadline = Instant::now() + self.settings.timeout;
+        let mut state = self.state.subscribe();
+        let ready = tokio::time::timeout_at(deadline, async {
+            state
+                .wait_for(|s| !matches!(s, ProcessState::Starting))
+                .await
+                .map(|s| s.clone())
+        })
+        .await;
+
+        let Ok(Ok(state)) = ready else {
+            return Err(crate::smarthome::Error::Communication(
+                "Plugin did not start in time".to_string(),
+            ));
+        };
+
+        match state {
+            ProcessState::Running(connection) => connection.request(method, params, deadline).await,
+            ProcessState::Starting => unreachable!(),
+            ProcessState::Failed(e) => Err(crate::smarthome::Error::Communication(format!(
+                "Plugin is not running: {e}"
+            ))),
+            ProcessState::Incompatible(e) => Err(crate::smarthome::Error::Configuration(e)),
+        }
+    }
+}
+
+/// Keep the plugin running, restarting it with backoff when it exits or
+/// fails to start
+async fn run(process: Arc<PluginProcess>) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+    loop {
+        let started_at = Instant::now();
+        let error = match start_and_serve(&process).await {
+            Ok(status) => {
+                tracing::warn!(command = process.settings.command, %status, "Plugin exited");
+                format!("Plugin exited with {status}")
+            }
+            Err(e) => {
+                let err_ref: &(dyn std::error::Error + Send + Sync) = e.as_ref();
+                tracing::warn!(
+                    command = process.settings.command,
+                    error = err_ref,
+                    "Plugin failed"
+                );
+                if let Some(incompatible) = e.downcast_ref::<Incompatible>() {
+                    process
+                        .state
+                        .send_replace(ProcessState::Incompatible(incompatible.0.clone()));
+                    return;
+                }
+                format!("{e:#}")
+            }
+        };
+
+        process.state.send_replace(ProcessState::Failed(error));
+        process.events.set_connected(false);
+
+        if started_at.elapsed() >= STABLE_RUN {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        tracing::debug!(
+            command = process.settings.command,
+            "Restarting plugin in {delay:?}"
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct Incompatible(String);
+
+/// Start the plugin, do the handshake and serve it until it exits
+async fn start_and_serve(process: &Arc<PluginProcess>) -> anyhow::Result<ExitStatus> {
+    let settings = &process.settings;
+    let mut child = Command::new(&settings.command)
+        .args(&settings.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Starting {} failed", settings.command))?;
+
+    let stdin = child.stdin.take().context("No stdin")?;
+    let stdout = child.stdout.take().context("No stdout")?;
+    let connection = Arc::new(Connection {
+        stdin: tokio::sync::Mutex::new(stdin),
+        pending: Default::default(),
+        next_id: AtomicU64::new(1),
+        timeouts: AtomicU32::new(0),
+        stuck: Notify::new(),
+    });
+
+    let mut reader = tokio::spawn(read_messages(stdout, connection.clone(), process.clone()));
+
+    let params = json!({ "protocolVersion": PROTOCOL_VERSION, "client": CLIENT_INFO });
+    let deadline = Instant::now() + settings.timeout;
+    let initialize = tokio::select! {
+        response = connection.request("initialize", params, deadline) => response,
+        _ = &mut reader => bail!("Plugin closed its output during the handshake"),
+    };
+    let result = match initialize {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => bail!("Plugin rejected initialize: {}", e.message),
+        Err(e) => return Err(anyhow!(e)),
+    };
+    let result: InitializeResult =
+        serde_json::from_value(result).context("Invalid initialize result")?;
+    if result.protocol_version != PROTOCOL_VERSION {
+        return Err(Incompatible(format!(
+            "Plugin protocol version {} is not supported, expected {PROTOCOL_VERSION}",
+            result.protocol_version
+        ))
+        .into());
+    }
+
+    tracing::info!(
+        command = settings.command,
+        name = result.name,
+        events = result.events,
+        "Plugin started"
+    );
+    process
+        .state
+        .send_replace(ProcessState::Running(connection.clone()));
+    process.events.set_connected(result.events);
+
+    tokio::select! {
+        _ = &mut reader => (),
+        _ = connection.stuck.notified() => {
+            // Processes started by the plugin may keep its output open
+            reader.abort();
+            connection.pending.lock().unwrap().clear();
+            child.kill().await?;
+            bail!("Plugin did not answer {MAX_CONSECUTIVE_TIMEOUTS} calls in a row");
+        }
+    }
+    process
+        .state
+        .send_replace(ProcessState::Failed("Plugin closed its output".to_string()));
+    Ok(child.wait().await?)
+}
+
+/// Dispatch responses and notifications until the plugin closes its output
+async fn read_messages(
+    stdout: ChildStdout,
+    connection: Arc<Connection>,
+    process: Arc<PluginProcess>,
+) {
+    let mut lines = BufReader::new(stdout).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: Message = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::warn!(error = %e, line, "Invalid message from plugin");
+                continue;
+            }
+        };
+
+        match (message.id, message.method) {
+            (Some(id), None) => {
+                let response = match message.error {
+                    Some(error) => Err(error),
+                    None => Ok(message.result.unwrap_or(Value::Null)),
+                };
+                if let Some(sender) = connection.pending.lock().unwrap().remove(&id) {
+                    let _ = sender.send(response);
+                }
+            }
+            (None, Some(method)) if method == "deviceChanged" => {
+                match message.params.get("id").and_then(Value::as_str) {
+                    Some(id) => process.events.send_changed(id),
+                    None => tracing::warn!("deviceChanged notification without ID"),
+                }
+            }
+            (_, method) => tracing::debug!(method, "Ignoring message from plugin"),
+        }
+    }
+
+    // Waiting calls fail when their senders are dropped
+    connection.pending.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use futures::StreamExt;
+
+    use super::*;
+
+    /// Answers `initialize` with the protocol version in `$1`, `ping` with
+    /// `pong`, `fail` with an error and `hang` not at all. `slow` is answered
+    /// after `$2` seconds. Each start is appended to the file in `$3`.
+    const PLUGIN: &str = r#"
+        echo start >> "$3"
+        while read -r line; do
+            id=$(echo "$line" | sed 's/.*"id":\([0-9][0-9]*\),"jsonrpc".*/\1/')
+            case "$line" in
+                *'"method":"initialize"'*)
+                    echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"protocolVersion\":$1,\"events\":true}}" ;;
+                *'"method":"ping"'*)
+                    echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":\"pong\"}" ;;
+                *'"method":"fail"'*)
+                    echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"error\":{\"code\":2,\"message\":\"No such light\"}}" ;;
+                *'"method":"slow"'*)
+                    sleep "$2"
+                    echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":\"late\"}" ;;
+                *'"method":"notify"'*)
+                    echo '{"jsonrpc":"2.0","method":"deviceChanged","params":{"id":"light-1"}}'
+                    echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":null}" ;;
+            esac
+        done
+    "#;
+
+    /// Exits right after starting
+    const CRASHING_PLUGIN: &str = r#"echo start >> "$3"; exit 1"#;
+
+    struct TestPlugin {
+        process: Arc<PluginProcess>,
+        starts_file: PathBuf,
+    }
+
+    impl TestPlugin {
+        fn new(name: &str, script: &str, protocol_version: u32, slow_seconds: f64) -> Self {
+            let starts_file = std::env::temp_dir().join(format!(
+                "light-operator-plugin-{name}-{}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&starts_file);
+
+            let process = PluginProcess::new(PluginSettings {
+                command: "sh".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    script.to_string(),
+                    "plugin".to_string(),
+                    protocol_version.to_string(),
+                    slow_seconds.to_string(),
+                    starts_file.to_string_lossy().into_owned(),
+                ],
+                timeout: Duration::from_millis(300),
+            });
+
+            Self {
+                process: Arc::new(process),
+                starts_file,
+            }
+        }
+
+        fn starts(&self) -> usize {
+            std::fs::read_to_string(&self.starts_file)
+                .map(|s| s.lines().count())
+                .unwrap_or(0)
+        }
+    }
+
+    impl Drop for TestPlugin {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.starts_file);
+        }
+    }
+
+    async fn sleep_ms(ms: u64) {
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+    }
+
+    #[tokio::test]
+    async fn calls_are_answered() {
+        let plugin = TestPlugin::new("answers", PLUGIN, PROTOCOL_VERSION, 0.0);
+        let process = &plugin.process;
+        let mut events = process.events.subscribe();
+
+        let result = process.call("ping", json!({})).await.unwrap().unwrap();
+        assert_eq!(result, json!("pong"));
+        assert!(process.events.is_connected());
+
+        let error = process.call("fail", json!({})).await.unwrap().unwrap_err();
+        assert_eq!(error.code, 2);
+        assert_eq!(error.message, "No such light");
+
+        // Connecting the events requests a resync
+        process.call("notify", json!({})).await.unwrap().unwrap();
+        let timeout = Duration::from_secs(1);
+        assert!(matches!(
+            tokio::time::timeout(timeout, events.next()).await.unwrap(),
+            Some(crate::smarthome::DeviceEvent::Resync)
+        ));
+        assert!(matches!(
+            tokio::time::timeout(timeout, events.next()).await.unwrap(),
+            Some(crate::smarthome::DeviceEvent::Changed(id)) if id == "light-1"
+        ));
+        assert_eq!(plugin.starts(), 1);
+    }
+
+    #[tokio::test]
+    async fn crashing_plugin_is_restarted_with_backoff() {
+        let plugin = TestPlugin::new("crashing", CRASHING_PLUGIN, PROTOCOL_VERSION, 0.0);
+
+        // Restarted after 1 s and then after 2 s more
+        let res = plugin.process.call("ping", json!({})).await;
+        assert!(matches!(
+            res,
+            Err(crate::smarthome::Error::Communication(_))
+        ));
+        sleep_ms(500).await;
+        assert_eq!(plugin.starts(), 1);
+        sleep_ms(1000).await;
+        assert_eq!(plugin.starts(), 2);
+        sleep_ms(1000).await;
+        assert_eq!(plugin.starts(), 2);
+        sleep_ms(1000).await;
+        assert_eq!(plugin.starts(), 3);
+        assert!(!plugin.process.events.is_connected());
+
+        let res = plugin.process.call("ping", json!({})).await;
+        assert!(
+            matches!(res, Err(crate::smarthome::Error::Communication(e)) if e.starts_with("Plugin is not running"))
+        );
+    }
+
+    #[tokio::test]
+    async fn incompatible_plugin_is_not_restarted() {
+        let plugin = TestPlugin::new("incompatible", PLUGIN, PROTOCOL_VERSION + 1, 0.0);
+
+        let res = plugin.process.call("ping", json!({})).await;
+        assert!(matches!(
+            res,
+            Err(crate::smarthome::Error::Configuration(_))
+        ));
+        sleep_ms(1500).await;
+        assert_eq!(plugin.starts(), 1);
+        let res = plugin.process.call("ping", json!({})).await;
+        assert!(matches!(
+            res,
+            Err(crate::smarthome::Error::Configuration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn late_answers_time_out() {
+        let plugin = TestPlugin::new("late", PLUGIN, PROTOCOL_VERSION, 0.5);
+        let process = &plugin.process;
+        process.call("ping", json!({})).await.unwrap().unwrap();
+
+        let started = Instant::now();
+        let res = process.call("slow", json!({})).await;
+        assert!(
+            matches!(res, Err(crate::smarthome::Error::Communication(e)) if e == "Plugin did not answer slow in time")
+        );
+        assert!(started.elapsed() < Duration::from_millis(450));
+
+        // The late answer doesn't get mixed up with the next call
+        let result = process.call("ping", json!({})).await.unwrap().unwrap();
+        assert_eq!(result, json!("pong"));
+        assert_eq!(plugin.starts(), 1);
+    }
+
+    #[tokio::test]
+    async fn stuck_plugin_is_restarted() {
+        let plugin = TestPlugin::new("stuck", PLUGIN, PROTOCOL_VERSION, 0.0);
+        let process = &plugin.process;
+        process.call("ping", json!({})).await.unwrap().unwrap();
+
+        // An answer in between starts the count over
+        for method in ["hang", "hang", "ping", "hang", "hang"] {
+            let _ = process.call(method, json!({})).await;
+        }
+        sleep_ms(200).await;
+        assert_eq!(plugin.starts(), 1);
+
+        let res = process.call("hang", json!({})).await;
+        assert!(matches!(
+            res,
+            Err(crate::smarthome::Error::Communication(_))
+        ));
+        sleep_ms(100).await;
+        let res = process.call("ping", json!({})).await;
+        assert!(
+            matches!(res, Err(crate::smarthome::Error::Communication(e)) if e.starts_with("Plugin is not running"))
+        );
+
+        // Restarted after the backoff
+        sleep_ms(1200).await;
+        assert_eq!(plugin.starts(), 2);
+        let result = process.call("ping", json!({})).await.unwrap().unwrap();
+        assert_eq!(result, json!("pong"));
+    }
+}
@@ -0,0 +1,63 @@
+use std::{collections::HashMap, sync::Mutex};
+
+/// Remembers the colors and brightness last requested for each device.
+///
+/// Many devices store colors with less precision than the Light resources
+/// use (mireds instead of Kelvin, 8-bit RGB, ...), or only accept part of the
+/// brightness range. Converting the device value back would then never
+/// exactly match the declared value, and the light would be re-set on every
+/// sync. Backends can instead report the requested value for as long as the
+/// device still holds what it was sent.
+#[derive(Default)]
+pub struct RequestedValues {
+    devices: Mutex<HashMap<String, Requested>>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Requested {
+    brightness: Option<u8>,
+    color_temperature: Option<u16>,
+    color: Option<(u8, u8)>,
+}
+
+impl RequestedValues {
+    pub fn set_brightness(&self, id: &str, brightness: u8) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(id.to_string()).or_default().brightness = Some(brightness);
+    }
+
+    pub fn set_color_temperature(&self, id: &str, kelvin: u16) {
+        let mut devices = self.devices.lock().unwrap();
+        let requested = devices.entry(id.to_string()).or_default();
+        requested.color_temperature = Some(kelvin);
+        requested.color = None;
+    }
+
+    pub fn set_color(&self, id: &str, hue: u8, saturation: u8) {
+        let mut devices = self.devices.lock().unwrap();
+        let requested = devices.entry(id.to_string()).or_default();
+        requested.color = Some((hue, saturation));
+        requested.color_temperature = None;
+    }
+
+    /// The last requested brightness, if `is_current` says the device still
+    /// has it
+    pub fn brightness(&self, id: &str, is_current: impl FnOnce(u8) -> bool) -> Option<u8> {
+        let requested = self.devices.lock().unwrap().get(id).copied()?;
+        requested.brightness.filter(|&b| is_current(b))
+    }
+
+    /// The last requested color temperature, if `is_current` says the device
+    /// still has it
+    pub fn color_temperature(&self, id: &str, is_current: impl FnOnce(u16) -> bool) -> Option<u16> {
+        let requested = self.devices.lock().unwrap().get(id).copied()?;
+        requested.color_temperature.filter(|&k| is_current(k))
+    }
+
+    /// The last requested hue and saturation, if `is_current` says the device
+    /// still has them
+    pub fn color(&self, id: &str, is_current: impl FnOnce(u8, u8) -> bool) -> Option<(u8, u8)> {
+        let requested = self.devices.lock().unwrap().get(id).copied()?;
+        requested.color.filter(|&(h, s)| is_current(h, s))
+    }
+}
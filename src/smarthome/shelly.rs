@@ -0,0 +1,408 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use api_models::*;
+use async_trait::async_trait;
+use reqwest::{header, Client, StatusCode, Url};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+
+use super::{color, requested::RequestedValues, LightOptions, LightStatus, SmartHomeApi};
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+/// Gen2 devices always use this user name
+const AUTH_USER: &str = "admin";
+
+/// Error code for unknown component IDs
+const ERROR_NOT_FOUND: i32 = -105;
+
+pub struct Shelly {
+    _config: Arc<Config>,
+    client: Client,
+    password: Option<String>,
+    components: Mutex<HashMap<String, Component>>,
+    requested: RequestedValues,
+    request_id: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Component {
+    Light,
+    Rgb,
+    Rgbw,
+    Switch,
+}
+
+impl Component {
+    const ALL: [Component; 4] = [
+        Component::Light,
+        Component::Rgb,
+        Component::Rgbw,
+        Component::Switch,
+    ];
+
+    /// Component type in method names, e.g. `Light` in `Light.Set`
+    fn method_prefix(&self) -> &'static str {
+        match self {
+            Component::Light => "Light",
+            Component::Rgb => "RGB",
+            Component::Rgbw => "RGBW",
+            Component::Switch => "Switch",
+        }
+    }
+
+    /// Component type in status keys, e.g. `light` in `light:0`
+    fn key(&self) -> &'static str {
+        match self {
+            Component::Light => "light",
+            Component::Rgb => "rgb",
+            Component::Rgbw => "rgbw",
+            Component::Switch => "switch",
+        }
+    }
+}
+
+/// A device ID split into its parts
+struct DeviceId<'a> {
+    host: &'a str,
+    index: u8,
+}
+
+impl Shelly {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let shelly_config = &config.smart_home.shelly;
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .timeout(Duration::from_millis(shelly_config.timeout_ms))
+            .build()
+            .unwrap();
+
+        Ok(Self {
+            password: shelly_config.password.clone(),
+            _config: config,
+            client,
+            components: Default::default(),
+            requested: Default::default(),
+            request_id: AtomicU64::new(1),
+        })
+    }
+
+    /// Device IDs are the host and the component index, e.g. `shelly-dimmer.lan#0`.
+    /// The index can be left out for single-channel devices.
+    fn parse_device_id(id: &str) -> super::Result<DeviceId<'_>> {
+        let invalid = || super::Error::InvalidId(id.to_string());
+
+        let (host, index) = match id.split_once('#') {
+            Some((host, index)) => (host, index.parse().map_err(|_| invalid())?),
+            None => (id, 0),
+        };
+
+        let valid_host = !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'));
+        if !valid_host {
+            return Err(invalid());
+        }
+
+        Ok(DeviceId { host, index })
+    }
+
+    /// Call an RPC method, answering digest authentication challenges if a
+    /// password is configured
+    async fn call(
+        &self,
+        host: &str,
+        method: &str,
+        params: serde_json::Value,
+    ) -> super::Result<serde_json::Value> {
+        let url: Url = format!("http://{host}/rpc")
+            .parse()
+            .map_err(|_| super::Error::InvalidId(host.to_string()))?;
+
+        let body = serde_json::json!({
+            "id": self.request_id.fetch_add(1, Ordering::Relaxed),
+            "method": method,
+            "params": params,
+        });
+
+        tracing::debug!(host, request = body.to_string(), "Calling RPC method");
+
+        let mut res = self.client.post(url.clone()).json(&body).send().await?;
+
+        if res.status() == StatusCode::UNAUTHORIZED {
+            let Some(password) = &self.password else {
+                return Err(super::Error::Configuration(format!(
+                    "Shelly device {host} requires a password"
+                )));
+            };
+            let challenge = res
+                .headers()
+                .get(header::WWW_AUTHENTICATE)
+                .and_then(|h| h.to_str().ok())
+                .and_then(DigestChallenge::parse)
+                .ok_or_else(|| {
+                    super::Error::Communication("Invalid authentication challenge".to_string())
+                })?;
+
+            let authorization = challenge.authorization(password, url.path());
+            res = self
+                .client
+                .post(url)
+                .header(header::AUTHORIZATION, authorization)
+                .json(&body)
+                .send()
+                .await?;
+        }
+
+        let res: RpcResponse = res.error_for_status()?.json().await?;
+
+        match (res.result, res.error) {
+            (_, Some(e)) if e.code == ERROR_NOT_FOUND => Err(super::Error::UnknownDeviceId),
+            (_, Some(e)) => Err(super::Error::Communication(format!(
+                "{method} failed: {} ({})",
+                e.message, e.code
+            ))),
+            (Some(result), None) => Ok(result),
+            (None, None) => Ok(serde_json::Value::Null),
+        }
+    }
+
+    /// Find out which kind of component the device ID refers to
+    async fn component(&self, id: &str, device: &DeviceId<'_>) -> super::Result<Component> {
+        if let Some(component) = self.components.lock().unwrap().get(id) {
+            return Ok(*component);
+        }
+
+        let status = self
+            .call(device.host, "Shelly.GetStatus", serde_json::json!({}))
+            .await?;
+
+        let component = Component::ALL
+            .into_iter()
+            .find(|c| {
+                status
+                    .get(format!("{}:{}", c.key(), device.index))
+                    .is_some()
+            })
+            .ok_or(super::Error::UnknownDeviceId)?;
+
+        tracing::debug!(device_id = id, ?component, "Detected component type");
+        self.components
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), component);
+        Ok(component)
+    }
+
+    async fn set(
+        &self,
+        id: &str,
+        supported: impl FnOnce(Component) -> bool,
+        capability: &'static str,
+        params: serde_json::Value,
+    ) -> super::Result<()> {
+        let device = Self::parse_device_id(id)?;
+        let component = self.component(id, &device).await?;
+        if !supported(component) {
+            return Err(super::Error::UnsupportedCapability(capability));
+        }
+
+        let mut params = params;
+        params["id"] = device.index.into();
+        let method = format!("{}.Set", component.method_prefix());
+        self.call(device.host, &method, params).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Shelly {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        let device = Self::parse_device_id(id)?;
+
+        tracing::debug!("Getting status for device {id}");
+
+        let status = async {
+            let component = self.component(id, &device).await?;
+            let method = format!("{}.GetStatus", component.method_prefix());
+            self.call(
+                device.host,
+                &method,
+                serde_json::json!({ "id": device.index }),
+            )
+            .await
+        }
+        .await;
+
+        let status = match status {
+            Ok(status) => status,
+            Err(super::Error::RequestFailed(e)) if e.is_connect() || e.is_timeout() => {
+                tracing::debug!(error = %e, "Shelly device is unreachable");
+                return Ok(LightStatus::Offline);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let status: ComponentStatus = serde_json::from_value(status)
+            .map_err(|e| super::Error::Communication(format!("Invalid status: {e}")))?;
+
+        tracing::debug!("Got status {status:#?}");
+
+        let brightness = status.brightness.map(|b| b.round().clamp(0.0, 100.0) as u8);
+
+        let mut color_temperature = None;
+        let mut color = None;
+        if let Some(rgb) = status.rgb {
+            color_temperature = self
+                .requested
+                .color_temperature(id, |k| color::to_rgb8(color::kelvin_to_rgb(k)) == rgb);
+
+            if color_temperature.is_none() {
+                let (hue, saturation) = self
+                    .requested
+                    .color(id, |h, s| color::to_rgb8(color::hs_to_rgb(h, s)) == rgb)
+                    .unwrap_or_else(|| {
+                        let (r, g, b) = color::from_rgb8(rgb);
+                        color::rgb_to_hs(r, g, b)
+                    });
+                color = Some(super::Color { hue, saturation });
+            }
+        }
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: status.output,
+            brightness,
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let params = serde_json::json!({ "on": switched_on });
+        self.set(id, |_| true, "switching", params).await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let params = serde_json::json!({ "brightness": brightness.clamp(0, 100) });
+        self.set(id, |c| c != Component::Switch, "brightness", params)
+            .await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let rgb = color::to_rgb8(color::kelvin_to_rgb(temp));
+        let params = serde_json::json!({ "rgb": rgb });
+        self.set(
+            id,
+            |c| matches!(c, Component::Rgb | Component::Rgbw),
+            "color temperature",
+            params,
+        )
+        .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let rgb = color::to_rgb8(color::hs_to_rgb(hue, saturation));
+        let params = serde_json::json!({ "rgb": rgb });
+        self.set(
+            id,
+            |c| matches!(c, Component::Rgb | Component::Rgbw),
+            "color",
+            params,
+        )
+        .await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+}
+
+/// A `WWW-Authenticate: Digest ...` challenge. Gen2 devices use SHA-256.
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+}
+
+impl DigestChallenge {
+    fn parse(header: &str) -> Option<Self> {
+        let params = header.strip_prefix("Digest ")?;
+
+        let mut realm = None;
+        let mut nonce = None;
+        for param in params.split(',') {
+            let Some((key, value)) = param.trim().split_once('=') else {
+                continue;
+            };
+            let value = value.trim_matches('"').to_string();
+            match key {
+                "realm" => realm = Some(value),
+                "nonce" => nonce = Some(value),
+                _ => (),
+            }
+        }
+
+        Some(Self {
+            realm: realm?,
+            nonce: nonce?,
+        })
+    }
+
+    fn authorization(&self, password: &str, uri: &str) -> String {
+        let hash = |s: String| format!("{:x}", Sha256::digest(s.as_bytes()));
+
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let cnonce = hash(format!("{nanos}:{}", self.nonce))[..16].to_string();
+        let nc = "00000001";
+
+        let ha1 = hash(format!("{AUTH_USER}:{}:{password}", self.realm));
+        let ha2 = hash(format!("POST:{uri}"));
+        let response = hash(format!("{ha1}:{}:{nc}:{cnonce}:auth:{ha2}", self.nonce));
+
+        format!(
+            r#"Digest username="{AUTH_USER}", realm="{}", nonce="{}", uri="{uri}", algorithm=SHA-256, response="{response}", qop=auth, nc={nc}, cnonce="{cnonce}""#,
+            self.realm, self.nonce
+        )
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    pub struct RpcError {
+        pub code: i32,
+        pub message: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct RpcResponse {
+        pub result: Option<serde_json::Value>,
+        pub error: Option<RpcError>,
+    }
+
+    /// Status of a Light, RGB, RGBW or Switch component
+    #[derive(Deserialize, Debug)]
+    pub struct ComponentStatus {
+        pub output: bool,
+        pub brightness: Option<f64>,
+        pub rgb: Option<[u8; 3]>,
+    }
+}
@@ -0,0 +1,540 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, Once},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::time::Instant;
+
+use crate::config::{Config, SimulatedLightConfig};
+
+use super::{
+    events::EventChannel, requested::RequestedValues, DeviceEventStream, DeviceInfo, LightOptions,
+    LightStatus, SmartHomeApi,
+};
+
+const DEFAULT_COLOR_TEMPERATURE_STEP: u16 = 100;
+const DEFAULT_COLOR_TEMPERATURE_RANGE: (u16, u16) = (2700, 6500);
+
+/// How often the simulation checks for lights going offline or changing
+const TICK: Duration = Duration::from_secs(1);
+
+/// Virtual lights kept in memory, for running the operator without devices
+pub struct Simulated {
+    _config: Arc<Config>,
+    simulation: Arc<Simulation>,
+    random_change_interval: Option<Duration>,
+    seed: Option<u64>,
+    requested: RequestedValues,
+    start_simulation: Once,
+}
+
+struct Simulation {
+    lights: Mutex<BTreeMap<String, Light>>,
+    started_at: Instant,
+    events: EventChannel,
+}
+
+struct Light {
+    name: Option<String>,
+    switched_on: bool,
+    brightness: Option<u8>,
+    color_temperature: Option<u16>,
+    color: Option<(u8, u8)>,
+    /// Whether the light shows `color` rather than `color_temperature`
+    color_mode: bool,
+    color_temperature_step: u16,
+    color_temperature_range: (u16, u16),
+    /// Period and the time at the end of it that the light is offline
+    offline_schedule: Option<(Duration, Duration)>,
+}
+
+impl Light {
+    fn from_config(light: &SimulatedLightConfig) -> super::Result<Self> {
+        let step = light
+            .color_temperature_step
+            .unwrap_or(DEFAULT_COLOR_TEMPERATURE_STEP)
+            .max(1);
+        let range = (
+            light
+                .min_color_temperature
+                .unwrap_or(DEFAULT_COLOR_TEMPERATURE_RANGE.0),
+            light
+                .max_color_temperature
+                .unwrap_or(DEFAULT_COLOR_TEMPERATURE_RANGE.1),
+        );
+        if range.0 > range.1 {
+            return Err(super::Error::Configuration(format!(
+                "Invalid color temperature range for simulated light {}",
+                light.id
+            )));
+        }
+
+        let offline_schedule = match light.offline_every_seconds {
+            Some(0) => {
+                return Err(super::Error::Configuration(format!(
+                    "Invalid offline schedule for simulated light {}",
+                    light.id
+                )))
+            }
+            Some(every) => Some((
+                Duration::from_secs(every),
+                Duration::from_secs(light.offline_seconds.min(every)),
+            )),
+            None => None,
+        };
+
+        let mut light = Self {
+            name: light.name.clone(),
+            switched_on: light.switched_on,
+            brightness: light.brightness.map(|b| b.min(100)),
+            color_temperature: light.color_temperature,
+            color: light
+                .hue
+                .map(|h| (h % 100, light.saturation.unwrap_or(100).min(100))),
+            color_mode: light.color_temperature.is_none(),
+            color_temperature_step: step,
+            color_temperature_range: range,
+            offline_schedule,
+        };
+        light.color_temperature = light.color_temperature.map(|k| light.round_kelvin(k));
+        Ok(light)
+    }
+
+    /// The color temperature the light ends up with, like a device that only
+    /// has a limited number of steps
+    fn round_kelvin(&self, kelvin: u16) -> u16 {
+        let step = self.color_temperature_step;
+        let (min, max) = self.color_temperature_range;
+        let rounded = (u32::from(kelvin) + u32::from(step) / 2) / u32::from(step) * u32::from(step);
+        (rounded.min(u32::from(u16::MAX)) as u16).clamp(min, max)
+    }
+
+    fn is_online(&self, elapsed: Duration) -> bool {
+        match self.offline_schedule {
+            Some((every, offline)) => {
+                let position = elapsed.as_millis() % every.as_millis();
+                position < (every - offline).as_millis()
+            }
+            None => true,
+        }
+    }
+
+    /// Change the light like a person would, e.g. switch it off or pick
+    /// another color
+    fn change_randomly(&mut self, rng: &mut impl Rng) {
+        match rng.gen_range(0..4) {
+            1 if self.brightness.is_some() => self.brightness = Some(rng.gen_range(1..=100)),
+            2 if self.color_temperature.is_some() => {
+                let (min, max) = self.color_temperature_range;
+                self.color_temperature = Some(self.round_kelvin(rng.gen_range(min..=max)));
+                self.color_mode = false;
+            }
+            3 if self.color.is_some() => {
+                self.color = Some((rng.gen_range(0..100), rng.gen_range(0..=100)));
+                self.color_mode = true;
+            }
+            _ => self.switched_on = !self.switched_on,
+        }
+    }
+}
+
+impl Simulation {
+    /// Apply `f` to the light if it is online
+    fn with_light<T>(&self, id: &str, f: impl FnOnce(&mut Light) -> T) -> super::Result<T> {
+        let mut lights = self.lights.lock().unwrap();
+        let light = lights.get_mut(id).ok_or(super::Error::UnknownDeviceId)?;
+        if !light.is_online(self.started_at.elapsed()) {
+            return Err(super::Error::Communication(format!(
+                "Simulated light {id} is offline"
+            )));
+        }
+        Ok(f(light))
+    }
+}
+
+impl Simulated {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let simulated_config = &config.smart_home.simulated;
+
+        let lights = simulated_config
+            .lights
+            .iter()
+            .map(|l| Ok((l.id.clone(), Light::from_config(l)?)))
+            .collect::<super::Result<_>>()?;
+
+        let random_change_interval = match simulated_config.random_change_interval_seconds {
+            Some(0) => {
+                return Err(super::Error::Configuration(
+                    "Random change interval must be positive".to_string(),
+                ))
+            }
+            interval => interval.map(Duration::from_secs),
+        };
+
+        Ok(Self {
+            simulation: Arc::new(Simulation {
+                lights: Mutex::new(lights),
+                started_at: Instant::now(),
+                events: EventChannel::new(),
+            }),
+            random_change_interval,
+            seed: simulated_config.seed,
+            _config: config,
+            requested: Default::default(),
+            start_simulation: Once::new(),
+        })
+    }
+}
+
+/// Publish the lights that go offline or come back online, and change
+/// lights randomly if enabled
+async fn simulate(
+    simulation: Arc<Simulation>,
+    random_change_interval: Option<Duration>,
+    seed: Option<u64>,
+) {
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    // Chance of a light changing on each tick
+    let change_probability =
+        random_change_interval.map(|i| (TICK.as_secs_f64() / i.as_secs_f64()).min(1.0));
+
+    let mut was_online = BTreeMap::new();
+    let mut interval = tokio::time::interval(TICK);
+    simulation.events.set_connected(true);
+
+    loop {
+        interval.tick().await;
+        let elapsed = simulation.started_at.elapsed();
+
+        let mut changed = Vec::new();
+        {
+            let mut lights = simulation.lights.lock().unwrap();
+            for (id, light) in lights.iter_mut() {
+                let online = light.is_online(elapsed);
+                if was_online
+                    .insert(id.clone(), online)
+                    .is_some_and(|was| was != online)
+                {
+                    tracing::info!(
+                        device_id = id,
+                        online,
+                        "Simulated light availability changed"
+                    );
+                    changed.push(id.clone());
+                    continue;
+                }
+
+                if online && change_probability.is_some_and(|p| rng.gen_bool(p)) {
+                    light.change_randomly(&mut rng);
+                    tracing::info!(device_id = id, "Simulated light was changed by a user");
+                    changed.push(id.clone());
+                }
+            }
+        }
+
+        for id in changed {
+            simulation.events.send_changed(id);
+        }
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Simulated {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let simulation = &self.simulation;
+        let lights = simulation.lights.lock().unwrap();
+        let light = lights.get(id).ok_or(super::Error::UnknownDeviceId)?;
+        if !light.is_online(simulation.started_at.elapsed()) {
+            return Ok(LightStatus::Offline);
+        }
+
+        let color_temperature =
+            light
+                .color_temperature
+                .filter(|_| !light.color_mode)
+                .map(|kelvin| {
+                    self.requested
+                        .color_temperature(id, |k| light.round_kelvin(k) == kelvin)
+                        .unwrap_or(kelvin)
+                });
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: light.switched_on,
+            brightness: light.brightness,
+            color_temperature,
+            color: light
+                .color
+                .filter(|_| light.color_mode)
+                .map(|(hue, saturation)| super::Color { hue, saturation }),
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        self.simulation
+            .with_light(id, |light| light.switched_on = switched_on)
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        self.simulation.with_light(id, |light| {
+            let Some(current) = light.brightness.as_mut() else {
+                return Err(super::Error::UnsupportedCapability("brightness"));
+            };
+            *current = brightness.min(100);
+            Ok(())
+        })?
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        self.simulation.with_light(id, |light| {
+            if light.color_temperature.is_none() {
+                return Err(super::Error::UnsupportedCapability("color temperature"));
+            }
+            light.color_temperature = Some(light.round_kelvin(temp));
+            light.color_mode = false;
+            Ok(())
+        })??;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        self.simulation.with_light(id, |light| {
+            let Some(color) = light.color.as_mut() else {
+                return Err(super::Error::UnsupportedCapability("color"));
+            };
+            *color = (hue % 100, saturation.min(100));
+            light.color_mode = true;
+            Ok(())
+        })?
+    }
+
+    fn device_events(&self) -> Option<DeviceEventStream> {
+        let stream = self.simulation.events.subscribe();
+        self.start_simulation.call_once(|| {
+            tokio::spawn(simulate(
+                self.simulation.clone(),
+                self.random_change_interval,
+                self.seed,
+            ));
+        });
+        Some(stream)
+    }
+
+    fn events_connected(&self) -> bool {
+        self.simulation.events.is_connected()
+    }
+
+    async fn list_devices(&self) -> super::Result<Vec<DeviceInfo>> {
+        Ok(self
+            .simulation
+            .lights
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, light)| DeviceInfo {
+                id: id.clone(),
+                name: light.name.clone(),
+                model: Some("Simulated".to_string()),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::test_config;
+
+    use super::*;
+
+    fn config(lights: serde_json::Value) -> Arc<Config> {
+        test_config(serde_json::json!({
+            "platform": "Simulated",
+            "simulated": { "lights": lights },
+        }))
+    }
+
+    fn light(config: serde_json::Value) -> Light {
+        Light::from_config(&serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn kelvin_is_rounded_to_steps_and_clamped() {
+        let light = self::light(serde_json::json!({ "id": "1", "color_temperature": 4000 }));
+        assert_eq!(light.round_kelvin(4049), 4000);
+        assert_eq!(light.round_kelvin(4050), 4100);
+        assert_eq!(light.round_kelvin(1000), 2700);
+        assert_eq!(light.round_kelvin(12000), 6500);
+        assert_eq!(light.round_kelvin(u16::MAX), 6500);
+
+        let light = self::light(serde_json::json!({
+            "id": "1",
+            "color_temperature": 3200,
+            "color_temperature_step": 500,
+            "min_color_temperature": 2000,
+            "max_color_temperature": 9000,
+        }));
+        assert_eq!(light.round_kelvin(3100), 3000);
+        assert_eq!(light.round_kelvin(3300), 3500);
+        assert_eq!(light.round_kelvin(1000), 2000);
+        assert_eq!(light.color_temperature, Some(3000));
+    }
+
+    #[test]
+    fn invalid_light_configs_are_rejected() {
+        let config: SimulatedLightConfig = serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "min_color_temperature": 5000,
+            "max_color_temperature": 3000,
+        }))
+        .unwrap();
+        assert!(matches!(
+            Light::from_config(&config),
+            Err(super::super::Error::Configuration(_))
+        ));
+
+        let config: SimulatedLightConfig = serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "offline_every_seconds": 0,
+        }))
+        .unwrap();
+        assert!(matches!(
+            Light::from_config(&config),
+            Err(super::super::Error::Configuration(_))
+        ));
+    }
+
+    #[test]
+    fn light_is_offline_at_the_end_of_each_period() {
+        let light = self::light(serde_json::json!({
+            "id": "1",
+            "offline_every_seconds": 60,
+            "offline_seconds": 10,
+        }));
+        let at = |secs: f64| light.is_online(Duration::from_secs_f64(secs));
+        assert!(at(0.0));
+        assert!(at(49.9));
+        assert!(!at(50.0));
+        assert!(!at(59.9));
+        assert!(at(60.0));
+        assert!(at(109.9));
+        assert!(!at(110.0));
+
+        // Offline time is capped to the period
+        let light = self::light(serde_json::json!({
+            "id": "1",
+            "offline_every_seconds": 5,
+            "offline_seconds": 10,
+        }));
+        assert!(!light.is_online(Duration::ZERO));
+        assert!(!light.is_online(Duration::from_secs(7)));
+
+        let light = self::light(serde_json::json!({ "id": "1" }));
+        assert!(light.is_online(Duration::from_secs(1_000_000)));
+    }
+
+    #[test]
+    fn random_changes_are_repeatable_with_a_seed() {
+        let new_light = || {
+            light(serde_json::json!({
+                "id": "1",
+                "brightness": 50,
+                "color_temperature": 4000,
+                "hue": 10,
+                "saturation": 20,
+            }))
+        };
+        let state = |l: &Light| {
+            (
+                l.switched_on,
+                l.brightness,
+                l.color_temperature,
+                l.color,
+                l.color_mode,
+            )
+        };
+
+        let mut first = new_light();
+        let mut second = new_light();
+        let mut first_rng = StdRng::seed_from_u64(42);
+        let mut second_rng = StdRng::seed_from_u64(42);
+        let mut states = Vec::new();
+        for _ in 0..50 {
+            first.change_randomly(&mut first_rng);
+            second.change_randomly(&mut second_rng);
+            assert_eq!(state(&first), state(&second));
+            states.push(state(&first));
+
+            let kelvin = first.color_temperature.unwrap();
+            assert_eq!(kelvin % 100, 0);
+            assert!((2700..=6500).contains(&kelvin));
+            let (hue, saturation) = first.color.unwrap();
+            assert!(hue < 100 && saturation <= 100);
+            assert!((1..=100).contains(&first.brightness.unwrap()));
+        }
+
+        // Every kind of change happens
+        assert!(states.iter().any(|s| s.0) && states.iter().any(|s| !s.0));
+        assert!(states.iter().any(|s| s.1 != Some(50)));
+        assert!(states.iter().any(|s| s.2 != Some(4000)));
+        assert!(states.iter().any(|s| s.3 != Some((10, 20))));
+    }
+
+    #[test]
+    fn lights_only_change_what_they_support() {
+        let mut light = self::light(serde_json::json!({ "id": "1" }));
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..20 {
+            light.change_randomly(&mut rng);
+            assert_eq!(light.brightness, None);
+            assert_eq!(light.color_temperature, None);
+            assert_eq!(light.color, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn requested_color_temperature_is_reported() {
+        let simulated = Simulated::new(config(serde_json::json!([
+            { "id": "1", "switched_on": true, "brightness": 20, "color_temperature": 3000, "hue": 10 },
+            { "id": "2", "offline_every_seconds": 3600, "offline_seconds": 3600 },
+        ])))
+        .unwrap();
+
+        simulated.set_color_temperature("1", 12000).await.unwrap();
+        let LightStatus::Online(options) = simulated.get_light_status("1").await.unwrap() else {
+            panic!("light is offline");
+        };
+        assert_eq!(options.color_temperature, Some(12000));
+        assert!(options.color.is_none());
+        assert_eq!(options.brightness, Some(20));
+
+        simulated.set_color("1", 30, 40).await.unwrap();
+        let LightStatus::Online(options) = simulated.get_light_status("1").await.unwrap() else {
+            panic!("light is offline");
+        };
+        assert_eq!(options.color_temperature, None);
+        assert_eq!(options.color.map(|c| (c.hue, c.saturation)), Some((30, 40)));
+
+        assert!(matches!(
+            simulated.get_light_status("2").await,
+            Ok(LightStatus::Offline)
+        ));
+        assert!(matches!(
+            simulated.set_switched_on("2", true).await,
+            Err(super::super::Error::Communication(_))
+        ));
+        assert!(matches!(
+            simulated.get_light_status("3").await,
+            Err(super::super::Error::UnknownDeviceId)
+        ));
+    }
+}
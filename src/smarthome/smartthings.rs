@@ -188,6 +188,7 @@ impl SmartHomeApi for SmartThings {
             brightness,
             color_temperature,
             color,
+            effect: None,
         }))
     }
 
@@ -0,0 +1,702 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use api_models::*;
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use rumqttc::{AsyncClient, Event, EventLoop, Packet, QoS};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::config::{Config, TasmotaDeviceConfig, TasmotaTransport};
+
+use super::{
+    color,
+    events::{Backoff, EventChannel},
+    mqtt,
+    requested::RequestedValues,
+    DeviceEventStream, LightOptions, LightStatus, SmartHomeApi,
+};
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+const CLIENT_ID: &str = "light-operator-tasmota";
+
+/// Tasmota's user name for the web admin password
+const HTTP_USER: &str = "admin";
+
+/// Start of the warning that `/cm` answers with when the password is missing
+/// or wrong
+const AUTH_WARNING: &str = "Need user=";
+
+pub struct Tasmota {
+    config: Arc<Config>,
+    http_client: Client,
+    mqtt: Option<MqttConnection>,
+    timeout: Duration,
+    requested: RequestedValues,
+}
+
+/// Connection for the devices using the MQTT transport
+struct MqttConnection {
+    client: AsyncClient,
+    cache: Arc<StateCache>,
+    events: Arc<EventChannel>,
+}
+
+/// Latest state and LWT messages received for each device topic
+#[derive(Default)]
+struct StateCache {
+    topics: Mutex<HashMap<String, CachedTopic>>,
+    updated: Notify,
+}
+
+#[derive(Default, Clone)]
+struct CachedTopic {
+    /// All `STATE` and `RESULT` fields received, merged together
+    state: serde_json::Map<String, serde_json::Value>,
+    online: Option<bool>,
+}
+
+/// Where a device is reached
+enum Target<'a> {
+    Http(&'a str),
+    Mqtt(&'a str),
+}
+
+impl Tasmota {
+    /// Connects to the MQTT broker in the background if any device uses it,
+    /// so this must be called within a Tokio runtime
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let tasmota_config = &config.smart_home.tasmota;
+        let timeout = Duration::from_millis(tasmota_config.timeout_ms);
+
+        for device in &tasmota_config.devices {
+            if device.transport == TasmotaTransport::Http && device.host.is_none() {
+                return Err(super::Error::Configuration(format!(
+                    "Tasmota device {} uses HTTP but has no host",
+                    device.id
+                )));
+            }
+        }
+
+        let http_client = reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .timeout(timeout)
+            .build()
+            .unwrap();
+
+        let mqtt_devices: Vec<_> = tasmota_config
+            .devices
+            .iter()
+            .filter(|d| d.transport == TasmotaTransport::Mqtt)
+            .map(|d| (Self::mqtt_topic(d).to_string(), d.id.clone()))
+            .collect();
+
+        let mqtt = if mqtt_devices.is_empty() {
+            None
+        } else {
+            let (client, event_loop) = mqtt::create_client(&tasmota_config.mqtt, CLIENT_ID)?;
+            let cache: Arc<StateCache> = Default::default();
+            let events: Arc<EventChannel> = Default::default();
+
+            tokio::spawn(run_event_loop(
+                event_loop,
+                client.clone(),
+                mqtt_devices,
+                cache.clone(),
+                events.clone(),
+            ));
+
+            Some(MqttConnection {
+                client,
+                cache,
+                events,
+            })
+        };
+
+        Ok(Self {
+            config,
+            http_client,
+            mqtt,
+            timeout,
+            requested: Default::default(),
+        })
+    }
+
+    fn mqtt_topic(device: &TasmotaDeviceConfig) -> &str {
+        device.topic.as_deref().unwrap_or(&device.id)
+    }
+
+    /// Device IDs are the IDs of the devices listed in the configuration
+    fn target(&self, id: &str) -> super::Result<Target<'_>> {
+        let device = self
+            .config
+            .smart_home
+            .tasmota
+            .devices
+            .iter()
+            .find(|d| d.id == id)
+            .ok_or(super::Error::UnknownDeviceId)?;
+
+        match device.transport {
+            // Checked when the backend is created
+            TasmotaTransport::Http => Ok(Target::Http(device.host.as_deref().unwrap())),
+            TasmotaTransport::Mqtt => Ok(Target::Mqtt(Self::mqtt_topic(device))),
+        }
+    }
+
+    /// Run a command over the `/cm` HTTP interface
+    async fn http_command(&self, host: &str, command: &str) -> super::Result<CommandResult> {
+        let mut url: Url = format!("http://{host}/cm")
+            .parse()
+            .map_err(|_| super::Error::Configuration(format!("Invalid Tasmota host `{host}`")))?;
+
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(password) = &self.config.smart_home.tasmota.password {
+                query
+                    .append_pair("user", HTTP_USER)
+                    .append_pair("password", password);
+            }
+            query.append_pair("cmnd", command);
+        }
+
+        tracing::debug!(host, command, "Sending command");
+
+        let res = self.http_client.get(url).send().await?;
+        let result: CommandResult = res.error_for_status()?.json().await?;
+
+        match &result.warning {
+            Some(warning) if warning.starts_with(AUTH_WARNING) => {
+                Err(super::Error::Communication(warning.clone()))
+            }
+            Some(warning) => {
+                tracing::debug!(host, command, warning, "Command answered with a warning");
+                Ok(result)
+            }
+            None => Ok(result),
+        }
+    }
+
+    /// Publish a command to `cmnd/<topic>/<command>`
+    async fn mqtt_command(&self, topic: &str, command: &str, payload: &str) -> super::Result<()> {
+        let Some(mqtt) = &self.mqtt else {
+            return Err(super::Error::Configuration(
+                "Tasmota MQTT connection not configured".to_string(),
+            ));
+        };
+
+        let topic = format!("cmnd/{topic}/{command}");
+        tracing::debug!(topic, payload, "Publishing");
+
+        mqtt.client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .await?;
+        Ok(())
+    }
+
+    async fn command(
+        &self,
+        id: &str,
+        command: &str,
+        payload: &str,
+        capability: &'static str,
+    ) -> super::Result<()> {
+        match self.target(id)? {
+            Target::Http(host) => {
+                let result = self
+                    .http_command(host, &format!("{command} {payload}"))
+                    .await?;
+                if result.command.as_deref() == Some("Unknown") {
+                    return Err(super::Error::UnsupportedCapability(capability));
+                }
+                Ok(())
+            }
+            Target::Mqtt(topic) => self.mqtt_command(topic, command, payload).await,
+        }
+    }
+
+    /// Get the state of a device over MQTT, requesting it with the `State`
+    /// command if it has not been received yet. `None` means that the device
+    /// is offline.
+    async fn mqtt_state(&self, topic: &str) -> super::Result<Option<DeviceState>> {
+        let Some(mqtt) = &self.mqtt else {
+            return Err(super::Error::Configuration(
+                "Tasmota MQTT connection not configured".to_string(),
+            ));
+        };
+
+        // States may have changed without us hearing about it
+        if !mqtt.events.is_connected() {
+            return Err(super::Error::Communication(
+                "Not connected to MQTT broker".to_string(),
+            ));
+        }
+
+        let cached = |cache: &StateCache| {
+            let topic = cache.get(topic)?;
+            if topic.online == Some(false) {
+                return Some(None);
+            }
+            // Partial results only contain the values that changed
+            if !topic.state.contains_key("POWER") && !topic.state.contains_key("POWER1") {
+                return None;
+            }
+            serde_json::from_value(topic.state.into()).ok().map(Some)
+        };
+
+        if let Some(state) = cached(&mqtt.cache) {
+            return Ok(state);
+        }
+
+        self.mqtt_command(topic, "State", "").await?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let updated = mqtt.cache.updated.notified();
+            if let Some(state) = cached(&mqtt.cache) {
+                return Ok(state);
+            }
+            if tokio::time::timeout_at(deadline, updated).await.is_err() {
+                tracing::debug!(topic, "Tasmota device did not answer");
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Tasmota {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let state = match self.target(id)? {
+            Target::Http(host) => match self.http_command(host, "State").await {
+                Ok(result) if result.state.power().is_some() => Some(result.state),
+                Ok(_) => {
+                    return Err(super::Error::Communication(
+                        "State has no power status".to_string(),
+                    ))
+                }
+                Err(super::Error::RequestFailed(e)) if e.is_connect() || e.is_timeout() => {
+                    tracing::debug!(error = %e, "Tasmota device is unreachable");
+                    None
+                }
+                Err(e) => return Err(e),
+            },
+            Target::Mqtt(topic) => self.mqtt_state(topic).await?,
+        };
+
+        let Some(state) = state else {
+            return Ok(LightStatus::Offline);
+        };
+
+        tracing::debug!("Got status {state:#?}");
+
+        let hsb = state.hsb_color.as_deref().and_then(parse_hsb);
+
+        let color_temperature = state
+            .ct
+            .filter(|_| hsb.is_none() || state.white_mode())
+            .map(|mired| {
+                self.requested
+                    .color_temperature(id, |k| color::kelvin_to_mired(k) == mired)
+                    .unwrap_or_else(|| color::mired_to_kelvin(mired))
+            });
+
+        let color = hsb
+            .filter(|_| color_temperature.is_none())
+            .map(|(hue, saturation)| {
+                let (hue, saturation) = self
+                    .requested
+                    .color(id, |rh, rs| {
+                        rh.abs_diff(hue) <= 1 && rs.abs_diff(saturation) <= 1
+                    })
+                    .unwrap_or((hue, saturation));
+                super::Color { hue, saturation }
+            });
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: state.power() == Some("ON"),
+            brightness: state.dimmer,
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let power = if switched_on { "ON" } else { "OFF" };
+        self.command(id, "Power", power, "switching").await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let dimmer = brightness.clamp(0, 100).to_string();
+        self.command(id, "Dimmer", &dimmer, "brightness").await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let mired = color::kelvin_to_mired(temp).to_string();
+        self.command(id, "CT", &mired, "color temperature").await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        // Set hue and saturation only, leaving the brightness as it is.
+        // Backlog would do both at once, but doesn't answer over HTTP.
+        let hue_degrees = (f64::from(hue) * 3.6).round() as u16 % 360;
+        self.command(id, "HSBColor1", &hue_degrees.to_string(), "color")
+            .await?;
+        self.command(id, "HSBColor2", &saturation.min(100).to_string(), "color")
+            .await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    fn device_events(&self) -> Option<DeviceEventStream> {
+        self.mqtt.as_ref().map(|mqtt| mqtt.events.subscribe())
+    }
+
+    fn events_connected(&self) -> bool {
+        // HTTP devices can only be polled
+        let all_mqtt = self
+            .config
+            .smart_home
+            .tasmota
+            .devices
+            .iter()
+            .all(|d| d.transport == TasmotaTransport::Mqtt);
+
+        all_mqtt
+            && self
+                .mqtt
+                .as_ref()
+                .is_some_and(|mqtt| mqtt.events.is_connected())
+    }
+}
+
+/// Parse `HSBColor` (`<hue 0-360>,<saturation>,<brightness>`) into hue and
+/// saturation percentages
+fn parse_hsb(value: &str) -> Option<(u8, u8)> {
+    let mut parts = value.split(',').map(|p| p.trim().parse::<f64>());
+    let hue = parts.next()?.ok()?;
+    let saturation = parts.next()?.ok()?;
+    Some((
+        (hue / 3.6).round() as u8 % 100,
+        saturation.round().clamp(0.0, 100.0) as u8,
+    ))
+}
+
+impl StateCache {
+    fn get(&self, topic: &str) -> Option<CachedTopic> {
+        self.topics.lock().unwrap().get(topic).cloned()
+    }
+
+    /// Forget all device states, which are requested again after reconnecting
+    fn clear(&self) {
+        self.topics.lock().unwrap().clear();
+    }
+
+    /// Returns the topic of the device if the message was about one
+    fn handle_message<'a>(&self, topic: &'a str, payload: &[u8]) -> Option<&'a str> {
+        let mut parts = topic.splitn(3, '/');
+        let (prefix, device_topic, suffix) = (parts.next()?, parts.next()?, parts.next()?);
+
+        let mut topics = self.topics.lock().unwrap();
+        match (prefix, suffix) {
+            ("tele", "LWT") => {
+                let entry = topics.entry(device_topic.to_string()).or_default();
+                entry.online = Some(payload == b"Online");
+            }
+            ("tele", "STATE") | ("stat", "RESULT") => {
+                let serde_json::Value::Object(fields) = serde_json::from_slice(payload).ok()?
+                else {
+                    return None;
+                };
+                let entry = topics.entry(device_topic.to_string()).or_default();
+                entry.state.extend(fields);
+                // Getting state means that the device is up, even if the
+                // LWT message was missed
+                entry.online = Some(true);
+            }
+            _ => return None,
+        }
+
+        self.updated.notify_waiters();
+        Some(device_topic)
+    }
+}
+
+async fn run_event_loop(
+    mut event_loop: EventLoop,
+    client: AsyncClient,
+    devices: Vec<(String, String)>,
+    cache: Arc<StateCache>,
+    events: Arc<EventChannel>,
+) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to MQTT broker");
+                backoff.reset();
+                // Subscriptions don't survive reconnects with a clean session
+                for (topic, _) in &devices {
+                    for filter in [format!("tele/{topic}/+"), format!("stat/{topic}/RESULT")] {
+                        if let Err(e) = client.subscribe(filter, QoS::AtMostOnce).await {
+                            tracing::warn!(error = %e, "Subscribing to Tasmota topics failed");
+                        }
+                    }
+                }
+                events.set_connected(true);
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                if let Some(topic) = cache.handle_message(&publish.topic, &publish.payload) {
+                    devices
+                        .iter()
+                        .filter(|(t, _)| t == topic)
+                        .for_each(|(_, id)| events.send_changed(id.clone()));
+                }
+            }
+            Ok(_) => (),
+            Err(e) => {
+                events.set_connected(false);
+                cache.clear();
+                let delay = backoff.next_delay();
+                tracing::warn!(error = %e, "MQTT connection failed, retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug, Default)]
+    #[serde(default)]
+    pub struct DeviceState {
+        #[serde(rename = "POWER")]
+        pub power: Option<String>,
+        /// Devices with several relays report the light as `POWER1`, and
+        /// merged states can have both
+        #[serde(rename = "POWER1")]
+        pub power1: Option<String>,
+        #[serde(rename = "Dimmer")]
+        pub dimmer: Option<u8>,
+        /// Color temperature in mireds
+        #[serde(rename = "CT")]
+        pub ct: Option<u16>,
+        #[serde(rename = "HSBColor")]
+        pub hsb_color: Option<String>,
+        /// Channel values as hex (`FF8000`) or, with `SetOption17`,
+        /// comma-separated decimals
+        #[serde(rename = "Color")]
+        pub color: Option<String>,
+    }
+
+    impl DeviceState {
+        pub fn power(&self) -> Option<&str> {
+            self.power.as_deref().or(self.power1.as_deref())
+        }
+
+        /// Whether a light with both RGB and white channels is showing white
+        pub fn white_mode(&self) -> bool {
+            let Some(color) = &self.color else {
+                return false;
+            };
+
+            let channels: Vec<u8> = if color.contains(',') {
+                color
+                    .split(',')
+                    .filter_map(|c| c.trim().parse().ok())
+                    .collect()
+            } else {
+                (0..color.len() / 2)
+                    .filter_map(|i| u8::from_str_radix(color.get(i * 2..i * 2 + 2)?, 16).ok())
+                    .collect()
+            };
+
+            channels.len() > 3 && channels[..3].iter().all(|&c| c == 0)
+        }
+    }
+
+    /// Response of the `/cm` HTTP interface
+    #[derive(Deserialize, Debug)]
+    pub struct CommandResult {
+        /// `Unknown` for unsupported commands
+        #[serde(rename = "Command")]
+        pub command: Option<String>,
+        /// Set when authentication is required, and for commands that don't
+        /// answer over HTTP, like `Backlog`
+        #[serde(rename = "WARNING")]
+        pub warning: Option<String>,
+        #[serde(flatten)]
+        pub state: DeviceState,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::test_config;
+
+    use super::*;
+
+    fn tasmota() -> Tasmota {
+        let config = test_config(serde_json::json!({
+            "platform": "Tasmota",
+            "tasmota": {
+                // Nothing listens here, so the client never connects
+                "mqtt": { "broker_url": "mqtt://127.0.0.1:1" },
+                "devices": [{ "id": "lamp", "transport": "Mqtt" }],
+            },
+        }));
+        Tasmota::new(config).unwrap()
+    }
+
+    #[test]
+    fn caches_states_and_lwt() {
+        let cache = StateCache::default();
+
+        assert_eq!(
+            cache.handle_message("tele/lamp/STATE", br#"{"POWER":"ON","Dimmer":40}"#),
+            Some("lamp")
+        );
+        assert_eq!(
+            cache.handle_message("stat/lamp/RESULT", br#"{"Dimmer":60}"#),
+            Some("lamp")
+        );
+        assert_eq!(cache.handle_message("cmnd/lamp/Power", b"OFF"), None);
+
+        let topic = cache.get("lamp").unwrap();
+        assert_eq!(topic.online, Some(true));
+        assert_eq!(topic.state["POWER"], "ON");
+        assert_eq!(topic.state["Dimmer"], 60);
+
+        cache.handle_message("tele/lamp/LWT", b"Offline");
+        assert_eq!(cache.get("lamp").unwrap().online, Some(false));
+
+        cache.clear();
+        assert!(cache.get("lamp").is_none());
+    }
+
+    /// Commands received by a device stand-in on the `/cm` HTTP interface
+    type Commands = Arc<Mutex<Vec<String>>>;
+
+    /// An HTTP device that answers like Tasmota, requiring `password` if set
+    async fn http_device(password: Option<&'static str>) -> (String, Commands) {
+        use axum::{extract::State, http::Uri, routing::get, Json, Router};
+        use serde_json::{json, Value};
+
+        async fn cm(
+            State((commands, password)): State<(Commands, Option<&'static str>)>,
+            uri: Uri,
+        ) -> Json<Value> {
+            let url: Url = format!("http://device{uri}").parse().unwrap();
+            let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+            if password.is_some_and(|p| query.get("password").map(String::as_str) != Some(p)) {
+                return Json(json!({ "WARNING": "Need user=<username>&password=<password>" }));
+            }
+
+            let command = query["cmnd"].clone();
+            commands.lock().unwrap().push(command.clone());
+            let (name, value) = command.split_once(' ').unwrap_or((&command, ""));
+            Json(match name {
+                "State" => json!({
+                    "POWER": "ON", "Dimmer": 50, "HSBColor": "180,40,50", "CT": 153,
+                    "Color": "408080000000",
+                }),
+                "HSBColor1" => json!({ "HSBColor": format!("{value},40,50") }),
+                "HSBColor2" => json!({ "HSBColor": format!("180,{value},50") }),
+                "Backlog" => json!({ "WARNING": "Enable weblog 2 if response expected" }),
+                _ => json!({ "Command": "Unknown" }),
+            })
+        }
+
+        let commands = Commands::default();
+        let app = Router::new()
+            .route("/cm", get(cm))
+            .with_state((commands.clone(), password));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (host, commands)
+    }
+
+    fn http_tasmota(host: &str, password: Option<&str>) -> Tasmota {
+        let config = test_config(serde_json::json!({
+            "platform": "Tasmota",
+            "tasmota": {
+                "password": password,
+                "devices": [{ "id": "lamp", "transport": "Http", "host": host }],
+            },
+        }));
+        Tasmota::new(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn controls_http_devices() {
+        let (host, commands) = http_device(Some("secret")).await;
+        let tasmota = http_tasmota(&host, Some("secret"));
+
+        let LightStatus::Online(options) = tasmota.get_light_status("lamp").await.unwrap() else {
+            panic!("lamp is offline");
+        };
+        assert!(options.switched_on);
+        assert_eq!(options.brightness, Some(50));
+        assert_eq!(options.color.map(|c| (c.hue, c.saturation)), Some((50, 40)));
+        assert_eq!(options.color_temperature, None);
+
+        tasmota.set_color("lamp", 25, 80).await.unwrap();
+        assert_eq!(
+            commands.lock().unwrap()[1..],
+            ["HSBColor1 90", "HSBColor2 80"]
+        );
+
+        // Backlog only answers with a warning over HTTP
+        tasmota
+            .http_command(&host, "Backlog Power ON; Dimmer 20")
+            .await
+            .unwrap();
+
+        // The stand-in doesn't know Dimmer
+        assert!(matches!(
+            tasmota.set_brightness("lamp", 20).await,
+            Err(crate::smarthome::Error::UnsupportedCapability("brightness"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn http_devices_need_the_password() {
+        let (host, commands) = http_device(Some("secret")).await;
+        let tasmota = http_tasmota(&host, Some("wrong"));
+
+        assert!(matches!(
+            tasmota.set_color("lamp", 25, 80).await,
+            Err(crate::smarthome::Error::Communication(_))
+        ));
+        assert!(commands.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn does_not_report_cached_states_while_disconnected() {
+        let tasmota = tasmota();
+        let mqtt = tasmota.mqtt.as_ref().unwrap();
+        mqtt.cache
+            .handle_message("tele/lamp/STATE", br#"{"POWER":"ON"}"#);
+
+        assert!(matches!(
+            tasmota.get_light_status("lamp").await,
+            Err(crate::smarthome::Error::Communication(_))
+        ));
+    }
+}
@@ -0,0 +1,736 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use serde_json::{json, Map, Value};
+use tokio::sync::Mutex;
+
+use crate::config::{Config, TuyaDeviceConfig};
+
+use self::protocol::{ProtocolError, Session, Version};
+
+use super::{requested::RequestedValues, LightOptions, LightStatus, SmartHomeApi};
+
+mod protocol;
+
+const PORT: u16 = 6668;
+
+const DEFAULT_COLOR_TEMPERATURE_RANGE: (u16, u16) = (2700, 6500);
+
+/// Standard data points of Tuya lights
+mod dp {
+    /// `switch_led`, boolean
+    pub const SWITCH: &str = "20";
+    /// `work_mode`: `white`, `colour`, `scene` or `music`
+    pub const WORK_MODE: &str = "21";
+    /// `bright_value`, 10-1000
+    pub const BRIGHT: &str = "22";
+    /// `temp_value`, 0-1000 from warm to cold
+    pub const TEMP: &str = "23";
+    /// `colour_data`, hue 0-360, saturation 0-1000 and value 0-1000 as 4
+    /// hex digits each
+    pub const COLOUR: &str = "24";
+}
+
+/// Tuya and Smart Life lights controlled over the local network
+pub struct Tuya {
+    _config: Arc<Config>,
+    devices: HashMap<String, Device>,
+    timeout: Duration,
+    requested: RequestedValues,
+}
+
+struct Device {
+    address: SocketAddr,
+    version: Version,
+    local_key: [u8; 16],
+    color_temperature_range: (u16, u16),
+    /// Connection kept open between requests, until the device closes it
+    session: Mutex<Option<Session>>,
+}
+
+impl Device {
+    fn from_config(device: &TuyaDeviceConfig) -> super::Result<Self> {
+        let invalid = |what: &str| {
+            super::Error::Configuration(format!("Invalid {what} for Tuya device {}", device.id))
+        };
+
+        let address = match device.address.parse::<SocketAddr>() {
+            Ok(address) => address,
+            Err(_) => SocketAddr::new(
+                device
+                    .address
+                    .parse::<IpAddr>()
+                    .map_err(|_| invalid("address"))?,
+                PORT,
+            ),
+        };
+        let version = match &device.version {
+            Some(version) => Version::parse(version).ok_or_else(|| invalid("protocol version"))?,
+            None => Version::V33,
+        };
+        let local_key = device
+            .local_key
+            .as_bytes()
+            .try_into()
+            .map_err(|_| invalid("local key"))?;
+
+        let color_temperature_range = (
+            device
+                .min_color_temperature
+                .unwrap_or(DEFAULT_COLOR_TEMPERATURE_RANGE.0),
+            device
+                .max_color_temperature
+                .unwrap_or(DEFAULT_COLOR_TEMPERATURE_RANGE.1),
+        );
+        if color_temperature_range.0 >= color_temperature_range.1 {
+            return Err(invalid("color temperature range"));
+        }
+
+        Ok(Self {
+            address,
+            version,
+            local_key,
+            color_temperature_range,
+            session: Mutex::new(None),
+        })
+    }
+
+    /// Send a command and return the payload of the response to it
+    async fn request(
+        &self,
+        command: u32,
+        payload: &Value,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let payload = payload.to_string();
+        let mut session = self.session.lock().await;
+
+        let result = tokio::time::timeout(
+            timeout,
+            self.request_with_reconnect(&mut session, command, payload.as_bytes()),
+        )
+        .await
+        .unwrap_or_else(|_| Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()));
+
+        if result.is_err() {
+            *session = None;
+        }
+        result
+    }
+
+    async fn request_with_reconnect(
+        &self,
+        session: &mut Option<Session>,
+        command: u32,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, ProtocolError> {
+        // Devices close idle connections, which is only noticed when the
+        // connection is used again
+        if let Some(session) = session.as_mut() {
+            match exchange(session, command, payload).await {
+                Ok(response) => return Ok(response),
+                Err(e) => tracing::debug!(error = %e, "Reconnecting to Tuya device"),
+            }
+        }
+
+        let new = Session::connect(self.address, self.version, self.local_key).await?;
+        exchange(session.insert(new), command, payload).await
+    }
+
+    fn temp_to_kelvin(&self, temp: u16) -> u16 {
+        let (min, max) = self.color_temperature_range;
+        let kelvin = f64::from(min) + f64::from(temp.min(1000)) / 1000.0 * f64::from(max - min);
+        kelvin.round() as u16
+    }
+
+    fn kelvin_to_temp(&self, kelvin: u16) -> u16 {
+        let (min, max) = self.color_temperature_range;
+        let temp = f64::from(kelvin.clamp(min, max) - min) / f64::from(max - min) * 1000.0;
+        temp.round() as u16
+    }
+}
+
+/// Send a command and wait for the response to it, skipping the status
+/// updates and heartbeats that devices send in between
+async fn exchange(
+    session: &mut Session,
+    command: u32,
+    payload: &[u8],
+) -> Result<Vec<u8>, ProtocolError> {
+    session.send(command, payload).await?;
+
+    loop {
+        let message = session.receive().await?;
+        if message.command != command {
+            continue;
+        }
+        return match message.return_code {
+            Some(code) if code != 0 => Err(ProtocolError::Rejected(format!(
+                "Return code {code}, {}",
+                String::from_utf8_lossy(&message.payload)
+            ))),
+            _ => Ok(message.payload),
+        };
+    }
+}
+
+/// Color as encoded in `colour_data`
+#[derive(Debug, Clone, Copy)]
+struct Hsv {
+    /// 0-360
+    h: u16,
+    /// 0-1000
+    s: u16,
+    /// 0-1000
+    v: u16,
+}
+
+impl Hsv {
+    fn parse(hex: &str) -> Option<Self> {
+        if hex.len() != 12 || !hex.is_ascii() {
+            return None;
+        }
+        let part = |i: usize| u16::from_str_radix(&hex[i * 4..i * 4 + 4], 16).ok();
+        Some(Self {
+            h: part(0)?.min(360),
+            s: part(1)?.min(1000),
+            v: part(2)?.min(1000),
+        })
+    }
+
+    fn to_hex(self) -> String {
+        format!("{:04x}{:04x}{:04x}", self.h, self.s, self.v)
+    }
+
+    fn from_hue_saturation(hue: u8, saturation: u8, v: u16) -> Self {
+        Self {
+            h: (f64::from(hue % 100) * 3.6).round() as u16,
+            s: u16::from(saturation.min(100)) * 10,
+            v,
+        }
+    }
+
+    fn hue(&self) -> u8 {
+        ((f64::from(self.h) / 3.6).round() as u16 % 100) as u8
+    }
+
+    fn saturation(&self) -> u8 {
+        percent(self.s)
+    }
+}
+
+/// Lighting data points reported by a device
+#[derive(Debug)]
+struct LightDps {
+    switch: bool,
+    work_mode: Option<String>,
+    bright: Option<u16>,
+    temp: Option<u16>,
+    colour: Option<Hsv>,
+}
+
+impl LightDps {
+    fn from_dps(dps: &Map<String, Value>) -> Result<Self, ProtocolError> {
+        let number = |dp: &str| {
+            dps.get(dp)
+                .and_then(Value::as_u64)
+                .map(|v| v.min(1000) as u16)
+        };
+
+        Ok(Self {
+            switch: dps
+                .get(dp::SWITCH)
+                .and_then(Value::as_bool)
+                .ok_or(ProtocolError::Malformed("no switch data point"))?,
+            work_mode: dps
+                .get(dp::WORK_MODE)
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            bright: number(dp::BRIGHT),
+            temp: number(dp::TEMP),
+            colour: dps
+                .get(dp::COLOUR)
+                .and_then(Value::as_str)
+                .and_then(Hsv::parse),
+        })
+    }
+
+    fn colour_mode(&self) -> bool {
+        self.work_mode.as_deref() == Some("colour") && self.colour.is_some()
+    }
+
+    fn brightness(&self) -> Option<u16> {
+        if self.colour_mode() {
+            self.colour.map(|c| c.v)
+        } else {
+            self.bright
+        }
+    }
+}
+
+/// Tuya brightness and saturation values are 0-1000
+fn percent(value: u16) -> u8 {
+    (f64::from(value.min(1000)) / 10.0).round() as u8
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn map_error(id: &str, error: ProtocolError) -> super::Error {
+    match error {
+        ProtocolError::Decrypt => super::Error::Configuration(format!(
+            "Decrypting messages from Tuya device {id} failed, check its local key and protocol version"
+        )),
+        e => super::Error::Communication(format!("Tuya device {id}: {e}")),
+    }
+}
+
+impl Tuya {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let tuya_config = &config.smart_home.tuya;
+
+        let devices = tuya_config
+            .devices
+            .iter()
+            .map(|d| Ok((d.id.clone(), Device::from_config(d)?)))
+            .collect::<super::Result<_>>()?;
+
+        Ok(Self {
+            devices,
+            timeout: Duration::from_millis(tuya_config.timeout_ms),
+            _config: config,
+            requested: Default::default(),
+        })
+    }
+
+    /// Device IDs are the IDs of devices listed in the configuration
+    fn device(&self, id: &str) -> super::Result<&Device> {
+        self.devices.get(id).ok_or(super::Error::UnknownDeviceId)
+    }
+
+    async fn query(&self, id: &str, device: &Device) -> Result<LightDps, ProtocolError> {
+        let (command, payload) = match device.version {
+            Version::V33 => (
+                protocol::DP_QUERY,
+                json!({ "gwId": id, "devId": id, "uid": id, "t": unix_time().to_string() }),
+            ),
+            _ => (protocol::DP_QUERY_NEW, json!({})),
+        };
+
+        let response = device.request(command, &payload, self.timeout).await?;
+        let response: Value = serde_json::from_slice(&response)
+            .map_err(|_| ProtocolError::Malformed("status is not JSON"))?;
+
+        // 3.5 devices put the data points under `data`
+        let dps = response
+            .get("dps")
+            .or_else(|| response.get("data").and_then(|d| d.get("dps")))
+            .and_then(Value::as_object)
+            .ok_or(ProtocolError::Malformed("no data points in status"))?;
+
+        LightDps::from_dps(dps)
+    }
+
+    async fn get_dps(&self, id: &str, device: &Device) -> super::Result<LightDps> {
+        self.query(id, device).await.map_err(|e| map_error(id, e))
+    }
+
+    async fn set_dps(&self, id: &str, device: &Device, dps: Value) -> super::Result<()> {
+        tracing::debug!(device_id = id, dps = dps.to_string(), "Setting data points");
+
+        let (command, payload) = match device.version {
+            Version::V33 => (
+                protocol::CONTROL,
+                json!({ "devId": id, "uid": id, "t": unix_time().to_string(), "dps": dps }),
+            ),
+            _ => (
+                protocol::CONTROL_NEW,
+                json!({ "protocol": 5, "t": unix_time(), "data": { "dps": dps } }),
+            ),
+        };
+
+        device
+            .request(command, &payload, self.timeout)
+            .await
+            .map_err(|e| map_error(id, e))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Tuya {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let device = self.device(id)?;
+        let dps = match self.query(id, device).await {
+            Ok(dps) => dps,
+            Err(ProtocolError::Io(e)) => {
+                tracing::debug!(error = %e, "Tuya device is unreachable");
+                return Ok(LightStatus::Offline);
+            }
+            Err(e) => return Err(map_error(id, e)),
+        };
+
+        tracing::debug!("Got status {dps:#?}");
+
+        let white_mode = matches!(dps.work_mode.as_deref(), None | Some("white"));
+        let color_temperature = dps.temp.filter(|_| white_mode).map(|temp| {
+            self.requested
+                .color_temperature(id, |k| device.kelvin_to_temp(k) == temp)
+                .unwrap_or_else(|| device.temp_to_kelvin(temp))
+        });
+
+        let color = dps.colour.filter(|_| dps.colour_mode()).map(|colour| {
+            let (hue, saturation) = self
+                .requested
+                .color(id, |h, s| {
+                    let requested = Hsv::from_hue_saturation(h, s, colour.v);
+                    (requested.h, requested.s) == (colour.h, colour.s)
+                })
+                .unwrap_or_else(|| (colour.hue(), colour.saturation()));
+            super::Color { hue, saturation }
+        });
+
+        let effect = dps
+            .work_mode
+            .clone()
+            .filter(|mode| mode != "white" && mode != "colour");
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: dps.switch,
+            brightness: dps.brightness().map(percent),
+            color_temperature,
+            color,
+            effect,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let device = self.device(id)?;
+        self.set_dps(id, device, json!({ dp::SWITCH: switched_on }))
+            .await
+    }
+
+    /// Brightness is the value of the color in color mode, and a separate
+    /// data point otherwise
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let device = self.device(id)?;
+        let current = self.get_dps(id, device).await?;
+        let value = (u16::from(brightness.min(100)) * 10).max(10);
+
+        let dps = match current.colour {
+            Some(colour) if current.colour_mode() => {
+                json!({ dp::COLOUR: Hsv { v: value, ..colour }.to_hex() })
+            }
+            _ if current.bright.is_some() => json!({ dp::BRIGHT: value }),
+            _ => return Err(super::Error::UnsupportedCapability("brightness")),
+        };
+        self.set_dps(id, device, dps).await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let device = self.device(id)?;
+        let current = self.get_dps(id, device).await?;
+        if current.temp.is_none() {
+            return Err(super::Error::UnsupportedCapability("color temperature"));
+        }
+
+        let value = device.kelvin_to_temp(temp);
+        let dps = match current.work_mode {
+            Some(_) => json!({ dp::WORK_MODE: "white", dp::TEMP: value }),
+            None => json!({ dp::TEMP: value }),
+        };
+        self.set_dps(id, device, dps).await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    /// The color keeps the current brightness
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let device = self.device(id)?;
+        let current = self.get_dps(id, device).await?;
+        if current.colour.is_none() {
+            return Err(super::Error::UnsupportedCapability("color"));
+        }
+
+        let v = current.brightness().unwrap_or(1000).max(10);
+        let colour = Hsv::from_hue_saturation(hue, saturation, v);
+        let dps = json!({ dp::WORK_MODE: "colour", dp::COLOUR: colour.to_hex() });
+        self.set_dps(id, device, dps).await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::config::test_config;
+
+    use super::*;
+
+    const LOCAL_KEY: &str = "0123456789abcdef";
+
+    fn device_config(address: &str) -> TuyaDeviceConfig {
+        serde_json::from_value(json!({
+            "id": "bulb",
+            "local_key": LOCAL_KEY,
+            "address": address,
+        }))
+        .unwrap()
+    }
+
+    fn device() -> Device {
+        Device::from_config(&device_config("192.168.1.10")).unwrap()
+    }
+
+    #[test]
+    fn devices_are_configured() {
+        let device = device();
+        assert_eq!(device.address, "192.168.1.10:6668".parse().unwrap());
+        assert_eq!(device.version, Version::V33);
+        assert_eq!(&device.local_key, LOCAL_KEY.as_bytes());
+
+        let mut config = device_config("192.168.1.10:7000");
+        config.version = Some("3.5".to_string());
+        let device = Device::from_config(&config).unwrap();
+        assert_eq!(device.address.port(), 7000);
+        assert_eq!(device.version, Version::V35);
+
+        let invalid = |config: TuyaDeviceConfig| {
+            matches!(
+                Device::from_config(&config),
+                Err(super::super::Error::Configuration(_))
+            )
+        };
+        assert!(invalid(device_config("bulb.local")));
+        assert!(invalid(TuyaDeviceConfig {
+            local_key: "short".to_string(),
+            ..device_config("192.168.1.10")
+        }));
+        assert!(invalid(TuyaDeviceConfig {
+            version: Some("3.1".to_string()),
+            ..device_config("192.168.1.10")
+        }));
+        assert!(invalid(TuyaDeviceConfig {
+            min_color_temperature: Some(6500),
+            ..device_config("192.168.1.10")
+        }));
+    }
+
+    #[test]
+    fn color_temperatures_are_scaled_to_the_range() {
+        let device = device();
+        assert_eq!(device.kelvin_to_temp(2700), 0);
+        assert_eq!(device.kelvin_to_temp(4600), 500);
+        assert_eq!(device.kelvin_to_temp(6500), 1000);
+        assert_eq!(device.kelvin_to_temp(2000), 0);
+        assert_eq!(device.kelvin_to_temp(12000), 1000);
+
+        assert_eq!(device.temp_to_kelvin(0), 2700);
+        assert_eq!(device.temp_to_kelvin(500), 4600);
+        assert_eq!(device.temp_to_kelvin(1000), 6500);
+        assert_eq!(device.temp_to_kelvin(5000), 6500);
+
+        let config = TuyaDeviceConfig {
+            min_color_temperature: Some(2000),
+            max_color_temperature: Some(7000),
+            ..device_config("192.168.1.10")
+        };
+        let device = Device::from_config(&config).unwrap();
+        for kelvin in (2000..=7000).step_by(5) {
+            assert_eq!(device.temp_to_kelvin(device.kelvin_to_temp(kelvin)), kelvin);
+        }
+    }
+
+    #[test]
+    fn colour_data_is_parsed() {
+        let hsv = Hsv::parse("0078032003e8").unwrap();
+        assert_eq!((hsv.h, hsv.s, hsv.v), (120, 800, 1000));
+        assert_eq!(hsv.to_hex(), "0078032003e8");
+        assert_eq!((hsv.hue(), hsv.saturation()), (33, 80));
+
+        // Out of range values are clamped
+        let hsv = Hsv::parse("FFFFFFFFFFFF").unwrap();
+        assert_eq!((hsv.h, hsv.s, hsv.v), (360, 1000, 1000));
+        assert_eq!(hsv.hue(), 0);
+
+        assert!(Hsv::parse("0078032003e").is_none());
+        assert!(Hsv::parse("0078032003e80").is_none());
+        assert!(Hsv::parse("00780320zzzz").is_none());
+        assert!(Hsv::parse("0078032003é").is_none());
+    }
+
+    #[test]
+    fn hue_and_saturation_survive_colour_data() {
+        for hue in 0..100 {
+            for saturation in 0..=100 {
+                let hsv =
+                    Hsv::parse(&Hsv::from_hue_saturation(hue, saturation, 10).to_hex()).unwrap();
+                assert_eq!((hsv.hue(), hsv.saturation(), hsv.v), (hue, saturation, 10));
+            }
+        }
+        let hsv = Hsv::from_hue_saturation(150, 200, 1000);
+        assert_eq!((hsv.h, hsv.s), (180, 1000));
+    }
+
+    #[test]
+    fn brightness_follows_work_mode() {
+        let dps = |value: Value| LightDps::from_dps(value.as_object().unwrap());
+
+        let white =
+            dps(json!({ "20": true, "21": "white", "22": 500, "24": "0078032003e8" })).unwrap();
+        assert!(!white.colour_mode());
+        assert_eq!(white.brightness(), Some(500));
+
+        let colour =
+            dps(json!({ "20": true, "21": "colour", "22": 500, "24": "007803200064" })).unwrap();
+        assert!(colour.colour_mode());
+        assert_eq!(colour.brightness(), Some(100));
+
+        let plain = dps(json!({ "20": false, "22": 5000 })).unwrap();
+        assert_eq!(plain.bright, Some(1000));
+        assert!(plain.colour.is_none());
+
+        assert!(matches!(
+            dps(json!({ "22": 500 })),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    fn tuya(address: SocketAddr) -> Tuya {
+        let config = test_config(json!({
+            "platform": "Tuya",
+            "tuya": {
+                "devices": [
+                    { "id": "bulb", "local_key": LOCAL_KEY, "address": address.to_string() },
+                ],
+            },
+        }));
+        Tuya::new(config).unwrap()
+    }
+
+    /// A 3.3 device that answers status queries with `dps` and records the
+    /// data points it is set to
+    async fn device_stand_in(
+        listener: TcpListener,
+        mut dps: Map<String, Value>,
+        set: tokio::sync::mpsc::UnboundedSender<Value>,
+    ) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut session = Session::accept(
+            stream,
+            Version::V33,
+            LOCAL_KEY.as_bytes().try_into().unwrap(),
+        );
+
+        while let Ok(message) = session.receive().await {
+            let request: Value = serde_json::from_slice(&message.payload).unwrap();
+            match message.command {
+                protocol::DP_QUERY => {
+                    assert_eq!(request["devId"], "bulb");
+                    let status = json!({ "devId": "bulb", "dps": dps });
+                    session
+                        .send(protocol::DP_QUERY, status.to_string().as_bytes())
+                        .await
+                        .unwrap();
+                }
+                protocol::CONTROL => {
+                    let changed = request["dps"].as_object().unwrap().clone();
+                    dps.extend(changed.clone());
+                    set.send(Value::Object(changed)).unwrap();
+                    session.send(protocol::CONTROL, b"").await.unwrap();
+                }
+                command => panic!("Unexpected command {command}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn lights_are_controlled_over_the_local_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (set, mut set_dps) = tokio::sync::mpsc::unbounded_channel();
+        let dps = json!({ "20": true, "21": "white", "22": 1000, "23": 500, "24": "000003e80064" });
+        tokio::spawn(device_stand_in(
+            listener,
+            dps.as_object().unwrap().clone(),
+            set,
+        ));
+
+        let tuya = tuya(address);
+
+        let LightStatus::Online(options) = tuya.get_light_status("bulb").await.unwrap() else {
+            panic!("light is offline");
+        };
+        assert!(options.switched_on);
+        assert_eq!(options.brightness, Some(100));
+        assert_eq!(options.color_temperature, Some(4600));
+        assert!(options.color.is_none());
+
+        // Colors keep the brightness
+        tuya.set_color("bulb", 50, 40).await.unwrap();
+        assert_eq!(
+            set_dps.recv().await.unwrap(),
+            json!({ "21": "colour", "24": "00b4019003e8" })
+        );
+        tuya.set_brightness("bulb", 30).await.unwrap();
+        assert_eq!(
+            set_dps.recv().await.unwrap(),
+            json!({ "24": "00b40190012c" })
+        );
+
+        let LightStatus::Online(options) = tuya.get_light_status("bulb").await.unwrap() else {
+            panic!("light is offline");
+        };
+        assert_eq!(options.brightness, Some(30));
+        assert_eq!(options.color.map(|c| (c.hue, c.saturation)), Some((50, 40)));
+        assert_eq!(options.color_temperature, None);
+
+        // The requested color temperature is reported while the device has
+        // the clamped value
+        tuya.set_color_temperature("bulb", 12000).await.unwrap();
+        assert_eq!(
+            set_dps.recv().await.unwrap(),
+            json!({ "21": "white", "23": 1000 })
+        );
+        let LightStatus::Online(options) = tuya.get_light_status("bulb").await.unwrap() else {
+            panic!("light is offline");
+        };
+        assert_eq!(options.color_temperature, Some(12000));
+
+        assert!(matches!(
+            tuya.get_light_status("lamp").await,
+            Err(super::super::Error::UnknownDeviceId)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unreachable_lights_are_offline() {
+        // Nothing listens on the port after the listener is dropped
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let tuya = tuya(address);
+
+        assert!(matches!(
+            tuya.get_light_status("bulb").await,
+            Ok(LightStatus::Offline)
+        ));
+    }
+}
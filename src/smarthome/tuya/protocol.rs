@@ -0,0 +1,796 @@
+//! Tuya local protocol, versions 3.3, 3.4 and 3.5.
+//!
+//! 3.3 and 3.4 frames start with `000055AA`. 3.3 payloads are encrypted with
+//! AES-128-ECB using the device's local key and the frames end with a CRC32.
+//! 3.4 first negotiates a session key, and the frames end with an HMAC-SHA256.
+//! 3.5 frames start with `00006699` and are encrypted with AES-128-GCM using
+//! a session key negotiated like in 3.4.
+
+use std::net::SocketAddr;
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use aes_gcm::{aead::AeadInPlace, Aes128Gcm, Nonce, Tag};
+use rand::Rng;
+use ring::hmac;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+pub const SESS_KEY_NEG_START: u32 = 0x03;
+pub const SESS_KEY_NEG_RESP: u32 = 0x04;
+pub const SESS_KEY_NEG_FINISH: u32 = 0x05;
+pub const CONTROL: u32 = 0x07;
+pub const HEART_BEAT: u32 = 0x09;
+pub const DP_QUERY: u32 = 0x0a;
+pub const CONTROL_NEW: u32 = 0x0d;
+pub const DP_QUERY_NEW: u32 = 0x10;
+
+const PREFIX_55AA: [u8; 4] = [0x00, 0x00, 0x55, 0xaa];
+const SUFFIX_55AA: [u8; 4] = [0x00, 0x00, 0xaa, 0x55];
+const PREFIX_6699: [u8; 4] = [0x00, 0x00, 0x66, 0x99];
+const SUFFIX_6699: [u8; 4] = [0x00, 0x00, 0x99, 0x66];
+
+const HEADER_LEN_55AA: usize = 16;
+const HEADER_LEN_6699: usize = 18;
+const GCM_IV_LEN: usize = 12;
+const GCM_TAG_LEN: usize = 16;
+const HMAC_LEN: usize = 32;
+
+/// Frames larger than this are not accepted from devices
+const MAX_FRAME_LEN: usize = 0x10000;
+
+/// Commands whose payload doesn't start with the protocol version header
+const NO_VERSION_HEADER: [u32; 7] = [
+    DP_QUERY,
+    DP_QUERY_NEW,
+    HEART_BEAT,
+    SESS_KEY_NEG_START,
+    SESS_KEY_NEG_RESP,
+    SESS_KEY_NEG_FINISH,
+    0x12, // UPDATEDPS
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    V33,
+    V34,
+    V35,
+}
+
+impl Version {
+    pub fn parse(version: &str) -> Option<Self> {
+        match version.trim() {
+            "3.3" => Some(Self::V33),
+            "3.4" => Some(Self::V34),
+            "3.5" => Some(Self::V35),
+            _ => None,
+        }
+    }
+
+    /// Version string followed by 12 zero bytes
+    fn header(&self) -> [u8; 15] {
+        let mut header = [0u8; 15];
+        let version: &[u8; 3] = match self {
+            Self::V33 => b"3.3",
+            Self::V34 => b"3.4",
+            Self::V35 => b"3.5",
+        };
+        header[..3].copy_from_slice(version);
+        header
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProtocolError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+
+    /// The message could not be authenticated or decrypted, usually because
+    /// the local key or the protocol version is wrong
+    #[error("Decrypting message failed")]
+    Decrypt,
+
+    #[error("Invalid message: {0}")]
+    Malformed(&'static str),
+
+    #[error("Device rejected the request: {0}")]
+    Rejected(String),
+}
+
+pub struct Message {
+    pub command: u32,
+    /// Return code, which devices add to their responses
+    pub return_code: Option<u32>,
+    pub payload: Vec<u8>,
+}
+
+/// Connection to a device, with the session key negotiated if the protocol
+/// version has one
+pub struct Session {
+    stream: TcpStream,
+    version: Version,
+    local_key: [u8; 16],
+    /// Key for encrypting and authenticating messages. The local key until a
+    /// session key has been negotiated.
+    key: [u8; 16],
+    sequence: u32,
+    buffer: Vec<u8>,
+}
+
+impl Session {
+    pub async fn connect(
+        address: SocketAddr,
+        version: Version,
+        local_key: [u8; 16],
+    ) -> Result<Self, ProtocolError> {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+
+        let mut session = Self {
+            stream,
+            version,
+            local_key,
+            key: local_key,
+            sequence: 0,
+            buffer: Vec::new(),
+        };
+        if version != Version::V33 {
+            session.negotiate_session_key().await?;
+        }
+        Ok(session)
+    }
+
+    /// The device end of a connection, for device stand-ins in tests
+    #[cfg(test)]
+    pub fn accept(stream: TcpStream, version: Version, local_key: [u8; 16]) -> Self {
+        Self {
+            stream,
+            version,
+            local_key,
+            key: local_key,
+            sequence: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    async fn negotiate_session_key(&mut self) -> Result<(), ProtocolError> {
+        let local_nonce: [u8; 16] = rand::thread_rng().gen();
+        self.send(SESS_KEY_NEG_START, &local_nonce).await?;
+
+        let response = loop {
+            let message = self.receive().await?;
+            if message.command == SESS_KEY_NEG_RESP {
+                break message.payload;
+            }
+        };
+        if response.len() < 16 + HMAC_LEN {
+            return Err(ProtocolError::Malformed("short session key response"));
+        }
+        let remote_nonce: [u8; 16] = response[..16].try_into().unwrap();
+        if hmac_sha256(&self.local_key, &local_nonce)[..] != response[16..16 + HMAC_LEN] {
+            return Err(ProtocolError::Decrypt);
+        }
+
+        let finish = hmac_sha256(&self.local_key, &remote_nonce);
+        self.send(SESS_KEY_NEG_FINISH, &finish).await?;
+
+        let mut nonce_xor = [0u8; 16];
+        for (i, b) in nonce_xor.iter_mut().enumerate() {
+            *b = local_nonce[i] ^ remote_nonce[i];
+        }
+        self.key = match self.version {
+            Version::V35 => {
+                let cipher = Aes128Gcm::new_from_slice(&self.local_key).unwrap();
+                cipher
+                    .encrypt_in_place_detached(
+                        Nonce::from_slice(&local_nonce[..GCM_IV_LEN]),
+                        &[],
+                        &mut nonce_xor,
+                    )
+                    .map_err(|_| ProtocolError::Decrypt)?;
+                nonce_xor
+            }
+            _ => {
+                let cipher = Aes128::new_from_slice(&self.local_key).unwrap();
+                cipher.encrypt_block(GenericArray::from_mut_slice(&mut nonce_xor));
+                nonce_xor
+            }
+        };
+        Ok(())
+    }
+
+    pub async fn send(&mut self, command: u32, payload: &[u8]) -> Result<(), ProtocolError> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let frame = self.encode(self.sequence, command, payload)?;
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+
+    /// Read the next message from the device
+    pub async fn receive(&mut self) -> Result<Message, ProtocolError> {
+        loop {
+            if let Some(frame_len) = self.complete_frame_len()? {
+                let frame: Vec<u8> = self.buffer.drain(..frame_len).collect();
+                return self.decode(&frame);
+            }
+
+            let mut chunk = [0u8; 1024];
+            let n = self.stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Length of the frame at the start of the buffer, once all of it has
+    /// been received
+    fn complete_frame_len(&mut self) -> Result<Option<usize>, ProtocolError> {
+        let prefix = match self.version {
+            Version::V35 => PREFIX_6699,
+            _ => PREFIX_55AA,
+        };
+        // Skip anything before the next frame
+        match self.buffer.windows(4).position(|w| w == prefix) {
+            Some(start) => drop(self.buffer.drain(..start)),
+            None => {
+                let keep = self.buffer.len().min(3);
+                self.buffer.drain(..self.buffer.len() - keep);
+                return Ok(None);
+            }
+        }
+
+        let (header_len, length_at, trailer_len) = match self.version {
+            Version::V35 => (HEADER_LEN_6699, 14, 4),
+            _ => (HEADER_LEN_55AA, 12, 0),
+        };
+        if self.buffer.len() < header_len {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(self.buffer[length_at..length_at + 4].try_into().unwrap());
+        let frame_len = header_len + length as usize + trailer_len;
+        if frame_len > MAX_FRAME_LEN {
+            return Err(ProtocolError::Malformed("frame too long"));
+        }
+        Ok((self.buffer.len() >= frame_len).then_some(frame_len))
+    }
+
+    fn encode(
+        &self,
+        sequence: u32,
+        command: u32,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let mut plaintext = Vec::new();
+        let add_header = !NO_VERSION_HEADER.contains(&command);
+
+        match self.version {
+            Version::V33 => {
+                let encrypted = ecb_encrypt(&self.key, payload);
+                if add_header {
+                    plaintext.extend_from_slice(&self.version.header());
+                }
+                plaintext.extend_from_slice(&encrypted);
+                Ok(frame_55aa(sequence, command, &plaintext, None))
+            }
+            Version::V34 => {
+                if add_header {
+                    plaintext.extend_from_slice(&self.version.header());
+                }
+                plaintext.extend_from_slice(payload);
+                let encrypted = ecb_encrypt(&self.key, &plaintext);
+                Ok(frame_55aa(sequence, command, &encrypted, Some(&self.key)))
+            }
+            Version::V35 => {
+                if add_header {
+                    plaintext.extend_from_slice(&self.version.header());
+                }
+                plaintext.extend_from_slice(payload);
+
+                let length = (GCM_IV_LEN + plaintext.len() + GCM_TAG_LEN) as u32;
+                let mut frame = Vec::with_capacity(HEADER_LEN_6699 + length as usize + 4);
+                frame.extend_from_slice(&PREFIX_6699);
+                frame.extend_from_slice(&[0, 0]);
+                frame.extend_from_slice(&sequence.to_be_bytes());
+                frame.extend_from_slice(&command.to_be_bytes());
+                frame.extend_from_slice(&length.to_be_bytes());
+
+                let iv: [u8; GCM_IV_LEN] = rand::thread_rng().gen();
+                let cipher = Aes128Gcm::new_from_slice(&self.key).unwrap();
+                let tag = cipher
+                    .encrypt_in_place_detached(
+                        Nonce::from_slice(&iv),
+                        &frame[4..HEADER_LEN_6699],
+                        &mut plaintext,
+                    )
+                    .map_err(|_| ProtocolError::Decrypt)?;
+
+                frame.extend_from_slice(&iv);
+                frame.extend_from_slice(&plaintext);
+                frame.extend_from_slice(&tag);
+                frame.extend_from_slice(&SUFFIX_6699);
+                Ok(frame)
+            }
+        }
+    }
+
+    fn decode(&self, frame: &[u8]) -> Result<Message, ProtocolError> {
+        let payload = match self.version {
+            Version::V35 => {
+                let command = u32::from_be_bytes(frame[10..14].try_into().unwrap());
+                let body = &frame[HEADER_LEN_6699..frame.len() - 4];
+                if body.len() < GCM_IV_LEN + GCM_TAG_LEN || frame[frame.len() - 4..] != SUFFIX_6699
+                {
+                    return Err(ProtocolError::Malformed("invalid 3.5 frame"));
+                }
+                let (iv, rest) = body.split_at(GCM_IV_LEN);
+                let (ciphertext, tag) = rest.split_at(rest.len() - GCM_TAG_LEN);
+
+                let mut plaintext = ciphertext.to_vec();
+                let cipher = Aes128Gcm::new_from_slice(&self.key).unwrap();
+                cipher
+                    .decrypt_in_place_detached(
+                        Nonce::from_slice(iv),
+                        &frame[4..HEADER_LEN_6699],
+                        &mut plaintext,
+                        Tag::from_slice(tag),
+                    )
+                    .map_err(|_| ProtocolError::Decrypt)?;
+
+                let (return_code, payload) = split_return_code(&plaintext);
+                return Ok(Message {
+                    command,
+                    return_code,
+                    payload: self.strip_version_header(payload).to_vec(),
+                });
+            }
+            Version::V34 => {
+                let trailer = HMAC_LEN + 4;
+                if frame.len() < HEADER_LEN_55AA + trailer {
+                    return Err(ProtocolError::Malformed("short frame"));
+                }
+                let (signed, hmac) = frame[..frame.len() - 4].split_at(frame.len() - trailer);
+                if frame[frame.len() - 4..] != SUFFIX_55AA {
+                    return Err(ProtocolError::Malformed("invalid 3.4 frame"));
+                }
+                if hmac_sha256(&self.key, signed)[..] != *hmac {
+                    return Err(ProtocolError::Decrypt);
+                }
+                &signed[HEADER_LEN_55AA..]
+            }
+            Version::V33 => {
+                if frame.len() < HEADER_LEN_55AA + 8 {
+                    return Err(ProtocolError::Malformed("short frame"));
+                }
+                let (body, crc) = frame[..frame.len() - 4].split_at(frame.len() - 8);
+                if crc32fast::hash(body).to_be_bytes() != *crc {
+                    return Err(ProtocolError::Decrypt);
+                }
+                &body[HEADER_LEN_55AA..]
+            }
+        };
+
+        let command = u32::from_be_bytes(frame[8..12].try_into().unwrap());
+        let (return_code, payload) = split_return_code(payload);
+        let payload = self.strip_version_header(payload);
+        // Some devices answer errors in plain text
+        let payload = if payload.is_empty() || !payload.len().is_multiple_of(16) {
+            payload.to_vec()
+        } else {
+            let decrypted = ecb_decrypt(&self.key, payload)?;
+            self.strip_version_header(&decrypted).to_vec()
+        };
+
+        Ok(Message {
+            command,
+            return_code,
+            payload,
+        })
+    }
+
+    fn strip_version_header<'a>(&self, payload: &'a [u8]) -> &'a [u8] {
+        let header = self.version.header();
+        if payload.len() >= header.len() && payload[..3] == header[..3] {
+            &payload[header.len()..]
+        } else {
+            payload
+        }
+    }
+}
+
+/// Frame with a CRC32, or with an HMAC-SHA256 if there is a key
+fn frame_55aa(sequence: u32, command: u32, payload: &[u8], hmac_key: Option<&[u8; 16]>) -> Vec<u8> {
+    let trailer_len = if hmac_key.is_some() { HMAC_LEN } else { 4 } + 4;
+    let mut frame = Vec::with_capacity(HEADER_LEN_55AA + payload.len() + trailer_len);
+    frame.extend_from_slice(&PREFIX_55AA);
+    frame.extend_from_slice(&sequence.to_be_bytes());
+    frame.extend_from_slice(&command.to_be_bytes());
+    frame.extend_from_slice(&((payload.len() + trailer_len) as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    match hmac_key {
+        Some(key) => {
+            let hmac = hmac_sha256(key, &frame);
+            frame.extend_from_slice(&hmac);
+        }
+        None => frame.extend_from_slice(&crc32fast::hash(&frame).to_be_bytes()),
+    }
+    frame.extend_from_slice(&SUFFIX_55AA);
+    frame
+}
+
+/// Devices prefix their responses with a 4-byte return code, which is
+/// almost always 0 or 1. Encrypted data and JSON don't start with three
+/// zero bytes.
+fn split_return_code(payload: &[u8]) -> (Option<u32>, &[u8]) {
+    if payload.len() >= 4 && payload[..3] == [0, 0, 0] {
+        let (code, rest) = payload.split_at(4);
+        (Some(u32::from_be_bytes(code.try_into().unwrap())), rest)
+    } else {
+        (None, payload)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; HMAC_LEN] {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data).as_ref().try_into().unwrap()
+}
+
+/// AES-128-ECB with PKCS#7 padding
+fn ecb_encrypt(key: &[u8; 16], data: &[u8]) -> Vec<u8> {
+    let cipher = Aes128::new_from_slice(key).unwrap();
+    let padding = 16 - data.len() % 16;
+    let mut buffer = data.to_vec();
+    buffer.resize(data.len() + padding, padding as u8);
+    for block in buffer.chunks_exact_mut(16) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    buffer
+}
+
+fn ecb_decrypt(key: &[u8; 16], data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    let cipher = Aes128::new_from_slice(key).unwrap();
+    let mut buffer = data.to_vec();
+    for block in buffer.chunks_exact_mut(16) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+
+    let padding = *buffer.last().ok_or(ProtocolError::Decrypt)? as usize;
+    if padding == 0
+        || padding > 16
+        || buffer[buffer.len() - padding..]
+            .iter()
+            .any(|&b| b as usize != padding)
+    {
+        return Err(ProtocolError::Decrypt);
+    }
+    buffer.truncate(buffer.len() - padding);
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const LOCAL_KEY: [u8; 16] = *b"0123456789abcdef";
+
+    /// Frames of another implementation of the protocol, using `LOCAL_KEY`
+    const V33_CONTROL: &str = "000055aa000000010000000700000037332e330000000000000000000000006c43740537c46421392c83bfa222a5ee31836d84bd28cb4b20619eb84882ca2ad1a187750000aa55";
+    const V33_DP_QUERY: &str =
+        "000055aa000000020000000a00000018cb70ddc25a2a2045b4c13084418a9abb064375190000aa55";
+    const V34_CONTROL_NEW: &str = "000055aa000000020000000d000000544490b05d74be9368c24a038cbaeded8e7dafb7b4370dab264878a7dbed69289164215f365b0dad6b2ac8fab15b1d40bdb8af3b99368110e9b0316a215e4d36405fd47bb571fa547ef0feddf10860bf1c0000aa55";
+    const V33_STATUS: &str = "000055aa000000050000000a0000002c00000000c756271dbf61895af70ee82ef0d1b12a2e9169f544a64f0fa17b5d4b24be35635f0809040000aa55";
+    const V34_STATUS: &str = "000055aa00000006000000100000004800000000c756271dbf61895af70ee82ef0d1b12a2e9169f544a64f0fa17b5d4b24be3563b40b584c396d270c3bca0b8f03e2aa3f0cc11f204ce4d1314c16250bc2d088f90000aa55";
+    const V35_STATUS: &str = "00006699000000000007000000100000004c30313233343536373839616262420dfb1d4e1889030377e484daa3f39688761348df357046a50a5245a2f9b49d98c274e6a0abea3208f295e141e9f44466c4515addc88903ba0a40935ea1e400009966";
+
+    const CONTROL_PAYLOAD: &[u8] = br#"{"dps":{"20":true}}"#;
+    const STATUS_PAYLOAD: &[u8] = br#"{"dps":{"20":false,"22":500}}"#;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// A client and a device end of a connection, without a session key
+    async fn sessions(version: Version) -> (Session, Session) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, device) = tokio::join!(client, listener.accept());
+        (
+            Session::accept(client.unwrap(), version, LOCAL_KEY),
+            Session::accept(device.unwrap().0, version, LOCAL_KEY),
+        )
+    }
+
+    #[tokio::test]
+    async fn v33_frames() {
+        let (session, _device) = sessions(Version::V33).await;
+        assert_eq!(
+            session.encode(1, CONTROL, CONTROL_PAYLOAD).unwrap(),
+            hex(V33_CONTROL)
+        );
+        assert_eq!(
+            session.encode(2, DP_QUERY, b"{}").unwrap(),
+            hex(V33_DP_QUERY)
+        );
+
+        let message = session.decode(&hex(V33_STATUS)).unwrap();
+        assert_eq!(message.command, DP_QUERY);
+        assert_eq!(message.return_code, Some(0));
+        assert_eq!(message.payload, STATUS_PAYLOAD);
+
+        let mut corrupted = hex(V33_STATUS);
+        corrupted[20] ^= 1;
+        assert!(matches!(
+            session.decode(&corrupted),
+            Err(ProtocolError::Decrypt)
+        ));
+    }
+
+    #[tokio::test]
+    async fn v34_frames() {
+        let (session, _device) = sessions(Version::V34).await;
+        assert_eq!(
+            session.encode(2, CONTROL_NEW, CONTROL_PAYLOAD).unwrap(),
+            hex(V34_CONTROL_NEW)
+        );
+
+        let message = session.decode(&hex(V34_STATUS)).unwrap();
+        assert_eq!(message.command, DP_QUERY_NEW);
+        assert_eq!(message.return_code, Some(0));
+        assert_eq!(message.payload, STATUS_PAYLOAD);
+
+        let mut corrupted = hex(V34_STATUS);
+        corrupted[20] ^= 1;
+        assert!(matches!(
+            session.decode(&corrupted),
+            Err(ProtocolError::Decrypt)
+        ));
+    }
+
+    #[tokio::test]
+    async fn v35_frames() {
+        let (session, _device) = sessions(Version::V35).await;
+        let message = session.decode(&hex(V35_STATUS)).unwrap();
+        assert_eq!(message.command, DP_QUERY_NEW);
+        assert_eq!(message.return_code, Some(0));
+        assert_eq!(message.payload, STATUS_PAYLOAD);
+
+        // The header is authenticated too
+        let mut corrupted = hex(V35_STATUS);
+        corrupted[9] ^= 1;
+        assert!(matches!(
+            session.decode(&corrupted),
+            Err(ProtocolError::Decrypt)
+        ));
+
+        // Frames have a random IV
+        let frame = session.encode(3, CONTROL_NEW, CONTROL_PAYLOAD).unwrap();
+        assert_ne!(
+            frame,
+            session.encode(3, CONTROL_NEW, CONTROL_PAYLOAD).unwrap()
+        );
+        assert_eq!(frame[..4], PREFIX_6699);
+        assert_eq!(frame[6..10], 3u32.to_be_bytes());
+        assert_eq!(frame[10..14], CONTROL_NEW.to_be_bytes());
+        let length = GCM_IV_LEN + 15 + CONTROL_PAYLOAD.len() + GCM_TAG_LEN;
+        assert_eq!(frame[14..18], (length as u32).to_be_bytes());
+        assert_eq!(frame.len(), HEADER_LEN_6699 + length + 4);
+        assert_eq!(frame[frame.len() - 4..], SUFFIX_6699);
+
+        let message = session.decode(&frame).unwrap();
+        assert_eq!(message.command, CONTROL_NEW);
+        assert_eq!(message.return_code, None);
+        assert_eq!(message.payload, CONTROL_PAYLOAD);
+    }
+
+    #[test]
+    fn frame_55aa_has_crc_or_hmac() {
+        let frame = frame_55aa(1, HEART_BEAT, &[], None);
+        assert_eq!(
+            frame[..16],
+            [0, 0, 0x55, 0xaa, 0, 0, 0, 1, 0, 0, 0, 9, 0, 0, 0, 8]
+        );
+        assert_eq!(frame[16..20], crc32fast::hash(&frame[..16]).to_be_bytes());
+        assert_eq!(frame[20..], SUFFIX_55AA);
+
+        let frame = frame_55aa(1, HEART_BEAT, &[1, 2], Some(&LOCAL_KEY));
+        assert_eq!(frame[12..16], 38u32.to_be_bytes());
+        assert_eq!(frame[18..50], hmac_sha256(&LOCAL_KEY, &frame[..18]));
+        assert_eq!(frame.len(), 54);
+    }
+
+    #[test]
+    fn ecb_pads_to_blocks() {
+        for len in [0, 1, 15, 16, 17, 32] {
+            let data: Vec<u8> = (0..len as u8).collect();
+            let encrypted = ecb_encrypt(&LOCAL_KEY, &data);
+            assert_eq!(encrypted.len(), (len / 16 + 1) * 16);
+            assert_eq!(ecb_decrypt(&LOCAL_KEY, &encrypted).unwrap(), data);
+        }
+
+        let encrypted = ecb_encrypt(&LOCAL_KEY, b"data");
+        assert!(matches!(
+            ecb_decrypt(b"fedcba9876543210", &encrypted),
+            Err(ProtocolError::Decrypt)
+        ));
+        assert!(matches!(
+            ecb_decrypt(&LOCAL_KEY, &[]),
+            Err(ProtocolError::Decrypt)
+        ));
+    }
+
+    #[tokio::test]
+    async fn plain_text_errors_are_kept() {
+        assert_eq!(
+            split_return_code(b"\0\0\0\x01error"),
+            (Some(1), &b"error"[..])
+        );
+        assert_eq!(split_return_code(b"{}"), (None, &b"{}"[..]));
+        assert_eq!(split_return_code(b"\0\0"), (None, &b"\0\0"[..]));
+
+        let (session, _device) = sessions(Version::V33).await;
+        assert_eq!(
+            session.strip_version_header(b"data format error"),
+            b"data format error"
+        );
+        assert_eq!(session.strip_version_header(b"3.3"), b"3.3");
+        let mut with_header = session.version.header().to_vec();
+        with_header.extend_from_slice(b"json obj data unvalid");
+        assert_eq!(
+            session.strip_version_header(&with_header),
+            b"json obj data unvalid"
+        );
+
+        // Devices answer requests they can't handle in plain text
+        let frame = frame_55aa(4, CONTROL, b"\0\0\0\x01json obj data unvalid", None);
+        let message = session.decode(&frame).unwrap();
+        assert_eq!(message.return_code, Some(1));
+        assert_eq!(message.payload, b"json obj data unvalid");
+    }
+
+    #[tokio::test]
+    async fn frames_are_read_from_the_stream() {
+        let (mut session, mut device) = sessions(Version::V33).await;
+        let frame = hex(V33_STATUS);
+
+        // Garbage before a frame is skipped, and a frame can arrive in parts
+        device.stream.write_all(b"\x01\x02\x00\x00").await.unwrap();
+        device.stream.write_all(&frame[..10]).await.unwrap();
+        let receive = tokio::spawn(async move {
+            let first = session.receive().await.unwrap();
+            let second = session.receive().await.unwrap();
+            (first, second)
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        device.stream.write_all(&frame[10..]).await.unwrap();
+        device.send(HEART_BEAT, b"").await.unwrap();
+
+        let (first, second) = receive.await.unwrap();
+        assert_eq!(first.payload, STATUS_PAYLOAD);
+        assert_eq!(second.command, HEART_BEAT);
+    }
+
+    #[tokio::test]
+    async fn frames_that_are_too_long_are_rejected() {
+        let (mut session, mut device) = sessions(Version::V33).await;
+        let mut header = PREFIX_55AA.to_vec();
+        header.extend_from_slice(&1u32.to_be_bytes());
+        header.extend_from_slice(&CONTROL.to_be_bytes());
+        header.extend_from_slice(&(MAX_FRAME_LEN as u32).to_be_bytes());
+        device.stream.write_all(&header).await.unwrap();
+
+        assert!(matches!(
+            session.receive().await,
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    /// Session key negotiation of a device stand-in, using `remote_nonce`
+    /// as the device's nonce. Returns the local nonce that the client sent.
+    async fn device_negotiation(device: &mut Session, remote_nonce: [u8; 16]) -> [u8; 16] {
+        let start = device.receive().await.unwrap();
+        assert_eq!(start.command, SESS_KEY_NEG_START);
+        let local_nonce: [u8; 16] = start.payload.try_into().unwrap();
+
+        let mut response = remote_nonce.to_vec();
+        response.extend_from_slice(&hmac_sha256(&LOCAL_KEY, &local_nonce));
+        device.send(SESS_KEY_NEG_RESP, &response).await.unwrap();
+
+        let finish = device.receive().await.unwrap();
+        assert_eq!(finish.command, SESS_KEY_NEG_FINISH);
+        assert_eq!(finish.payload, hmac_sha256(&LOCAL_KEY, &remote_nonce));
+        local_nonce
+    }
+
+    #[tokio::test]
+    async fn v34_session_key_is_negotiated() {
+        let (mut session, mut device) = sessions(Version::V34).await;
+        let remote_nonce = *b"remote nonce 123";
+
+        let (negotiated, local_nonce) = tokio::join!(
+            session.negotiate_session_key(),
+            device_negotiation(&mut device, remote_nonce)
+        );
+        negotiated.unwrap();
+
+        // The nonces XORed and encrypted with the local key
+        let mut key = [0u8; 16];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = local_nonce[i] ^ remote_nonce[i];
+        }
+        Aes128::new(&LOCAL_KEY.into()).encrypt_block(GenericArray::from_mut_slice(&mut key));
+        assert_eq!(session.key, key);
+        assert_eq!(session.local_key, LOCAL_KEY);
+
+        device.key = key;
+        device.send(DP_QUERY_NEW, STATUS_PAYLOAD).await.unwrap();
+        assert_eq!(session.receive().await.unwrap().payload, STATUS_PAYLOAD);
+        session.send(CONTROL_NEW, CONTROL_PAYLOAD).await.unwrap();
+        assert_eq!(device.receive().await.unwrap().payload, CONTROL_PAYLOAD);
+    }
+
+    #[tokio::test]
+    async fn v35_session_key_is_negotiated() {
+        let (mut session, mut device) = sessions(Version::V35).await;
+        let remote_nonce = *b"remote nonce 123";
+
+        let (negotiated, local_nonce) = tokio::join!(
+            session.negotiate_session_key(),
+            device_negotiation(&mut device, remote_nonce)
+        );
+        negotiated.unwrap();
+
+        // The nonces XORed and encrypted with AES-GCM, without the tag
+        let mut key = [0u8; 16];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = local_nonce[i] ^ remote_nonce[i];
+        }
+        Aes128Gcm::new(&LOCAL_KEY.into())
+            .encrypt_in_place_detached(Nonce::from_slice(&local_nonce[..12]), &[], &mut key)
+            .unwrap();
+        assert_eq!(session.key, key);
+
+        device.key = key;
+        device.send(DP_QUERY_NEW, STATUS_PAYLOAD).await.unwrap();
+        assert_eq!(session.receive().await.unwrap().payload, STATUS_PAYLOAD);
+        session.send(CONTROL_NEW, CONTROL_PAYLOAD).await.unwrap();
+        assert_eq!(device.receive().await.unwrap().payload, CONTROL_PAYLOAD);
+    }
+
+    #[tokio::test]
+    async fn negotiation_fails_with_another_local_key() {
+        let (mut session, mut device) = sessions(Version::V34).await;
+        device.local_key = *b"fedcba9876543210";
+        device.key = device.local_key;
+
+        let device = async move {
+            let start = device.receive().await;
+            assert!(matches!(start, Err(ProtocolError::Decrypt)));
+            // Answer like a device with the other key
+            let mut response = b"remote nonce 123".to_vec();
+            response.extend_from_slice(&[0; HMAC_LEN]);
+            device.send(SESS_KEY_NEG_RESP, &response).await.unwrap();
+        };
+        let (negotiated, ()) = tokio::join!(session.negotiate_session_key(), device);
+        assert!(matches!(negotiated, Err(ProtocolError::Decrypt)));
+    }
+
+    #[test]
+    fn versions_are_parsed() {
+        assert_eq!(Version::parse("3.3"), Some(Version::V33));
+        assert_eq!(Version::parse(" 3.4 "), Some(Version::V34));
+        assert_eq!(Version::parse("3.5"), Some(Version::V35));
+        assert_eq!(Version::parse("3.1"), None);
+    }
+}
@@ -0,0 +1,186 @@
+use std::{sync::Arc, time::Duration};
+
+use api_models::*;
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+
+use crate::config::Config;
+
+use super::{color, requested::RequestedValues, LightOptions, LightStatus, SmartHomeApi};
+
+const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+pub struct Wled {
+    _config: Arc<Config>,
+    client: Client,
+    requested: RequestedValues,
+}
+
+impl Wled {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let timeout = Duration::from_millis(config.smart_home.wled.timeout_ms);
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(APP_USER_AGENT)
+            .connection_verbose(true)
+            .use_rustls_tls()
+            .timeout(timeout)
+            .build()
+            .unwrap();
+
+        Ok(Self {
+            _config: config,
+            client,
+            requested: Default::default(),
+        })
+    }
+
+    /// Device IDs are controller base URLs (`http://wled-office.lan`) or
+    /// plain host names and IP addresses
+    fn state_url(id: &str) -> super::Result<Url> {
+        let invalid = || super::Error::InvalidId(id.to_string());
+
+        let base = if id.contains("://") {
+            id.to_string()
+        } else {
+            format!("http://{id}")
+        };
+        let base: Url = base.parse().map_err(|_| invalid())?;
+
+        if !matches!(base.scheme(), "http" | "https") || base.host_str().is_none() {
+            return Err(invalid());
+        }
+
+        let path = format!("{}/json/state", base.path().trim_end_matches('/'));
+        base.join(&path).map_err(|_| invalid())
+    }
+
+    async fn get_state(&self, id: &str) -> super::Result<State> {
+        let url = Self::state_url(id)?;
+        let res = self.client.get(url).send().await?;
+        Ok(res.error_for_status()?.json().await?)
+    }
+
+    async fn post_state(&self, id: &str, body: serde_json::Value) -> super::Result<()> {
+        let url = Self::state_url(id)?;
+
+        tracing::debug!(device_id = id, state = body.to_string(), "Updating state");
+
+        let res = self.client.post(url).json(&body).send().await?;
+        res.error_for_status()?;
+        Ok(())
+    }
+
+    /// Set the primary color of the main segment
+    async fn set_primary_color(&self, id: &str, rgb: [u8; 3]) -> super::Result<()> {
+        let state = self.get_state(id).await?;
+        let body = serde_json::json!({
+            "seg": [{ "id": state.mainseg, "col": [rgb] }]
+        });
+        self.post_state(id, body).await
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Wled {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let state = match self.get_state(id).await {
+            Ok(state) => state,
+            Err(super::Error::RequestFailed(e)) if e.is_connect() || e.is_timeout() => {
+                tracing::debug!(error = %e, "WLED controller is unreachable");
+                return Ok(LightStatus::Offline);
+            }
+            Err(e) => return Err(e),
+        };
+
+        tracing::debug!("Got status {state:#?}");
+
+        let brightness = (f64::from(state.bri) * 100.0 / 255.0).round() as u8;
+
+        let primary = state
+            .seg
+            .iter()
+            .find(|s| s.id == state.mainseg)
+            .or(state.seg.first())
+            .and_then(|s| s.col.first())
+            .and_then(|c| Some([*c.first()?, *c.get(1)?, *c.get(2)?]));
+
+        let mut color_temperature = None;
+        let mut color = None;
+        if let Some(rgb) = primary {
+            color_temperature = self
+                .requested
+                .color_temperature(id, |k| color::to_rgb8(color::kelvin_to_rgb(k)) == rgb);
+
+            if color_temperature.is_none() {
+                let (hue, saturation) = self
+                    .requested
+                    .color(id, |h, s| color::to_rgb8(color::hs_to_rgb(h, s)) == rgb)
+                    .unwrap_or_else(|| {
+                        let (r, g, b) = color::from_rgb8(rgb);
+                        color::rgb_to_hs(r, g, b)
+                    });
+                color = Some(super::Color { hue, saturation });
+            }
+        }
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: state.on,
+            brightness: Some(brightness),
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        self.post_state(id, serde_json::json!({ "on": switched_on }))
+            .await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let bri = (f64::from(brightness.clamp(0, 100)) * 255.0 / 100.0).round() as u8;
+        self.post_state(id, serde_json::json!({ "bri": bri })).await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let rgb = color::to_rgb8(color::kelvin_to_rgb(temp));
+        self.set_primary_color(id, rgb).await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let rgb = color::to_rgb8(color::hs_to_rgb(hue, saturation));
+        self.set_primary_color(id, rgb).await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    pub struct Segment {
+        #[serde(default)]
+        pub id: u8,
+        /// Primary, secondary and tertiary colors as RGB(W) values
+        #[serde(default)]
+        pub col: Vec<Vec<u8>>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct State {
+        pub on: bool,
+        pub bri: u8,
+        #[serde(default)]
+        pub mainseg: u8,
+        #[serde(default)]
+        pub seg: Vec<Segment>,
+    }
+}
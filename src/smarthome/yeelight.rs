@@ -0,0 +1,556 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use api_models::*;
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpStream, UdpSocket},
+    time::Instant,
+};
+
+use crate::config::Config;
+
+use super::{
+    color, requested::RequestedValues, DeviceInfo, LightOptions, LightStatus, SmartHomeApi,
+};
+
+const PORT: u16 = 55443;
+
+const DISCOVERY_ADDRESS: &str = "239.255.255.250:1982";
+
+const DISCOVERY_REQUEST: &str = "M-SEARCH * HTTP/1.1\r\n\
+    HOST: 239.255.255.250:1982\r\n\
+    MAN: \"ssdp:discover\"\r\n\
+    ST: wifi_bulb\r\n";
+
+/// The period bulbs count their command quota over
+const QUOTA_PERIOD: Duration = Duration::from_secs(60);
+
+/// Color temperature range supported by Yeelight bulbs
+const KELVIN_RANGE: (u16, u16) = (1700, 6500);
+
+const PROPERTIES: [&str; 7] = ["power", "bright", "ct", "rgb", "hue", "sat", "color_mode"];
+
+pub struct Yeelight {
+    _config: Arc<Config>,
+    timeout: Duration,
+    commands_per_minute: usize,
+    /// Addresses of discovered bulbs by bulb ID
+    discovered: Mutex<HashMap<String, SocketAddr>>,
+    /// Times of the commands sent to each address within the quota period
+    sent_commands: Mutex<HashMap<String, VecDeque<Instant>>>,
+    requested: RequestedValues,
+    request_id: AtomicU64,
+}
+
+/// A bulb found with discovery
+struct DiscoveredBulb {
+    id: String,
+    address: SocketAddr,
+    model: Option<String>,
+    name: Option<String>,
+}
+
+impl Yeelight {
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let yeelight_config = &config.smart_home.yeelight;
+
+        if yeelight_config.commands_per_minute == 0 {
+            return Err(super::Error::Configuration(
+                "Yeelight commands_per_minute must be positive".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            timeout: Duration::from_millis(yeelight_config.timeout_ms),
+            commands_per_minute: yeelight_config.commands_per_minute,
+            discovered: Default::default(),
+            sent_commands: Default::default(),
+            requested: Default::default(),
+            request_id: AtomicU64::new(1),
+            _config: config,
+        })
+    }
+
+    /// Device IDs are either bulb IDs reported by discovery, e.g.
+    /// `0x000000000015243f`, or bulb host names and IP addresses
+    fn is_bulb_id(id: &str) -> super::Result<bool> {
+        if let Some(hex) = id.strip_prefix("0x") {
+            if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(super::Error::InvalidId(id.to_string()));
+            }
+            return Ok(true);
+        }
+
+        let valid_host = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'));
+        if !valid_host {
+            return Err(super::Error::InvalidId(id.to_string()));
+        }
+        Ok(false)
+    }
+
+    /// Address of the bulb, or `None` if a bulb with the ID was not
+    /// discovered
+    async fn address(&self, id: &str) -> super::Result<Option<String>> {
+        if !Self::is_bulb_id(id)? {
+            let has_port = id
+                .rsplit_once(':')
+                .is_some_and(|(_, port)| port.parse::<u16>().is_ok() && !id.ends_with(']'));
+            return Ok(Some(if has_port {
+                id.to_string()
+            } else {
+                format!("{id}:{PORT}")
+            }));
+        }
+
+        if let Some(address) = self.discovered.lock().unwrap().get(id) {
+            return Ok(Some(address.to_string()));
+        }
+
+        self.discover().await?;
+        let address = self.discovered.lock().unwrap().get(id).copied();
+        Ok(address.map(|a| a.to_string()))
+    }
+
+    /// Find bulbs with an SSDP-style multicast search
+    async fn discover(&self) -> super::Result<Vec<DiscoveredBulb>> {
+        tracing::debug!("Discovering Yeelight bulbs");
+
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        socket
+            .send_to(DISCOVERY_REQUEST.as_bytes(), DISCOVERY_ADDRESS)
+            .await?;
+
+        let mut bulbs: Vec<DiscoveredBulb> = Vec::new();
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0; 2048];
+        while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, _) = res?;
+            let Some(bulb) = parse_discovery_response(&buf[..len]) else {
+                continue;
+            };
+            // Bulbs answer several times
+            if bulbs.iter().any(|b| b.id == bulb.id) {
+                continue;
+            }
+
+            tracing::debug!(id = bulb.id, address = %bulb.address, "Discovered Yeelight bulb");
+            self.discovered
+                .lock()
+                .unwrap()
+                .insert(bulb.id.clone(), bulb.address);
+            bulbs.push(bulb);
+        }
+
+        Ok(bulbs)
+    }
+
+    /// Count a command sent to the address, unless that would go over the
+    /// command quota of the bulb. Then the time until there is quota again is
+    /// returned. Commands are not delayed until there is quota, so that
+    /// reconciling fails fast and is retried once the quota frees up instead.
+    fn use_quota(&self, address: &str) -> Result<(), Duration> {
+        let mut sent_commands = self.sent_commands.lock().unwrap();
+        let sent = sent_commands.entry(address.to_string()).or_default();
+        let now = Instant::now();
+        while sent.front().is_some_and(|&t| now - t >= QUOTA_PERIOD) {
+            sent.pop_front();
+        }
+        if sent.len() < self.commands_per_minute {
+            sent.push_back(now);
+            return Ok(());
+        }
+        let oldest = sent.front().copied().unwrap_or(now);
+        Err(QUOTA_PERIOD.saturating_sub(now - oldest))
+    }
+
+    /// Record that the bulb has no quota left, e.g. because other clients
+    /// have been sending it commands
+    fn quota_exceeded(&self, address: &str) {
+        let mut sent_commands = self.sent_commands.lock().unwrap();
+        let sent = sent_commands.entry(address.to_string()).or_default();
+        let now = Instant::now();
+        sent.clear();
+        sent.extend(std::iter::repeat_n(now, self.commands_per_minute));
+    }
+
+    /// Send a command and wait for its result. Commands over the quota fail
+    /// without being sent.
+    async fn call(
+        &self,
+        address: &str,
+        method: &str,
+        params: &serde_json::Value,
+    ) -> Result<Vec<serde_json::Value>, CallError> {
+        if let Err(retry_after) = self.use_quota(address) {
+            tracing::debug!(address, "Yeelight command quota used");
+            return Err(CallError::QuotaExceeded(retry_after));
+        }
+
+        let id = self.request_id.fetch_add(1, Ordering::Relaxed);
+        let request = serde_json::json!({ "id": id, "method": method, "params": params });
+
+        tracing::debug!(address, request = request.to_string(), "Sending command");
+
+        let response = tokio::time::timeout(self.timeout, async {
+            let mut stream = TcpStream::connect(address).await?;
+            stream
+                .write_all(format!("{request}\r\n").as_bytes())
+                .await?;
+
+            let mut lines = BufReader::new(stream).lines();
+            while let Some(line) = lines.next_line().await? {
+                // Bulbs also send property change notifications
+                match serde_json::from_str::<Response>(&line) {
+                    Ok(response) if response.id == Some(id) => return Ok(response),
+                    _ => continue,
+                }
+            }
+            Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+        })
+        .await
+        .map_err(|_| CallError::Unreachable)?
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::HostUnreachable
+            | std::io::ErrorKind::NetworkUnreachable => CallError::Unreachable,
+            _ => CallError::Failed(e.into()),
+        })?;
+
+        match response.error {
+            Some(e) if e.message.contains("quota") => {
+                tracing::info!(address, "Yeelight command quota exceeded");
+                self.quota_exceeded(address);
+                Err(CallError::QuotaExceeded(QUOTA_PERIOD))
+            }
+            Some(e) if e.message.contains("not supported") => Err(CallError::Failed(
+                super::Error::UnsupportedCapability(capability_of(method)),
+            )),
+            Some(e) => Err(CallError::Failed(super::Error::Communication(format!(
+                "{method} failed: {} ({})",
+                e.message, e.code
+            )))),
+            None => Ok(response.result.unwrap_or_default()),
+        }
+    }
+
+    async fn command(
+        &self,
+        id: &str,
+        method: &str,
+        params: serde_json::Value,
+    ) -> super::Result<()> {
+        let Some(address) = self.address(id).await? else {
+            return Err(super::Error::UnknownDeviceId);
+        };
+        self.call(&address, method, &params).await?;
+        Ok(())
+    }
+}
+
+/// Whether the bulb could be reached and accepted the command
+enum CallError {
+    Unreachable,
+    /// No quota left for this long
+    QuotaExceeded(Duration),
+    Failed(super::Error),
+}
+
+impl From<CallError> for super::Error {
+    fn from(value: CallError) -> Self {
+        match value {
+            CallError::Unreachable => {
+                super::Error::Communication("Bulb is unreachable".to_string())
+            }
+            CallError::QuotaExceeded(retry_after) => super::Error::Busy(retry_after),
+            CallError::Failed(e) => e,
+        }
+    }
+}
+
+fn capability_of(method: &str) -> &'static str {
+    match method {
+        "set_bright" => "brightness",
+        "set_ct_abx" => "color temperature",
+        "set_hsv" => "color",
+        _ => "switching",
+    }
+}
+
+fn parse_discovery_response(response: &[u8]) -> Option<DiscoveredBulb> {
+    let response = std::str::from_utf8(response).ok()?;
+
+    let mut id = None;
+    let mut address = None;
+    let mut model = None;
+    let mut name = None;
+    for line in response.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.to_ascii_lowercase().as_str() {
+            "id" => id = Some(value.to_string()),
+            "location" => address = value.strip_prefix("yeelight://")?.parse().ok(),
+            "model" => model = Some(value.to_string()),
+            "name" if !value.is_empty() => name = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    Some(DiscoveredBulb {
+        id: id?,
+        address: address?,
+        model,
+        name,
+    })
+}
+
+#[async_trait]
+impl SmartHomeApi for Yeelight {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        tracing::debug!("Getting status for device {id}");
+
+        let Some(address) = self.address(id).await? else {
+            return Ok(LightStatus::Offline);
+        };
+
+        let values = match self
+            .call(&address, "get_prop", &serde_json::json!(PROPERTIES))
+            .await
+        {
+            Ok(values) => values,
+            Err(CallError::Unreachable) => {
+                tracing::debug!(address, "Yeelight bulb is unreachable");
+                if Self::is_bulb_id(id)? {
+                    // The address may have changed
+                    self.discovered.lock().unwrap().remove(id);
+                }
+                return Ok(LightStatus::Offline);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let props = Properties::from_values(&values);
+
+        tracing::debug!("Got status {props:#?}");
+
+        let mut color_temperature = None;
+        let mut color = None;
+        match props.color_mode {
+            Some(ColorMode::ColorTemperature) => {
+                color_temperature = props.ct.map(|ct| {
+                    self.requested
+                        .color_temperature(id, |k| k.clamp(KELVIN_RANGE.0, KELVIN_RANGE.1) == ct)
+                        .unwrap_or(ct)
+                });
+            }
+            Some(ColorMode::Hsv) => {
+                if let (Some(hue), Some(sat)) = (props.hue, props.sat) {
+                    let hue = (f64::from(hue) / 3.6).round() as u8 % 100;
+                    let (hue, saturation) = self
+                        .requested
+                        .color(id, |rh, rs| rh.abs_diff(hue) <= 1 && rs == sat)
+                        .unwrap_or((hue, sat));
+                    color = Some(super::Color { hue, saturation });
+                }
+            }
+            Some(ColorMode::Rgb) => {
+                if let Some(rgb) = props.rgb {
+                    let rgb = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+                    let (hue, saturation) = self
+                        .requested
+                        .color(id, |h, s| color::to_rgb8(color::hs_to_rgb(h, s)) == rgb)
+                        .unwrap_or_else(|| {
+                            let (r, g, b) = color::from_rgb8(rgb);
+                            color::rgb_to_hs(r, g, b)
+                        });
+                    color = Some(super::Color { hue, saturation });
+                }
+            }
+            None => (),
+        }
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: props.power,
+            brightness: props.bright.map(|bright| {
+                self.requested
+                    .brightness(id, |b| b.clamp(1, 100) == bright)
+                    .unwrap_or(bright)
+            }),
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let power = if switched_on { "on" } else { "off" };
+        self.command(id, "set_power", serde_json::json!([power, "sudden", 0]))
+            .await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        // Bulbs can't be dimmed to zero without switching them off
+        let bright = brightness.clamp(1, 100);
+        self.command(id, "set_bright", serde_json::json!([bright, "sudden", 0]))
+            .await?;
+
+        self.requested.set_brightness(id, brightness);
+        Ok(())
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let ct = temp.clamp(KELVIN_RANGE.0, KELVIN_RANGE.1);
+        self.command(id, "set_ct_abx", serde_json::json!([ct, "sudden", 0]))
+            .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let hue_degrees = (f64::from(hue) * 3.6).round() as u16 % 360;
+        let sat = saturation.clamp(0, 100);
+        let params = serde_json::json!([hue_degrees, sat, "sudden", 0]);
+        self.command(id, "set_hsv", params).await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    async fn list_devices(&self) -> super::Result<Vec<DeviceInfo>> {
+        let bulbs = self.discover().await?;
+        Ok(bulbs
+            .into_iter()
+            .map(|b| DeviceInfo {
+                id: b.id,
+                name: b.name.or_else(|| Some(b.address.ip().to_string())),
+                model: b.model,
+            })
+            .collect())
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    pub struct ResponseError {
+        pub code: i32,
+        pub message: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Response {
+        pub id: Option<u64>,
+        pub result: Option<Vec<serde_json::Value>>,
+        pub error: Option<ResponseError>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum ColorMode {
+        Rgb,
+        ColorTemperature,
+        Hsv,
+    }
+
+    /// Values of the properties requested with `get_prop`. Bulbs return
+    /// every value as a string, and empty strings for unsupported ones.
+    #[derive(Debug)]
+    pub struct Properties {
+        pub power: bool,
+        pub bright: Option<u8>,
+        pub ct: Option<u16>,
+        pub rgb: Option<u32>,
+        pub hue: Option<u16>,
+        pub sat: Option<u8>,
+        pub color_mode: Option<ColorMode>,
+    }
+
+    impl Properties {
+        /// Parse the values in the order of `PROPERTIES`
+        pub fn from_values(values: &[serde_json::Value]) -> Self {
+            let value = |i: usize| values.get(i).and_then(|v| v.as_str()).unwrap_or_default();
+
+            Self {
+                power: value(0) == "on",
+                bright: value(1).parse().ok(),
+                ct: value(2).parse().ok(),
+                rgb: value(3).parse().ok(),
+                hue: value(4).parse().ok(),
+                sat: value(5).parse().ok(),
+                color_mode: match value(6) {
+                    "1" => Some(ColorMode::Rgb),
+                    "2" => Some(ColorMode::ColorTemperature),
+                    "3" => Some(ColorMode::Hsv),
+                    _ => None,
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::test_config;
+
+    use super::*;
+
+    fn yeelight() -> Yeelight {
+        let config = test_config(serde_json::json!({
+            "platform": "Yeelight",
+            "yeelight": { "commands_per_minute": 2 },
+        }));
+        Yeelight::new(config).unwrap()
+    }
+
+    #[test]
+    fn reports_time_until_quota_frees_up() {
+        let yeelight = yeelight();
+        let address = "192.0.2.1:55443";
+
+        // One command sent before the quota period and one within it
+        let now = Instant::now();
+        yeelight.sent_commands.lock().unwrap().insert(
+            address.to_string(),
+            [now - Duration::from_secs(70), now - Duration::from_secs(20)].into(),
+        );
+
+        assert_eq!(yeelight.use_quota(address), Ok(()));
+        let retry_after = yeelight.use_quota(address).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(40), "{retry_after:?}");
+        assert!(retry_after > Duration::from_secs(39), "{retry_after:?}");
+
+        // Other bulbs have their own quota
+        assert_eq!(yeelight.use_quota("192.0.2.2:55443"), Ok(()));
+
+        yeelight.quota_exceeded(address);
+        let retry_after = yeelight.use_quota(address).unwrap_err();
+        assert!(retry_after > Duration::from_secs(59), "{retry_after:?}");
+    }
+
+    #[tokio::test]
+    async fn does_not_send_commands_over_quota() {
+        let yeelight = yeelight();
+        // Nothing listens here, so sending would fail differently
+        let address = "127.0.0.1:1";
+        yeelight.quota_exceeded(address);
+
+        let result = yeelight
+            .command(address, "set_power", serde_json::json!(["on", "sudden", 0]))
+            .await;
+        assert!(matches!(result, Err(crate::smarthome::Error::Busy(_))));
+    }
+}
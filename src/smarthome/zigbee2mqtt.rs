@@ -0,0 +1,427 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use api_models::*;
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, EventLoop, Packet, QoS};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::config::Config;
+
+use super::{
+    color,
+    events::{Backoff, EventChannel},
+    mqtt,
+    requested::RequestedValues,
+    DeviceEventStream, LightOptions, LightStatus, SmartHomeApi,
+};
+
+const CLIENT_ID: &str = "light-operator";
+
+/// How long to wait for Zigbee2MQTT to answer a state request
+const STATE_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub struct Zigbee2Mqtt {
+    _config: Arc<Config>,
+    client: AsyncClient,
+    base_topic: String,
+    cache: Arc<DeviceCache>,
+    events: Arc<EventChannel>,
+    requested: RequestedValues,
+}
+
+/// Latest state and availability messages received for each device
+#[derive(Default)]
+struct DeviceCache {
+    devices: Mutex<HashMap<String, CachedDevice>>,
+    /// Friendly names from the bridge device list, once it has been received
+    known_devices: Mutex<Option<HashSet<String>>>,
+    updated: Notify,
+}
+
+#[derive(Default, Clone)]
+struct CachedDevice {
+    state: Option<DeviceState>,
+    available: Option<bool>,
+}
+
+impl Zigbee2Mqtt {
+    /// Connects to the broker in the background, so this must be called
+    /// within a Tokio runtime
+    pub fn new(config: Arc<Config>) -> super::Result<Self> {
+        let z2m_config = &config.smart_home.zigbee2mqtt;
+        let (client, event_loop) = mqtt::create_client(&z2m_config.mqtt, CLIENT_ID)?;
+        let base_topic = z2m_config.base_topic.trim_end_matches('/').to_string();
+
+        let cache: Arc<DeviceCache> = Default::default();
+        let events: Arc<EventChannel> = Default::default();
+
+        tokio::spawn(run_event_loop(
+            event_loop,
+            client.clone(),
+            base_topic.clone(),
+            cache.clone(),
+            events.clone(),
+        ));
+
+        Ok(Self {
+            _config: config,
+            client,
+            base_topic,
+            cache,
+            events,
+            requested: Default::default(),
+        })
+    }
+
+    /// Device IDs are Zigbee2MQTT friendly names
+    fn validate_device_id(id: &str) -> super::Result<()> {
+        let valid = !id.is_empty()
+            && !id.contains(['+', '#'])
+            && !id.starts_with('/')
+            && !id.ends_with('/')
+            && !id.starts_with("bridge/");
+
+        if !valid {
+            return Err(super::Error::InvalidId(id.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn publish(
+        &self,
+        id: &str,
+        action: &str,
+        payload: serde_json::Value,
+    ) -> super::Result<()> {
+        Self::validate_device_id(id)?;
+
+        let topic = format!("{}/{id}/{action}", self.base_topic);
+        tracing::debug!(topic, payload = payload.to_string(), "Publishing");
+
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload.to_string())
+            .await?;
+        Ok(())
+    }
+
+    /// Get the cached device state, requesting it from Zigbee2MQTT if it has
+    /// not been received yet
+    async fn device_state(&self, id: &str) -> super::Result<CachedDevice> {
+        // States may have changed without us hearing about it
+        if !self.events.is_connected() {
+            return Err(super::Error::Communication(
+                "Not connected to MQTT broker".to_string(),
+            ));
+        }
+
+        if let Some(device) = self.cache.get(id).filter(|d| d.state.is_some()) {
+            return Ok(device);
+        }
+
+        self.publish(id, "get", serde_json::json!({ "state": "" }))
+            .await?;
+
+        let deadline = Instant::now() + STATE_TIMEOUT;
+        loop {
+            let updated = self.cache.updated.notified();
+            if let Some(device) = self.cache.get(id).filter(|d| d.state.is_some()) {
+                return Ok(device);
+            }
+            if tokio::time::timeout_at(deadline, updated).await.is_err() {
+                break;
+            }
+        }
+
+        let known = self
+            .cache
+            .known_devices
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|devices| devices.contains(id));
+        if known == Some(false) {
+            return Err(super::Error::UnknownDeviceId);
+        }
+
+        Ok(self.cache.get(id).unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl SmartHomeApi for Zigbee2Mqtt {
+    async fn get_light_status(&self, id: &str) -> super::Result<LightStatus> {
+        Self::validate_device_id(id)?;
+
+        tracing::debug!("Getting status for device {id}");
+
+        let device = self.device_state(id).await?;
+        let (Some(state), Some(true) | None) = (device.state, device.available) else {
+            return Ok(LightStatus::Offline);
+        };
+
+        tracing::debug!("Got status {state:#?}");
+
+        let brightness = state
+            .brightness
+            .map(|b| (b.clamp(0.0, 254.0) * 100.0 / 254.0).round() as u8);
+
+        let color_temperature = state
+            .color_temp
+            .filter(|_| state.color_mode.as_deref() == Some("color_temp"))
+            .map(|mired| {
+                self.requested
+                    .color_temperature(id, |k| color::kelvin_to_mired(k) == mired)
+                    .unwrap_or_else(|| color::mired_to_kelvin(mired))
+            });
+
+        let color = state
+            .color
+            .filter(|_| color_temperature.is_none())
+            .and_then(|c| match (c.hue, c.saturation, c.x, c.y) {
+                (Some(h), Some(s), _, _) => Some((
+                    (h / 3.6).round() as u8 % 100,
+                    s.round().clamp(0.0, 100.0) as u8,
+                )),
+                (_, _, Some(x), Some(y)) => Some(color::xy_to_hs(color::Xy { x, y })),
+                _ => None,
+            })
+            .map(|(hue, saturation)| {
+                let (hue, saturation) = self
+                    .requested
+                    .color(id, |rh, rs| {
+                        rh.abs_diff(hue) <= 1 && rs.abs_diff(saturation) <= 1
+                    })
+                    .unwrap_or((hue, saturation));
+                super::Color { hue, saturation }
+            });
+
+        Ok(LightStatus::Online(LightOptions {
+            switched_on: state.state.as_deref() == Some("ON"),
+            brightness,
+            color_temperature,
+            color,
+            effect: None,
+        }))
+    }
+
+    async fn set_switched_on(&self, id: &str, switched_on: bool) -> super::Result<()> {
+        let state = if switched_on { "ON" } else { "OFF" };
+        self.publish(id, "set", serde_json::json!({ "state": state }))
+            .await
+    }
+
+    async fn set_brightness(&self, id: &str, brightness: u8) -> super::Result<()> {
+        let brightness = (f64::from(brightness.clamp(0, 100)) * 254.0 / 100.0).round();
+        self.publish(id, "set", serde_json::json!({ "brightness": brightness }))
+            .await
+    }
+
+    async fn set_color_temperature(&self, id: &str, temp: u16) -> super::Result<()> {
+        let mired = color::kelvin_to_mired(temp);
+        self.publish(id, "set", serde_json::json!({ "color_temp": mired }))
+            .await?;
+
+        self.requested.set_color_temperature(id, temp);
+        Ok(())
+    }
+
+    async fn set_color(&self, id: &str, hue: u8, saturation: u8) -> super::Result<()> {
+        let payload = serde_json::json!({
+            "color": { "hue": f64::from(hue) * 3.6, "saturation": saturation }
+        });
+        self.publish(id, "set", payload).await?;
+
+        self.requested.set_color(id, hue, saturation);
+        Ok(())
+    }
+
+    fn device_events(&self) -> Option<DeviceEventStream> {
+        Some(self.events.subscribe())
+    }
+
+    fn events_connected(&self) -> bool {
+        self.events.is_connected()
+    }
+}
+
+impl DeviceCache {
+    fn get(&self, id: &str) -> Option<CachedDevice> {
+        self.devices.lock().unwrap().get(id).cloned()
+    }
+
+    /// Forget all device states, which are requested again after reconnecting
+    fn clear(&self) {
+        self.devices.lock().unwrap().clear();
+    }
+
+    fn handle_message(&self, base_topic: &str, topic: &str, payload: &[u8]) -> Option<String> {
+        let subtopic = topic.strip_prefix(base_topic)?.strip_prefix('/')?;
+
+        if subtopic == "bridge/devices" {
+            let devices: Vec<BridgeDevice> = serde_json::from_slice(payload).ok()?;
+            let names = devices.into_iter().map(|d| d.friendly_name).collect();
+            *self.known_devices.lock().unwrap() = Some(names);
+            return None;
+        }
+        if subtopic.starts_with("bridge/")
+            || subtopic.ends_with("/set")
+            || subtopic.ends_with("/get")
+        {
+            return None;
+        }
+
+        let mut devices = self.devices.lock().unwrap();
+        let id = if let Some(id) = subtopic.strip_suffix("/availability") {
+            // Either a plain `online`/`offline` string or a JSON object
+            let available = match serde_json::from_slice::<Availability>(payload) {
+                Ok(availability) => availability.state == "online",
+                Err(_) => payload == b"online",
+            };
+            devices.entry(id.to_string()).or_default().available = Some(available);
+            id
+        } else {
+            let state: DeviceState = serde_json::from_slice(payload).ok()?;
+            devices.entry(subtopic.to_string()).or_default().state = Some(state);
+            subtopic
+        };
+
+        self.updated.notify_waiters();
+        Some(id.to_string())
+    }
+}
+
+async fn run_event_loop(
+    mut event_loop: EventLoop,
+    client: AsyncClient,
+    base_topic: String,
+    cache: Arc<DeviceCache>,
+    events: Arc<EventChannel>,
+) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to MQTT broker");
+                backoff.reset();
+                // Subscriptions don't survive reconnects with a clean session
+                let topic = format!("{base_topic}/#");
+                if let Err(e) = client.subscribe(topic, QoS::AtMostOnce).await {
+                    tracing::warn!(error = %e, "Subscribing to Zigbee2MQTT topics failed");
+                }
+                events.set_connected(true);
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                if let Some(id) =
+                    cache.handle_message(&base_topic, &publish.topic, &publish.payload)
+                {
+                    events.send_changed(id);
+                }
+            }
+            Ok(_) => (),
+            Err(e) => {
+                events.set_connected(false);
+                cache.clear();
+                let delay = backoff.next_delay();
+                tracing::warn!(error = %e, "MQTT connection failed, retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+pub(super) mod api_models {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug, Clone, Default)]
+    #[serde(default)]
+    pub struct ColorValue {
+        pub hue: Option<f64>,
+        pub saturation: Option<f64>,
+        pub x: Option<f64>,
+        pub y: Option<f64>,
+    }
+
+    #[derive(Deserialize, Debug, Clone, Default)]
+    #[serde(default)]
+    pub struct DeviceState {
+        pub state: Option<String>,
+        pub brightness: Option<f64>,
+        pub color_temp: Option<u16>,
+        pub color_mode: Option<String>,
+        pub color: Option<ColorValue>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Availability {
+        pub state: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct BridgeDevice {
+        pub friendly_name: String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::test_config;
+
+    use super::*;
+
+    fn zigbee2mqtt() -> Zigbee2Mqtt {
+        let config = test_config(serde_json::json!({
+            "platform": "Zigbee2Mqtt",
+            "zigbee2mqtt": {
+                // Nothing listens here, so the client never connects
+                "mqtt": { "broker_url": "mqtt://127.0.0.1:1" },
+            },
+        }));
+        Zigbee2Mqtt::new(config).unwrap()
+    }
+
+    #[test]
+    fn caches_states_and_availability() {
+        let cache = DeviceCache::default();
+
+        assert_eq!(
+            cache.handle_message("zigbee2mqtt", "zigbee2mqtt/lamp", br#"{"state":"ON"}"#),
+            Some("lamp".to_string())
+        );
+        assert_eq!(
+            cache.handle_message(
+                "zigbee2mqtt",
+                "zigbee2mqtt/lamp/availability",
+                br#"{"state":"offline"}"#
+            ),
+            Some("lamp".to_string())
+        );
+        assert_eq!(
+            cache.handle_message("zigbee2mqtt", "zigbee2mqtt/lamp/set", br#"{"state":"OFF"}"#),
+            None
+        );
+
+        let device = cache.get("lamp").unwrap();
+        assert_eq!(device.state.unwrap().state.as_deref(), Some("ON"));
+        assert_eq!(device.available, Some(false));
+
+        cache.clear();
+        assert!(cache.get("lamp").is_none());
+    }
+
+    #[tokio::test]
+    async fn does_not_report_cached_states_while_disconnected() {
+        let z2m = zigbee2mqtt();
+        z2m.cache
+            .handle_message("zigbee2mqtt", "zigbee2mqtt/lamp", br#"{"state":"ON"}"#);
+
+        assert!(matches!(
+            z2m.get_light_status("lamp").await,
+            Err(crate::smarthome::Error::Communication(_))
+        ));
+    }
+}